@@ -0,0 +1,71 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use libsignal_protocol_rust::*;
+use rand::rngs::OsRng;
+
+const BATCH_SIZE: usize = 64;
+
+pub fn xeddsa_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+    let key_pair = KeyPair::generate(&mut csprng);
+    let message = [0x42; 64];
+    let signature = key_pair.calculate_signature(&message, &mut csprng)?;
+
+    c.bench_function("xeddsa sign", |b| {
+        b.iter(|| {
+            key_pair
+                .calculate_signature(&message, &mut csprng)
+                .expect("success")
+        })
+    });
+    c.bench_function("xeddsa verify", |b| {
+        b.iter(|| {
+            assert!(key_pair
+                .public_key
+                .verify_signature(&message, &signature)
+                .expect("success"))
+        })
+    });
+
+    let key_pairs: Vec<KeyPair> = (0..BATCH_SIZE)
+        .map(|_| KeyPair::generate(&mut csprng))
+        .collect();
+    let signatures = key_pairs
+        .iter()
+        .map(|key_pair| key_pair.calculate_signature(&message, &mut csprng))
+        .collect::<Result<Vec<_>, _>>()?;
+    let entries: Vec<(&PublicKey, &[u8], &[u8])> = key_pairs
+        .iter()
+        .zip(&signatures)
+        .map(|(key_pair, signature)| (&key_pair.public_key, &message[..], &signature[..]))
+        .collect();
+
+    let mut group = c.benchmark_group("xeddsa verify 64");
+    group.bench_function("individually", |b| {
+        b.iter(|| {
+            for (key, message, signature) in &entries {
+                assert!(key.verify_signature(message, signature).expect("success"))
+            }
+        })
+    });
+    group.bench_function("batch", |b| {
+        b.iter(|| PublicKey::verify_signatures_batch(&entries, &mut csprng).expect("success"))
+    });
+    group.finish();
+
+    Ok(())
+}
+
+pub fn xeddsa(mut c: &mut Criterion) {
+    xeddsa_result(&mut c).expect("success");
+}
+
+criterion_group!(benches, xeddsa);
+
+criterion_main!(benches);
@@ -0,0 +1,44 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use libsignal_protocol_rust::*;
+use rand::rngs::OsRng;
+
+pub fn fingerprint_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+    let alice_identity = IdentityKeyPair::generate(&mut csprng);
+    let bob_identity = IdentityKeyPair::generate(&mut csprng);
+
+    let mut group = c.benchmark_group("fingerprint");
+    // Each iteration takes milliseconds, so the default 100 samples would take a while.
+    group.sample_size(20);
+    group.bench_function("new", |b| {
+        b.iter(|| {
+            Fingerprint::new(
+                1,
+                DEFAULT_FINGERPRINT_ITERATIONS,
+                b"+14152222222",
+                alice_identity.identity_key(),
+                b"+14153333333",
+                bob_identity.identity_key(),
+            )
+            .expect("success")
+        })
+    });
+    group.finish();
+
+    Ok(())
+}
+
+pub fn fingerprint(mut c: &mut Criterion) {
+    fingerprint_result(&mut c).expect("success");
+}
+
+criterion_group!(benches, fingerprint);
+
+criterion_main!(benches);
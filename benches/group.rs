@@ -0,0 +1,85 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use libsignal_protocol_rust::*;
+use rand::rngs::OsRng;
+use std::convert::TryFrom;
+use std::time::SystemTime;
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+const MESSAGE_SIZES: &[usize] = &[16, 256, 4096, 65536];
+
+pub fn group_encrypt_decrypt_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1);
+    let group_sender =
+        SenderKeyName::new("summer camp planning committee".to_owned(), sender_address)?;
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let sent_distribution_message =
+        create_sender_key_distribution_message(&group_sender, &mut alice_store, &mut csprng, None)?;
+    let recv_distribution_message =
+        SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())?;
+    process_sender_key_distribution_message(
+        &group_sender,
+        &recv_distribution_message,
+        &mut bob_store,
+        None,
+    )?;
+
+    let mut encrypt = c.benchmark_group("group encrypt");
+    for &size in MESSAGE_SIZES {
+        let plaintext = vec![0x42; size];
+        encrypt.throughput(Throughput::Bytes(size as u64));
+        encrypt.bench_with_input(BenchmarkId::from_parameter(size), &plaintext, |b, p| {
+            b.iter(|| {
+                group_encrypt(&mut alice_store, &group_sender, p, &mut csprng, None)
+                    .expect("success")
+            })
+        });
+    }
+    encrypt.finish();
+
+    let mut decrypt = c.benchmark_group("group decrypt");
+    for &size in MESSAGE_SIZES {
+        let ciphertext = group_encrypt(
+            &mut alice_store,
+            &group_sender,
+            &vec![0x42; size],
+            &mut csprng,
+            None,
+        )?;
+        decrypt.throughput(Throughput::Bytes(size as u64));
+        decrypt.bench_with_input(BenchmarkId::from_parameter(size), &ciphertext, |b, m| {
+            b.iter_batched(
+                || bob_store.clone(),
+                |mut bob_store| {
+                    group_decrypt(m, &mut bob_store, &group_sender, SystemTime::now(), None)
+                        .expect("success")
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    decrypt.finish();
+
+    Ok(())
+}
+
+pub fn group_encrypt_decrypt(mut c: &mut Criterion) {
+    group_encrypt_decrypt_result(&mut c).expect("success");
+}
+
+criterion_group!(benches, group_encrypt_decrypt);
+
+criterion_main!(benches);
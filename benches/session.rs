@@ -5,8 +5,10 @@
 // SPDX-License-Identifier: GPL-3.0-only
 //
 
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
 use libsignal_protocol_rust::*;
+use rand::rngs::OsRng;
+use std::time::SystemTime;
 
 #[path = "../tests/support/mod.rs"]
 mod support;
@@ -22,8 +24,8 @@ pub fn session_encrypt_result(c: &mut Criterion) -> Result<(), SignalProtocolErr
     let mut alice_store = support::test_in_memory_protocol_store();
     let mut bob_store = support::test_in_memory_protocol_store();
 
-    alice_store.store_session(&bob_address, &alice_session_record)?;
-    bob_store.store_session(&alice_address, &bob_session_record)?;
+    alice_store.store_session(&bob_address, &alice_session_record, None)?;
+    bob_store.store_session(&alice_address, &bob_session_record, None)?;
 
     let message_to_decrypt = support::encrypt(&mut alice_store, &bob_address, "a short message")?;
 
@@ -63,8 +65,8 @@ pub fn session_encrypt_decrypt_result(c: &mut Criterion) -> Result<(), SignalPro
     let mut alice_store = support::test_in_memory_protocol_store();
     let mut bob_store = support::test_in_memory_protocol_store();
 
-    alice_store.store_session(&bob_address, &alice_session_record)?;
-    bob_store.store_session(&alice_address, &bob_session_record)?;
+    alice_store.store_session(&bob_address, &alice_session_record, None)?;
+    bob_store.store_session(&alice_address, &bob_session_record, None)?;
 
     c.bench_function("session encrypt+decrypt 1 way", |b| {
         b.iter(|| {
@@ -89,6 +91,187 @@ pub fn session_encrypt_decrypt_result(c: &mut Criterion) -> Result<(), SignalPro
     Ok(())
 }
 
+pub fn session_setup_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+    let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+    let bob_pre_key_bundle = support::create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+
+    c.bench_function("session process_prekey_bundle", |b| {
+        b.iter_batched(
+            || alice_store.clone(),
+            |mut alice_store| {
+                process_prekey_bundle(
+                    &bob_address,
+                    &mut alice_store.session_store,
+                    &mut alice_store.identity_store,
+                    &bob_pre_key_bundle,
+                    &mut OsRng,
+                    SystemTime::now(),
+                    None,
+                )
+                .expect("success")
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    let pre_key_message = match support::encrypt(&mut alice_store, &bob_address, "a short message")?
+    {
+        CiphertextMessage::PreKeySignalMessage(m) => m,
+        _ => panic!("expected a pre-key message"),
+    };
+
+    c.bench_function("session process_prekey", |b| {
+        b.iter_batched(
+            || bob_store.clone(),
+            |mut bob_store| {
+                process_prekey(
+                    &pre_key_message,
+                    &alice_address,
+                    &mut SessionRecord::new_fresh(),
+                    &mut bob_store.identity_store,
+                    &mut bob_store.pre_key_store,
+                    &mut bob_store.signed_pre_key_store,
+                    SystemTime::now(),
+                    None,
+                )
+                .expect("success")
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    Ok(())
+}
+
+pub fn session_ratchet_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let (alice_session, bob_session) = support::initialize_sessions_v3()?;
+    let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    alice_store.store_session(&bob_address, &SessionRecord::new(alice_session), None)?;
+    bob_store.store_session(&alice_address, &SessionRecord::new(bob_session), None)?;
+
+    // A reply from Bob makes Alice's next message start a new chain, so decrypting it takes a
+    // DH ratchet step.
+    let ctext = support::encrypt(&mut alice_store, &bob_address, "a short message")?;
+    support::decrypt(&mut bob_store, &alice_address, &ctext)?;
+    let ctext = support::encrypt(&mut bob_store, &alice_address, "a short message")?;
+    support::decrypt(&mut alice_store, &bob_address, &ctext)?;
+    let message_with_new_ratchet_key =
+        support::encrypt(&mut alice_store, &bob_address, "a short message")?;
+
+    c.bench_function("session decrypt with DH ratchet step", |b| {
+        b.iter_batched(
+            || bob_store.clone(),
+            |mut bob_store| {
+                support::decrypt(
+                    &mut bob_store,
+                    &alice_address,
+                    &message_with_new_ratchet_key,
+                )
+                .expect("success")
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // Bob receives the start of Alice's new chain, so that later messages on it only skip ahead.
+    support::decrypt(
+        &mut bob_store,
+        &alice_address,
+        &message_with_new_ratchet_key,
+    )?;
+
+    let mut group = c.benchmark_group("session decrypt out of order");
+    for &gap in &[10usize, 100, 1000, 2000] {
+        let mut alice_store = alice_store.clone();
+        for _ in 0..gap {
+            support::encrypt(&mut alice_store, &bob_address, "a short message")?;
+        }
+        let late_message = support::encrypt(&mut alice_store, &bob_address, "a short message")?;
+
+        group.bench_with_input(BenchmarkId::from_parameter(gap), &late_message, |b, m| {
+            b.iter_batched(
+                || bob_store.clone(),
+                |mut bob_store| {
+                    support::decrypt(&mut bob_store, &alice_address, m).expect("success")
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+
+    Ok(())
+}
+
+pub fn session_record_result(c: &mut Criterion) -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+    let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+    let bob_pre_key_bundle = support::create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+
+    let mut serialize = c.benchmark_group("session record serialize");
+    let mut records = Vec::new();
+    for &archived in &[0usize, 10, 40] {
+        // Each new session from a bundle archives the previous one.
+        while records.len() <= archived {
+            process_prekey_bundle(
+                &bob_address,
+                &mut alice_store.session_store,
+                &mut alice_store.identity_store,
+                &bob_pre_key_bundle,
+                &mut csprng,
+                SystemTime::now(),
+                None,
+            )?;
+            let record = alice_store
+                .load_session(&bob_address, None)?
+                .expect("session");
+            records.push(record);
+        }
+        let record = records.last().expect("record");
+        assert_eq!(record.previous_sessions.len(), archived);
+
+        serialize.bench_with_input(BenchmarkId::from_parameter(archived), record, |b, r| {
+            b.iter(|| r.serialize().expect("success"))
+        });
+    }
+    serialize.finish();
+
+    let mut deserialize = c.benchmark_group("session record deserialize");
+    for &archived in &[0usize, 10, 40] {
+        let serialized = records[archived].serialize()?;
+        deserialize.bench_with_input(
+            BenchmarkId::from_parameter(archived),
+            &serialized,
+            |b, s| b.iter(|| SessionRecord::deserialize(s).expect("success")),
+        );
+    }
+    deserialize.finish();
+
+    Ok(())
+}
+
 pub fn session_encrypt(mut c: &mut Criterion) {
     session_encrypt_result(&mut c).expect("success");
 }
@@ -97,6 +280,25 @@ pub fn session_encrypt_decrypt(mut c: &mut Criterion) {
     session_encrypt_decrypt_result(&mut c).expect("success");
 }
 
-criterion_group!(benches, session_encrypt, session_encrypt_decrypt);
+pub fn session_setup(mut c: &mut Criterion) {
+    session_setup_result(&mut c).expect("success");
+}
+
+pub fn session_ratchet(mut c: &mut Criterion) {
+    session_ratchet_result(&mut c).expect("success");
+}
+
+pub fn session_record(mut c: &mut Criterion) {
+    session_record_result(&mut c).expect("success");
+}
+
+criterion_group!(
+    benches,
+    session_encrypt,
+    session_encrypt_decrypt,
+    session_setup,
+    session_ratchet,
+    session_record
+);
 
 criterion_main!(benches);
@@ -0,0 +1,32 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol_rust::*;
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    match (
+        PreKeySignalMessage::try_from(data),
+        PreKeySignalMessageRef::try_from(data),
+    ) {
+        (Ok(owned), Ok(borrowed)) => {
+            let borrowed = borrowed.into_owned();
+            assert_eq!(owned.registration_id(), borrowed.registration_id());
+            assert_eq!(owned.message().header(), borrowed.message().header());
+        }
+        (Err(_), Err(_)) => {}
+        (owned, borrowed) => panic!(
+            "parsers disagree: owned ok {}, borrowed ok {}",
+            owned.is_ok(),
+            borrowed.is_ok()
+        ),
+    }
+    let _ = CiphertextMessage::parse(data, CiphertextMessageType::PreKey).map(|m| m.inspect());
+});
@@ -0,0 +1,21 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol_rust::*;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(key) = PublicKey::deserialize(data) {
+        // Whatever was accepted must serialize back to a prefix of the input.
+        let serialized = key.serialize();
+        assert_eq!(&data[..serialized.len()], &serialized[..]);
+        let _ = key.verify_signature(data, data);
+    }
+    let _ = IdentityKey::decode(data);
+});
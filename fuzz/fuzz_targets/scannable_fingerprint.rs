@@ -0,0 +1,20 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol_rust::*;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(fingerprint) = ScannableFingerprint::deserialize(data) {
+        let _ = fingerprint.serialize();
+        let _ = fingerprint.compare(data);
+        let _ = fingerprint.verify(data);
+    }
+    let _ = ScannableGroupFingerprint::deserialize(data).map(|f| f.verify(data));
+});
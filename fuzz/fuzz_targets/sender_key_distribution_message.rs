@@ -0,0 +1,23 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol_rust::*;
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = SenderKeyDistributionMessage::try_from(data) {
+        let _ = message.id();
+        let _ = message.iteration();
+        let _ = message.chain_key();
+        let _ = message.signing_key();
+    }
+    let _ = CiphertextMessage::parse(data, CiphertextMessageType::SenderKeyDistribution)
+        .map(|m| m.inspect());
+});
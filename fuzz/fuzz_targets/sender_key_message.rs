@@ -0,0 +1,31 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol_rust::*;
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    match (
+        SenderKeyMessage::try_from(data),
+        SenderKeyMessageRef::try_from(data),
+    ) {
+        (Ok(owned), Ok(borrowed)) => {
+            assert_eq!(owned.key_id(), borrowed.key_id());
+            assert_eq!(owned.iteration(), borrowed.iteration());
+        }
+        (Err(_), Err(_)) => {}
+        (owned, borrowed) => panic!(
+            "parsers disagree: owned ok {}, borrowed ok {}",
+            owned.is_ok(),
+            borrowed.is_ok()
+        ),
+    }
+    let _ = CiphertextMessage::parse(data, CiphertextMessageType::SenderKey).map(|m| m.inspect());
+});
@@ -0,0 +1,27 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol_rust::*;
+use std::time::{Duration, SystemTime};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut record) = SessionRecord::deserialize(data) {
+        if let Ok(state) = record.session_state() {
+            let _ = state.session_version();
+            let _ = state.remote_identity_key();
+            let _ = state.local_identity_key();
+            let _ = state.root_key();
+            let _ = state.sender_ratchet_key();
+            let _ = state.get_sender_chain_key();
+        }
+        record.prune_expired(SystemTime::now(), Duration::from_secs(86400));
+        let _ = record.serialize();
+    }
+});
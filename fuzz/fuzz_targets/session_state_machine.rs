@@ -0,0 +1,173 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+#![no_main]
+
+//! Drives a session between two stores through an arbitrary sequence of encrypts, deliveries,
+//! drops, duplicates and reorderings. Decryption may fail, but must never panic, and a message
+//! that does decrypt must decrypt to what was sent.
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol_rust::*;
+use rand::rngs::StdRng;
+use rand::{CryptoRng, Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+struct Party {
+    address: ProtocolAddress,
+    store: InMemSignalProtocolStore,
+    // Messages in flight towards this party, serialized, with the plaintext they carry.
+    inbox: VecDeque<(CiphertextMessageType, Vec<u8>, Vec<u8>)>,
+}
+
+impl Party {
+    fn new<R: Rng + CryptoRng>(name: &str, rng: &mut R) -> Self {
+        let identity = IdentityKeyPair::generate(rng);
+        Self {
+            address: ProtocolAddress::new(name.to_owned(), 1),
+            store: InMemSignalProtocolStore::new(identity, rng.gen::<u32>() & 0x3fff)
+                .expect("in-memory store"),
+            inbox: VecDeque::new(),
+        }
+    }
+
+    fn bundle<R: Rng + CryptoRng>(&mut self, rng: &mut R) -> PreKeyBundle {
+        let pre_key = KeyPair::generate(rng);
+        let signed_pre_key = KeyPair::generate(rng);
+        let identity = self.store.get_identity_key_pair(None).expect("identity");
+        let signature = identity
+            .private_key()
+            .calculate_signature(&signed_pre_key.public_key.serialize(), rng)
+            .expect("sign");
+        self.store
+            .save_pre_key(1, &PreKeyRecord::new(1, &pre_key), None)
+            .expect("save pre key");
+        self.store
+            .save_signed_pre_key(
+                2,
+                &SignedPreKeyRecord::new(2, 0, &signed_pre_key, &signature),
+                None,
+            )
+            .expect("save signed pre key");
+        PreKeyBundle::new(
+            self.store.get_local_registration_id(None).expect("id"),
+            self.address.device_id(),
+            Some(1),
+            Some(pre_key.public_key),
+            2,
+            signed_pre_key.public_key,
+            signature.to_vec(),
+            *identity.identity_key(),
+        )
+        .expect("bundle")
+    }
+}
+
+fn send(from: &mut Party, to: &mut Party, counter: &mut u32) {
+    *counter += 1;
+    let plaintext = counter.to_be_bytes().to_vec();
+    if let Ok(message) = message_encrypt(
+        &plaintext,
+        &to.address,
+        &mut from.store.session_store,
+        &mut from.store.identity_store,
+        None,
+    ) {
+        to.inbox.push_back((
+            message.message_type(),
+            message.serialize().to_vec(),
+            plaintext,
+        ));
+    }
+}
+
+fn deliver<R: Rng + CryptoRng>(from: &Party, to: &mut Party, keep: bool, rng: &mut R) {
+    let (message_type, bytes, plaintext) = match if keep {
+        to.inbox.front().cloned()
+    } else {
+        to.inbox.pop_front()
+    } {
+        Some(m) => m,
+        None => return,
+    };
+    let message = CiphertextMessage::parse(&bytes, message_type).expect("sent message parses");
+    if let Ok(decrypted) = message_decrypt(
+        &message,
+        &from.address,
+        &mut to.store.session_store,
+        &mut to.store.identity_store,
+        &mut to.store.pre_key_store,
+        &mut to.store.signed_pre_key_store,
+        rng,
+        SystemTime::now(),
+        None,
+    ) {
+        assert_eq!(decrypted, plaintext);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut alice = Party::new("alice", &mut rng);
+    let mut bob = Party::new("bob", &mut rng);
+
+    let bundle = bob.bundle(&mut rng);
+    process_prekey_bundle(
+        &bob.address,
+        &mut alice.store.session_store,
+        &mut alice.store.identity_store,
+        &bundle,
+        &mut rng,
+        SystemTime::now(),
+        None,
+    )
+    .expect("process bundle");
+
+    let mut counter = 0;
+    let mut jumps = 0;
+    let mut ops = data.iter();
+    while let Some(&op) = ops.next() {
+        let arg = ops.next().copied().unwrap_or(0) as usize;
+        let (this, other) = if op & 1 == 0 {
+            (&mut alice, &mut bob)
+        } else {
+            (&mut bob, &mut alice)
+        };
+        match (op >> 1) % 6 {
+            0 => {
+                for _ in 0..=arg % 4 {
+                    send(this, other, &mut counter);
+                }
+            }
+            1 => deliver(this, other, false, &mut rng),
+            // Deliver a message but leave it queued, so it arrives again later.
+            2 => deliver(this, other, true, &mut rng),
+            3 => {
+                other.inbox.pop_front();
+            }
+            4 => {
+                let len = other.inbox.len();
+                if len > 1 {
+                    other.inbox.swap(0, arg % len);
+                }
+            }
+            _ => {
+                // Lose a long run of messages, sometimes past the forward jump limit. Each
+                // message encrypted here costs a session load and store, so only allow a couple
+                // of these per input.
+                if jumps < 2 {
+                    jumps += 1;
+                    for _ in 0..(arg % 4) * 700 {
+                        send(this, other, &mut counter);
+                        other.inbox.pop_back();
+                    }
+                }
+            }
+        }
+    }
+});
@@ -0,0 +1,33 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol_rust::*;
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    // The owned and borrowed parsers must agree on what they accept.
+    match (
+        SignalMessage::try_from(data),
+        SignalMessageRef::try_from(data),
+    ) {
+        (Ok(owned), Ok(borrowed)) => {
+            let borrowed = borrowed.into_owned();
+            assert_eq!(owned.header(), borrowed.header());
+            assert_eq!(owned.body(), borrowed.body());
+        }
+        (Err(_), Err(_)) => {}
+        (owned, borrowed) => panic!(
+            "parsers disagree: owned {:?}, borrowed {:?}",
+            owned.map(|m| m.header()),
+            borrowed.map(|m| m.counter())
+        ),
+    }
+    let _ = CiphertextMessage::parse(data, CiphertextMessageType::Whisper).map(|m| m.inspect());
+});
@@ -0,0 +1,524 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+//! Streaming encryption of attachments too large to hold in memory.
+//!
+//! An encrypted attachment is a header followed by a sequence of frames:
+//!
+//! ```text
+//! header: version (1) || chunk size (4, BE)
+//! frame:  sealed length (4, BE) || iv (16) || AES-256-CBC ciphertext || HMAC-SHA256 (32)
+//! ```
+//!
+//! Every frame holds one chunk of plaintext and is authenticated on its own, binding the header,
+//! the chunk index and whether it is the last chunk, so a reader can release plaintext chunk by
+//! chunk while still detecting reordered, dropped or truncated frames. A SHA-256 digest of the
+//! whole encrypted stream is computed while writing and checked when the reader reaches the end.
+
+use crate::crypto;
+use crate::error::{Result, SignalProtocolError};
+
+use arrayref::array_ref;
+use rand::{CryptoRng, Rng};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use subtle::ConstantTimeEq;
+
+const ATTACHMENT_VERSION: u8 = 1;
+const ATTACHMENT_HEADER_LENGTH: usize = 1 + 4;
+const ATTACHMENT_IV_LENGTH: usize = 16;
+const ATTACHMENT_MAC_LENGTH: usize = 32;
+const MAX_CHUNK_SIZE: usize = 1 << 20;
+const ATTACHMENT_SECRETS_LENGTH: usize = 1 + 64 + 32 + 8;
+
+pub const DEFAULT_ATTACHMENT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A random key for a single attachment.
+#[derive(Clone)]
+pub struct AttachmentKey {
+    cipher_key: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+impl AttachmentKey {
+    pub fn generate<R: Rng + CryptoRng>(csprng: &mut R) -> Self {
+        let mut cipher_key = [0u8; 32];
+        let mut mac_key = [0u8; 32];
+        csprng.fill_bytes(&mut cipher_key);
+        csprng.fill_bytes(&mut mac_key);
+        Self {
+            cipher_key,
+            mac_key,
+        }
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 64 {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "attachment key must be 64 bytes, got {}",
+                bytes.len()
+            )));
+        }
+        Ok(Self {
+            cipher_key: *array_ref![bytes, 0, 32],
+            mac_key: *array_ref![bytes, 32, 32],
+        })
+    }
+
+    pub fn serialize(&self) -> [u8; 64] {
+        let mut result = [0u8; 64];
+        result[..32].copy_from_slice(&self.cipher_key);
+        result[32..].copy_from_slice(&self.mac_key);
+        result
+    }
+}
+
+/// Everything a recipient needs to fetch and decrypt an attachment, meant to be sent inside a
+/// regular [`message_encrypt`](crate::message_encrypt) payload.
+#[derive(Clone)]
+pub struct AttachmentSecrets {
+    key: AttachmentKey,
+    digest: [u8; 32],
+    plaintext_length: u64,
+}
+
+impl AttachmentSecrets {
+    #[inline]
+    pub fn key(&self) -> &AttachmentKey {
+        &self.key
+    }
+
+    /// SHA-256 of the encrypted attachment.
+    #[inline]
+    pub fn digest(&self) -> &[u8; 32] {
+        &self.digest
+    }
+
+    #[inline]
+    pub fn plaintext_length(&self) -> u64 {
+        self.plaintext_length
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(ATTACHMENT_SECRETS_LENGTH);
+        result.push(ATTACHMENT_VERSION);
+        result.extend_from_slice(&self.key.serialize());
+        result.extend_from_slice(&self.digest);
+        result.extend_from_slice(&self.plaintext_length.to_be_bytes());
+        result
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != ATTACHMENT_SECRETS_LENGTH {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "attachment secrets must be {} bytes, got {}",
+                ATTACHMENT_SECRETS_LENGTH,
+                bytes.len()
+            )));
+        }
+        if bytes[0] != ATTACHMENT_VERSION {
+            return Err(SignalProtocolError::UnrecognizedCiphertextVersion(bytes[0]));
+        }
+        Ok(Self {
+            key: AttachmentKey::deserialize(&bytes[1..65])?,
+            digest: *array_ref![bytes, 65, 32],
+            plaintext_length: u64::from_be_bytes(*array_ref![bytes, 97, 8]),
+        })
+    }
+}
+
+fn chunk_associated_data(
+    header: &[u8; ATTACHMENT_HEADER_LENGTH],
+    index: u64,
+    is_final: bool,
+) -> [u8; ATTACHMENT_HEADER_LENGTH + 9] {
+    let mut ad = [0u8; ATTACHMENT_HEADER_LENGTH + 9];
+    ad[..ATTACHMENT_HEADER_LENGTH].copy_from_slice(header);
+    ad[ATTACHMENT_HEADER_LENGTH..ATTACHMENT_HEADER_LENGTH + 8]
+        .copy_from_slice(&index.to_be_bytes());
+    ad[ATTACHMENT_HEADER_LENGTH + 8] = is_final as u8;
+    ad
+}
+
+fn invalid_data(e: SignalProtocolError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// A [`Write`] adapter that encrypts everything written to it into `inner`.
+///
+/// [`finish`](Self::finish) must be called to write the final frame; dropping the encryptor
+/// leaves a stream that readers reject as truncated.
+pub struct AttachmentEncryptor<W: Write, R: Rng + CryptoRng> {
+    inner: W,
+    key: AttachmentKey,
+    csprng: R,
+    header: [u8; ATTACHMENT_HEADER_LENGTH],
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    chunk_index: u64,
+    plaintext_length: u64,
+    digest: Sha256,
+}
+
+impl<W: Write, R: Rng + CryptoRng> AttachmentEncryptor<W, R> {
+    pub fn new(inner: W, key: AttachmentKey, csprng: R) -> io::Result<Self> {
+        Self::with_chunk_size(inner, key, csprng, DEFAULT_ATTACHMENT_CHUNK_SIZE)
+    }
+
+    /// `chunk_size` must be a non-zero multiple of 16 of at most 1 MiB.
+    pub fn with_chunk_size(
+        mut inner: W,
+        key: AttachmentKey,
+        csprng: R,
+        chunk_size: usize,
+    ) -> io::Result<Self> {
+        if chunk_size == 0 || chunk_size % 16 != 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                SignalProtocolError::InvalidArgument(format!(
+                    "invalid attachment chunk size {}",
+                    chunk_size
+                )),
+            ));
+        }
+        let mut header = [0u8; ATTACHMENT_HEADER_LENGTH];
+        header[0] = ATTACHMENT_VERSION;
+        header[1..].copy_from_slice(&(chunk_size as u32).to_be_bytes());
+
+        inner.write_all(&header)?;
+        let mut digest = Sha256::new();
+        digest.update(header);
+
+        Ok(Self {
+            inner,
+            key,
+            csprng,
+            header,
+            chunk_size,
+            buffer: Vec::with_capacity(chunk_size),
+            chunk_index: 0,
+            plaintext_length: 0,
+            digest,
+        })
+    }
+
+    /// Write the final frame and return the inner writer with the secrets for the recipient.
+    pub fn finish(mut self) -> io::Result<(W, AttachmentSecrets)> {
+        let chunk = std::mem::take(&mut self.buffer);
+        self.write_frame(&chunk, true)?;
+        self.inner.flush()?;
+        let secrets = AttachmentSecrets {
+            key: self.key,
+            digest: self.digest.finalize().into(),
+            plaintext_length: self.plaintext_length,
+        };
+        Ok((self.inner, secrets))
+    }
+
+    fn write_frame(&mut self, chunk: &[u8], is_final: bool) -> io::Result<()> {
+        let mut iv = [0u8; ATTACHMENT_IV_LENGTH];
+        self.csprng.fill_bytes(&mut iv);
+
+        let ad = chunk_associated_data(&self.header, self.chunk_index, is_final);
+        let sealed = crypto::aes256_cbc_hmacsha256_seal(
+            chunk,
+            &self.key.cipher_key,
+            &self.key.mac_key,
+            &iv,
+            &ad,
+        )
+        .map_err(invalid_data)?;
+
+        let length = (sealed.len() as u32).to_be_bytes();
+        for part in &[&length[..], &iv[..], &sealed[..]] {
+            self.inner.write_all(part)?;
+            self.digest.update(part);
+        }
+
+        self.chunk_index += 1;
+        self.plaintext_length += chunk.len() as u64;
+        Ok(())
+    }
+}
+
+impl<W: Write, R: Rng + CryptoRng> Write for AttachmentEncryptor<W, R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let take = std::cmp::min(buf.len(), self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        if self.buffer.len() == self.chunk_size {
+            let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(self.chunk_size));
+            self.write_frame(&chunk, false)?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A [`Read`] adapter that decrypts an attachment produced by [`AttachmentEncryptor`].
+///
+/// Plaintext is only returned once the frame holding it has been authenticated. Reaching the
+/// end of the stream also checks the digest and length from the [`AttachmentSecrets`]; any
+/// failure is reported as an [`io::ErrorKind::InvalidData`] error.
+pub struct AttachmentDecryptor<Rd: Read> {
+    inner: Rd,
+    secrets: AttachmentSecrets,
+    header: Option<[u8; ATTACHMENT_HEADER_LENGTH]>,
+    chunk_size: usize,
+    chunk_index: u64,
+    plaintext: Vec<u8>,
+    position: usize,
+    plaintext_length: u64,
+    digest: Sha256,
+    finished: bool,
+}
+
+impl<Rd: Read> AttachmentDecryptor<Rd> {
+    pub fn new(inner: Rd, secrets: &AttachmentSecrets) -> Self {
+        Self {
+            inner,
+            secrets: secrets.clone(),
+            header: None,
+            chunk_size: 0,
+            chunk_index: 0,
+            plaintext: Vec::new(),
+            position: 0,
+            plaintext_length: 0,
+            digest: Sha256::new(),
+            finished: false,
+        }
+    }
+
+    pub fn into_inner(self) -> Rd {
+        self.inner
+    }
+
+    fn read_header(&mut self) -> io::Result<[u8; ATTACHMENT_HEADER_LENGTH]> {
+        if let Some(header) = self.header {
+            return Ok(header);
+        }
+        let mut header = [0u8; ATTACHMENT_HEADER_LENGTH];
+        self.read_exact_or_truncated(&mut header)?;
+        if header[0] != ATTACHMENT_VERSION {
+            return Err(invalid_data(
+                SignalProtocolError::UnrecognizedCiphertextVersion(header[0]),
+            ));
+        }
+        let chunk_size = u32::from_be_bytes(*array_ref![header, 1, 4]) as usize;
+        if chunk_size == 0 || chunk_size % 16 != 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(invalid_data(SignalProtocolError::InvalidCiphertext));
+        }
+        self.chunk_size = chunk_size;
+        self.header = Some(header);
+        Ok(header)
+    }
+
+    fn read_exact_or_truncated(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        match self.inner.read_exact(buf) {
+            Ok(()) => {
+                self.digest.update(&*buf);
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(invalid_data(SignalProtocolError::InvalidCiphertext))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read, authenticate and decrypt the next frame into `self.plaintext`.
+    fn read_frame(&mut self) -> io::Result<()> {
+        let header = self.read_header()?;
+
+        let mut length = [0u8; 4];
+        self.read_exact_or_truncated(&mut length)?;
+        let sealed_length = u32::from_be_bytes(length) as usize;
+        // One block of padding beyond the chunk, plus the tag.
+        if sealed_length < 16 + ATTACHMENT_MAC_LENGTH
+            || sealed_length > self.chunk_size + 16 + ATTACHMENT_MAC_LENGTH
+        {
+            return Err(invalid_data(SignalProtocolError::InvalidCiphertext));
+        }
+
+        let mut frame = vec![0u8; ATTACHMENT_IV_LENGTH + sealed_length];
+        self.read_exact_or_truncated(&mut frame)?;
+        let (iv, sealed) = frame.split_at(ATTACHMENT_IV_LENGTH);
+
+        // A frame that authenticates as non-final but is short, or as final, tells us where the
+        // stream ends; anything else is a forgery.
+        let open = |is_final| {
+            crypto::aes256_cbc_hmacsha256_open(
+                sealed,
+                &self.secrets.key.cipher_key,
+                &self.secrets.key.mac_key,
+                iv,
+                &chunk_associated_data(&header, self.chunk_index, is_final),
+            )
+        };
+        let (chunk, is_final) = match open(false) {
+            Ok(chunk) => (chunk, false),
+            Err(_) => (open(true).map_err(invalid_data)?, true),
+        };
+        if !is_final && chunk.len() != self.chunk_size {
+            return Err(invalid_data(SignalProtocolError::InvalidCiphertext));
+        }
+
+        self.chunk_index += 1;
+        self.plaintext_length += chunk.len() as u64;
+        self.plaintext = chunk;
+        self.position = 0;
+
+        if is_final {
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut trailing = [0u8; 1];
+        if self.inner.read(&mut trailing)? != 0 {
+            return Err(invalid_data(SignalProtocolError::InvalidCiphertext));
+        }
+        let digest = std::mem::replace(&mut self.digest, Sha256::new()).finalize();
+        if !bool::from(digest.ct_eq(&self.secrets.digest[..]))
+            || self.plaintext_length != self.secrets.plaintext_length
+        {
+            return Err(invalid_data(SignalProtocolError::InvalidCiphertext));
+        }
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl<Rd: Read> Read for AttachmentDecryptor<Rd> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_frame()?;
+        }
+        let n = std::cmp::min(buf.len(), self.plaintext.len() - self.position);
+        buf[..n].copy_from_slice(&self.plaintext[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Encrypt everything read from `plaintext` into `ciphertext` under a fresh random key.
+pub fn encrypt_attachment<Rd: Read, W: Write, R: Rng + CryptoRng>(
+    plaintext: &mut Rd,
+    ciphertext: W,
+    csprng: &mut R,
+) -> io::Result<AttachmentSecrets> {
+    let key = AttachmentKey::generate(csprng);
+    let mut encryptor = AttachmentEncryptor::new(ciphertext, key, &mut *csprng)?;
+    io::copy(plaintext, &mut encryptor)?;
+    let (_, secrets) = encryptor.finish()?;
+    Ok(secrets)
+}
+
+/// Decrypt `ciphertext` into `plaintext`, returning the number of bytes written.
+///
+/// Authenticated plaintext is written as it is decrypted, so on error `plaintext` may already
+/// hold a prefix of the attachment that must be discarded.
+pub fn decrypt_attachment<Rd: Read, W: Write>(
+    ciphertext: Rd,
+    plaintext: &mut W,
+    secrets: &AttachmentSecrets,
+) -> io::Result<u64> {
+    let mut decryptor = AttachmentDecryptor::new(ciphertext, secrets);
+    io::copy(&mut decryptor, plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+
+    fn encrypt_with_chunk_size(
+        plaintext: &[u8],
+        chunk_size: usize,
+    ) -> (Vec<u8>, AttachmentSecrets) {
+        let mut csprng = OsRng;
+        let key = AttachmentKey::generate(&mut csprng);
+        let mut encryptor =
+            AttachmentEncryptor::with_chunk_size(Vec::new(), key, csprng, chunk_size).unwrap();
+        encryptor.write_all(plaintext).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(ciphertext: &[u8], secrets: &AttachmentSecrets) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        decrypt_attachment(ciphertext, &mut plaintext, secrets)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_attachment_round_trip() {
+        let mut csprng = OsRng;
+        for &length in &[0usize, 1, 63, 64, 65, 64 * 3, 64 * 3 + 17] {
+            let mut plaintext = vec![0u8; length];
+            csprng.fill_bytes(&mut plaintext);
+
+            let (ciphertext, secrets) = encrypt_with_chunk_size(&plaintext, 64);
+            assert_eq!(secrets.plaintext_length(), length as u64);
+            assert_eq!(&Sha256::digest(&ciphertext)[..], &secrets.digest()[..]);
+
+            let secrets = AttachmentSecrets::deserialize(&secrets.serialize()).unwrap();
+            assert_eq!(decrypt(&ciphertext, &secrets).unwrap(), plaintext);
+        }
+
+        let mut plaintext = vec![0u8; 3 * DEFAULT_ATTACHMENT_CHUNK_SIZE + 5];
+        csprng.fill_bytes(&mut plaintext);
+        let mut ciphertext = Vec::new();
+        let secrets =
+            encrypt_attachment(&mut &plaintext[..], &mut ciphertext, &mut csprng).unwrap();
+        assert_eq!(decrypt(&ciphertext, &secrets).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn test_attachment_tampering() {
+        let plaintext = vec![0x42u8; 64 * 3 + 17];
+        let (ciphertext, secrets) = encrypt_with_chunk_size(&plaintext, 64);
+        let frame_length = 4 + ATTACHMENT_IV_LENGTH + 64 + 16 + ATTACHMENT_MAC_LENGTH;
+
+        for i in (0..ciphertext.len()).step_by(7) {
+            let mut tampered = ciphertext.clone();
+            tampered[i] ^= 0x01;
+            assert!(decrypt(&tampered, &secrets).is_err());
+        }
+
+        // Dropping the final frame, or a middle one.
+        let without_final = &ciphertext[..ATTACHMENT_HEADER_LENGTH + 3 * frame_length];
+        assert!(decrypt(without_final, &secrets).is_err());
+        let mut dropped = ciphertext[..ATTACHMENT_HEADER_LENGTH + frame_length].to_vec();
+        dropped.extend_from_slice(&ciphertext[ATTACHMENT_HEADER_LENGTH + 2 * frame_length..]);
+        assert!(decrypt(&dropped, &secrets).is_err());
+
+        // Swapping two frames.
+        let mut swapped = ciphertext[..ATTACHMENT_HEADER_LENGTH].to_vec();
+        let frames = &ciphertext[ATTACHMENT_HEADER_LENGTH..];
+        swapped.extend_from_slice(&frames[frame_length..2 * frame_length]);
+        swapped.extend_from_slice(&frames[..frame_length]);
+        swapped.extend_from_slice(&frames[2 * frame_length..]);
+        assert!(decrypt(&swapped, &secrets).is_err());
+
+        // Trailing data.
+        let mut extended = ciphertext.clone();
+        extended.push(0);
+        assert!(decrypt(&extended, &secrets).is_err());
+
+        // The wrong key.
+        let (_, other_secrets) = encrypt_with_chunk_size(&plaintext, 64);
+        assert!(decrypt(&ciphertext, &other_secrets).is_err());
+    }
+}
@@ -0,0 +1,513 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+use crate::crypto;
+use crate::error::{Result, SignalProtocolError};
+use crate::proto::storage::{backup_structure, BackupStructure};
+use crate::state::{PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId, SignedPreKeyRecord};
+use crate::storage::{Context, ProtocolStore, SenderKeyStore};
+use crate::{IdentityKey, IdentityKeyPair, ProtocolAddress, SenderKeyName, SenderKeyRecord};
+
+use arrayref::array_ref;
+use prost::Message;
+use rand::{CryptoRng, Rng};
+use std::convert::TryFrom;
+
+const BACKUP_VERSION: u8 = 1;
+const BACKUP_SALT_LENGTH: usize = 16;
+const BACKUP_IV_LENGTH: usize = 16;
+// version || log_n || r || p || salt
+const BACKUP_KDF_HEADER_LENGTH: usize = 1 + 1 + 4 + 4 + BACKUP_SALT_LENGTH;
+const BACKUP_HEADER_LENGTH: usize = BACKUP_KDF_HEADER_LENGTH + BACKUP_IV_LENGTH;
+
+// Upper bounds on the work factor accepted from a backup file. The header is only authenticated
+// by the key scrypt derives, so these are checked first: a corrupted or hostile header can cost
+// at most 256 MiB of memory (scrypt needs 128 * r * 2^log_n bytes) and a few passes over it.
+const MAX_BACKUP_LOG_N: u8 = 20;
+const MAX_BACKUP_R: u32 = 32;
+const MAX_BACKUP_P: u32 = 4;
+const MAX_BACKUP_MEMORY: u64 = 256 * 1024 * 1024;
+
+/// scrypt work factor used to derive the backup key from a passphrase.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BackupParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+impl BackupParams {
+    pub fn new(log_n: u8, r: u32, p: u32) -> Result<Self> {
+        if log_n > MAX_BACKUP_LOG_N
+            || r > MAX_BACKUP_R
+            || p > MAX_BACKUP_P
+            || (128 * u64::from(r)) << log_n > MAX_BACKUP_MEMORY
+        {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "backup work factor too large (log_n {}, r {}, p {})",
+                log_n, r, p
+            )));
+        }
+        scrypt::ScryptParams::new(log_n, r, p).map_err(|_| {
+            SignalProtocolError::InvalidArgument(format!(
+                "invalid backup work factor (log_n {}, r {}, p {})",
+                log_n, r, p
+            ))
+        })?;
+        Ok(Self { log_n, r, p })
+    }
+
+    pub fn log_n(&self) -> u8 {
+        self.log_n
+    }
+
+    pub fn r(&self) -> u32 {
+        self.r
+    }
+
+    pub fn p(&self) -> u32 {
+        self.p
+    }
+
+    fn derive_keys(&self, passphrase: &str, salt: &[u8]) -> Result<([u8; 32], [u8; 32])> {
+        let params = scrypt::ScryptParams::new(self.log_n, self.r, self.p)
+            .map_err(|_| SignalProtocolError::InvalidBackup("invalid work factor"))?;
+        let mut derived = [0u8; 64];
+        scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut derived)
+            .map_err(|_| SignalProtocolError::InternalError("scrypt output length"))?;
+        Ok((*array_ref![derived, 0, 32], *array_ref![derived, 32, 32]))
+    }
+}
+
+impl Default for BackupParams {
+    /// 32 MiB of memory per derivation.
+    fn default() -> Self {
+        Self {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+/// The records to include in a backup.
+///
+/// The store traits have no way to enumerate their contents, so the caller lists what to
+/// export. Addresses select both the session and the saved identity for that address; entries
+/// with no stored session or identity are skipped. Listed pre key ids must exist.
+#[derive(Debug, Clone, Default)]
+pub struct BackupManifest {
+    pub addresses: Vec<ProtocolAddress>,
+    pub pre_key_ids: Vec<PreKeyId>,
+    pub signed_pre_key_ids: Vec<SignedPreKeyId>,
+    pub sender_keys: Vec<SenderKeyName>,
+}
+
+/// The decrypted and validated contents of a backup.
+#[derive(Clone)]
+pub struct ProtocolBackup {
+    identity_key_pair: IdentityKeyPair,
+    registration_id: u32,
+    identities: Vec<(ProtocolAddress, IdentityKey)>,
+    sessions: Vec<(ProtocolAddress, SessionRecord)>,
+    pre_keys: Vec<PreKeyRecord>,
+    signed_pre_keys: Vec<SignedPreKeyRecord>,
+    sender_keys: Vec<(SenderKeyName, SenderKeyRecord)>,
+}
+
+impl ProtocolBackup {
+    /// Decrypt a backup produced by [`export_backup`].
+    ///
+    /// Fails with [`SignalProtocolError::BackupAuthenticationFailed`] if the passphrase is wrong
+    /// or the file has been modified. Every record is parsed before this returns, so a backup
+    /// that decrypts can be restored in full.
+    pub fn decrypt(backup: &[u8], passphrase: &str) -> Result<Self> {
+        if backup.len() < BACKUP_HEADER_LENGTH {
+            return Err(SignalProtocolError::InvalidBackup("backup too short"));
+        }
+        if backup[0] != BACKUP_VERSION {
+            return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
+                backup[0],
+            ));
+        }
+        let params = BackupParams::new(
+            backup[1],
+            u32::from_be_bytes(*array_ref![backup, 2, 4]),
+            u32::from_be_bytes(*array_ref![backup, 6, 4]),
+        )
+        .map_err(|_| SignalProtocolError::InvalidBackup("unsupported work factor"))?;
+        let salt = &backup[10..BACKUP_KDF_HEADER_LENGTH];
+        let iv = &backup[BACKUP_KDF_HEADER_LENGTH..BACKUP_HEADER_LENGTH];
+
+        let (cipher_key, mac_key) = params.derive_keys(passphrase, salt)?;
+        let contents = crypto::aes256_cbc_hmacsha256_open(
+            &backup[BACKUP_HEADER_LENGTH..],
+            &cipher_key,
+            &mac_key,
+            iv,
+            &backup[..BACKUP_KDF_HEADER_LENGTH],
+        )
+        .map_err(|_| SignalProtocolError::BackupAuthenticationFailed)?;
+
+        Self::from_protobuf(BackupStructure::decode(&contents[..])?)
+    }
+
+    pub fn identity_key_pair(&self) -> &IdentityKeyPair {
+        &self.identity_key_pair
+    }
+
+    pub fn registration_id(&self) -> u32 {
+        self.registration_id
+    }
+
+    /// Write every record in the backup to `store`.
+    ///
+    /// The store must already hold the backed-up identity key pair, e.g. by constructing it from
+    /// [`identity_key_pair`](Self::identity_key_pair) and
+    /// [`registration_id`](Self::registration_id).
+    pub fn restore_into<S: ProtocolStore + SenderKeyStore>(
+        &self,
+        store: &mut S,
+        ctx: Context,
+    ) -> Result<()> {
+        let local_identity = store.get_identity_key_pair(ctx)?;
+        if local_identity.identity_key() != self.identity_key_pair.identity_key() {
+            return Err(SignalProtocolError::InvalidArgument(
+                "store identity key does not match the backup".to_string(),
+            ));
+        }
+        if store.get_local_registration_id(ctx)? != self.registration_id {
+            return Err(SignalProtocolError::InvalidArgument(
+                "store registration id does not match the backup".to_string(),
+            ));
+        }
+
+        for (address, identity) in &self.identities {
+            store.save_identity(address, identity, ctx)?;
+        }
+        for (address, record) in &self.sessions {
+            store.store_session(address, record, ctx)?;
+        }
+        for record in &self.pre_keys {
+            store.save_pre_key(record.id()?, record, ctx)?;
+        }
+        for record in &self.signed_pre_keys {
+            store.save_signed_pre_key(record.id()?, record, ctx)?;
+        }
+        for (name, record) in &self.sender_keys {
+            store.store_sender_key(name, record, ctx)?;
+        }
+        Ok(())
+    }
+
+    fn from_protobuf(structure: BackupStructure) -> Result<Self> {
+        let identity_key_pair = IdentityKeyPair::try_from(&structure.identity_key_pair[..])?;
+
+        let identities = structure
+            .identities
+            .into_iter()
+            .map(|entry| {
+                Ok((
+                    address_from_protobuf(entry.address)?,
+                    IdentityKey::decode(&entry.identity)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        let sessions = structure
+            .sessions
+            .into_iter()
+            .map(|entry| {
+                Ok((
+                    address_from_protobuf(entry.address)?,
+                    SessionRecord::deserialize(&entry.record)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        let pre_keys = structure
+            .pre_keys
+            .iter()
+            .map(|record| PreKeyRecord::deserialize(record))
+            .collect::<Result<_>>()?;
+        let signed_pre_keys = structure
+            .signed_pre_keys
+            .iter()
+            .map(|record| SignedPreKeyRecord::deserialize(record))
+            .collect::<Result<_>>()?;
+        let sender_keys = structure
+            .sender_keys
+            .into_iter()
+            .map(|entry| {
+                Ok((
+                    SenderKeyName::new(entry.group_id, address_from_protobuf(entry.sender)?)?,
+                    SenderKeyRecord::deserialize(&entry.record)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            identity_key_pair,
+            registration_id: structure.registration_id,
+            identities,
+            sessions,
+            pre_keys,
+            signed_pre_keys,
+            sender_keys,
+        })
+    }
+}
+
+/// Export the records listed in `manifest`, together with the local identity, as a single
+/// passphrase-encrypted backup.
+///
+/// The key is derived from the passphrase with scrypt under a fresh random salt; the contents
+/// are encrypted with AES-256-CBC and authenticated, along with the header, with HMAC-SHA256.
+pub fn export_backup<S: ProtocolStore + SenderKeyStore, R: Rng + CryptoRng>(
+    store: &mut S,
+    manifest: &BackupManifest,
+    passphrase: &str,
+    params: &BackupParams,
+    csprng: &mut R,
+    ctx: Context,
+) -> Result<Vec<u8>> {
+    let mut structure = BackupStructure {
+        identity_key_pair: store.get_identity_key_pair(ctx)?.serialize().to_vec(),
+        registration_id: store.get_local_registration_id(ctx)?,
+        ..Default::default()
+    };
+
+    for address in &manifest.addresses {
+        if let Some(identity) = store.get_identity(address, ctx)? {
+            structure.identities.push(backup_structure::IdentityEntry {
+                address: Some(address_as_protobuf(address)),
+                identity: identity.serialize().to_vec(),
+            });
+        }
+        if let Some(record) = store.load_session(address, ctx)? {
+            structure.sessions.push(backup_structure::SessionEntry {
+                address: Some(address_as_protobuf(address)),
+                record: record.serialize()?,
+            });
+        }
+    }
+    for id in &manifest.pre_key_ids {
+        structure
+            .pre_keys
+            .push(store.get_pre_key(*id, ctx)?.serialize()?);
+    }
+    for id in &manifest.signed_pre_key_ids {
+        structure
+            .signed_pre_keys
+            .push(store.get_signed_pre_key(*id, ctx)?.serialize()?);
+    }
+    for name in &manifest.sender_keys {
+        if let Some(record) = store.load_sender_key(name, ctx)? {
+            structure
+                .sender_keys
+                .push(backup_structure::SenderKeyEntry {
+                    group_id: name.group_id()?,
+                    sender: Some(address_as_protobuf(&name.sender()?)),
+                    record: record.serialize()?,
+                });
+        }
+    }
+
+    let mut contents = Vec::new();
+    structure.encode(&mut contents)?;
+
+    let mut salt = [0u8; BACKUP_SALT_LENGTH];
+    csprng.fill_bytes(&mut salt);
+    let mut iv = [0u8; BACKUP_IV_LENGTH];
+    csprng.fill_bytes(&mut iv);
+
+    let mut backup = Vec::with_capacity(BACKUP_HEADER_LENGTH + contents.len() + 48);
+    backup.push(BACKUP_VERSION);
+    backup.push(params.log_n);
+    backup.extend_from_slice(&params.r.to_be_bytes());
+    backup.extend_from_slice(&params.p.to_be_bytes());
+    backup.extend_from_slice(&salt);
+    backup.extend_from_slice(&iv);
+
+    let (cipher_key, mac_key) = params.derive_keys(passphrase, &salt)?;
+    let sealed = crypto::aes256_cbc_hmacsha256_seal(
+        &contents,
+        &cipher_key,
+        &mac_key,
+        &iv,
+        &backup[..BACKUP_KDF_HEADER_LENGTH],
+    )?;
+    backup.extend_from_slice(&sealed);
+    Ok(backup)
+}
+
+/// Decrypt `backup` and restore it into `store`; see [`ProtocolBackup::restore_into`].
+pub fn import_backup<S: ProtocolStore + SenderKeyStore>(
+    backup: &[u8],
+    passphrase: &str,
+    store: &mut S,
+    ctx: Context,
+) -> Result<()> {
+    ProtocolBackup::decrypt(backup, passphrase)?.restore_into(store, ctx)
+}
+
+fn address_as_protobuf(address: &ProtocolAddress) -> backup_structure::Address {
+    backup_structure::Address {
+        name: address.name().to_string(),
+        device_id: address.device_id(),
+    }
+}
+
+fn address_from_protobuf(address: Option<backup_structure::Address>) -> Result<ProtocolAddress> {
+    let address = address.ok_or(SignalProtocolError::InvalidBackup("missing address"))?;
+    Ok(ProtocolAddress::new(address.name, address.device_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{IdentityKeyStore, InMemSignalProtocolStore, PreKeyStore, SessionStore};
+    use crate::KeyPair;
+
+    use rand::rngs::OsRng;
+
+    fn test_params() -> BackupParams {
+        BackupParams::new(4, 8, 1).unwrap()
+    }
+
+    fn test_store() -> Result<(InMemSignalProtocolStore, BackupManifest)> {
+        let mut csprng = OsRng;
+        let mut store =
+            InMemSignalProtocolStore::new(IdentityKeyPair::generate(&mut csprng), 1234)?;
+
+        let address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let remote_identity = IdentityKeyPair::generate(&mut csprng);
+        store.save_identity(&address, remote_identity.identity_key(), None)?;
+
+        let pre_key = PreKeyRecord::new(7, &KeyPair::generate(&mut csprng));
+        store.save_pre_key(7, &pre_key, None)?;
+
+        let manifest = BackupManifest {
+            addresses: vec![address, ProtocolAddress::new("+14152222222".to_owned(), 1)],
+            pre_key_ids: vec![7],
+            ..Default::default()
+        };
+        Ok((store, manifest))
+    }
+
+    #[test]
+    fn test_backup_round_trip() -> Result<()> {
+        let mut csprng = OsRng;
+        let (mut store, manifest) = test_store()?;
+        let backup = export_backup(
+            &mut store,
+            &manifest,
+            "correct horse",
+            &test_params(),
+            &mut csprng,
+            None,
+        )?;
+
+        let restored = ProtocolBackup::decrypt(&backup, "correct horse")?;
+        assert_eq!(restored.registration_id(), 1234);
+        assert_eq!(restored.identities.len(), 1);
+        assert_eq!(restored.sessions.len(), 0);
+        assert_eq!(restored.pre_keys.len(), 1);
+
+        let mut fresh = InMemSignalProtocolStore::new(
+            *restored.identity_key_pair(),
+            restored.registration_id(),
+        )?;
+        restored.restore_into(&mut fresh, None)?;
+        assert_eq!(
+            fresh.get_identity(&manifest.addresses[0], None)?,
+            store.get_identity(&manifest.addresses[0], None)?
+        );
+        assert_eq!(
+            fresh.get_pre_key(7, None)?.serialize()?,
+            store.get_pre_key(7, None)?.serialize()?
+        );
+        assert!(fresh.load_session(&manifest.addresses[1], None)?.is_none());
+
+        let mut other =
+            InMemSignalProtocolStore::new(IdentityKeyPair::generate(&mut csprng), 1234)?;
+        assert!(restored.restore_into(&mut other, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_integrity() -> Result<()> {
+        let mut csprng = OsRng;
+        let (mut store, manifest) = test_store()?;
+        let backup = export_backup(
+            &mut store,
+            &manifest,
+            "correct horse",
+            &test_params(),
+            &mut csprng,
+            None,
+        )?;
+
+        assert!(matches!(
+            ProtocolBackup::decrypt(&backup, "battery staple"),
+            Err(SignalProtocolError::BackupAuthenticationFailed)
+        ));
+
+        // Every byte is covered, from the work factor to the last byte of the tag.
+        for i in 0..backup.len() {
+            let mut tampered = backup.clone();
+            tampered[i] ^= 0x01;
+            assert!(ProtocolBackup::decrypt(&tampered, "correct horse").is_err());
+        }
+
+        assert!(matches!(
+            ProtocolBackup::decrypt(&backup[..BACKUP_HEADER_LENGTH - 1], "correct horse"),
+            Err(SignalProtocolError::InvalidBackup("backup too short"))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hostile_work_factor_rejected_before_kdf() -> Result<()> {
+        let mut csprng = OsRng;
+        let (mut store, manifest) = test_store()?;
+        let backup = export_backup(
+            &mut store,
+            &manifest,
+            "correct horse",
+            &test_params(),
+            &mut csprng,
+            None,
+        )?;
+
+        // 4 GiB of scrypt memory, 64 sequential passes, and a shift that would overflow. Running
+        // scrypt on any of these would make this test allocate or run for far too long.
+        for (log_n, r, p) in &[(20u8, 32u32, 1u32), (18, 8, 64), (255, 8, 1)] {
+            let mut hostile = backup.clone();
+            hostile[1] = *log_n;
+            hostile[2..6].copy_from_slice(&r.to_be_bytes());
+            hostile[6..10].copy_from_slice(&p.to_be_bytes());
+            assert!(matches!(
+                ProtocolBackup::decrypt(&hostile, "correct horse"),
+                Err(SignalProtocolError::InvalidBackup(
+                    "unsupported work factor"
+                ))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_params_bounds() {
+        assert!(BackupParams::new(MAX_BACKUP_LOG_N + 1, 8, 1).is_err());
+        assert!(BackupParams::new(10, MAX_BACKUP_R + 1, 1).is_err());
+        assert!(BackupParams::new(10, 8, MAX_BACKUP_P + 1).is_err());
+        // Each bound on its own allows this, but together it would be 4 GiB.
+        assert!(BackupParams::new(MAX_BACKUP_LOG_N, MAX_BACKUP_R, 1).is_err());
+        assert!(BackupParams::new(18, 8, MAX_BACKUP_P).is_ok());
+        assert!(BackupParams::new(10, 8, 0).is_err());
+        assert_eq!(BackupParams::default().log_n(), 15);
+    }
+}
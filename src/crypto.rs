@@ -5,12 +5,16 @@
 // SPDX-License-Identifier: GPL-3.0-only
 //
 
-use crate::{error::Result, SignalProtocolError};
+use crate::{error::Result, utils, SignalProtocolError};
 
 use aes::Aes256;
-use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use block_modes::block_padding::{NoPadding, Pkcs7};
+use block_modes::{BlockMode, Cbc};
 use hmac::{Hmac, Mac, NewMac};
 use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+const HMAC_SHA256_LENGTH: usize = 32;
 
 pub fn aes_256_cbc_encrypt(ptext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
     match Cbc::<Aes256, Pkcs7>::new_var(key, iv) {
@@ -26,7 +30,9 @@ pub fn aes_256_cbc_decrypt(ctext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8
         return Err(SignalProtocolError::InvalidCiphertext);
     }
 
-    let mode = match Cbc::<Aes256, Pkcs7>::new_var(key, iv) {
+    // The padding is removed here rather than by block_modes, whose Pkcs7 stops at the first
+    // bad byte.
+    let mode = match Cbc::<Aes256, NoPadding>::new_var(key, iv) {
         Ok(mode) => mode,
         Err(block_modes::InvalidKeyIvLength) => {
             return Err(SignalProtocolError::InvalidCipherCryptographicParameters(
@@ -36,9 +42,13 @@ pub fn aes_256_cbc_decrypt(ctext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8
         }
     };
 
-    Ok(mode
+    let mut ptext = mode
         .decrypt_vec(ctext)
-        .map_err(|_| SignalProtocolError::InvalidCiphertext)?)
+        .map_err(|_| SignalProtocolError::InvalidCiphertext)?;
+    let len = utils::constant_time_pkcs7_unpadded_len(&ptext, 16)
+        .ok_or(SignalProtocolError::InvalidCiphertext)?;
+    ptext.truncate(len);
+    Ok(ptext)
 }
 
 pub fn hmac_sha256(key: &[u8], input: &[u8]) -> Result<[u8; 32]> {
@@ -47,6 +57,56 @@ pub fn hmac_sha256(key: &[u8], input: &[u8]) -> Result<[u8; 32]> {
     Ok(hmac.finalize().into_bytes().into())
 }
 
+/// Encrypt-then-MAC composition of AES-256-CBC and HMAC-SHA256.
+///
+/// The tag covers the length-prefixed associated data, the IV and the ciphertext, and is
+/// appended to the returned ciphertext.
+pub fn aes256_cbc_hmacsha256_seal(
+    ptext: &[u8],
+    cipher_key: &[u8],
+    mac_key: &[u8],
+    iv: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>> {
+    let mut ctext = aes_256_cbc_encrypt(ptext, cipher_key, iv)?;
+    let tag = aead_tag(mac_key, iv, associated_data, &ctext);
+    ctext.extend_from_slice(&tag);
+    Ok(ctext)
+}
+
+pub fn aes256_cbc_hmacsha256_open(
+    sealed: &[u8],
+    cipher_key: &[u8],
+    mac_key: &[u8],
+    iv: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>> {
+    if sealed.len() < HMAC_SHA256_LENGTH {
+        return Err(SignalProtocolError::InvalidCiphertext);
+    }
+    let (ctext, their_tag) = sealed.split_at(sealed.len() - HMAC_SHA256_LENGTH);
+    let our_tag = aead_tag(mac_key, iv, associated_data, ctext);
+    if !bool::from(our_tag.ct_eq(their_tag)) {
+        return Err(SignalProtocolError::InvalidCiphertext);
+    }
+    aes_256_cbc_decrypt(ctext, cipher_key, iv)
+}
+
+fn aead_tag(
+    mac_key: &[u8],
+    iv: &[u8],
+    associated_data: &[u8],
+    ctext: &[u8],
+) -> [u8; HMAC_SHA256_LENGTH] {
+    let mut hmac =
+        Hmac::<Sha256>::new_varkey(mac_key).expect("HMAC-SHA256 should accept any size key");
+    hmac.update(&(associated_data.len() as u64).to_be_bytes());
+    hmac.update(associated_data);
+    hmac.update(iv);
+    hmac.update(ctext);
+    hmac.finalize().into_bytes().into()
+}
+
 #[cfg(test)]
 mod test {
 
@@ -76,4 +136,46 @@ mod test {
         let recovered = super::aes_256_cbc_decrypt(&ctext, &key, &bad_iv).unwrap();
         assert_eq!(hex::encode(recovered), "b0736294a124482a4159");
     }
+
+    #[test]
+    fn aes_cbc_hmac_seal_open_test() {
+        let cipher_key = [0x42u8; 32];
+        let mac_key = [0x17u8; 32];
+        let iv = [0x01u8; 16];
+
+        let sealed =
+            super::aes256_cbc_hmacsha256_seal(b"a record", &cipher_key, &mac_key, &iv, b"ad")
+                .unwrap();
+        let opened =
+            super::aes256_cbc_hmacsha256_open(&sealed, &cipher_key, &mac_key, &iv, b"ad").unwrap();
+        assert_eq!(opened, b"a record");
+
+        // wrong associated data:
+        assert!(
+            super::aes256_cbc_hmacsha256_open(&sealed, &cipher_key, &mac_key, &iv, b"da").is_err()
+        );
+
+        // any bitflip in the ciphertext or tag is detected:
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x01;
+            assert!(super::aes256_cbc_hmacsha256_open(
+                &tampered,
+                &cipher_key,
+                &mac_key,
+                &iv,
+                b"ad"
+            )
+            .is_err());
+        }
+
+        assert!(super::aes256_cbc_hmacsha256_open(
+            &sealed[..31],
+            &cipher_key,
+            &mac_key,
+            &iv,
+            b"ad"
+        )
+        .is_err());
+    }
 }
@@ -5,7 +5,9 @@
 // SPDX-License-Identifier: GPL-3.0-only
 //
 
+mod batch;
 mod curve25519;
+mod ed25519;
 
 use crate::error::{Result, SignalProtocolError};
 
@@ -20,6 +22,7 @@ use subtle::ConstantTimeEq;
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum KeyType {
     Djb,
+    Ed25519,
 }
 
 impl fmt::Display for KeyType {
@@ -32,6 +35,7 @@ impl KeyType {
     fn value(&self) -> u8 {
         match &self {
             KeyType::Djb => 0x05u8,
+            KeyType::Ed25519 => 0x06u8,
         }
     }
 }
@@ -42,6 +46,7 @@ impl TryFrom<u8> for KeyType {
     fn try_from(x: u8) -> Result<Self> {
         match x {
             0x05u8 => Ok(KeyType::Djb),
+            0x06u8 => Ok(KeyType::Ed25519),
             t => Err(SignalProtocolError::BadKeyType(t)),
         }
     }
@@ -50,6 +55,7 @@ impl TryFrom<u8> for KeyType {
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum PublicKeyData {
     DjbPublicKey([u8; 32]),
+    Ed25519PublicKey([u8; 32]),
 }
 
 #[derive(Clone, Copy, Eq)]
@@ -62,7 +68,20 @@ impl PublicKey {
         Self { key }
     }
 
+    /// Parse a Djb public key, as used for ratchet, pre-key and base keys.
+    ///
+    /// Ed25519 keys are only accepted as identity keys, through
+    /// [`IdentityKey::decode`](crate::IdentityKey::decode). Allowing them here would let one
+    /// X25519 key appear under two encodings.
     pub fn deserialize(value: &[u8]) -> Result<Self> {
+        match value.first() {
+            Some(&t) if t == KeyType::Ed25519.value() => Err(SignalProtocolError::BadKeyType(t)),
+            _ => Self::deserialize_identity_key(value),
+        }
+    }
+
+    /// Parse a public key of either type, for use as an identity key.
+    pub(crate) fn deserialize_identity_key(value: &[u8]) -> Result<Self> {
         if value.is_empty() {
             return Err(SignalProtocolError::NoKeyTypeIdentifier);
         }
@@ -79,35 +98,141 @@ impl PublicKey {
                     key: PublicKeyData::DjbPublicKey(key),
                 })
             }
+            KeyType::Ed25519 => {
+                if value.len() < 32 + 1 {
+                    return Err(SignalProtocolError::BadKeyLength(
+                        KeyType::Ed25519,
+                        value.len(),
+                    ));
+                }
+                let mut key = [0u8; 32];
+                key.copy_from_slice(&value[1..33]);
+                if !ed25519::is_valid_public_key(&key) {
+                    return Err(SignalProtocolError::InvalidPublicKeyPoint(KeyType::Ed25519));
+                }
+                Ok(PublicKey {
+                    key: PublicKeyData::Ed25519PublicKey(key),
+                })
+            }
         }
     }
 
     pub fn serialize(&self) -> Box<[u8]> {
-        let value_len = match self.key {
-            PublicKeyData::DjbPublicKey(v) => v.len(),
-        };
-        let mut result = Vec::with_capacity(1 + value_len);
+        let value = self.key_data();
+        let mut result = Vec::with_capacity(1 + value.len());
         result.push(self.key_type().value());
-        match self.key {
-            PublicKeyData::DjbPublicKey(v) => result.extend_from_slice(&v),
-        }
+        result.extend_from_slice(value);
         result.into_boxed_slice()
     }
 
+    /// Verifies an XEdDSA signature for a Djb key or an Ed25519 signature for an Ed25519 key.
     pub fn verify_signature(&self, message: &[u8], signature: &[u8]) -> Result<bool> {
+        if signature.len() != 64 {
+            return Err(SignalProtocolError::MismatchedSignatureLengthForKey(
+                self.key_type(),
+                signature.len(),
+            ));
+        }
+        let signature = array_ref![signature, 0, 64];
+        match self.key {
+            PublicKeyData::DjbPublicKey(pub_key) => Ok(curve25519::KeyPair::verify_signature(
+                &pub_key, message, signature,
+            )),
+            PublicKeyData::Ed25519PublicKey(pub_key) => Ok(ed25519::KeyPair::verify_signature(
+                &pub_key, message, signature,
+            )),
+        }
+    }
+
+    /// Verifies many `(key, message, signature)` entries at once, with a single multiscalar
+    /// multiplication rather than one double scalar multiplication per entry.
+    ///
+    /// If the batch check fails, each entry is verified on its own, and the error is
+    /// [`SignalProtocolError::BatchSignatureValidationFailed`] with the indices of the entries that
+    /// fail [`verify_signature`](Self::verify_signature), including those whose signature has the
+    /// wrong length.
+    ///
+    /// The batch check is cofactored, while [`verify_signature`](Self::verify_signature) follows
+    /// the specification's byte comparison. A batch can therefore be accepted even though one of
+    /// its signatures has an `R` off by a point of small order, which
+    /// [`verify_signature`](Self::verify_signature) rejects. Only the holder of the private key
+    /// can produce such a signature.
+    pub fn verify_signatures_batch<R: CryptoRng + Rng>(
+        entries: &[(&PublicKey, &[u8], &[u8])],
+        csprng: &mut R,
+    ) -> Result<()> {
+        let terms: Vec<_> = entries
+            .iter()
+            .map(|(key, message, signature)| key.verification_terms(message, signature))
+            .collect();
+
+        if terms.iter().all(Option::is_some) && batch::verify(terms.iter().flatten(), csprng) {
+            return Ok(());
+        }
+
+        let failed: Vec<usize> = terms
+            .iter()
+            .enumerate()
+            .filter(|(_, terms)| !matches!(terms, Some(terms) if terms.verify()))
+            .map(|(i, _)| i)
+            .collect();
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(SignalProtocolError::BatchSignatureValidationFailed(failed))
+        }
+    }
+
+    fn verification_terms(
+        &self,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Option<batch::VerificationTerms> {
+        if signature.len() != 64 {
+            return None;
+        }
+        let signature = array_ref![signature, 0, 64];
         match self.key {
             PublicKeyData::DjbPublicKey(pub_key) => {
-                if signature.len() != 64 {
-                    return Err(SignalProtocolError::MismatchedSignatureLengthForKey(
-                        KeyType::Djb,
-                        signature.len(),
-                    ));
-                }
-                Ok(curve25519::KeyPair::verify_signature(
-                    &pub_key,
-                    message,
-                    array_ref![signature, 0, 64],
-                ))
+                curve25519::KeyPair::verification_terms(&pub_key, message, signature)
+            }
+            PublicKeyData::Ed25519PublicKey(pub_key) => {
+                ed25519::KeyPair::verification_terms(&pub_key, message, signature)
+            }
+        }
+    }
+
+    /// Verifies a VXEdDSA signature from [`PrivateKey::calculate_vrf_signature`], returning the
+    /// 32 byte VRF output. An Ed25519 key is converted to X25519 first.
+    pub fn verify_vrf_signature(&self, message: &[u8], signature: &[u8]) -> Result<[u8; 32]> {
+        if signature.len() != 96 {
+            return Err(SignalProtocolError::MismatchedSignatureLengthForKey(
+                self.key_type(),
+                signature.len(),
+            ));
+        }
+        match self.to_x25519()?.key {
+            PublicKeyData::DjbPublicKey(pub_key) => curve25519::KeyPair::verify_vrf_signature(
+                &pub_key,
+                message,
+                array_ref![signature, 0, 96],
+            )
+            .ok_or(SignalProtocolError::SignatureValidationFailed),
+            _ => Err(SignalProtocolError::InternalError(
+                "to_x25519 returned a non-Djb key",
+            )),
+        }
+    }
+
+    /// The X25519 (Djb) key to use for agreement with this key. An Ed25519 key is mapped to the
+    /// equivalent Montgomery point; a Djb key is returned unchanged.
+    pub fn to_x25519(&self) -> Result<PublicKey> {
+        match self.key {
+            PublicKeyData::DjbPublicKey(_) => Ok(*self),
+            PublicKeyData::Ed25519PublicKey(pub_key) => {
+                let converted = ed25519::x25519_public_key(&pub_key)
+                    .ok_or(SignalProtocolError::InvalidPublicKeyPoint(KeyType::Ed25519))?;
+                Ok(PublicKey::new(PublicKeyData::DjbPublicKey(converted)))
             }
         }
     }
@@ -115,12 +240,14 @@ impl PublicKey {
     fn key_data(&self) -> &[u8] {
         match self.key {
             PublicKeyData::DjbPublicKey(ref k) => k.as_ref(),
+            PublicKeyData::Ed25519PublicKey(ref k) => k.as_ref(),
         }
     }
 
     pub fn key_type(&self) -> KeyType {
         match self.key {
             PublicKeyData::DjbPublicKey(_) => KeyType::Djb,
+            PublicKeyData::Ed25519PublicKey(_) => KeyType::Ed25519,
         }
     }
 }
@@ -167,6 +294,7 @@ impl fmt::Debug for PublicKey {
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum PrivateKeyData {
     DjbPrivateKey([u8; 32]),
+    Ed25519PrivateKey([u8; 32]),
 }
 
 #[derive(Clone, Copy, Eq, PartialEq)]
@@ -176,20 +304,28 @@ pub struct PrivateKey {
 
 impl PrivateKey {
     pub fn deserialize(value: &[u8]) -> Result<Self> {
+        Self::deserialize_with_key_type(KeyType::Djb, value)
+    }
+
+    /// Private keys are serialized without a type byte, so the type must come from elsewhere,
+    /// usually the matching public key. An Ed25519 private key is its 32 byte RFC 8032 seed.
+    pub fn deserialize_with_key_type(key_type: KeyType, value: &[u8]) -> Result<Self> {
         if value.len() != 32 {
-            Err(SignalProtocolError::BadKeyLength(KeyType::Djb, value.len()))
-        } else {
-            let mut key = [0u8; 32];
-            key.copy_from_slice(&value[..32]);
-            Ok(Self {
-                key: PrivateKeyData::DjbPrivateKey(key),
-            })
+            return Err(SignalProtocolError::BadKeyLength(key_type, value.len()));
         }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&value[..32]);
+        let key = match key_type {
+            KeyType::Djb => PrivateKeyData::DjbPrivateKey(key),
+            KeyType::Ed25519 => PrivateKeyData::Ed25519PrivateKey(key),
+        };
+        Ok(Self { key })
     }
 
     pub fn serialize(&self) -> Vec<u8> {
         match self.key {
             PrivateKeyData::DjbPrivateKey(v) => v.to_vec(),
+            PrivateKeyData::Ed25519PrivateKey(v) => v.to_vec(),
         }
     }
 
@@ -199,15 +335,35 @@ impl PrivateKey {
                 let public_key = curve25519::derive_public_key(&private_key);
                 Ok(PublicKey::new(PublicKeyData::DjbPublicKey(public_key)))
             }
+            PrivateKeyData::Ed25519PrivateKey(private_key) => {
+                let kp = ed25519::KeyPair::from(private_key);
+                Ok(PublicKey::new(PublicKeyData::Ed25519PublicKey(
+                    *kp.public_key(),
+                )))
+            }
         }
     }
 
     pub fn key_type(&self) -> KeyType {
         match self.key {
             PrivateKeyData::DjbPrivateKey(_) => KeyType::Djb,
+            PrivateKeyData::Ed25519PrivateKey(_) => KeyType::Ed25519,
+        }
+    }
+
+    /// The X25519 (Djb) key to use for agreement with this key, matching
+    /// [`PublicKey::to_x25519`] for the corresponding public key.
+    pub fn to_x25519(&self) -> PrivateKey {
+        match self.key {
+            PrivateKeyData::DjbPrivateKey(_) => *self,
+            PrivateKeyData::Ed25519PrivateKey(seed) => PrivateKey::from(
+                PrivateKeyData::DjbPrivateKey(ed25519::x25519_private_key(&seed)),
+            ),
         }
     }
 
+    /// Calculates an XEdDSA signature for a Djb key or an Ed25519 signature for an Ed25519 key.
+    /// Ed25519 signatures are deterministic and do not use `csprng`.
     pub fn calculate_signature<R: CryptoRng + Rng>(
         &self,
         message: &[u8],
@@ -218,15 +374,44 @@ impl PrivateKey {
                 let kp = curve25519::KeyPair::from(k);
                 Ok(Box::new(kp.calculate_signature(csprng, message)))
             }
+            PrivateKeyData::Ed25519PrivateKey(k) => {
+                let kp = ed25519::KeyPair::from(k);
+                Ok(Box::new(kp.calculate_signature(message)))
+            }
         }
     }
 
+    /// Calculates a 96 byte VXEdDSA signature, returning it along with the 32 byte VRF output.
+    /// The output depends only on the key and `message`, and [`PublicKey::verify_vrf_signature`]
+    /// recovers it from the signature. An Ed25519 key is converted to X25519 first.
+    pub fn calculate_vrf_signature<R: CryptoRng + Rng>(
+        &self,
+        message: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Box<[u8]>, [u8; 32])> {
+        match self.to_x25519().key {
+            PrivateKeyData::DjbPrivateKey(k) => {
+                let kp = curve25519::KeyPair::from(k);
+                let (signature, output) = kp.calculate_vrf_signature(csprng, message);
+                Ok((Box::new(signature), output))
+            }
+            _ => Err(SignalProtocolError::InternalError(
+                "to_x25519 returned a non-Djb key",
+            )),
+        }
+    }
+
+    /// Calculates an X25519 agreement, first converting either key to X25519 if it is Ed25519.
+    /// Only identity keys can be Ed25519; every other key in the protocol is parsed as Djb.
     pub fn calculate_agreement(&self, their_key: &PublicKey) -> Result<Box<[u8]>> {
-        match (self.key, their_key.key) {
+        match (self.to_x25519().key, their_key.to_x25519()?.key) {
             (PrivateKeyData::DjbPrivateKey(priv_key), PublicKeyData::DjbPublicKey(pub_key)) => {
                 let kp = curve25519::KeyPair::from(priv_key);
                 Ok(Box::new(kp.calculate_agreement(&pub_key)))
             }
+            _ => Err(SignalProtocolError::InternalError(
+                "to_x25519 returned a non-Djb key",
+            )),
         }
     }
 }
@@ -256,6 +441,19 @@ impl KeyPair {
         }
     }
 
+    pub fn generate_ed25519<R: Rng + CryptoRng>(csprng: &mut R) -> Self {
+        let keypair = ed25519::KeyPair::new(csprng);
+
+        let public_key = PublicKey::from(PublicKeyData::Ed25519PublicKey(*keypair.public_key()));
+        let private_key =
+            PrivateKey::from(PrivateKeyData::Ed25519PrivateKey(*keypair.private_key()));
+
+        Self {
+            public_key,
+            private_key,
+        }
+    }
+
     pub fn new(public_key: PublicKey, private_key: PrivateKey) -> Self {
         Self {
             public_key,
@@ -265,7 +463,8 @@ impl KeyPair {
 
     pub fn from_public_and_private(public_key: &[u8], private_key: &[u8]) -> Result<Self> {
         let public_key = decode_point(public_key)?;
-        let private_key = decode_private_point(private_key)?;
+        let private_key =
+            PrivateKey::deserialize_with_key_type(public_key.key_type(), private_key)?;
         Ok(Self {
             public_key,
             private_key,
@@ -280,6 +479,14 @@ impl KeyPair {
         self.private_key.calculate_signature(message, csprng)
     }
 
+    pub fn calculate_vrf_signature<R: CryptoRng + Rng>(
+        &self,
+        message: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Box<[u8]>, [u8; 32])> {
+        self.private_key.calculate_vrf_signature(message, csprng)
+    }
+
     pub fn calculate_agreement(&self, their_key: &PublicKey) -> Result<Box<[u8]>> {
         self.private_key.calculate_agreement(their_key)
     }
@@ -364,4 +571,210 @@ mod tests {
             &extra_space_decode.unwrap().serialize()[..]
         );
     }
+
+    #[test]
+    fn test_ed25519_signatures() {
+        let mut csprng = OsRng;
+        let key_pair = KeyPair::generate_ed25519(&mut csprng);
+        assert_eq!(key_pair.public_key.key_type(), KeyType::Ed25519);
+        assert_eq!(key_pair.public_key.serialize()[0], 0x06);
+
+        let mut message = [0u8; 1024];
+        let signature = key_pair.calculate_signature(&message, &mut csprng).unwrap();
+        assert!(key_pair
+            .public_key
+            .verify_signature(&message, &signature)
+            .unwrap());
+        message[0] ^= 0x01u8;
+        assert!(!key_pair
+            .public_key
+            .verify_signature(&message, &signature)
+            .unwrap());
+        assert!(key_pair
+            .public_key
+            .verify_signature(&message, &signature[1..])
+            .is_err());
+
+        // An XEdDSA signature does not verify under the Ed25519 key, nor the reverse.
+        let djb_key_pair = KeyPair::generate(&mut csprng);
+        let djb_signature = djb_key_pair
+            .calculate_signature(&message, &mut csprng)
+            .unwrap();
+        assert!(!key_pair
+            .public_key
+            .verify_signature(&message, &djb_signature)
+            .unwrap());
+        let signature = key_pair.calculate_signature(&message, &mut csprng).unwrap();
+        assert!(!djb_key_pair
+            .public_key
+            .verify_signature(&message, &signature)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_ed25519_decode() {
+        let mut csprng = OsRng;
+        let key_pair = KeyPair::generate_ed25519(&mut csprng);
+        let serialized_public = key_pair.public_key.serialize();
+
+        let decoded = PublicKey::deserialize_identity_key(&serialized_public).unwrap();
+        assert_eq!(decoded, key_pair.public_key);
+        assert_ne!(
+            decoded.to_x25519().unwrap().serialize(),
+            decoded.serialize()
+        );
+        assert!(PublicKey::deserialize_identity_key(&serialized_public[..32]).is_err());
+
+        // y = 2 is not on the curve.
+        let mut not_a_point = [0u8; 33];
+        not_a_point[0] = 0x06;
+        not_a_point[1] = 0x02;
+        assert!(matches!(
+            PublicKey::deserialize_identity_key(&not_a_point),
+            Err(SignalProtocolError::InvalidPublicKeyPoint(KeyType::Ed25519))
+        ));
+
+        // Anywhere but an identity key, Ed25519 keys are rejected.
+        assert!(matches!(
+            decode_point(&serialized_public),
+            Err(SignalProtocolError::BadKeyType(0x06))
+        ));
+        assert!(KeyPair::from_public_and_private(
+            &serialized_public,
+            &key_pair.private_key.serialize()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_ed25519_agreement() {
+        let mut csprng = OsRng;
+        for _ in 0..20 {
+            let ed_key_pair = KeyPair::generate_ed25519(&mut csprng);
+            let ed_key_pair2 = KeyPair::generate_ed25519(&mut csprng);
+            let djb_key_pair = KeyPair::generate(&mut csprng);
+
+            assert_eq!(
+                ed_key_pair.private_key.to_x25519().public_key().unwrap(),
+                ed_key_pair.public_key.to_x25519().unwrap()
+            );
+
+            assert_eq!(
+                ed_key_pair
+                    .calculate_agreement(&djb_key_pair.public_key)
+                    .unwrap(),
+                djb_key_pair
+                    .calculate_agreement(&ed_key_pair.public_key)
+                    .unwrap()
+            );
+            assert_eq!(
+                ed_key_pair
+                    .calculate_agreement(&ed_key_pair2.public_key)
+                    .unwrap(),
+                ed_key_pair2
+                    .calculate_agreement(&ed_key_pair.public_key)
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_vrf_signatures() {
+        let mut csprng = OsRng;
+        for key_pair in &[
+            KeyPair::generate(&mut csprng),
+            KeyPair::generate_ed25519(&mut csprng),
+        ] {
+            let message = [0x42u8; 100];
+            let (signature, output) = key_pair
+                .calculate_vrf_signature(&message, &mut csprng)
+                .unwrap();
+            assert_eq!(signature.len(), 96);
+            assert_eq!(output.len(), 32);
+
+            assert_eq!(
+                key_pair
+                    .public_key
+                    .verify_vrf_signature(&message, &signature)
+                    .unwrap(),
+                output
+            );
+            assert!(matches!(
+                key_pair
+                    .public_key
+                    .verify_vrf_signature(&message[1..], &signature),
+                Err(SignalProtocolError::SignatureValidationFailed)
+            ));
+            assert!(matches!(
+                key_pair
+                    .public_key
+                    .verify_vrf_signature(&message, &signature[..64]),
+                Err(SignalProtocolError::MismatchedSignatureLengthForKey(_, 64))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_verify_signatures_batch() {
+        let mut csprng = OsRng;
+        assert!(PublicKey::verify_signatures_batch(&[], &mut csprng).is_ok());
+
+        let key_pairs: Vec<KeyPair> = (0..64)
+            .map(|i| {
+                if i % 3 == 0 {
+                    KeyPair::generate_ed25519(&mut csprng)
+                } else {
+                    KeyPair::generate(&mut csprng)
+                }
+            })
+            .collect();
+        let messages: Vec<Vec<u8>> = (0..64).map(|i| vec![i as u8; i + 1]).collect();
+        let signatures: Vec<Box<[u8]>> = key_pairs
+            .iter()
+            .zip(&messages)
+            .map(|(key_pair, message)| key_pair.calculate_signature(message, &mut csprng).unwrap())
+            .collect();
+
+        let mut entries: Vec<(&PublicKey, &[u8], &[u8])> = key_pairs
+            .iter()
+            .zip(&messages)
+            .zip(&signatures)
+            .map(|((key_pair, message), signature)| {
+                (&key_pair.public_key, &message[..], &signature[..])
+            })
+            .collect();
+        assert!(PublicKey::verify_signatures_batch(&entries, &mut csprng).is_ok());
+
+        let mut bad_signature = signatures[17].to_vec();
+        bad_signature[40] ^= 0x01;
+        entries[3].1 = &messages[4];
+        entries[17].2 = &bad_signature;
+        entries[40].2 = &signatures[40][..63];
+        entries[41].0 = &key_pairs[42].public_key;
+
+        let expected = vec![3, 17, 40, 41];
+        for (i, entry) in entries.iter().enumerate() {
+            assert_eq!(
+                entry.0.verify_signature(entry.1, entry.2).unwrap_or(false),
+                !expected.contains(&i)
+            );
+        }
+        match PublicKey::verify_signatures_batch(&entries, &mut csprng) {
+            Err(SignalProtocolError::BatchSignatureValidationFailed(failed)) => {
+                assert_eq!(failed, expected)
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // A single bad entry is found even when every signature decodes.
+        let mut entries = entries[41..].to_vec();
+        entries[0].0 = &key_pairs[41].public_key;
+        entries[10].1 = &messages[0];
+        match PublicKey::verify_signatures_batch(&entries, &mut csprng) {
+            Err(SignalProtocolError::BatchSignatureValidationFailed(failed)) => {
+                assert_eq!(failed, vec![10])
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
 }
@@ -0,0 +1,206 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::{IsIdentity, VartimeMultiscalarMul};
+use rand::{CryptoRng, Rng};
+use subtle::ConstantTimeEq;
+
+/// A decoded XEdDSA or Ed25519 signature, valid if `R` is the encoding of `sB - hA`.
+pub struct VerificationTerms {
+    pub cap_a: EdwardsPoint,
+    pub cap_r: [u8; 32],
+    pub h: Scalar,
+    pub s: Scalar,
+}
+
+impl VerificationTerms {
+    /// The check from the XEdDSA specification: compares the encoding of `sB - hA` with `R`.
+    pub fn verify(&self) -> bool {
+        let cap_r_check =
+            EdwardsPoint::vartime_double_scalar_mul_basepoint(&self.h, &-self.cap_a, &self.s);
+        bool::from(cap_r_check.compress().as_bytes().ct_eq(&self.cap_r))
+    }
+}
+
+/// Checks `8 * sum(z_i * (R_i - s_i B + h_i A_i)) == 0` for random 128 bit `z_i` with a single
+/// multiscalar multiplication.
+///
+/// The check is cofactored. It holds if every entry passes [`VerificationTerms::verify`], and
+/// fails except with negligible probability if some `R_i - s_i B + h_i A_i` has a component of
+/// large order. It also holds for entries whose `R_i` is off only by a point of small order,
+/// which [`VerificationTerms::verify`] rejects; only the signer can produce those. Without the
+/// cofactor such an entry would pass or fail depending on `z_i`.
+pub fn verify<'a, R, I>(terms: I, csprng: &mut R) -> bool
+where
+    R: CryptoRng + Rng,
+    I: IntoIterator<Item = &'a VerificationTerms>,
+{
+    let mut scalars = vec![Scalar::zero()];
+    let mut points = vec![ED25519_BASEPOINT_POINT];
+    let mut basepoint_scalar = Scalar::zero();
+
+    for terms in terms {
+        let cap_r = match decode_point(&terms.cap_r) {
+            Some(cap_r) => cap_r,
+            None => return false,
+        };
+
+        let mut z = [0u8; 32];
+        csprng.fill_bytes(&mut z[..16]);
+        let z = Scalar::from_bits(z);
+
+        basepoint_scalar -= z * terms.s;
+        scalars.push(z);
+        points.push(cap_r);
+        scalars.push(z * terms.h);
+        points.push(terms.cap_a);
+    }
+    scalars[0] = basepoint_scalar;
+
+    EdwardsPoint::vartime_multiscalar_mul(scalars, points)
+        .mul_by_cofactor()
+        .is_identity()
+}
+
+/// Decompresses `R`, rejecting the non-canonical encodings that [`VerificationTerms::verify`]
+/// rejects.
+fn decode_point(bytes: &[u8; 32]) -> Option<EdwardsPoint> {
+    if !is_canonical_point_encoding(bytes) {
+        return None;
+    }
+    CompressedEdwardsY(*bytes).decompress()
+}
+
+/// Whether `bytes` is the encoding `compress` would produce for the point it decompresses to:
+/// y must be less than p = 2^255 - 19, and the sign bit must be clear if x is 0, which is when
+/// y is 1 or -1.
+fn is_canonical_point_encoding(bytes: &[u8; 32]) -> bool {
+    let mut y = *bytes;
+    y[31] &= 0b0111_1111_u8;
+    let high_bytes_set = y[1..31].iter().all(|&b| b == 0xFF) && y[31] == 0x7F;
+    let high_bytes_clear = y[1..].iter().all(|&b| b == 0);
+    if high_bytes_set && y[0] >= 0xED {
+        return false;
+    }
+    let x_is_zero = (high_bytes_clear && y[0] == 1) || (high_bytes_set && y[0] == 0xEC);
+    !(x_is_zero && bytes[31] & 0b1000_0000_u8 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use curve25519_dalek::constants::EIGHT_TORSION;
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+
+    use super::super::{curve25519, ed25519};
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let mut csprng = OsRng;
+        let message = b"batch";
+
+        let mut terms = Vec::new();
+        for _ in 0..32 {
+            let key_pair = curve25519::KeyPair::new(&mut csprng);
+            let signature = key_pair.calculate_signature(&mut csprng, message);
+            terms.push(
+                curve25519::KeyPair::verification_terms(key_pair.public_key(), message, &signature)
+                    .unwrap(),
+            );
+
+            let key_pair = ed25519::KeyPair::new(&mut csprng);
+            let signature = key_pair.calculate_signature(message);
+            terms.push(
+                ed25519::KeyPair::verification_terms(key_pair.public_key(), message, &signature)
+                    .unwrap(),
+            );
+        }
+        assert!(terms.iter().all(VerificationTerms::verify));
+        assert!(verify(&terms, &mut csprng));
+        assert!(verify(&[], &mut csprng));
+
+        // Terms that differ from valid ones in h, s or R.
+        terms[7].h += Scalar::one();
+        assert!(!verify(&terms, &mut csprng));
+        terms[7].h -= Scalar::one();
+        terms[30].s += Scalar::one();
+        assert!(!verify(&terms, &mut csprng));
+        terms[30].s -= Scalar::one();
+        terms[63].cap_r = terms[62].cap_r;
+        assert!(!verify(&terms, &mut csprng));
+    }
+
+    #[test]
+    fn test_verify_small_order_components() {
+        let mut csprng = OsRng;
+        let torsion = EIGHT_TORSION[1];
+
+        for _ in 0..32 {
+            let a = Scalar::random(&mut csprng);
+            let r = Scalar::random(&mut csprng);
+            let h = Scalar::random(&mut csprng);
+            let cap_r = r * ED25519_BASEPOINT_POINT;
+
+            // R off by a point of order 8: rejected on its own, accepted by the cofactored batch
+            // check.
+            let mut terms = vec![VerificationTerms {
+                cap_a: a * ED25519_BASEPOINT_POINT,
+                cap_r: (cap_r + torsion).compress().to_bytes(),
+                h,
+                s: r + h * a,
+            }];
+            assert!(!terms[0].verify());
+            // A key with a small order component, which leaves sB - hA off by -hT.
+            terms.push(VerificationTerms {
+                cap_a: a * ED25519_BASEPOINT_POINT + torsion,
+                cap_r: cap_r.compress().to_bytes(),
+                h,
+                s: r + h * a,
+            });
+            assert_eq!(terms[1].verify(), (h * torsion).is_identity());
+            assert!(verify(&terms, &mut csprng));
+
+            // Off by a point of large order as well.
+            terms[0].cap_r = (cap_r + torsion + ED25519_BASEPOINT_POINT)
+                .compress()
+                .to_bytes();
+            assert!(!terms[0].verify());
+            assert!(!verify(&terms, &mut csprng));
+        }
+    }
+
+    #[test]
+    fn test_is_canonical_point_encoding() {
+        let mut csprng = OsRng;
+        for _ in 0..100 {
+            let mut bytes = [0u8; 32];
+            csprng.fill_bytes(&mut bytes);
+            if let Some(point) = CompressedEdwardsY(bytes).decompress() {
+                assert!(is_canonical_point_encoding(&point.compress().to_bytes()));
+            }
+        }
+
+        // y = p + 1 decodes to the identity; y = 1 with the sign bit set has x = -0.
+        let mut p_plus_one = [0xFFu8; 32];
+        p_plus_one[0] = 0xEE;
+        p_plus_one[31] = 0x7F;
+        let mut minus_zero = [0u8; 32];
+        minus_zero[0] = 1;
+        minus_zero[31] = 0x80;
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        for &(bytes, canonical) in &[(p_plus_one, false), (minus_zero, false), (identity, true)] {
+            let point = CompressedEdwardsY(bytes).decompress().unwrap();
+            assert_eq!(point.compress().to_bytes() == bytes, canonical);
+            assert_eq!(is_canonical_point_encoding(&bytes), canonical);
+        }
+    }
+}
@@ -6,18 +6,23 @@
 //
 
 use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
-use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
 use curve25519_dalek::montgomery::MontgomeryPoint;
 use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
 use rand::{CryptoRng, Rng};
 use sha2::{Digest, Sha512};
 use subtle::ConstantTimeEq;
 use x25519_dalek::{PublicKey, StaticSecret};
 
+use super::batch::VerificationTerms;
+
 const AGREEMENT_LENGTH: usize = 32;
 const PRIVATE_KEY_LENGTH: usize = 32;
 const PUBLIC_KEY_LENGTH: usize = 32;
 const SIGNATURE_LENGTH: usize = 64;
+const VRF_SIGNATURE_LENGTH: usize = 96;
+const VRF_OUTPUT_LENGTH: usize = 32;
 
 #[derive(Debug, Clone)]
 pub struct KeyPair {
@@ -102,12 +107,22 @@ impl KeyPair {
         message: &[u8],
         signature: &[u8; SIGNATURE_LENGTH],
     ) -> bool {
+        match Self::verification_terms(their_public_key, message, signature) {
+            Some(terms) => terms.verify(),
+            None => false,
+        }
+    }
+
+    /// Decodes a signature for [`verify_signature`](Self::verify_signature) or batch
+    /// verification, or returns None if it cannot be valid.
+    pub fn verification_terms(
+        their_public_key: &[u8; PUBLIC_KEY_LENGTH],
+        message: &[u8],
+        signature: &[u8; SIGNATURE_LENGTH],
+    ) -> Option<VerificationTerms> {
         let mont_point = MontgomeryPoint(*their_public_key);
         let ed_pub_key_point =
-            match mont_point.to_edwards((signature[SIGNATURE_LENGTH - 1] & 0b1000_0000_u8) >> 7) {
-                Some(x) => x,
-                None => return false,
-            };
+            mont_point.to_edwards((signature[SIGNATURE_LENGTH - 1] & 0b1000_0000_u8) >> 7)?;
         let cap_a = ed_pub_key_point.compress();
         let mut cap_r = [0u8; 32];
         cap_r.copy_from_slice(&signature[..32]);
@@ -115,9 +130,8 @@ impl KeyPair {
         s.copy_from_slice(&signature[32..]);
         s[31] &= 0b0111_1111_u8;
         if (s[31] & 0b1110_0000_u8) != 0 {
-            return false;
+            return None;
         }
-        let minus_cap_a = -ed_pub_key_point;
 
         let mut hash = Sha512::new();
         hash.update(&cap_r);
@@ -125,14 +139,128 @@ impl KeyPair {
         hash.update(&message);
         let h = Scalar::from_hash(hash);
 
-        let cap_r_check_point = EdwardsPoint::vartime_double_scalar_mul_basepoint(
-            &h,
-            &minus_cap_a,
-            &Scalar::from_bits(s),
-        );
-        let cap_r_check = cap_r_check_point.compress();
+        Some(VerificationTerms {
+            cap_a: ed_pub_key_point,
+            cap_r,
+            h,
+            s: Scalar::from_bits(s),
+        })
+    }
 
-        bool::from(cap_r_check.as_bytes().ct_eq(&cap_r))
+    /// Calculates a VXEdDSA signature and its VRF output.
+    ///
+    /// Refer to https://signal.org/docs/specifications/xeddsa/#vxeddsa for more details. Unlike
+    /// [`calculate_signature`](Self::calculate_signature), this follows the specification exactly:
+    /// the Edwards public key always has sign bit 0 and the private scalar is negated to match.
+    pub fn calculate_vrf_signature<R>(
+        &self,
+        csprng: &mut R,
+        message: &[u8],
+    ) -> ([u8; VRF_SIGNATURE_LENGTH], [u8; VRF_OUTPUT_LENGTH])
+    where
+        R: CryptoRng + Rng,
+    {
+        let mut random_bytes = [0u8; 64];
+        csprng.fill_bytes(&mut random_bytes);
+        self.calculate_vrf_signature_with_random(&random_bytes, message)
+    }
+
+    fn calculate_vrf_signature_with_random(
+        &self,
+        random_bytes: &[u8; 64],
+        message: &[u8],
+    ) -> ([u8; VRF_SIGNATURE_LENGTH], [u8; VRF_OUTPUT_LENGTH]) {
+        // calculate_key_pair
+        let k = Scalar::from_bits(self.private_key).reduce();
+        let mut cap_a = (&k * &ED25519_BASEPOINT_TABLE).compress().to_bytes();
+        let a = if cap_a[31] & 0b1000_0000_u8 != 0 {
+            -k
+        } else {
+            k
+        };
+        cap_a[31] &= 0b0111_1111_u8;
+
+        let cap_bv = hash_to_point(&cap_a, message);
+        let cap_v = a * cap_bv;
+        let cap_v_bytes = cap_v.compress();
+
+        let mut hash = hash_i(3);
+        hash.update(a.as_bytes());
+        hash.update(cap_v_bytes.as_bytes());
+        hash.update(&random_bytes[..]);
+        let r = Scalar::from_hash(hash);
+
+        let cap_r = &r * &ED25519_BASEPOINT_TABLE;
+        let cap_rv = r * cap_bv;
+
+        let mut hash = hash_i(4);
+        hash.update(cap_a);
+        hash.update(cap_v_bytes.as_bytes());
+        hash.update(cap_r.compress().as_bytes());
+        hash.update(cap_rv.compress().as_bytes());
+        hash.update(message);
+        let h = Scalar::from_hash(hash);
+
+        let s = r + (h * a);
+
+        let mut signature = [0u8; VRF_SIGNATURE_LENGTH];
+        signature[..32].copy_from_slice(cap_v_bytes.as_bytes());
+        signature[32..64].copy_from_slice(h.as_bytes());
+        signature[64..].copy_from_slice(s.as_bytes());
+        (signature, vrf_output(&cap_v))
+    }
+
+    /// Verifies a VXEdDSA signature, returning its VRF output if it is valid.
+    pub fn verify_vrf_signature(
+        their_public_key: &[u8; PUBLIC_KEY_LENGTH],
+        message: &[u8],
+        signature: &[u8; VRF_SIGNATURE_LENGTH],
+    ) -> Option<[u8; VRF_OUTPUT_LENGTH]> {
+        let mut cap_v_bytes = [0u8; 32];
+        cap_v_bytes.copy_from_slice(&signature[..32]);
+        let mut h = [0u8; 32];
+        h.copy_from_slice(&signature[32..64]);
+        let mut s = [0u8; 32];
+        s.copy_from_slice(&signature[64..]);
+
+        let mut cap_v_y = cap_v_bytes;
+        cap_v_y[31] &= 0b0111_1111_u8;
+        if !is_canonical_field_element(their_public_key)
+            || !is_canonical_field_element(&cap_v_y)
+            || (h[31] & 0b1110_0000_u8) != 0
+            || (s[31] & 0b1110_0000_u8) != 0
+        {
+            return None;
+        }
+
+        // convert_mont
+        let cap_a_point = MontgomeryPoint(*their_public_key).to_edwards(0)?;
+        let cap_a = cap_a_point.compress();
+        let cap_bv = hash_to_point(cap_a.as_bytes(), message);
+        let cap_v = CompressedEdwardsY(cap_v_bytes).decompress()?;
+        if cap_a_point.is_small_order() || cap_v.is_small_order() || cap_bv.is_identity() {
+            return None;
+        }
+
+        let h_scalar = Scalar::from_bits(h);
+        let s_scalar = Scalar::from_bits(s);
+        let cap_r =
+            EdwardsPoint::vartime_double_scalar_mul_basepoint(&h_scalar, &-cap_a_point, &s_scalar);
+        let cap_rv = (s_scalar * cap_bv) - (h_scalar * cap_v);
+
+        let mut hash = hash_i(4);
+        hash.update(cap_a.as_bytes());
+        hash.update(cap_v_bytes);
+        hash.update(cap_r.compress().as_bytes());
+        hash.update(cap_rv.compress().as_bytes());
+        hash.update(message);
+        let h_check = Scalar::from_hash(hash);
+
+        if bool::from(h_check.as_bytes().ct_eq(&h)) {
+            Some(vrf_output(&cap_v))
+        } else {
+            None
+        }
     }
 
     pub fn public_key(&self) -> &[u8; PUBLIC_KEY_LENGTH] {
@@ -144,6 +272,39 @@ impl KeyPair {
     }
 }
 
+/// The specification's `hash_i`: SHA-512 prefixed with the 32 byte encoding of 2^256 - 1 - i.
+fn hash_i(i: u8) -> Sha512 {
+    let mut prefix = [0xFFu8; 32];
+    prefix[0] -= i;
+    let mut hash = Sha512::new();
+    hash.update(prefix);
+    hash
+}
+
+/// The specification's `hash_to_point(A || M)`, using `hash_2` and Elligator 2.
+fn hash_to_point(cap_a: &[u8; 32], message: &[u8]) -> EdwardsPoint {
+    let mut input = Vec::with_capacity(64 + message.len());
+    input.extend_from_slice(&[0xFFu8; 32]);
+    input[0] -= 2;
+    input.extend_from_slice(cap_a);
+    input.extend_from_slice(message);
+    EdwardsPoint::hash_from_bytes::<Sha512>(&input)
+}
+
+fn vrf_output(cap_v: &EdwardsPoint) -> [u8; VRF_OUTPUT_LENGTH] {
+    let mut hash = hash_i(5);
+    hash.update(cap_v.mul_by_cofactor().compress().as_bytes());
+    let mut output = [0u8; VRF_OUTPUT_LENGTH];
+    output.copy_from_slice(&hash.finalize()[..VRF_OUTPUT_LENGTH]);
+    output
+}
+
+/// Whether the little-endian `bytes` are less than p = 2^255 - 19.
+fn is_canonical_field_element(bytes: &[u8; 32]) -> bool {
+    !(bytes[31] & 0b1000_0000_u8 != 0
+        || (bytes[31] == 0x7F && bytes[1..31].iter().all(|&b| b == 0xFF) && bytes[0] >= 0xED))
+}
+
 pub fn derive_public_key(private_key: &[u8; 32]) -> [u8; 32] {
     *PublicKey::from(&StaticSecret::from(*private_key)).as_bytes()
 }
@@ -291,4 +452,132 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_vrf_signature() {
+        // (private key, message, signature, VRF output) with the 64 random bytes Z = 0, 1, .., 63.
+        // These were generated by this implementation and reproduced by the Python signer in
+        // tests/data/vxeddsa/vxeddsa.py, which follows the XEdDSA/VXEdDSA specification but was
+        // written alongside this code. The vector from vxeddsa_fast_test in libsignal-protocol-c
+        // (curve25519/ed25519/tests/internal_fast_tests.c) still needs to be ported here.
+        //
+        // The first key's Edwards public key has sign bit 1, so its scalar is negated; the
+        // second's has sign bit 0.
+        let vectors = [
+            (
+                "c097248412e58bf05df487968205132794178e367637f5818f81e0e6ce73e865",
+                "05edce9d9c415ca78cb7252e72c2c4a554d3eb29485a0e1d503118d1a82d99fb4a",
+                "b0df1cfadf62c8344a6a10c164970ad416edcf3df465fd0c0a46c9bda537532f\
+                 24314cd8d888e4f28dee6506e18638043ca7ab6418080c4fb01441e61741ce05\
+                 bd92edd32d0b7ad1a315d391b80a5906bdb6d3c58a44968f71439c5fc8274a0f",
+                "999ae2bbbcdbb188bef355040f5c4f98b7e49814d13c1ea2ecbba4bf35071d7b",
+            ),
+            (
+                "b03b34c33a1c44f225b662d2bf4859b8135411fa7b0386d45fb75dc5b91b4466",
+                "00",
+                "f48ec84027d06db0a19077897a2c231e7c44638fa403e3bf352d30bbc22b08e5\
+                 602bb5f4f5434b59a9092d398387e33fc031066a3d8050ada860ce34000c5e03\
+                 07cccb031d4c308879295ca3e57d312e6b4ae06a68f11727300b99c4ccc56005",
+                "5f19e0dfab1800e6a4a183dee10bdac45f76e99e58a2b2bc2373c23b257fb458",
+            ),
+        ];
+        let mut random_bytes = [0u8; 64];
+        for (i, b) in random_bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        for (private, message, signature, output) in vectors.iter() {
+            let mut private_key = [0u8; PRIVATE_KEY_LENGTH];
+            private_key.copy_from_slice(&hex::decode(private).unwrap());
+            let key_pair = KeyPair::from(private_key);
+            let message = hex::decode(message).unwrap();
+
+            let (calculated_signature, calculated_output) =
+                key_pair.calculate_vrf_signature_with_random(&random_bytes, &message);
+            assert_eq!(hex::encode(&calculated_signature[..]), *signature);
+            assert_eq!(hex::encode(calculated_output), *output);
+
+            assert_eq!(
+                KeyPair::verify_vrf_signature(
+                    key_pair.public_key(),
+                    &message,
+                    &calculated_signature
+                ),
+                Some(calculated_output)
+            );
+
+            for i in 0..calculated_signature.len() {
+                let mut signature_copy = calculated_signature;
+                signature_copy[i] ^= 0x01u8;
+                assert_eq!(
+                    KeyPair::verify_vrf_signature(key_pair.public_key(), &message, &signature_copy),
+                    None,
+                    "signature check passed when it should not have"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_vrf_signatures() {
+        let mut csprng = OsRng;
+        for _ in 0..50 {
+            let mut message = [0u8; 64];
+            csprng.fill_bytes(&mut message);
+            let key_pair = KeyPair::new(&mut csprng);
+            let (signature, output) = key_pair.calculate_vrf_signature(&mut csprng, &message);
+            let (signature2, output2) = key_pair.calculate_vrf_signature(&mut csprng, &message);
+
+            // The signatures differ but the VRF output is a function of the key and message.
+            assert_ne!(signature[..], signature2[..]);
+            assert_eq!(output, output2);
+            assert_eq!(
+                KeyPair::verify_vrf_signature(key_pair.public_key(), &message, &signature2),
+                Some(output)
+            );
+
+            message[0] ^= 0x01u8;
+            assert_eq!(
+                KeyPair::verify_vrf_signature(key_pair.public_key(), &message, &signature),
+                None
+            );
+            let other = KeyPair::new(&mut csprng);
+            assert_ne!(
+                other.calculate_vrf_signature(&mut csprng, &message).1,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_vrf_signature_rejects_non_canonical_encodings() {
+        let mut csprng = OsRng;
+        let key_pair = KeyPair::new(&mut csprng);
+        let message = b"vxeddsa";
+        let (signature, _) = key_pair.calculate_vrf_signature(&mut csprng, message);
+
+        // h and s are reduced mod q, so their top three bits are always clear.
+        for &offset in &[63, 95] {
+            let mut signature_copy = signature;
+            signature_copy[offset] |= 0b0010_0000_u8;
+            assert_eq!(
+                KeyPair::verify_vrf_signature(key_pair.public_key(), message, &signature_copy),
+                None
+            );
+        }
+
+        // u >= p
+        let mut public_key = *key_pair.public_key();
+        public_key[31] |= 0b1000_0000_u8;
+        assert_eq!(
+            KeyPair::verify_vrf_signature(&public_key, message, &signature),
+            None
+        );
+        let mut p = [0xFFu8; 32];
+        p[0] = 0xED;
+        p[31] = 0x7F;
+        assert!(!is_canonical_field_element(&p));
+        p[0] = 0xEC;
+        assert!(is_canonical_field_element(&p));
+    }
 }
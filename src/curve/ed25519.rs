@@ -0,0 +1,274 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+use rand::{CryptoRng, Rng};
+use sha2::{Digest, Sha512};
+
+use super::batch::VerificationTerms;
+
+const PRIVATE_KEY_LENGTH: usize = 32;
+const PUBLIC_KEY_LENGTH: usize = 32;
+const SIGNATURE_LENGTH: usize = 64;
+
+/// An Ed25519 key pair as specified in RFC 8032. The private key is the 32 byte seed, from which
+/// the signing scalar and the nonce prefix are both derived.
+#[derive(Debug, Clone)]
+pub struct KeyPair {
+    public_key: [u8; PUBLIC_KEY_LENGTH],
+    private_key: [u8; PRIVATE_KEY_LENGTH],
+}
+
+impl KeyPair {
+    pub fn new<R>(csprng: &mut R) -> Self
+    where
+        R: CryptoRng + Rng,
+    {
+        let mut seed = [0u8; PRIVATE_KEY_LENGTH];
+        csprng.fill_bytes(&mut seed);
+        Self::from(seed)
+    }
+
+    /// Calculates a deterministic Ed25519 signature (RFC 8032 section 5.1.6).
+    pub fn calculate_signature(&self, message: &[u8]) -> [u8; SIGNATURE_LENGTH] {
+        let (a, prefix) = expand_seed(&self.private_key);
+
+        let mut hash1 = Sha512::new();
+        hash1.update(prefix);
+        hash1.update(message);
+        let r = Scalar::from_hash(hash1);
+        let cap_r = (&r * &ED25519_BASEPOINT_TABLE).compress();
+
+        let mut hash = Sha512::new();
+        hash.update(cap_r.as_bytes());
+        hash.update(self.public_key);
+        hash.update(message);
+        let h = Scalar::from_hash(hash);
+
+        let s = (h * a) + r;
+
+        let mut result = [0u8; SIGNATURE_LENGTH];
+        result[..32].copy_from_slice(cap_r.as_bytes());
+        result[32..].copy_from_slice(s.as_bytes());
+        result
+    }
+
+    /// Verifies an Ed25519 signature (RFC 8032 section 5.1.7), rejecting a non-canonical `S`.
+    pub fn verify_signature(
+        their_public_key: &[u8; PUBLIC_KEY_LENGTH],
+        message: &[u8],
+        signature: &[u8; SIGNATURE_LENGTH],
+    ) -> bool {
+        match Self::verification_terms(their_public_key, message, signature) {
+            Some(terms) => terms.verify(),
+            None => false,
+        }
+    }
+
+    /// Decodes a signature for [`verify_signature`](Self::verify_signature) or batch
+    /// verification, or returns None if it cannot be valid.
+    pub fn verification_terms(
+        their_public_key: &[u8; PUBLIC_KEY_LENGTH],
+        message: &[u8],
+        signature: &[u8; SIGNATURE_LENGTH],
+    ) -> Option<VerificationTerms> {
+        let cap_a = CompressedEdwardsY(*their_public_key).decompress()?;
+        let mut cap_r = [0u8; 32];
+        cap_r.copy_from_slice(&signature[..32]);
+        let mut s = [0u8; 32];
+        s.copy_from_slice(&signature[32..]);
+        let s = Scalar::from_canonical_bytes(s)?;
+
+        let mut hash = Sha512::new();
+        hash.update(cap_r);
+        hash.update(their_public_key);
+        hash.update(message);
+        let h = Scalar::from_hash(hash);
+
+        Some(VerificationTerms { cap_a, cap_r, h, s })
+    }
+
+    pub fn public_key(&self) -> &[u8; PUBLIC_KEY_LENGTH] {
+        &self.public_key
+    }
+
+    pub fn private_key(&self) -> &[u8; PRIVATE_KEY_LENGTH] {
+        &self.private_key
+    }
+}
+
+/// Splits the SHA-512 hash of `seed` into the clamped signing scalar and the nonce prefix.
+fn expand_seed(seed: &[u8; PRIVATE_KEY_LENGTH]) -> (Scalar, [u8; 32]) {
+    let hash = Sha512::digest(seed);
+    let mut prefix = [0u8; 32];
+    prefix.copy_from_slice(&hash[32..]);
+    (Scalar::from_bits(clamp(&hash[..32])), prefix)
+}
+
+fn clamp(bytes: &[u8]) -> [u8; 32] {
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(bytes);
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    scalar
+}
+
+pub fn is_valid_public_key(public_key: &[u8; PUBLIC_KEY_LENGTH]) -> bool {
+    CompressedEdwardsY(*public_key).decompress().is_some()
+}
+
+/// The X25519 private key for the same secret scalar as the Ed25519 key with this seed, as
+/// computed by libsodium's `crypto_sign_ed25519_sk_to_curve25519`.
+pub fn x25519_private_key(seed: &[u8; PRIVATE_KEY_LENGTH]) -> [u8; 32] {
+    clamp(&Sha512::digest(seed)[..32])
+}
+
+/// The X25519 public key for the birationally equivalent Montgomery point, as computed by
+/// libsodium's `crypto_sign_ed25519_pk_to_curve25519`. None if `public_key` is not a valid point.
+pub fn x25519_public_key(public_key: &[u8; PUBLIC_KEY_LENGTH]) -> Option<[u8; 32]> {
+    CompressedEdwardsY(*public_key)
+        .decompress()
+        .map(|point| point.to_montgomery().to_bytes())
+}
+
+impl From<[u8; PRIVATE_KEY_LENGTH]> for KeyPair {
+    fn from(private_key: [u8; PRIVATE_KEY_LENGTH]) -> Self {
+        let (a, _) = expand_seed(&private_key);
+        let public_key = (&a * &ED25519_BASEPOINT_TABLE).compress().to_bytes();
+
+        KeyPair {
+            public_key,
+            private_key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrayref::array_ref;
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+
+    use super::*;
+
+    #[test]
+    fn test_rfc8032_vectors() {
+        // RFC 8032 section 7.1, tests 1 to 3.
+        let vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                 5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+            (
+                "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+                "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+                "af82",
+                "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+                 18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+            ),
+        ];
+
+        for (private, public, message, signature) in vectors.iter() {
+            let private = hex::decode(private).unwrap();
+            let key_pair = KeyPair::from(*array_ref![private, 0, 32]);
+            let public = hex::decode(public).unwrap();
+            let public = array_ref![public, 0, 32];
+            let message = hex::decode(message).unwrap();
+            let signature = hex::decode(signature).unwrap();
+            let signature = *array_ref![signature, 0, 64];
+
+            assert_eq!(key_pair.public_key(), public);
+            assert_eq!(key_pair.calculate_signature(&message)[..], signature[..]);
+            assert!(KeyPair::verify_signature(public, &message, &signature));
+
+            for i in 0..signature.len() {
+                let mut signature_copy = signature;
+                signature_copy[i] ^= 0x01u8;
+                assert!(!KeyPair::verify_signature(
+                    public,
+                    &message,
+                    &signature_copy
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn test_non_canonical_s_rejected() {
+        let mut csprng = OsRng;
+        let key_pair = KeyPair::new(&mut csprng);
+        let message = b"malleable";
+        let signature = key_pair.calculate_signature(message);
+
+        // S + l verifies under a plain group equation check but is not canonical.
+        let l = curve25519_dalek::constants::BASEPOINT_ORDER;
+        let mut s = [0u8; 32];
+        s.copy_from_slice(&signature[32..]);
+        let mut carry = 0u16;
+        let mut s_plus_l = [0u8; 32];
+        for i in 0..32 {
+            let sum = s[i] as u16 + l.as_bytes()[i] as u16 + carry;
+            s_plus_l[i] = sum as u8;
+            carry = sum >> 8;
+        }
+        assert_eq!(carry, 0);
+
+        let mut malleated = signature;
+        malleated[32..].copy_from_slice(&s_plus_l);
+        assert!(KeyPair::verify_signature(
+            key_pair.public_key(),
+            message,
+            &signature
+        ));
+        assert!(!KeyPair::verify_signature(
+            key_pair.public_key(),
+            message,
+            &malleated
+        ));
+    }
+
+    #[test]
+    fn test_x25519_conversion() {
+        let mut csprng = OsRng;
+        for _ in 0..50 {
+            let key_pair = KeyPair::new(&mut csprng);
+            let x25519_private = x25519_private_key(key_pair.private_key());
+            assert_eq!(
+                x25519_public_key(key_pair.public_key()),
+                Some(super::super::curve25519::derive_public_key(&x25519_private))
+            );
+        }
+    }
+
+    #[test]
+    fn test_random_signatures() {
+        let mut csprng = OsRng;
+        for _ in 0..50 {
+            let mut message = [0u8; 64];
+            csprng.fill_bytes(&mut message);
+            let key_pair = KeyPair::new(&mut csprng);
+            let signature = key_pair.calculate_signature(&message);
+            assert!(
+                KeyPair::verify_signature(key_pair.public_key(), &message, &signature),
+                "signature check failed"
+            );
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+use crate::error::{Result, SignalProtocolError};
+use crate::proto::storage::{decrypt_delta_structure, DecryptDeltaStructure};
+use crate::state::{PreKeyId, SessionRecord};
+use crate::storage::{Direction, IdentityKeyStore, PreKeyStore, SenderKeyStore, SessionStore};
+use crate::{Context, IdentityKey, ProtocolAddress, SenderKeyName, SenderKeyRecord};
+
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone)]
+enum DecryptDeltaKind {
+    Session {
+        remote_address: ProtocolAddress,
+        record: Box<SessionRecord>,
+        identity_key: IdentityKey,
+        pre_key_id: Option<PreKeyId>,
+    },
+    SenderKey {
+        name: SenderKeyName,
+        record: SenderKeyRecord,
+    },
+}
+
+/// The state changes made by a preview decryption, to be applied with [`commit_decrypt_delta`],
+/// or dropped.
+///
+/// A delta records a digest of the record it was computed from, and only commits if the stored
+/// record is still the same. It can be serialized to hand it to another process.
+#[derive(Debug, Clone)]
+pub struct DecryptDelta {
+    base_digest: Vec<u8>,
+    kind: DecryptDeltaKind,
+}
+
+impl DecryptDelta {
+    pub(crate) fn for_session(
+        base: Option<&SessionRecord>,
+        remote_address: &ProtocolAddress,
+        record: SessionRecord,
+        identity_key: IdentityKey,
+        pre_key_id: Option<PreKeyId>,
+    ) -> Result<Self> {
+        Ok(Self {
+            base_digest: session_digest(base)?,
+            kind: DecryptDeltaKind::Session {
+                remote_address: remote_address.clone(),
+                record: Box::new(record),
+                identity_key,
+                pre_key_id,
+            },
+        })
+    }
+
+    pub(crate) fn for_sender_key(
+        base: &SenderKeyRecord,
+        name: &SenderKeyName,
+        record: SenderKeyRecord,
+    ) -> Result<Self> {
+        Ok(Self {
+            base_digest: sender_key_digest(Some(base))?,
+            kind: DecryptDeltaKind::SenderKey {
+                name: name.clone(),
+                record,
+            },
+        })
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        let structure = DecryptDeltaStructure::decode(bytes)?;
+        let kind = match structure
+            .delta
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
+        {
+            decrypt_delta_structure::Delta::Session(delta) => DecryptDeltaKind::Session {
+                remote_address: ProtocolAddress::new(delta.remote_name, delta.remote_device_id),
+                record: Box::new(SessionRecord::deserialize(&delta.session_record)?),
+                identity_key: IdentityKey::decode(&delta.identity_key)?,
+                pre_key_id: if delta.consumes_pre_key {
+                    Some(delta.pre_key_id)
+                } else {
+                    None
+                },
+            },
+            decrypt_delta_structure::Delta::SenderKey(delta) => DecryptDeltaKind::SenderKey {
+                name: SenderKeyName::new(
+                    delta.group_id,
+                    ProtocolAddress::new(delta.sender_name, delta.sender_device_id),
+                )?,
+                record: SenderKeyRecord::deserialize(&delta.sender_key_record)?,
+            },
+        };
+        Ok(Self {
+            base_digest: structure.base_digest,
+            kind,
+        })
+    }
+
+    /// Serialize the delta, e.g. to hand it from a notification extension to the main app.
+    ///
+    /// The output contains the complete updated [`SessionRecord`] or [`SenderKeyRecord`],
+    /// including its root, chain and message keys, in the clear. It needs the same protection as
+    /// the session store itself: keep it in storage only the app can read, or seal it, for
+    /// example with the [`StorageKey`](crate::StorageKey) used for the app's
+    /// [`EncryptedStore`](crate::EncryptedStore), before it leaves the process.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let delta = match &self.kind {
+            DecryptDeltaKind::Session {
+                remote_address,
+                record,
+                identity_key,
+                pre_key_id,
+            } => decrypt_delta_structure::Delta::Session(decrypt_delta_structure::SessionDelta {
+                remote_name: remote_address.name().to_string(),
+                remote_device_id: remote_address.device_id(),
+                session_record: record.serialize()?,
+                identity_key: identity_key.serialize().to_vec(),
+                consumes_pre_key: pre_key_id.is_some(),
+                pre_key_id: pre_key_id.unwrap_or(0),
+            }),
+            DecryptDeltaKind::SenderKey { name, record } => {
+                let sender = name.sender()?;
+                decrypt_delta_structure::Delta::SenderKey(decrypt_delta_structure::SenderKeyDelta {
+                    group_id: name.group_id()?,
+                    sender_name: sender.name().to_string(),
+                    sender_device_id: sender.device_id(),
+                    sender_key_record: record.serialize()?,
+                })
+            }
+        };
+        let structure = DecryptDeltaStructure {
+            base_digest: self.base_digest.clone(),
+            delta: Some(delta),
+        };
+        let mut buf = vec![];
+        structure.encode(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Apply a delta produced by [`message_decrypt_preview`](crate::message_decrypt_preview) or
+/// [`group_decrypt_preview`](crate::group_decrypt_preview). A session delta is written to
+/// `session_store`, `identity_store` and `pre_key_store`, a sender key delta to
+/// `sender_key_store`.
+///
+/// Fails with [`SignalProtocolError::StaleDecryptDelta`] if the record has changed since the
+/// preview, e.g. because another message was decrypted in the meantime; the message must then
+/// be decrypted again. A session delta that consumes a one-time prekey fails with
+/// [`SignalProtocolError::InvalidPreKeyId`] if the prekey has been used since, even by a
+/// message from another sender.
+pub fn commit_decrypt_delta(
+    delta: &DecryptDelta,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    sender_key_store: &mut dyn SenderKeyStore,
+    ctx: Context,
+) -> Result<()> {
+    match &delta.kind {
+        DecryptDeltaKind::Session {
+            remote_address,
+            record,
+            identity_key,
+            pre_key_id,
+        } => {
+            let current = session_store.load_session(remote_address, ctx)?;
+            if session_digest(current.as_ref())? != delta.base_digest {
+                return Err(SignalProtocolError::StaleDecryptDelta);
+            }
+
+            // The digest only covers this sender's session, so check separately that no other
+            // session has been set up with the same one-time prekey since the preview.
+            if let Some(pre_key_id) = pre_key_id {
+                pre_key_store.get_pre_key(*pre_key_id, ctx)?;
+            }
+
+            if !identity_store.is_trusted_identity(
+                remote_address,
+                identity_key,
+                Direction::Receiving,
+                ctx,
+            )? {
+                return Err(SignalProtocolError::UntrustedIdentity(
+                    remote_address.clone(),
+                ));
+            }
+            identity_store.save_identity(remote_address, identity_key, ctx)?;
+
+            session_store.store_session(remote_address, record, ctx)?;
+
+            if let Some(pre_key_id) = pre_key_id {
+                pre_key_store.remove_pre_key(*pre_key_id, ctx)?;
+            }
+            Ok(())
+        }
+        DecryptDeltaKind::SenderKey { name, record } => {
+            let current = sender_key_store.load_sender_key(name, ctx)?;
+            if sender_key_digest(current.as_ref())? != delta.base_digest {
+                return Err(SignalProtocolError::StaleDecryptDelta);
+            }
+
+            sender_key_store.store_sender_key(name, record, ctx)
+        }
+    }
+}
+
+fn session_digest(record: Option<&SessionRecord>) -> Result<Vec<u8>> {
+    match record {
+        None => Ok(vec![]),
+        Some(record) => Ok(Sha256::digest(&record.serialize()?).to_vec()),
+    }
+}
+
+fn sender_key_digest(record: Option<&SenderKeyRecord>) -> Result<Vec<u8>> {
+    match record {
+        None => Ok(vec![]),
+        Some(record) => Ok(Sha256::digest(&record.serialize()?).to_vec()),
+    }
+}
@@ -0,0 +1,424 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+//! Statistical timing tests for code that must run in constant time.
+//!
+//! This follows dudect (Reparaz, Balasch and Verbauwhede, "Dude, is my code constant time?"):
+//! the function under test is timed on two classes of input, one fixed and one random,
+//! interleaved in random order. Welch's t-test then checks whether the two timing
+//! distributions differ. The test is also repeated with the slowest measurements cropped at
+//! several percentiles, since interrupts and other noise lengthen the upper tail.
+//!
+//! The timing tests are ignored by default, as they are slow and only meaningful with
+//! optimizations enabled:
+//!
+//! ```text
+//! cargo test --release --lib dudect -- --ignored --nocapture
+//! ```
+
+use crate::{crypto, proto, utils};
+use crate::{Fingerprint, IdentityKeyPair, KeyPair, ScannableFingerprint, SignalMessage};
+
+use criterion::black_box;
+use prost::Message;
+use rand::{rngs::OsRng, CryptoRng, Rng};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Instant;
+
+/// A |t| above this means the timings certainly differ, as in dudect. Values between about 4.5
+/// and this are suspicious, and worth rerunning with more measurements.
+const T_THRESHOLD: f64 = 10.0;
+
+const DEFAULT_MEASUREMENTS: usize = 200_000;
+
+/// Number of cropped variants tested besides the uncropped measurements.
+const CROP_PERCENTILES: usize = 20;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Class {
+    Fixed,
+    Random,
+}
+
+/// Running mean and variance of one class, by Welford's method.
+#[derive(Clone, Default)]
+struct Moments {
+    n: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Moments {
+    fn push(&mut self, x: f64) {
+        self.n += 1.0;
+        let delta = x - self.mean;
+        self.mean += delta / self.n;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    fn variance(&self) -> f64 {
+        if self.n < 2.0 {
+            0.0
+        } else {
+            self.m2 / (self.n - 1.0)
+        }
+    }
+}
+
+/// Welch's t statistic for two classes of measurements.
+#[derive(Clone, Default)]
+struct WelchTest {
+    classes: [Moments; 2],
+}
+
+impl WelchTest {
+    fn push(&mut self, class: Class, x: f64) {
+        self.classes[class as usize].push(x);
+    }
+
+    fn samples(&self) -> f64 {
+        self.classes[0].n.min(self.classes[1].n)
+    }
+
+    fn t(&self) -> f64 {
+        let [a, b] = &self.classes;
+        let se = (a.variance() / a.n + b.variance() / b.n).sqrt();
+        if se == 0.0 {
+            return 0.0;
+        }
+        (a.mean - b.mean) / se
+    }
+}
+
+struct Report {
+    /// The largest |t| among the uncropped and cropped tests.
+    max_t: f64,
+    /// The percentile the largest |t| was cropped at, or `None` if uncropped.
+    cropped_at: Option<f64>,
+    measurements: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "max |t| = {:.2} over {} measurements",
+            self.max_t, self.measurements
+        )?;
+        if let Some(p) = self.cropped_at {
+            write!(f, ", cropped at percentile {:.4}", p)?;
+        }
+        Ok(())
+    }
+}
+
+impl Report {
+    fn assert_constant_time(&self, name: &str) {
+        println!("{}: {}", name, self);
+        assert!(
+            self.max_t < T_THRESHOLD,
+            "{} is not constant time: |t| = {:.2}",
+            name,
+            self.max_t
+        );
+    }
+}
+
+/// Time `op` on inputs drawn by `input` for randomly interleaved classes. Each measurement
+/// covers `repeat` calls on the same input, to stay well above the timer's resolution.
+fn measure<R, I, G, F>(
+    measurements: usize,
+    repeat: usize,
+    rng: &mut R,
+    mut input: G,
+    mut op: F,
+) -> Report
+where
+    R: Rng + CryptoRng,
+    G: FnMut(Class, &mut R) -> I,
+    F: FnMut(&I),
+{
+    let classes: Vec<Class> = (0..measurements)
+        .map(|_| {
+            if rng.gen() {
+                Class::Fixed
+            } else {
+                Class::Random
+            }
+        })
+        .collect();
+    let inputs: Vec<I> = classes.iter().map(|&class| input(class, rng)).collect();
+
+    // Warm up caches and branch predictors before timing anything.
+    for input in inputs.iter().take(measurements / 10) {
+        op(black_box(input));
+    }
+
+    let mut timings = Vec::with_capacity(measurements);
+    for input in &inputs {
+        let start = Instant::now();
+        for _ in 0..repeat {
+            op(black_box(input));
+        }
+        timings.push(start.elapsed().as_nanos() as f64);
+    }
+
+    // Crop thresholds at percentiles approaching 1, spaced as in dudect.
+    let mut sorted = timings.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).expect("timings are finite"));
+    let crops: Vec<(f64, f64)> = (0..CROP_PERCENTILES)
+        .map(|k| {
+            let p = 1.0 - 0.5f64.powf(10.0 * (k + 1) as f64 / CROP_PERCENTILES as f64);
+            (p, sorted[(p * (measurements - 1) as f64) as usize])
+        })
+        .collect();
+
+    let mut uncropped = WelchTest::default();
+    let mut cropped = vec![WelchTest::default(); crops.len()];
+    for (&class, &t) in classes.iter().zip(&timings) {
+        uncropped.push(class, t);
+        for (test, &(_, threshold)) in cropped.iter_mut().zip(&crops) {
+            if t <= threshold {
+                test.push(class, t);
+            }
+        }
+    }
+
+    let mut report = Report {
+        max_t: uncropped.t().abs(),
+        cropped_at: None,
+        measurements,
+    };
+    for (test, &(p, _)) in cropped.iter().zip(&crops) {
+        // A heavily cropped test has too few samples to mean much.
+        if test.samples() < (measurements / 100) as f64 {
+            continue;
+        }
+        if test.t().abs() > report.max_t {
+            report.max_t = test.t().abs();
+            report.cropped_at = Some(p);
+        }
+    }
+    report
+}
+
+#[test]
+fn welch_t_statistic() {
+    let mut test = WelchTest::default();
+    for &x in &[1.0, 2.0, 3.0, 4.0] {
+        test.push(Class::Fixed, x);
+    }
+    for &x in &[3.0, 4.0, 5.0, 6.0] {
+        test.push(Class::Random, x);
+    }
+    // Means 2.5 and 4.5, both variances 5/3.
+    let expected = -2.0 / (2.0 * 5.0 / 3.0 / 4.0f64).sqrt();
+    assert!((test.t() - expected).abs() < 1e-12);
+
+    let mut same = WelchTest::default();
+    for _ in 0..10 {
+        same.push(Class::Fixed, 7.0);
+        same.push(Class::Random, 7.0);
+    }
+    assert_eq!(same.t(), 0.0);
+}
+
+#[test]
+#[ignore]
+fn detects_early_exit_comparison() {
+    // An early-exit comparison of long inputs, as a check that the harness can see a leak.
+    let reference = vec![0x5a; 4096];
+    let report = measure(
+        DEFAULT_MEASUREMENTS / 10,
+        1,
+        &mut OsRng,
+        |class, rng| match class {
+            Class::Fixed => reference.clone(),
+            Class::Random => (0..4096).map(|_| rng.gen()).collect(),
+        },
+        |x: &Vec<u8>| {
+            black_box(x.iter().zip(&reference).all(|(a, b)| a == b));
+        },
+    );
+    println!("early exit comparison: {}", report);
+    assert!(report.max_t > T_THRESHOLD);
+}
+
+#[test]
+#[ignore]
+fn constant_time_cmp() {
+    let reference: [u8; 32] = OsRng.gen();
+    measure(
+        DEFAULT_MEASUREMENTS,
+        16,
+        &mut OsRng,
+        |class, rng| match class {
+            Class::Fixed => reference,
+            Class::Random => rng.gen(),
+        },
+        |x: &[u8; 32]| {
+            black_box(utils::constant_time_cmp(x, &reference));
+        },
+    )
+    .assert_constant_time("constant_time_cmp");
+}
+
+#[test]
+#[ignore]
+fn signal_message_verify_mac() {
+    let mut csprng = OsRng;
+    let sender = IdentityKeyPair::generate(&mut csprng);
+    let receiver = IdentityKeyPair::generate(&mut csprng);
+    let mac_key: [u8; 32] = csprng.gen();
+    let message = SignalMessage::new(
+        3,
+        &mac_key,
+        KeyPair::generate(&mut csprng).public_key,
+        42,
+        41,
+        &[0x17; 48],
+        sender.identity_key(),
+        receiver.identity_key(),
+    )
+    .expect("valid message");
+
+    measure(
+        DEFAULT_MEASUREMENTS,
+        1,
+        &mut csprng,
+        |class, rng| {
+            // Both classes are parsed the same way, so that only the MAC differs.
+            let mut serialized = message.serialized().to_vec();
+            if class == Class::Random {
+                // Replace the truncated 8 byte MAC.
+                let mac_start = serialized.len() - 8;
+                rng.fill(&mut serialized[mac_start..]);
+            }
+            SignalMessage::try_from(&serialized[..]).expect("valid message")
+        },
+        |message: &SignalMessage| {
+            black_box(
+                message
+                    .verify_mac(sender.identity_key(), receiver.identity_key(), &mac_key)
+                    .expect("well formed"),
+            );
+        },
+    )
+    .assert_constant_time("SignalMessage::verify_mac");
+}
+
+#[test]
+#[ignore]
+fn scannable_fingerprint_compare() {
+    let mut csprng = OsRng;
+    let alice = IdentityKeyPair::generate(&mut csprng);
+    let bob = IdentityKeyPair::generate(&mut csprng);
+    let alice_fprint = Fingerprint::new(
+        1,
+        2,
+        b"+14152222222",
+        alice.identity_key(),
+        b"+14153333333",
+        bob.identity_key(),
+    )
+    .expect("fingerprint");
+    let bob_fprint = Fingerprint::new(
+        1,
+        2,
+        b"+14153333333",
+        bob.identity_key(),
+        b"+14152222222",
+        alice.identity_key(),
+    )
+    .expect("fingerprint");
+    let scannable: &ScannableFingerprint = &alice_fprint.scannable;
+    let matching = proto::fingerprint::CombinedFingerprints::decode(
+        &bob_fprint.scannable.serialize().expect("serialize")[..],
+    )
+    .expect("decode");
+
+    measure(
+        DEFAULT_MEASUREMENTS,
+        1,
+        &mut csprng,
+        |class, rng| {
+            // Both classes are encoded the same way, so that only the contents differ.
+            let mut combined = matching.clone();
+            if class == Class::Random {
+                let local = combined.local_fingerprint.as_mut().expect("present");
+                local.content = rng.gen::<[u8; 32]>().to_vec();
+                let remote = combined.remote_fingerprint.as_mut().expect("present");
+                remote.content = rng.gen::<[u8; 32]>().to_vec();
+            }
+            let mut buf = vec![];
+            combined.encode(&mut buf).expect("encode");
+            buf
+        },
+        |combined: &Vec<u8>| {
+            black_box(scannable.compare(combined).expect("same version"));
+        },
+    )
+    .assert_constant_time("ScannableFingerprint::compare");
+}
+
+#[test]
+#[ignore]
+fn cbc_padding_removal() {
+    let key: [u8; 32] = OsRng.gen();
+    let iv: [u8; 16] = OsRng.gen();
+    // Encrypting a chosen final block and dropping the padding block added after it lets the
+    // tests set the padded plaintext directly.
+    let ciphertext_for = |last_block: &[u8; 16]| {
+        let mut padded = vec![0x42; 48];
+        padded[32..].copy_from_slice(last_block);
+        let mut ctext = crypto::aes_256_cbc_encrypt(&padded, &key, &iv).expect("encrypt");
+        ctext.truncate(48);
+        ctext
+    };
+    let valid_padding = |n: u8| {
+        let mut block = [0x42; 16];
+        for b in &mut block[16 - n as usize..] {
+            *b = n;
+        }
+        ciphertext_for(&block)
+    };
+
+    // Valid padding of any length takes as long as the shortest.
+    measure(
+        DEFAULT_MEASUREMENTS,
+        1,
+        &mut OsRng,
+        |class, rng| match class {
+            Class::Fixed => valid_padding(1),
+            Class::Random => valid_padding(rng.gen_range(1, 17)),
+        },
+        |ctext: &Vec<u8>| {
+            black_box(crypto::aes_256_cbc_decrypt(ctext, &key, &iv).expect("valid"));
+        },
+    )
+    .assert_constant_time("aes_256_cbc_decrypt (valid padding)");
+
+    // Bad padding is rejected in the same time wherever the mismatch is.
+    measure(
+        DEFAULT_MEASUREMENTS,
+        1,
+        &mut OsRng,
+        |class, rng| {
+            let mut block = [0x10; 16];
+            match class {
+                Class::Fixed => block[15] = 0,
+                Class::Random => block[rng.gen_range(0, 15)] = 0x0f,
+            }
+            ciphertext_for(&block)
+        },
+        |ctext: &Vec<u8>| {
+            black_box(crypto::aes_256_cbc_decrypt(ctext, &key, &iv).is_err());
+        },
+    )
+    .assert_constant_time("aes_256_cbc_decrypt (invalid padding)");
+}
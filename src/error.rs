@@ -6,12 +6,57 @@
 //
 
 use crate::curve::KeyType;
+use crate::{CiphertextMessageType, ProtocolAddress};
 
 use std::error::Error;
 use std::fmt;
+use std::sync::Arc;
 
 pub type Result<T> = std::result::Result<T, SignalProtocolError>;
 
+/// How a caller should react to a [`SignalProtocolError`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ErrorCategory {
+    /// A transient failure, usually of an application store, or a decrypt delta that went stale;
+    /// the same operation may succeed if repeated.
+    Retryable,
+    /// The input or local state cannot be processed; retrying the same call will fail again.
+    Fatal,
+    /// The peer or the data failed an authenticity or trust check and should not be trusted.
+    Security,
+}
+
+/// An error reported by an application-provided store.
+///
+/// The source error is shared rather than owned so that [`SignalProtocolError`] stays `Clone`;
+/// two `StoreError`s compare equal only if they wrap the same source.
+#[derive(Debug, Clone)]
+pub struct StoreError(Arc<dyn Error + Send + Sync + 'static>);
+
+impl StoreError {
+    pub fn new<E: Into<Box<dyn Error + Send + Sync + 'static>>>(source: E) -> Self {
+        Self(Arc::from(source.into()))
+    }
+
+    pub fn source(&self) -> &(dyn Error + Send + Sync + 'static) {
+        &*self.0
+    }
+}
+
+impl PartialEq for StoreError {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for StoreError {}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum SignalProtocolError {
     InvalidArgument(String),
@@ -22,6 +67,8 @@ pub enum SignalProtocolError {
     InvalidProtobufEncoding,
 
     CiphertextMessageTooShort(usize),
+    /// The caller-supplied buffer cannot hold the output; the field is the size required.
+    OutputBufferTooSmall(usize),
     LegacyCiphertextVersion(u8),
     UnrecognizedCiphertextVersion(u8),
     UnrecognizedMessageVersion(u32),
@@ -34,11 +81,15 @@ pub enum SignalProtocolError {
     BadKeyLength(KeyType, usize),
     MismatchedKeyTypes(KeyType, KeyType),
     MismatchedSignatureLengthForKey(KeyType, usize),
+    /// The key data does not encode a point on the curve for its key type.
+    InvalidPublicKeyPoint(KeyType),
 
     SignatureValidationFailed,
+    /// The indices of the entries in a batch whose signatures are invalid.
+    BatchSignatureValidationFailed(Vec<usize>),
     SignaturePubkeyMissing,
 
-    UntrustedIdentity(crate::ProtocolAddress),
+    UntrustedIdentity(ProtocolAddress),
 
     InvalidPreKeyId,
     InvalidSignedPreKeyId,
@@ -52,19 +103,113 @@ pub enum SignalProtocolError {
     InvalidMacKeyLength(usize),
     InvalidCipherCryptographicParameters(usize, usize),
     InvalidCiphertext,
+    /// A franked message whose commitment does not open to its plaintext.
+    InvalidFrankingCommitment,
+    UnknownStorageKey(u32),
+    InvalidBackup(&'static str),
+    BackupAuthenticationFailed,
+    /// An identity key log, inclusion proof or signed log head failed to verify.
+    IdentityLogVerificationFailed(&'static str),
 
     NoSenderKeyState,
     SenderKeySigningKeyMissing,
 
-    SessionNotFound,
+    SessionNotFound(ProtocolAddress),
     InvalidSessionStructure,
 
-    DuplicatedMessage(u32, u32),
-    InvalidMessage(&'static str),
+    /// A message whose keys have already been used, from `sender`. `chain_index` is the
+    /// receiving chain's current index (or sender key iteration) and `counter` the message's.
+    DuplicatedMessage {
+        sender: ProtocolAddress,
+        message_type: CiphertextMessageType,
+        chain_index: u32,
+        counter: u32,
+    },
+    /// A message from `sender` that none of the session states for it can decrypt.
+    NoMatchingSessionState {
+        sender: ProtocolAddress,
+        message_type: CiphertextMessageType,
+    },
+    /// A message from `sender` with a `counter` (or sender key iteration) more than
+    /// `MAX_FORWARD_JUMPS` ahead of the chain's current index.
+    MessageTooFarInFuture {
+        sender: ProtocolAddress,
+        message_type: CiphertextMessageType,
+        chain_index: u32,
+        counter: u32,
+    },
+    /// The record changed after a preview decryption; decrypt the message again.
+    StaleDecryptDelta,
     InternalError(&'static str),
+    /// A store operation, named by the first field, failed. Application stores report their
+    /// own failures this way, through [`SignalProtocolError::store_failure`].
+    StoreFailure(&'static str, StoreError),
     FfiBindingError(String),
-    ApplicationCallbackThrewException(&'static str, Option<String>, String),
-    ApplicationCallbackReturnedIntegerError(&'static str, i32),
+}
+
+impl SignalProtocolError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            SignalProtocolError::StoreFailure(_, _) | SignalProtocolError::StaleDecryptDelta => {
+                ErrorCategory::Retryable
+            }
+
+            SignalProtocolError::SignatureValidationFailed
+            | SignalProtocolError::BatchSignatureValidationFailed(_)
+            | SignalProtocolError::UntrustedIdentity(_)
+            | SignalProtocolError::InvalidCiphertext
+            | SignalProtocolError::InvalidFrankingCommitment
+            | SignalProtocolError::NoMatchingSessionState { .. }
+            | SignalProtocolError::MessageTooFarInFuture { .. }
+            | SignalProtocolError::BackupAuthenticationFailed
+            | SignalProtocolError::IdentityLogVerificationFailed(_) => ErrorCategory::Security,
+
+            SignalProtocolError::InvalidArgument(_)
+            | SignalProtocolError::InvalidState(_, _)
+            | SignalProtocolError::ProtobufDecodingError(_)
+            | SignalProtocolError::ProtobufEncodingError(_)
+            | SignalProtocolError::InvalidProtobufEncoding
+            | SignalProtocolError::CiphertextMessageTooShort(_)
+            | SignalProtocolError::OutputBufferTooSmall(_)
+            | SignalProtocolError::LegacyCiphertextVersion(_)
+            | SignalProtocolError::UnrecognizedCiphertextVersion(_)
+            | SignalProtocolError::UnrecognizedMessageVersion(_)
+            | SignalProtocolError::FingerprintIdentifierMismatch
+            | SignalProtocolError::FingerprintVersionMismatch
+            | SignalProtocolError::NoKeyTypeIdentifier
+            | SignalProtocolError::BadKeyType(_)
+            | SignalProtocolError::BadKeyLength(_, _)
+            | SignalProtocolError::MismatchedKeyTypes(_, _)
+            | SignalProtocolError::MismatchedSignatureLengthForKey(_, _)
+            | SignalProtocolError::InvalidPublicKeyPoint(_)
+            | SignalProtocolError::SignaturePubkeyMissing
+            | SignalProtocolError::InvalidPreKeyId
+            | SignalProtocolError::InvalidSignedPreKeyId
+            | SignalProtocolError::InvalidSenderKeyId
+            | SignalProtocolError::InvalidPreKeyBundle
+            | SignalProtocolError::InvalidRootKeyLength(_)
+            | SignalProtocolError::InvalidChainKeyLength(_)
+            | SignalProtocolError::InvalidMacKeyLength(_)
+            | SignalProtocolError::InvalidCipherCryptographicParameters(_, _)
+            | SignalProtocolError::UnknownStorageKey(_)
+            | SignalProtocolError::InvalidBackup(_)
+            | SignalProtocolError::NoSenderKeyState
+            | SignalProtocolError::SenderKeySigningKeyMissing
+            | SignalProtocolError::SessionNotFound(_)
+            | SignalProtocolError::InvalidSessionStructure
+            | SignalProtocolError::DuplicatedMessage { .. }
+            | SignalProtocolError::InternalError(_)
+            | SignalProtocolError::FfiBindingError(_) => ErrorCategory::Fatal,
+        }
+    }
+
+    /// Wrap an error returned by an application store during `operation`.
+    pub fn store_failure<E: Into<Box<dyn Error + Send + Sync + 'static>>>(
+        operation: &'static str,
+        source: E,
+    ) -> Self {
+        SignalProtocolError::StoreFailure(operation, StoreError::new(source))
+    }
 }
 
 impl Error for SignalProtocolError {
@@ -72,6 +217,7 @@ impl Error for SignalProtocolError {
         match self {
             SignalProtocolError::ProtobufEncodingError(e) => Some(e),
             SignalProtocolError::ProtobufDecodingError(e) => Some(e),
+            SignalProtocolError::StoreFailure(_, e) => Some(e.source()),
             _ => None,
         }
     }
@@ -108,6 +254,9 @@ impl fmt::Display for SignalProtocolError {
             SignalProtocolError::CiphertextMessageTooShort(size) => {
                 write!(f, "ciphertext serialized bytes were too short <{}>", size)
             }
+            SignalProtocolError::OutputBufferTooSmall(size) => {
+                write!(f, "output buffer too small; <{}> bytes required", size)
+            }
             SignalProtocolError::LegacyCiphertextVersion(version) => {
                 write!(f, "ciphertext version was too old <{}>", version)
             }
@@ -136,6 +285,13 @@ impl fmt::Display for SignalProtocolError {
                 "signature length <{}> does not match expected for key with type <{}>",
                 l, t
             ),
+            SignalProtocolError::InvalidPublicKeyPoint(t) => {
+                write!(
+                    f,
+                    "public key is not a valid point for key with type <{}>",
+                    t
+                )
+            }
             SignalProtocolError::InvalidPreKeyId => write!(f, "invalid prekey identifier"),
             SignalProtocolError::InvalidSignedPreKeyId => {
                 write!(f, "invalid signed prekey identifier")
@@ -160,15 +316,67 @@ impl fmt::Display for SignalProtocolError {
             SignalProtocolError::SignatureValidationFailed => {
                 write!(f, "invalid signature detected")
             }
+            SignalProtocolError::BatchSignatureValidationFailed(indices) => {
+                write!(f, "invalid signatures at batch indices {:?}", indices)
+            }
             SignalProtocolError::InvalidPreKeyBundle => write!(f, "invalid pre key bundle format"),
             SignalProtocolError::InvalidCiphertext => write!(f, "invalid ciphertext message"),
-            SignalProtocolError::SessionNotFound => write!(f, "session not found"),
+            SignalProtocolError::InvalidFrankingCommitment => {
+                write!(f, "franking commitment does not match the plaintext")
+            }
+            SignalProtocolError::UnknownStorageKey(id) => {
+                write!(f, "no storage key with id <{}>", id)
+            }
+            SignalProtocolError::InvalidBackup(m) => write!(f, "invalid backup: {}", m),
+            SignalProtocolError::BackupAuthenticationFailed => {
+                write!(
+                    f,
+                    "backup failed to authenticate (wrong passphrase or corrupted)"
+                )
+            }
+            SignalProtocolError::IdentityLogVerificationFailed(m) => {
+                write!(f, "identity key log verification failed: {}", m)
+            }
+            SignalProtocolError::SessionNotFound(addr) => {
+                write!(f, "session with {} not found", addr)
+            }
             SignalProtocolError::InvalidSessionStructure => write!(f, "invalid session structure"),
-            SignalProtocolError::DuplicatedMessage(i, c) => {
-                write!(f, "message with old counter {} / {}", i, c)
+            SignalProtocolError::DuplicatedMessage {
+                sender,
+                message_type,
+                chain_index,
+                counter,
+            } => write!(
+                f,
+                "{:?} message from {} with old counter {} / {}",
+                message_type, sender, chain_index, counter
+            ),
+            SignalProtocolError::NoMatchingSessionState {
+                sender,
+                message_type,
+            } => write!(
+                f,
+                "{:?} message from {} matches no session state",
+                message_type, sender
+            ),
+            SignalProtocolError::MessageTooFarInFuture {
+                sender,
+                message_type,
+                chain_index,
+                counter,
+            } => write!(
+                f,
+                "{:?} message from {} too far into the future, counter {} / {}",
+                message_type, sender, chain_index, counter
+            ),
+            SignalProtocolError::StaleDecryptDelta => {
+                write!(
+                    f,
+                    "decrypt delta is stale; the record changed since the preview"
+                )
             }
-            SignalProtocolError::InvalidMessage(m) => write!(f, "invalid message {}", m),
             SignalProtocolError::InternalError(m) => write!(f, "internal error {}", m),
+            SignalProtocolError::StoreFailure(op, e) => write!(f, "store {} failed: {}", op, e),
             SignalProtocolError::InvalidSenderKeyId => write!(f, "invalid send key id"),
             SignalProtocolError::NoSenderKeyState => write!(f, "no sender key state"),
             SignalProtocolError::SenderKeySigningKeyMissing => {
@@ -180,21 +388,70 @@ impl fmt::Display for SignalProtocolError {
             SignalProtocolError::FfiBindingError(m) => {
                 write!(f, "error while invoking an ffi callback: {}", m)
             }
-            SignalProtocolError::ApplicationCallbackReturnedIntegerError(func, c) => {
-                write!(f, "application callback {} returned error code {}", func, c)
-            }
-            SignalProtocolError::ApplicationCallbackThrewException(func, t, m) => match t {
-                Some(t) => write!(
-                    f,
-                    "application callback {} threw exception {} with message {}",
-                    func, t, m
-                ),
-                None => write!(
-                    f,
-                    "application callback {} threw exception with message {}",
-                    func, m
-                ),
-            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_failure_keeps_source() {
+        let io_error = std::io::Error::new(std::io::ErrorKind::TimedOut, "database busy");
+        let err = SignalProtocolError::store_failure("load_session", io_error);
+
+        assert_eq!(err.category(), ErrorCategory::Retryable);
+        assert_eq!(err.to_string(), "store load_session failed: database busy");
+        let source = err.source().expect("has source");
+        assert_eq!(
+            source.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+            Some(std::io::ErrorKind::TimedOut)
+        );
+
+        assert_eq!(err.clone(), err);
+        assert_ne!(
+            err,
+            SignalProtocolError::store_failure("load_session", "database busy")
+        );
+    }
+
+    #[test]
+    fn test_error_categories() {
+        let address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        assert_eq!(
+            SignalProtocolError::UntrustedIdentity(address.clone()).category(),
+            ErrorCategory::Security
+        );
+        assert_eq!(
+            SignalProtocolError::InvalidCiphertext.category(),
+            ErrorCategory::Security
+        );
+        assert_eq!(
+            SignalProtocolError::SessionNotFound(address.clone()).category(),
+            ErrorCategory::Fatal
+        );
+        assert_eq!(
+            SignalProtocolError::StaleDecryptDelta.category(),
+            ErrorCategory::Retryable
+        );
+        assert_eq!(
+            SignalProtocolError::NoMatchingSessionState {
+                sender: address.clone(),
+                message_type: CiphertextMessageType::PreKey,
+            }
+            .category(),
+            ErrorCategory::Security
+        );
+        assert_eq!(
+            SignalProtocolError::MessageTooFarInFuture {
+                sender: address,
+                message_type: CiphertextMessageType::SenderKey,
+                chain_index: 0,
+                counter: 3000,
+            }
+            .category(),
+            ErrorCategory::Security
+        );
+    }
+}
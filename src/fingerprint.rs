@@ -7,12 +7,142 @@
 
 use crate::error::{Result, SignalProtocolError};
 use crate::proto;
+use crate::qr::QrCode;
 use crate::IdentityKey;
 use prost::Message;
 use sha2::{digest::Digest, Sha512};
+use std::convert::TryFrom;
 use std::fmt;
 use subtle::ConstantTimeEq;
 
+/// The iteration count used by [`Fingerprint::from_identifiers`].
+pub const DEFAULT_FINGERPRINT_ITERATIONS: u32 = 5200;
+
+const IDENTIFIER_HASH_LABEL: &[u8] = b"Signal_SafetyNumberIdentifier";
+
+/// A stable identifier for the owner of an identity key, as mixed into a safety number.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StableIdentifier {
+    /// A phone number in E.164 format, such as `+14152222222`.
+    E164(String),
+    /// An account identifier (ACI), a UUID.
+    Aci([u8; 16]),
+}
+
+impl StableIdentifier {
+    pub fn e164(number: &str) -> Result<Self> {
+        let digits = number.strip_prefix('+').unwrap_or("");
+        if digits.is_empty()
+            || digits.len() > 15
+            || digits.starts_with('0')
+            || !digits.bytes().all(|b| b.is_ascii_digit())
+        {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "invalid E.164 number {}",
+                number
+            )));
+        }
+        Ok(Self::E164(number.to_string()))
+    }
+
+    /// Parse an ACI from its hyphenated UUID string form.
+    pub fn aci(uuid: &str) -> Result<Self> {
+        let invalid = || SignalProtocolError::InvalidArgument(format!("invalid UUID {}", uuid));
+
+        let lengths: Vec<usize> = uuid.split('-').map(str::len).collect();
+        if lengths != [8, 4, 4, 4, 12] {
+            return Err(invalid());
+        }
+        let digits: Vec<u8> = uuid
+            .chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+        let mut aci = [0u8; 16];
+        for (byte, pair) in aci.iter_mut().zip(digits.chunks(2)) {
+            *byte = pair[0] << 4 | pair[1];
+        }
+        Ok(Self::Aci(aci))
+    }
+
+    pub fn aci_from_bytes(bytes: &[u8]) -> Result<Self> {
+        let aci = <[u8; 16]>::try_from(bytes).map_err(|_| {
+            SignalProtocolError::InvalidArgument(format!(
+                "ACI must be 16 bytes, got {}",
+                bytes.len()
+            ))
+        })?;
+        Ok(Self::Aci(aci))
+    }
+
+    /// The scannable fingerprint version for safety numbers over this kind of identifier:
+    /// 1 for phone numbers and 2 for ACIs.
+    pub fn fingerprint_version(&self) -> u32 {
+        match self {
+            Self::E164(_) => 1,
+            Self::Aci(_) => 2,
+        }
+    }
+
+    /// The bytes hashed into the fingerprint: the number's ASCII form, or the 16 UUID bytes.
+    pub fn fingerprint_bytes(&self) -> &[u8] {
+        match self {
+            Self::E164(number) => number.as_bytes(),
+            Self::Aci(aci) => aci,
+        }
+    }
+
+    /// The commitment to this identifier carried in scannable fingerprints, if any.
+    ///
+    /// Phone numbers get none: there are few enough of them that a hash of one can be reversed
+    /// by search. They are still bound into the fingerprint content itself.
+    fn hash(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::E164(_) => None,
+            Self::Aci(aci) => {
+                let mut sha512 = Sha512::new();
+                sha512.update(IDENTIFIER_HASH_LABEL);
+                sha512.update(aci);
+                Some(sha512.finalize()[..32].to_vec())
+            }
+        }
+    }
+}
+
+impl fmt::Display for StableIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::E164(number) => write!(f, "{}", number),
+            Self::Aci(aci) => {
+                for (i, b) in aci.iter().enumerate() {
+                    if i == 4 || i == 6 || i == 8 || i == 10 {
+                        write!(f, "-")?;
+                    }
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The outcome of checking a scanned fingerprint against our own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintComparison {
+    Match,
+    /// The other side produced a different fingerprint version, so nothing can be concluded
+    /// about the keys; one of the two clients needs to be updated.
+    VersionMismatch {
+        ours: u32,
+        theirs: u32,
+    },
+    /// The scanned code was generated for a different pair of people.
+    IdentifierMismatch,
+    /// The identifiers agree (or were not included) but the identity keys do not.
+    KeyMismatch,
+}
+
 #[derive(Debug, Clone)]
 pub struct DisplayableFingerprint {
     local: String,
@@ -70,6 +200,9 @@ pub struct ScannableFingerprint {
     version: u32,
     local_fingerprint: Vec<u8>,
     remote_fingerprint: Vec<u8>,
+    // Hashes of the identifiers. Empty unless built from ACIs; older payloads never carry them.
+    local_identifier_hash: Vec<u8>,
+    remote_identifier_hash: Vec<u8>,
 }
 
 impl ScannableFingerprint {
@@ -78,22 +211,26 @@ impl ScannableFingerprint {
             version,
             local_fingerprint: local_fprint[..32].to_vec(),
             remote_fingerprint: remote_fprint[..32].to_vec(),
+            local_identifier_hash: vec![],
+            remote_identifier_hash: vec![],
         }
     }
 
     pub fn deserialize(protobuf: &[u8]) -> Result<Self> {
         let fingerprint = proto::fingerprint::CombinedFingerprints::decode(protobuf)?;
+        let local = fingerprint
+            .local_fingerprint
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let remote = fingerprint
+            .remote_fingerprint
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
 
         Ok(Self {
             version: fingerprint.version,
-            local_fingerprint: fingerprint
-                .local_fingerprint
-                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
-                .content,
-            remote_fingerprint: fingerprint
-                .remote_fingerprint
-                .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
-                .content,
+            local_fingerprint: local.content,
+            remote_fingerprint: remote.content,
+            local_identifier_hash: local.identifier_hash,
+            remote_identifier_hash: remote.identifier_hash,
         })
     }
 
@@ -102,9 +239,11 @@ impl ScannableFingerprint {
             version: self.version,
             local_fingerprint: Some(proto::fingerprint::LogicalFingerprint {
                 content: self.local_fingerprint.to_owned(),
+                identifier_hash: self.local_identifier_hash.to_owned(),
             }),
             remote_fingerprint: Some(proto::fingerprint::LogicalFingerprint {
                 content: self.remote_fingerprint.to_owned(),
+                identifier_hash: self.remote_identifier_hash.to_owned(),
             }),
         };
 
@@ -113,6 +252,62 @@ impl ScannableFingerprint {
         Ok(buf)
     }
 
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Encode the serialized fingerprint as a QR code for the other party to scan.
+    pub fn to_qr_code(&self) -> Result<QrCode> {
+        QrCode::new(&self.serialize()?)
+    }
+
+    pub fn from_qr_code(code: &QrCode) -> Result<Self> {
+        Self::deserialize(&code.payload()?)
+    }
+
+    /// Check a fingerprint scanned from the other party's device against ours.
+    ///
+    /// Unlike [`compare`](Self::compare), a version mismatch is a result rather than an error;
+    /// only an undecodable payload fails. Identifiers are checked only when both sides
+    /// included them.
+    pub fn verify(&self, combined: &[u8]) -> Result<FingerprintComparison> {
+        let combined = proto::fingerprint::CombinedFingerprints::decode(combined)?;
+
+        if combined.version != self.version {
+            return Ok(FingerprintComparison::VersionMismatch {
+                ours: self.version,
+                theirs: combined.version,
+            });
+        }
+
+        let (their_local, their_remote) =
+            match (&combined.local_fingerprint, &combined.remote_fingerprint) {
+                (Some(local), Some(remote)) => (local, remote),
+                _ => return Err(SignalProtocolError::InvalidProtobufEncoding),
+            };
+
+        let have_identifiers =
+            !self.local_identifier_hash.is_empty() && !self.remote_identifier_hash.is_empty();
+        let they_have_identifiers =
+            !their_local.identifier_hash.is_empty() && !their_remote.identifier_hash.is_empty();
+        if have_identifiers
+            && they_have_identifiers
+            && (their_local.identifier_hash != self.remote_identifier_hash
+                || their_remote.identifier_hash != self.local_identifier_hash)
+        {
+            return Ok(FingerprintComparison::IdentifierMismatch);
+        }
+
+        let same1 = their_local.content.ct_eq(&self.remote_fingerprint);
+        let same2 = their_remote.content.ct_eq(&self.local_fingerprint);
+
+        if (same1 & same2).into() {
+            Ok(FingerprintComparison::Match)
+        } else {
+            Ok(FingerprintComparison::KeyMismatch)
+        }
+    }
+
     pub fn compare(&self, combined: &[u8]) -> Result<bool> {
         let combined = proto::fingerprint::CombinedFingerprints::decode(combined)?;
 
@@ -200,11 +395,206 @@ impl Fingerprint {
         })
     }
 
+    /// Build a safety number from typed identifiers, using the scannable version for their kind
+    /// and [`DEFAULT_FINGERPRINT_ITERATIONS`].
+    ///
+    /// For ACIs, hashes of the identifiers are also embedded in the scannable form, so that
+    /// [`ScannableFingerprint::verify`] can tell a code for the wrong conversation from a key
+    /// change. Phone numbers are never embedded, hashed or not; a code for the wrong
+    /// conversation then shows up as a key mismatch. Both identifiers must be of the same kind.
+    pub fn from_identifiers(
+        local_id: &StableIdentifier,
+        local_key: &IdentityKey,
+        remote_id: &StableIdentifier,
+        remote_key: &IdentityKey,
+    ) -> Result<Fingerprint> {
+        let version = local_id.fingerprint_version();
+        if remote_id.fingerprint_version() != version {
+            return Err(SignalProtocolError::InvalidArgument(
+                "safety number identifiers must be of the same kind".to_string(),
+            ));
+        }
+
+        let mut fingerprint = Fingerprint::new(
+            version,
+            DEFAULT_FINGERPRINT_ITERATIONS,
+            local_id.fingerprint_bytes(),
+            local_key,
+            remote_id.fingerprint_bytes(),
+            remote_key,
+        )?;
+        if let (Some(local_hash), Some(remote_hash)) = (local_id.hash(), remote_id.hash()) {
+            fingerprint.scannable.local_identifier_hash = local_hash;
+            fingerprint.scannable.remote_identifier_hash = remote_hash;
+        }
+        Ok(fingerprint)
+    }
+
     pub fn display_string(&self) -> Result<String> {
         Ok(format!("{}", self.display))
     }
 }
 
+/// The scannable form of a [`GroupFingerprint`].
+#[derive(Debug, Clone)]
+pub struct ScannableGroupFingerprint {
+    version: u32,
+    membership: Vec<u8>,
+    content: Vec<u8>,
+}
+
+impl ScannableGroupFingerprint {
+    pub fn deserialize(protobuf: &[u8]) -> Result<Self> {
+        let fingerprint = proto::fingerprint::GroupFingerprint::decode(protobuf)?;
+        Ok(Self {
+            version: fingerprint.version,
+            membership: fingerprint.membership,
+            content: fingerprint.content,
+        })
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let fingerprint = proto::fingerprint::GroupFingerprint {
+            version: self.version,
+            membership: self.membership.to_owned(),
+            content: self.content.to_owned(),
+        };
+
+        let mut buf = Vec::new();
+        fingerprint.encode(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn to_qr_code(&self) -> Result<QrCode> {
+        QrCode::new(&self.serialize()?)
+    }
+
+    pub fn from_qr_code(code: &QrCode) -> Result<Self> {
+        Self::deserialize(&code.payload()?)
+    }
+
+    /// Check a group fingerprint scanned from another member's device against ours.
+    ///
+    /// A different member list is reported as
+    /// [`IdentifierMismatch`](FingerprintComparison::IdentifierMismatch); the same members with
+    /// different keys as [`KeyMismatch`](FingerprintComparison::KeyMismatch).
+    pub fn verify(&self, scanned: &[u8]) -> Result<FingerprintComparison> {
+        let scanned = proto::fingerprint::GroupFingerprint::decode(scanned)?;
+
+        if scanned.version != self.version {
+            return Ok(FingerprintComparison::VersionMismatch {
+                ours: self.version,
+                theirs: scanned.version,
+            });
+        }
+        if scanned.content.is_empty() || scanned.membership.is_empty() {
+            return Err(SignalProtocolError::InvalidProtobufEncoding);
+        }
+
+        if !bool::from(scanned.membership.ct_eq(&self.membership)) {
+            return Ok(FingerprintComparison::IdentifierMismatch);
+        }
+        if bool::from(scanned.content.ct_eq(&self.content)) {
+            Ok(FingerprintComparison::Match)
+        } else {
+            Ok(FingerprintComparison::KeyMismatch)
+        }
+    }
+}
+
+/// A safety number over the whole membership of a group.
+///
+/// Each member contributes the same iterated hash used for pairwise [`Fingerprint`]s; those are
+/// combined in identifier order, so every member computes the same value for the same set of
+/// identities no matter how their own member list is ordered.
+#[derive(Debug, Clone)]
+pub struct GroupFingerprint {
+    pub display: String,
+    pub scannable: ScannableGroupFingerprint,
+}
+
+impl GroupFingerprint {
+    pub fn new(
+        version: u32,
+        iterations: u32,
+        members: &[(&[u8], IdentityKey)],
+    ) -> Result<GroupFingerprint> {
+        let mut members = members.to_vec();
+        members.sort_by(|a, b| a.0.cmp(b.0));
+        members.dedup();
+        if members.is_empty() {
+            return Err(SignalProtocolError::InvalidArgument(
+                "group fingerprint needs at least one member".to_string(),
+            ));
+        }
+        if members.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(SignalProtocolError::InvalidArgument(
+                "group member listed with two different identity keys".to_string(),
+            ));
+        }
+
+        let fingerprint_version = [0u8, 0u8]; // 0x0000
+        let mut membership = Sha512::new();
+        let mut content = Sha512::new();
+        membership.update(fingerprint_version);
+        content.update(fingerprint_version);
+        for (id, key) in &members {
+            let id_len = (id.len() as u32).to_be_bytes();
+            membership.update(id_len);
+            membership.update(id);
+            content.update(id_len);
+            content.update(id);
+            content.update(&Fingerprint::get_fingerprint(iterations, id, key)?);
+        }
+        let content = content.finalize();
+
+        Ok(GroupFingerprint {
+            display: format!(
+                "{}{}",
+                get_encoded_string(&content[0..30])?,
+                get_encoded_string(&content[30..60])?
+            ),
+            scannable: ScannableGroupFingerprint {
+                version,
+                membership: membership.finalize()[..32].to_vec(),
+                content: content[..32].to_vec(),
+            },
+        })
+    }
+
+    /// Build a group safety number from typed identifiers, which must all be of the same kind.
+    pub fn from_identifiers(
+        members: &[(StableIdentifier, IdentityKey)],
+    ) -> Result<GroupFingerprint> {
+        let version = members
+            .first()
+            .map(|(id, _)| id.fingerprint_version())
+            .unwrap_or(1);
+        if members
+            .iter()
+            .any(|(id, _)| id.fingerprint_version() != version)
+        {
+            return Err(SignalProtocolError::InvalidArgument(
+                "safety number identifiers must be of the same kind".to_string(),
+            ));
+        }
+
+        let members: Vec<(&[u8], IdentityKey)> = members
+            .iter()
+            .map(|(id, key)| (id.fingerprint_bytes(), *key))
+            .collect();
+        GroupFingerprint::new(version, DEFAULT_FINGERPRINT_ITERATIONS, &members)
+    }
+
+    pub fn display_string(&self) -> Result<String> {
+        Ok(self.display.clone())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -554,4 +944,262 @@ mod test {
             hex::encode(a_fprint_v2.scannable.serialize().unwrap())
         );
     }
+
+    #[test]
+    fn fingerprint_stable_identifiers() {
+        let e164 = StableIdentifier::e164(ALICE_STABLE_ID).unwrap();
+        assert_eq!(e164.fingerprint_bytes(), ALICE_STABLE_ID.as_bytes());
+        assert_eq!(e164.to_string(), ALICE_STABLE_ID);
+        for bad in &["14152222222", "+", "+0123", "+1415abc", "+1234567890123456"] {
+            assert!(StableIdentifier::e164(bad).is_err());
+        }
+
+        let uuid = "9d0652a3-dcc3-4d11-975f-74d61598733f";
+        let aci = StableIdentifier::aci(uuid).unwrap();
+        assert_eq!(
+            hex::encode(aci.fingerprint_bytes()),
+            "9d0652a3dcc34d11975f74d61598733f"
+        );
+        assert_eq!(aci.to_string(), uuid);
+        assert_eq!(
+            StableIdentifier::aci_from_bytes(aci.fingerprint_bytes()).unwrap(),
+            aci
+        );
+        for bad in &[
+            "9d0652a3dcc34d11975f74d61598733f",
+            "9d0652a3-dcc3-4d11-975f-74d61598733",
+            "9d0652a3-dcc3-4d11-975f-74d61598733g",
+        ] {
+            assert!(StableIdentifier::aci(bad).is_err());
+        }
+        assert!(StableIdentifier::aci_from_bytes(&[0u8; 15]).is_err());
+    }
+
+    #[test]
+    fn fingerprint_from_identifiers() {
+        let a_key = IdentityKey::decode(&hex::decode(ALICE_IDENTITY).unwrap()).unwrap();
+        let b_key = IdentityKey::decode(&hex::decode(BOB_IDENTITY).unwrap()).unwrap();
+        let alice = StableIdentifier::e164(ALICE_STABLE_ID).unwrap();
+        let bob = StableIdentifier::e164(BOB_STABLE_ID).unwrap();
+
+        let a_fprint = Fingerprint::from_identifiers(&alice, &a_key, &bob, &b_key).unwrap();
+        let b_fprint = Fingerprint::from_identifiers(&bob, &b_key, &alice, &a_key).unwrap();
+
+        // Same digits and payload as the untyped v1 vectors: phone numbers are not embedded,
+        // not even hashed.
+        assert_eq!(format!("{}", a_fprint.display), DISPLAYABLE_FINGERPRINT_V1);
+        assert_eq!(a_fprint.scannable.version(), 1);
+        assert_eq!(
+            hex::encode(b_fprint.scannable.serialize().unwrap()),
+            BOB_SCANNABLE_FINGERPRINT_V1
+        );
+
+        assert_eq!(
+            a_fprint
+                .scannable
+                .verify(&b_fprint.scannable.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::Match
+        );
+        // Payloads without identifiers are still accepted.
+        assert_eq!(
+            a_fprint
+                .scannable
+                .verify(&hex::decode(BOB_SCANNABLE_FINGERPRINT_V1).unwrap())
+                .unwrap(),
+            FingerprintComparison::Match
+        );
+
+        let aci = StableIdentifier::aci("9d0652a3-dcc3-4d11-975f-74d61598733f").unwrap();
+        assert!(Fingerprint::from_identifiers(&alice, &a_key, &aci, &b_key).is_err());
+    }
+
+    #[test]
+    fn fingerprint_comparison_results() {
+        use crate::IdentityKeyPair;
+        use rand::rngs::OsRng;
+
+        let a_key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        let b_key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+        let m_key = *IdentityKeyPair::generate(&mut OsRng).identity_key();
+
+        let alice = StableIdentifier::aci("9d0652a3-dcc3-4d11-975f-74d61598733f").unwrap();
+        let bob = StableIdentifier::aci("796abedb-ca4e-4f18-8803-1fde5b921f9f").unwrap();
+        let carol = StableIdentifier::aci("c2a5e7a6-1b3c-4e5d-8f70-0a1b2c3d4e5f").unwrap();
+
+        let a_fprint = Fingerprint::from_identifiers(&alice, &a_key, &bob, &b_key).unwrap();
+        assert_eq!(a_fprint.scannable.version(), 2);
+
+        // ACIs are carried as hashes, never in the clear.
+        let payload = a_fprint.scannable.serialize().unwrap();
+        let scanned = ScannableFingerprint::deserialize(&payload).unwrap();
+        assert_eq!(Some(scanned.local_identifier_hash), alice.hash());
+        assert_eq!(Some(scanned.remote_identifier_hash), bob.hash());
+        for id in &[&alice, &bob] {
+            let bytes = id.fingerprint_bytes();
+            assert!(!payload.windows(bytes.len()).any(|w| w == bytes));
+        }
+
+        let b_fprint = Fingerprint::from_identifiers(&bob, &b_key, &alice, &a_key).unwrap();
+        assert_eq!(
+            a_fprint
+                .scannable
+                .verify(&b_fprint.scannable.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::Match
+        );
+
+        let b_sees_mitm = Fingerprint::from_identifiers(&bob, &b_key, &alice, &m_key).unwrap();
+        assert_eq!(
+            a_fprint
+                .scannable
+                .verify(&b_sees_mitm.scannable.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::KeyMismatch
+        );
+
+        let carol_fprint = Fingerprint::from_identifiers(&carol, &b_key, &alice, &a_key).unwrap();
+        assert_eq!(
+            a_fprint
+                .scannable
+                .verify(&carol_fprint.scannable.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::IdentifierMismatch
+        );
+
+        let b_e164 = Fingerprint::from_identifiers(
+            &StableIdentifier::e164(BOB_STABLE_ID).unwrap(),
+            &b_key,
+            &StableIdentifier::e164(ALICE_STABLE_ID).unwrap(),
+            &a_key,
+        )
+        .unwrap();
+        assert_eq!(
+            a_fprint
+                .scannable
+                .verify(&b_e164.scannable.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::VersionMismatch { ours: 2, theirs: 1 }
+        );
+
+        assert!(a_fprint.scannable.verify(&[0x08, 0x02]).is_err());
+    }
+
+    #[test]
+    fn fingerprint_qr_code() {
+        let a_key = IdentityKey::decode(&hex::decode(ALICE_IDENTITY).unwrap()).unwrap();
+        let b_key = IdentityKey::decode(&hex::decode(BOB_IDENTITY).unwrap()).unwrap();
+        let alice = StableIdentifier::aci("9d0652a3-dcc3-4d11-975f-74d61598733f").unwrap();
+        let bob = StableIdentifier::aci("796abedb-ca4e-4f18-8803-1fde5b921f9f").unwrap();
+
+        let a_fprint = Fingerprint::from_identifiers(&alice, &a_key, &bob, &b_key).unwrap();
+        let b_fprint = Fingerprint::from_identifiers(&bob, &b_key, &alice, &a_key).unwrap();
+
+        let code = b_fprint.scannable.to_qr_code().unwrap();
+        assert!(code.to_svg().starts_with("<svg"));
+
+        let scanned = ScannableFingerprint::from_qr_code(&code).unwrap();
+        assert_eq!(
+            a_fprint
+                .scannable
+                .verify(&scanned.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::Match
+        );
+    }
+
+    #[test]
+    fn group_fingerprint_order_independent() {
+        use crate::IdentityKeyPair;
+        use rand::rngs::OsRng;
+
+        let members: Vec<(StableIdentifier, IdentityKey)> = [
+            "9d0652a3-dcc3-4d11-975f-74d61598733f",
+            "796abedb-ca4e-4f18-8803-1fde5b921f9f",
+            "c2a5e7a6-1b3c-4e5d-8f70-0a1b2c3d4e5f",
+        ]
+        .iter()
+        .map(|uuid| {
+            (
+                StableIdentifier::aci(uuid).unwrap(),
+                *IdentityKeyPair::generate(&mut OsRng).identity_key(),
+            )
+        })
+        .collect();
+
+        let a_view = GroupFingerprint::from_identifiers(&members).unwrap();
+        let mut reordered = members.clone();
+        reordered.reverse();
+        reordered.push(members[0].clone());
+        let b_view = GroupFingerprint::from_identifiers(&reordered).unwrap();
+
+        assert_eq!(a_view.display_string().unwrap().len(), 60);
+        assert_eq!(a_view.display, b_view.display);
+        assert_eq!(a_view.scannable.version(), 2);
+        assert_eq!(
+            a_view
+                .scannable
+                .verify(&b_view.scannable.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::Match
+        );
+
+        let scanned =
+            ScannableGroupFingerprint::from_qr_code(&b_view.scannable.to_qr_code().unwrap())
+                .unwrap();
+        assert_eq!(
+            a_view
+                .scannable
+                .verify(&scanned.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::Match
+        );
+    }
+
+    #[test]
+    fn group_fingerprint_mismatches() {
+        let a_key = IdentityKey::decode(&hex::decode(ALICE_IDENTITY).unwrap()).unwrap();
+        let b_key = IdentityKey::decode(&hex::decode(BOB_IDENTITY).unwrap()).unwrap();
+        let alice = StableIdentifier::e164(ALICE_STABLE_ID).unwrap();
+        let bob = StableIdentifier::e164(BOB_STABLE_ID).unwrap();
+        let carol = StableIdentifier::e164("+14154444444").unwrap();
+
+        let group =
+            GroupFingerprint::from_identifiers(&[(alice.clone(), a_key), (bob.clone(), b_key)])
+                .unwrap();
+        let swapped_keys =
+            GroupFingerprint::from_identifiers(&[(alice.clone(), b_key), (bob.clone(), a_key)])
+                .unwrap();
+        let with_carol = GroupFingerprint::from_identifiers(&[
+            (alice.clone(), a_key),
+            (bob.clone(), b_key),
+            (carol, a_key),
+        ])
+        .unwrap();
+
+        assert_ne!(group.display, swapped_keys.display);
+        assert_eq!(
+            group
+                .scannable
+                .verify(&swapped_keys.scannable.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::KeyMismatch
+        );
+        assert_eq!(
+            group
+                .scannable
+                .verify(&with_carol.scannable.serialize().unwrap())
+                .unwrap(),
+            FingerprintComparison::IdentifierMismatch
+        );
+
+        // A pair of identities is not the same thing as a two-member group.
+        let pairwise = Fingerprint::from_identifiers(&alice, &a_key, &bob, &b_key).unwrap();
+        assert_ne!(group.display, pairwise.display_string().unwrap());
+
+        assert!(GroupFingerprint::from_identifiers(&[]).is_err());
+        assert!(
+            GroupFingerprint::from_identifiers(&[(alice.clone(), a_key), (alice, b_key)]).is_err()
+        );
+    }
 }
@@ -0,0 +1,163 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+//! Message franking, so that a recipient can report an abusive message to the server and the
+//! server can check the report without being able to read any other message.
+//!
+//! A franked message carries a commitment `HMAC-SHA256(franking_key, plaintext)`, covered by the
+//! message MAC. The franking key is derived from the message's MAC key, so only the two parties
+//! know it, and revealing it reveals nothing about other messages. On delivery the server binds
+//! the commitment to the sender, recipient and time with [`franking_tag`] under a key of its own.
+//! To report the message the recipient hands over the plaintext and the [`FrankingOpening`]
+//! returned by [`message_decrypt_franked`](crate::message_decrypt_franked), which the server
+//! checks against its stored tag with [`verify_franking_report`].
+
+use crate::crypto;
+use crate::error::Result;
+use crate::ProtocolAddress;
+
+use subtle::ConstantTimeEq;
+
+const FRANKING_KEY_LABEL: &[u8] = b"Signal_FrankingKey";
+const FRANKING_TAG_LABEL: &[u8] = b"Signal_FrankingTag";
+
+/// What a recipient reveals, together with the plaintext, to report a franked message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrankingOpening {
+    franking_key: [u8; 32],
+    commitment: [u8; 32],
+}
+
+impl FrankingOpening {
+    pub fn new(franking_key: [u8; 32], commitment: [u8; 32]) -> Self {
+        Self {
+            franking_key,
+            commitment,
+        }
+    }
+
+    #[inline]
+    pub fn franking_key(&self) -> &[u8; 32] {
+        &self.franking_key
+    }
+
+    #[inline]
+    pub fn commitment(&self) -> &[u8; 32] {
+        &self.commitment
+    }
+
+    /// Whether the commitment opens to `ptext`.
+    pub fn verify(&self, ptext: &[u8]) -> Result<bool> {
+        let commitment = commit(&self.franking_key, ptext)?;
+        Ok(commitment.ct_eq(&self.commitment).into())
+    }
+}
+
+/// The franking key for the message authenticated with `mac_key`.
+pub(crate) fn franking_key(mac_key: &[u8]) -> Result<[u8; 32]> {
+    crypto::hmac_sha256(mac_key, FRANKING_KEY_LABEL)
+}
+
+pub(crate) fn commit(franking_key: &[u8; 32], ptext: &[u8]) -> Result<[u8; 32]> {
+    crypto::hmac_sha256(franking_key, ptext)
+}
+
+/// The tag a server stores or attaches when it relays a message with `commitment` from `sender`
+/// to `recipient` at `timestamp`.
+pub fn franking_tag(
+    server_key: &[u8],
+    commitment: &[u8; 32],
+    sender: &ProtocolAddress,
+    recipient: &ProtocolAddress,
+    timestamp: u64,
+) -> Result<[u8; 32]> {
+    let mut input = Vec::with_capacity(
+        FRANKING_TAG_LABEL.len() + 32 + sender.name().len() + recipient.name().len() + 24,
+    );
+    input.extend_from_slice(FRANKING_TAG_LABEL);
+    input.extend_from_slice(commitment);
+    for address in &[sender, recipient] {
+        input.extend_from_slice(&(address.name().len() as u32).to_be_bytes());
+        input.extend_from_slice(address.name().as_bytes());
+        input.extend_from_slice(&address.device_id().to_be_bytes());
+    }
+    input.extend_from_slice(&timestamp.to_be_bytes());
+    crypto::hmac_sha256(server_key, &input)
+}
+
+/// Checks a report of `ptext` against the `tag` the server computed with [`franking_tag`] when
+/// relaying the message: the tag must match the reported metadata and commitment, and the
+/// commitment must open to `ptext`.
+pub fn verify_franking_report(
+    server_key: &[u8],
+    tag: &[u8],
+    sender: &ProtocolAddress,
+    recipient: &ProtocolAddress,
+    timestamp: u64,
+    ptext: &[u8],
+    opening: &FrankingOpening,
+) -> Result<bool> {
+    let expected_tag = franking_tag(
+        server_key,
+        opening.commitment(),
+        sender,
+        recipient,
+        timestamp,
+    )?;
+    let tag_valid: bool = expected_tag.ct_eq(tag).into();
+    Ok(tag_valid && opening.verify(ptext)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_franking_report() {
+        let server_key = [1u8; 32];
+        let alice = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob = ProtocolAddress::new("+14151111112".to_owned(), 1);
+        let ptext = b"abusive message";
+
+        let key = franking_key(&[2u8; 32]).unwrap();
+        let opening = FrankingOpening::new(key, commit(&key, ptext).unwrap());
+        let tag = franking_tag(&server_key, opening.commitment(), &alice, &bob, 1000).unwrap();
+        let report = |tag: &[u8], sender, recipient, timestamp, ptext: &[u8], opening| {
+            verify_franking_report(
+                &server_key,
+                tag,
+                sender,
+                recipient,
+                timestamp,
+                ptext,
+                opening,
+            )
+            .unwrap()
+        };
+
+        assert!(report(&tag, &alice, &bob, 1000, ptext, &opening));
+
+        // Any change to the tag, metadata, plaintext or opening is rejected.
+        let mut bad_tag = tag;
+        bad_tag[0] ^= 1;
+        assert!(!report(&bad_tag, &alice, &bob, 1000, ptext, &opening));
+        assert!(!report(&tag[..16], &alice, &bob, 1000, ptext, &opening));
+        assert!(!report(&tag, &bob, &alice, 1000, ptext, &opening));
+        assert!(!report(&tag, &alice, &bob, 1001, ptext, &opening));
+        assert!(!report(
+            &tag,
+            &alice,
+            &bob,
+            1000,
+            b"innocent message",
+            &opening
+        ));
+        let other_key = franking_key(&[3u8; 32]).unwrap();
+        let forged = FrankingOpening::new(other_key, *opening.commitment());
+        assert!(!report(&tag, &alice, &bob, 1000, ptext, &forged));
+    }
+}
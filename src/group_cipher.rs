@@ -8,13 +8,16 @@
 use crate::consts;
 use crate::crypto;
 use crate::curve;
+use crate::decrypt_delta::DecryptDelta;
 use crate::error::Result;
-use crate::protocol::{SenderKeyDistributionMessage, SenderKeyMessage};
+use crate::protocol::{CiphertextMessageType, SenderKeyDistributionMessage, SenderKeyMessage};
 use crate::sender_keys::{SenderKeyRecord, SenderKeyState, SenderMessageKey};
-use crate::{Context, SenderKeyName, SenderKeyStore, SignalProtocolError};
+use crate::{Context, ProtocolAddress, SenderKeyName, SenderKeyStore, SignalProtocolError};
 
 use rand::{CryptoRng, Rng};
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::time::SystemTime;
 
 pub fn group_encrypt<R: Rng + CryptoRng>(
     sender_key_store: &mut dyn SenderKeyStore,
@@ -53,31 +56,41 @@ pub fn group_encrypt<R: Rng + CryptoRng>(
     Ok(skm.serialized().to_vec())
 }
 
-fn get_sender_key(state: &mut SenderKeyState, iteration: u32) -> Result<SenderMessageKey> {
+fn get_sender_key(
+    sender: &ProtocolAddress,
+    state: &mut SenderKeyState,
+    iteration: u32,
+    now: SystemTime,
+) -> Result<SenderMessageKey> {
     let sender_chain_key = state.sender_chain_key()?;
 
     if sender_chain_key.iteration()? > iteration {
         if let Some(smk) = state.remove_sender_message_key(iteration)? {
             return Ok(smk);
         } else {
-            return Err(SignalProtocolError::DuplicatedMessage(
-                sender_chain_key.iteration()?,
-                iteration,
-            ));
+            return Err(SignalProtocolError::DuplicatedMessage {
+                sender: sender.clone(),
+                message_type: CiphertextMessageType::SenderKey,
+                chain_index: sender_chain_key.iteration()?,
+                counter: iteration,
+            });
         }
     }
 
     let jump = (iteration - sender_chain_key.iteration()?) as usize;
     if jump > consts::MAX_FORWARD_JUMPS {
-        return Err(SignalProtocolError::InvalidMessage(
-            "message from too far into the future",
-        ));
+        return Err(SignalProtocolError::MessageTooFarInFuture {
+            sender: sender.clone(),
+            message_type: CiphertextMessageType::SenderKey,
+            chain_index: sender_chain_key.iteration()?,
+            counter: iteration,
+        });
     }
 
     let mut sender_chain_key = sender_chain_key;
 
     while sender_chain_key.iteration()? < iteration {
-        state.add_sender_message_key(&sender_chain_key.sender_message_key()?)?;
+        state.add_sender_message_key(&sender_chain_key.sender_message_key()?, now)?;
         sender_chain_key = sender_chain_key.next()?;
     }
 
@@ -89,12 +102,99 @@ pub fn group_decrypt(
     skm_bytes: &[u8],
     sender_key_store: &mut dyn SenderKeyStore,
     sender_key_id: &SenderKeyName,
+    now: SystemTime,
     ctx: Context,
 ) -> Result<Vec<u8>> {
     let mut record = sender_key_store
         .load_sender_key(&sender_key_id, ctx)?
         .ok_or(SignalProtocolError::InvalidSenderKeyId)?;
 
+    let plaintext = decrypt_with_record(skm_bytes, &mut record, sender_key_id, now)?;
+
+    sender_key_store.store_sender_key(sender_key_id, &record, ctx)?;
+
+    Ok(plaintext)
+}
+
+/// Decrypt a sender key message without saving the updated sender key record.
+///
+/// Pass the delta to [`commit_decrypt_delta`](crate::commit_decrypt_delta) to apply it. See
+/// [`message_decrypt_preview`](crate::message_decrypt_preview).
+pub fn group_decrypt_preview(
+    skm_bytes: &[u8],
+    sender_key_store: &mut dyn SenderKeyStore,
+    sender_key_id: &SenderKeyName,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<(Vec<u8>, DecryptDelta)> {
+    let base_record = sender_key_store
+        .load_sender_key(sender_key_id, ctx)?
+        .ok_or(SignalProtocolError::InvalidSenderKeyId)?;
+
+    let mut record = base_record.clone();
+    let plaintext = decrypt_with_record(skm_bytes, &mut record, sender_key_id, now)?;
+
+    let delta = DecryptDelta::for_sender_key(&base_record, sender_key_id, record)?;
+    Ok((plaintext, delta))
+}
+
+/// Decrypt a queue of sender key messages, loading and storing each sender key record once.
+///
+/// Works like [`message_decrypt_batch`](crate::message_decrypt_batch): messages are decrypted
+/// in order, each gets its own result, and touched records are written back at the end. A store
+/// error aborts the batch; if it comes from a write, the records written before it are kept.
+pub fn group_decrypt_batch(
+    messages: &[(SenderKeyName, Vec<u8>)],
+    sender_key_store: &mut dyn SenderKeyStore,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<Vec<Result<Vec<u8>>>> {
+    let mut records: HashMap<SenderKeyName, (Option<SenderKeyRecord>, bool)> = HashMap::new();
+    let mut load_order = Vec::new();
+    let mut results = Vec::with_capacity(messages.len());
+
+    for (sender_key_id, skm_bytes) in messages {
+        if !records.contains_key(sender_key_id) {
+            let record = sender_key_store.load_sender_key(sender_key_id, ctx)?;
+            records.insert(sender_key_id.clone(), (record, false));
+            load_order.push(sender_key_id.clone());
+        }
+        let (cached, dirty) = records.get_mut(sender_key_id).expect("loaded above");
+
+        let record = match cached {
+            Some(record) => record,
+            None => {
+                results.push(Err(SignalProtocolError::InvalidSenderKeyId));
+                continue;
+            }
+        };
+
+        let mut updated = record.clone();
+        match decrypt_with_record(skm_bytes, &mut updated, sender_key_id, now) {
+            Ok(plaintext) => {
+                *record = updated;
+                *dirty = true;
+                results.push(Ok(plaintext));
+            }
+            Err(e) => results.push(Err(e)),
+        }
+    }
+
+    for sender_key_id in &load_order {
+        if let (Some(record), true) = &records[sender_key_id] {
+            sender_key_store.store_sender_key(sender_key_id, record, ctx)?;
+        }
+    }
+
+    Ok(results)
+}
+
+fn decrypt_with_record(
+    skm_bytes: &[u8],
+    record: &mut SenderKeyRecord,
+    sender_key_id: &SenderKeyName,
+    now: SystemTime,
+) -> Result<Vec<u8>> {
     let skm = SenderKeyMessage::try_from(skm_bytes)?;
 
     let mut sender_key_state = record.sender_key_state_for_keyid(skm.key_id())?;
@@ -104,17 +204,18 @@ pub fn group_decrypt(
         return Err(SignalProtocolError::SignatureValidationFailed);
     }
 
-    let sender_key = get_sender_key(&mut sender_key_state, skm.iteration())?;
+    let sender_key = get_sender_key(
+        &sender_key_id.sender()?,
+        &mut sender_key_state,
+        skm.iteration(),
+        now,
+    )?;
 
-    let plaintext = crypto::aes_256_cbc_decrypt(
+    crypto::aes_256_cbc_decrypt(
         skm.ciphertext(),
         &sender_key.cipher_key()?,
         &sender_key.iv()?,
-    )?;
-
-    sender_key_store.store_sender_key(sender_key_id, &record, ctx)?;
-
-    Ok(plaintext)
+    )
 }
 
 pub fn process_sender_key_distribution_message(
@@ -10,7 +10,7 @@ use crate::proto;
 
 use crate::error::{Result, SignalProtocolError};
 
-use rand::{CryptoRng, Rng};
+use rand::{CryptoRng, Rng, RngCore};
 use std::convert::TryFrom;
 
 use prost::Message;
@@ -35,8 +35,9 @@ impl IdentityKey {
         self.public_key.serialize()
     }
 
+    /// Parse an identity key, which may be a Djb or an Ed25519 key.
     pub fn decode(value: &[u8]) -> Result<Self> {
-        let pk = curve::PublicKey::deserialize(value)?;
+        let pk = curve::PublicKey::deserialize_identity_key(value)?;
         Ok(Self { public_key: pk })
     }
 }
@@ -78,6 +79,12 @@ impl IdentityKeyPair {
         }
     }
 
+    /// Generate an identity key pair whose public key is an Ed25519 key rather than a Djb one.
+    /// It signs with Ed25519 and is converted to X25519 for agreement.
+    pub fn generate_ed25519<R: CryptoRng + Rng>(csprng: &mut R) -> Self {
+        curve::KeyPair::generate_ed25519(csprng).into()
+    }
+
     #[inline]
     pub fn identity_key(&self) -> &IdentityKey {
         &self.identity_key
@@ -109,9 +116,14 @@ impl TryFrom<&[u8]> for IdentityKeyPair {
 
     fn try_from(value: &[u8]) -> Result<Self> {
         let structure = proto::storage::IdentityKeyPairStructure::decode(value)?;
+        let identity_key = IdentityKey::try_from(&structure.public_key[..])?;
+        let private_key = curve::PrivateKey::deserialize_with_key_type(
+            identity_key.public_key().key_type(),
+            &structure.private_key,
+        )?;
         Ok(Self {
-            identity_key: IdentityKey::try_from(&structure.public_key[..])?,
-            private_key: curve::PrivateKey::deserialize(&structure.private_key)?,
+            identity_key,
+            private_key,
         })
     }
 }
@@ -125,6 +137,48 @@ impl From<curve::KeyPair> for IdentityKeyPair {
     }
 }
 
+/// A cryptographically secure RNG that can be passed as a trait object, as
+/// [`IdentityKeySigner::calculate_signature`] needs. Implemented for every [`RngCore`] that is a
+/// [`CryptoRng`].
+pub trait SignerRng: RngCore + CryptoRng {}
+
+impl<R: RngCore + CryptoRng> SignerRng for R {}
+
+/// Signs and calculates agreements with the local identity private key, so that the key can be
+/// held by an external keystore or a separate process rather than loaded into memory.
+///
+/// [`IdentityKeyPair`] is the software implementation. Session setup and
+/// [`SignedPreKeyRecord::generate`](crate::SignedPreKeyRecord::generate) only use the private
+/// key through this trait, via [`IdentityKeyStore::get_identity_key_signer`](
+/// crate::IdentityKeyStore::get_identity_key_signer).
+pub trait IdentityKeySigner {
+    fn get_identity_key(&self) -> Result<IdentityKey>;
+
+    /// Sign `message` as [`curve::PrivateKey::calculate_signature`] does. A signer with its own
+    /// source of randomness may ignore `csprng`.
+    fn calculate_signature(&self, message: &[u8], csprng: &mut dyn SignerRng) -> Result<Box<[u8]>>;
+
+    fn calculate_agreement(&self, their_key: &curve::PublicKey) -> Result<Box<[u8]>>;
+}
+
+impl IdentityKeySigner for IdentityKeyPair {
+    fn get_identity_key(&self) -> Result<IdentityKey> {
+        Ok(self.identity_key)
+    }
+
+    fn calculate_signature(
+        &self,
+        message: &[u8],
+        mut csprng: &mut dyn SignerRng,
+    ) -> Result<Box<[u8]>> {
+        self.private_key.calculate_signature(message, &mut csprng)
+    }
+
+    fn calculate_agreement(&self, their_key: &curve::PublicKey) -> Result<Box<[u8]>> {
+        self.private_key.calculate_agreement(their_key)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -157,4 +211,56 @@ mod tests {
             deserialized_identity_key_pair.private_key().serialize()
         );
     }
+
+    #[test]
+    fn test_serialize_ed25519_identity_key_pair() {
+        let identity_key_pair = IdentityKeyPair::generate_ed25519(&mut OsRng);
+        let serialized = identity_key_pair.serialize();
+        let deserialized_identity_key_pair = IdentityKeyPair::try_from(&serialized[..]).unwrap();
+        assert_eq!(
+            identity_key_pair.identity_key(),
+            deserialized_identity_key_pair.identity_key()
+        );
+        assert_eq!(
+            deserialized_identity_key_pair.private_key().key_type(),
+            curve::KeyType::Ed25519
+        );
+        assert_eq!(
+            deserialized_identity_key_pair
+                .private_key()
+                .public_key()
+                .unwrap(),
+            *identity_key_pair.public_key()
+        );
+    }
+
+    #[test]
+    fn test_identity_key_pair_signer() {
+        let mut csprng = OsRng;
+        let identity_key_pair = IdentityKeyPair::generate(&mut csprng);
+        let their_key_pair = curve::KeyPair::generate(&mut csprng);
+        let signer: &dyn IdentityKeySigner = &identity_key_pair;
+
+        assert_eq!(
+            signer.get_identity_key().unwrap(),
+            *identity_key_pair.identity_key()
+        );
+
+        let message = b"signed prekey";
+        let signature = signer.calculate_signature(message, &mut csprng).unwrap();
+        assert!(identity_key_pair
+            .public_key()
+            .verify_signature(message, &signature)
+            .unwrap());
+
+        assert_eq!(
+            signer
+                .calculate_agreement(&their_key_pair.public_key)
+                .unwrap(),
+            their_key_pair
+                .private_key
+                .calculate_agreement(identity_key_pair.public_key())
+                .unwrap()
+        );
+    }
 }
@@ -0,0 +1,863 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+//! An append-only log of the identity keys observed for each address.
+//!
+//! Entries are hash-chained, and the entry hashes are also the leaves of an RFC 6962 Merkle
+//! tree, so a log head (tree size and root hash) signed by a transparency server commits to the
+//! whole history. Any single entry can be proven to be part of it, and a later head can be
+//! proven to extend an earlier one.
+
+use crate::curve::{KeyPair, PublicKey};
+use crate::error::{Result, SignalProtocolError};
+use crate::proto::storage as storage_proto;
+use crate::storage::{Context, Direction, IdentityKeyStore};
+use crate::utils;
+use crate::{IdentityKey, IdentityKeyPair, IdentityKeySigner, ProtocolAddress};
+
+use prost::Message;
+use rand::{CryptoRng, Rng};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+const LOG_HEAD_SIGNATURE_PREFIX: &[u8] = b"Signal_IdentityKeyLogHead";
+
+fn leaf_hash(data: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update([LEAF_PREFIX]);
+    hash.update(data);
+    hash.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update([NODE_PREFIX]);
+    hash.update(left);
+    hash.update(right);
+    hash.finalize().into()
+}
+
+/// The largest power of two smaller than `n`, for `n > 1`.
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+fn merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    match leaves.len() {
+        0 => Sha256::digest(&[]).into(),
+        1 => leaves[0],
+        n => {
+            let k = split_point(n);
+            node_hash(&merkle_root(&leaves[..k]), &merkle_root(&leaves[k..]))
+        }
+    }
+}
+
+fn merkle_path(index: usize, leaves: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    let n = leaves.len();
+    if n <= 1 {
+        return vec![];
+    }
+    let k = split_point(n);
+    let (mut path, sibling) = if index < k {
+        (merkle_path(index, &leaves[..k]), merkle_root(&leaves[k..]))
+    } else {
+        (
+            merkle_path(index - k, &leaves[k..]),
+            merkle_root(&leaves[..k]),
+        )
+    };
+    path.push(sibling);
+    path
+}
+
+// SUBPROOF from RFC 9162 section 2.1.4.1.
+fn merkle_subproof(m: usize, leaves: &[[u8; 32]], complete: bool) -> Vec<[u8; 32]> {
+    let n = leaves.len();
+    if m == n {
+        return if complete {
+            vec![]
+        } else {
+            vec![merkle_root(leaves)]
+        };
+    }
+    let k = split_point(n);
+    if m <= k {
+        let mut proof = merkle_subproof(m, &leaves[..k], complete);
+        proof.push(merkle_root(&leaves[k..]));
+        proof
+    } else {
+        let mut proof = merkle_subproof(m - k, &leaves[k..], false);
+        proof.push(merkle_root(&leaves[..k]));
+        proof
+    }
+}
+
+/// One observation of an identity key for an address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityLogEntry {
+    index: u64,
+    address: ProtocolAddress,
+    identity_key: IdentityKey,
+    observed_at: u64,
+    previous_hash: [u8; 32],
+}
+
+impl IdentityLogEntry {
+    #[inline]
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    #[inline]
+    pub fn address(&self) -> &ProtocolAddress {
+        &self.address
+    }
+
+    #[inline]
+    pub fn identity_key(&self) -> &IdentityKey {
+        &self.identity_key
+    }
+
+    /// When the key was observed, in seconds since the Unix epoch.
+    #[inline]
+    pub fn observed_at(&self) -> u64 {
+        self.observed_at
+    }
+
+    /// The hash of the entry before this one, or all zeros for the first entry.
+    #[inline]
+    pub fn previous_hash(&self) -> &[u8; 32] {
+        &self.previous_hash
+    }
+
+    /// The entry's hash, which is both the next entry's chain link and its Merkle leaf hash.
+    pub fn hash(&self) -> [u8; 32] {
+        let name = self.address.name().as_bytes();
+        let key = self.identity_key.serialize();
+
+        let mut data = Vec::with_capacity(8 + 4 + name.len() + 4 + key.len() + 8 + 32);
+        data.extend_from_slice(&self.index.to_be_bytes());
+        data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        data.extend_from_slice(name);
+        data.extend_from_slice(&self.address.device_id().to_be_bytes());
+        data.extend_from_slice(&key);
+        data.extend_from_slice(&self.observed_at.to_be_bytes());
+        data.extend_from_slice(&self.previous_hash);
+        leaf_hash(&data)
+    }
+
+    fn as_protobuf(&self) -> storage_proto::identity_key_log_structure::Entry {
+        storage_proto::identity_key_log_structure::Entry {
+            index: self.index,
+            name: self.address.name().to_string(),
+            device_id: self.address.device_id(),
+            identity_key: self.identity_key.serialize().to_vec(),
+            observed_at: self.observed_at,
+            previous_hash: self.previous_hash.to_vec(),
+        }
+    }
+
+    fn from_protobuf(entry: storage_proto::identity_key_log_structure::Entry) -> Result<Self> {
+        Ok(Self {
+            index: entry.index,
+            address: ProtocolAddress::new(entry.name, entry.device_id),
+            identity_key: IdentityKey::decode(&entry.identity_key)?,
+            observed_at: entry.observed_at,
+            previous_hash: <[u8; 32]>::try_from(&entry.previous_hash[..])
+                .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?,
+        })
+    }
+}
+
+/// A proof that a leaf is included in a Merkle tree of a given size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProof {
+    leaf_index: u64,
+    tree_size: u64,
+    path: Vec<[u8; 32]>,
+}
+
+impl InclusionProof {
+    pub fn new(leaf_index: u64, tree_size: u64, path: Vec<[u8; 32]>) -> Self {
+        Self {
+            leaf_index,
+            tree_size,
+            path,
+        }
+    }
+
+    #[inline]
+    pub fn leaf_index(&self) -> u64 {
+        self.leaf_index
+    }
+
+    #[inline]
+    pub fn tree_size(&self) -> u64 {
+        self.tree_size
+    }
+
+    #[inline]
+    pub fn path(&self) -> &[[u8; 32]] {
+        &self.path
+    }
+
+    /// Check that `leaf_hash` is at this proof's index in the tree with root `root_hash`,
+    /// following RFC 9162 section 2.1.3.2.
+    pub fn verify(&self, leaf_hash: &[u8; 32], root_hash: &[u8; 32]) -> Result<()> {
+        let fail = || SignalProtocolError::IdentityLogVerificationFailed("invalid inclusion proof");
+
+        if self.leaf_index >= self.tree_size {
+            return Err(fail());
+        }
+        let mut f_n = self.leaf_index;
+        let mut s_n = self.tree_size - 1;
+        let mut r = *leaf_hash;
+        for p in &self.path {
+            if s_n == 0 {
+                return Err(fail());
+            }
+            if f_n & 1 == 1 || f_n == s_n {
+                r = node_hash(p, &r);
+                while f_n & 1 == 0 && f_n != 0 {
+                    f_n >>= 1;
+                    s_n >>= 1;
+                }
+            } else {
+                r = node_hash(&r, p);
+            }
+            f_n >>= 1;
+            s_n >>= 1;
+        }
+
+        if s_n == 0 && bool::from(r.ct_eq(root_hash)) {
+            Ok(())
+        } else {
+            Err(fail())
+        }
+    }
+}
+
+/// A proof that the Merkle tree of one size is a prefix of the tree of a larger size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsistencyProof {
+    old_size: u64,
+    new_size: u64,
+    path: Vec<[u8; 32]>,
+}
+
+impl ConsistencyProof {
+    pub fn new(old_size: u64, new_size: u64, path: Vec<[u8; 32]>) -> Self {
+        Self {
+            old_size,
+            new_size,
+            path,
+        }
+    }
+
+    #[inline]
+    pub fn old_size(&self) -> u64 {
+        self.old_size
+    }
+
+    #[inline]
+    pub fn new_size(&self) -> u64 {
+        self.new_size
+    }
+
+    #[inline]
+    pub fn path(&self) -> &[[u8; 32]] {
+        &self.path
+    }
+
+    /// Check that the tree with root `old_root` is a prefix of the tree with root `new_root`,
+    /// following RFC 9162 section 2.1.4.2.
+    pub fn verify(&self, old_root: &[u8; 32], new_root: &[u8; 32]) -> Result<()> {
+        let fail =
+            || SignalProtocolError::IdentityLogVerificationFailed("invalid consistency proof");
+
+        if self.old_size > self.new_size {
+            return Err(fail());
+        }
+        if self.old_size == self.new_size {
+            return if self.path.is_empty() && bool::from(old_root.ct_eq(new_root)) {
+                Ok(())
+            } else {
+                Err(fail())
+            };
+        }
+        if self.old_size == 0 {
+            // Every tree extends the empty one.
+            return if self.path.is_empty() {
+                Ok(())
+            } else {
+                Err(fail())
+            };
+        }
+
+        let mut path = self.path.clone();
+        if self.old_size.is_power_of_two() {
+            path.insert(0, *old_root);
+        }
+        let (first, rest) = path.split_first().ok_or_else(fail)?;
+
+        let mut f_n = self.old_size - 1;
+        let mut s_n = self.new_size - 1;
+        while f_n & 1 == 1 {
+            f_n >>= 1;
+            s_n >>= 1;
+        }
+        let mut f_r = *first;
+        let mut s_r = *first;
+        for c in rest {
+            if s_n == 0 {
+                return Err(fail());
+            }
+            if f_n & 1 == 1 || f_n == s_n {
+                f_r = node_hash(c, &f_r);
+                s_r = node_hash(c, &s_r);
+                while f_n & 1 == 0 && f_n != 0 {
+                    f_n >>= 1;
+                    s_n >>= 1;
+                }
+            } else {
+                s_r = node_hash(&s_r, c);
+            }
+            f_n >>= 1;
+            s_n >>= 1;
+        }
+
+        if s_n == 0 && bool::from(f_r.ct_eq(old_root) & s_r.ct_eq(new_root)) {
+            Ok(())
+        } else {
+            Err(fail())
+        }
+    }
+}
+
+/// The append-only log of identity key observations.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityKeyLog {
+    entries: Vec<IdentityLogEntry>,
+}
+
+impl IdentityKeyLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a serialized log, checking the hash chain.
+    pub fn deserialize(buf: &[u8]) -> Result<Self> {
+        let log = storage_proto::IdentityKeyLogStructure::decode(buf)?;
+        let entries = log
+            .entries
+            .into_iter()
+            .map(IdentityLogEntry::from_protobuf)
+            .collect::<Result<Vec<_>>>()?;
+        let log = Self { entries };
+        log.verify_chain()?;
+        Ok(log)
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let log = storage_proto::IdentityKeyLogStructure {
+            entries: self.entries.iter().map(|e| e.as_protobuf()).collect(),
+        };
+        let mut buf = vec![];
+        log.encode(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Record that `identity_key` was seen for `address` at `observed_at`.
+    ///
+    /// Only the first key seen for an address and later changes are appended; seeing the
+    /// current key again returns `None`. Otherwise returns the new entry's index.
+    pub fn record(
+        &mut self,
+        address: &ProtocolAddress,
+        identity_key: &IdentityKey,
+        observed_at: SystemTime,
+    ) -> Result<Option<u64>> {
+        if self.current_identity(address) == Some(identity_key) {
+            return Ok(None);
+        }
+
+        let index = self.entries.len() as u64;
+        let previous_hash = self.entries.last().map_or([0u8; 32], |e| e.hash());
+        self.entries.push(IdentityLogEntry {
+            index,
+            address: address.clone(),
+            identity_key: *identity_key,
+            observed_at: utils::unix_seconds(observed_at),
+            previous_hash,
+        });
+        Ok(Some(index))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[IdentityLogEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&IdentityLogEntry> {
+        self.entries.get(index as usize)
+    }
+
+    /// Every key observed for `address`, oldest first.
+    pub fn history(&self, address: &ProtocolAddress) -> Vec<&IdentityLogEntry> {
+        self.entries
+            .iter()
+            .filter(|e| &e.address == address)
+            .collect()
+    }
+
+    /// The most recently observed key for `address`.
+    pub fn current_identity(&self, address: &ProtocolAddress) -> Option<&IdentityKey> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| &e.address == address)
+            .map(|e| &e.identity_key)
+    }
+
+    /// Check that each entry is at its index and links to the hash of the one before it.
+    pub fn verify_chain(&self) -> Result<()> {
+        let mut previous_hash = [0u8; 32];
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.index != i as u64 {
+                return Err(SignalProtocolError::IdentityLogVerificationFailed(
+                    "entry index out of sequence",
+                ));
+            }
+            if entry.previous_hash != previous_hash {
+                return Err(SignalProtocolError::IdentityLogVerificationFailed(
+                    "broken hash chain",
+                ));
+            }
+            previous_hash = entry.hash();
+        }
+        Ok(())
+    }
+
+    fn leaf_hashes(&self, tree_size: u64) -> Result<Vec<[u8; 32]>> {
+        if tree_size > self.entries.len() as u64 {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "tree size {} is larger than the log ({} entries)",
+                tree_size,
+                self.entries.len()
+            )));
+        }
+        Ok(self.entries[..tree_size as usize]
+            .iter()
+            .map(|e| e.hash())
+            .collect())
+    }
+
+    /// The Merkle root over the first `tree_size` entries.
+    pub fn root_hash(&self, tree_size: u64) -> Result<[u8; 32]> {
+        Ok(merkle_root(&self.leaf_hashes(tree_size)?))
+    }
+
+    /// Prove that entry `index` is included in the tree over the first `tree_size` entries.
+    pub fn inclusion_proof(&self, index: u64, tree_size: u64) -> Result<InclusionProof> {
+        let leaves = self.leaf_hashes(tree_size)?;
+        if index >= tree_size {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "entry {} is not in a tree of size {}",
+                index, tree_size
+            )));
+        }
+        Ok(InclusionProof::new(
+            index,
+            tree_size,
+            merkle_path(index as usize, &leaves),
+        ))
+    }
+
+    /// Prove that the tree over the first `old_size` entries is a prefix of the tree over the
+    /// first `new_size`.
+    pub fn consistency_proof(&self, old_size: u64, new_size: u64) -> Result<ConsistencyProof> {
+        let leaves = self.leaf_hashes(new_size)?;
+        if old_size > new_size {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "tree size {} is larger than {}",
+                old_size, new_size
+            )));
+        }
+        let path = if old_size == 0 {
+            vec![]
+        } else {
+            merkle_subproof(old_size as usize, &leaves, true)
+        };
+        Ok(ConsistencyProof::new(old_size, new_size, path))
+    }
+}
+
+/// A transparency server's signed commitment to the state of its log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedLogHead {
+    tree_size: u64,
+    root_hash: [u8; 32],
+    timestamp: u64,
+    signature: Vec<u8>,
+}
+
+impl SignedLogHead {
+    fn signed_message(tree_size: u64, root_hash: &[u8; 32], timestamp: u64) -> Vec<u8> {
+        let mut message = LOG_HEAD_SIGNATURE_PREFIX.to_vec();
+        message.extend_from_slice(&tree_size.to_be_bytes());
+        message.extend_from_slice(root_hash);
+        message.extend_from_slice(&timestamp.to_be_bytes());
+        message
+    }
+
+    pub fn new(tree_size: u64, root_hash: [u8; 32], timestamp: u64, signature: Vec<u8>) -> Self {
+        Self {
+            tree_size,
+            root_hash,
+            timestamp,
+            signature,
+        }
+    }
+
+    #[inline]
+    pub fn tree_size(&self) -> u64 {
+        self.tree_size
+    }
+
+    #[inline]
+    pub fn root_hash(&self) -> &[u8; 32] {
+        &self.root_hash
+    }
+
+    /// When the head was signed, in seconds since the Unix epoch.
+    #[inline]
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    #[inline]
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
+
+    pub fn verify(&self, server_key: &PublicKey) -> Result<()> {
+        let message = Self::signed_message(self.tree_size, &self.root_hash, self.timestamp);
+        if server_key.verify_signature(&message, &self.signature)? {
+            Ok(())
+        } else {
+            Err(SignalProtocolError::SignatureValidationFailed)
+        }
+    }
+
+    /// Check the head's signature and that `entry` is included in the tree it commits to.
+    pub fn verify_inclusion(
+        &self,
+        entry: &IdentityLogEntry,
+        proof: &InclusionProof,
+        server_key: &PublicKey,
+    ) -> Result<()> {
+        self.verify(server_key)?;
+        if proof.tree_size != self.tree_size || proof.leaf_index != entry.index {
+            return Err(SignalProtocolError::IdentityLogVerificationFailed(
+                "proof does not match the log head",
+            ));
+        }
+        proof.verify(&entry.hash(), &self.root_hash)
+    }
+
+    /// Check both heads' signatures and that the tree this head commits to extends the one
+    /// `previous` commits to, so the server has not rewritten anything it showed us before.
+    pub fn verify_consistency(
+        &self,
+        previous: &SignedLogHead,
+        proof: &ConsistencyProof,
+        server_key: &PublicKey,
+    ) -> Result<()> {
+        previous.verify(server_key)?;
+        self.verify(server_key)?;
+        if proof.old_size != previous.tree_size || proof.new_size != self.tree_size {
+            return Err(SignalProtocolError::IdentityLogVerificationFailed(
+                "proof does not match the log heads",
+            ));
+        }
+        proof.verify(&previous.root_hash, &self.root_hash)
+    }
+
+    /// Check our own observations against the server's entries.
+    ///
+    /// Each of `server_entries` must be included in this head, and every key our `log` has
+    /// observed must appear among them for the same address. The server's log holds other
+    /// clients' observations too, so it is never expected to equal ours.
+    pub fn verify_observations(
+        &self,
+        log: &IdentityKeyLog,
+        server_entries: &[(IdentityLogEntry, InclusionProof)],
+        server_key: &PublicKey,
+    ) -> Result<()> {
+        log.verify_chain()?;
+        for (entry, proof) in server_entries {
+            self.verify_inclusion(entry, proof, server_key)?;
+        }
+        for observed in log.entries() {
+            let published = server_entries.iter().any(|(entry, _)| {
+                entry.address == observed.address && entry.identity_key == observed.identity_key
+            });
+            if !published {
+                return Err(SignalProtocolError::IdentityLogVerificationFailed(
+                    "observed key is missing from the server's log",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A transparency server run in-process, for testing clients and auditors without a network.
+pub struct LocalTransparencyServer {
+    key_pair: KeyPair,
+    log: IdentityKeyLog,
+}
+
+impl LocalTransparencyServer {
+    pub fn new(key_pair: KeyPair) -> Self {
+        Self {
+            key_pair,
+            log: IdentityKeyLog::new(),
+        }
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key_pair.public_key
+    }
+
+    pub fn log(&self) -> &IdentityKeyLog {
+        &self.log
+    }
+
+    pub fn publish(
+        &mut self,
+        address: &ProtocolAddress,
+        identity_key: &IdentityKey,
+        now: SystemTime,
+    ) -> Result<Option<u64>> {
+        self.log.record(address, identity_key, now)
+    }
+
+    pub fn signed_head<R: Rng + CryptoRng>(
+        &self,
+        now: SystemTime,
+        csprng: &mut R,
+    ) -> Result<SignedLogHead> {
+        let tree_size = self.log.len() as u64;
+        let root_hash = self.log.root_hash(tree_size)?;
+        let timestamp = utils::unix_seconds(now);
+        let signature = self.key_pair.calculate_signature(
+            &SignedLogHead::signed_message(tree_size, &root_hash, timestamp),
+            csprng,
+        )?;
+        Ok(SignedLogHead::new(
+            tree_size,
+            root_hash,
+            timestamp,
+            signature.to_vec(),
+        ))
+    }
+
+    pub fn inclusion_proof(&self, index: u64, tree_size: u64) -> Result<InclusionProof> {
+        self.log.inclusion_proof(index, tree_size)
+    }
+
+    pub fn consistency_proof(&self, old_size: u64, new_size: u64) -> Result<ConsistencyProof> {
+        self.log.consistency_proof(old_size, new_size)
+    }
+}
+
+/// An [`IdentityKeyStore`] that records every key saved through it in an [`IdentityKeyLog`].
+pub struct LoggingIdentityKeyStore<S> {
+    inner: S,
+    log: IdentityKeyLog,
+    clock: Box<dyn Fn() -> SystemTime>,
+}
+
+impl<S: IdentityKeyStore> LoggingIdentityKeyStore<S> {
+    /// Timestamps log entries with the system clock.
+    pub fn new(inner: S, log: IdentityKeyLog) -> Self {
+        Self::with_clock(inner, log, Box::new(SystemTime::now))
+    }
+
+    /// Timestamps log entries with `clock`.
+    pub fn with_clock(inner: S, log: IdentityKeyLog, clock: Box<dyn Fn() -> SystemTime>) -> Self {
+        Self { inner, log, clock }
+    }
+
+    pub fn log(&self) -> &IdentityKeyLog {
+        &self.log
+    }
+
+    pub fn into_inner(self) -> (S, IdentityKeyLog) {
+        (self.inner, self.log)
+    }
+}
+
+impl<S: IdentityKeyStore> IdentityKeyStore for LoggingIdentityKeyStore<S> {
+    fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
+        self.inner.get_identity_key_pair(ctx)
+    }
+
+    fn get_identity_key_signer(&self, ctx: Context) -> Result<Box<dyn IdentityKeySigner + '_>> {
+        self.inner.get_identity_key_signer(ctx)
+    }
+
+    fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
+        self.inner.get_local_registration_id(ctx)
+    }
+
+    fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool> {
+        let replaced = self.inner.save_identity(address, identity, ctx)?;
+        self.log.record(address, identity, (self.clock)())?;
+        Ok(replaced)
+    }
+
+    fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.inner
+            .is_trusted_identity(address, identity, direction, ctx)
+    }
+
+    fn get_identity(&self, address: &ProtocolAddress, ctx: Context) -> Result<Option<IdentityKey>> {
+        self.inner.get_identity(address, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn random_key() -> IdentityKey {
+        *IdentityKeyPair::generate(&mut OsRng).identity_key()
+    }
+
+    #[test]
+    fn test_inclusion_proofs_all_sizes() {
+        let mut log = IdentityKeyLog::new();
+        for i in 0..17 {
+            let address = ProtocolAddress::new(format!("+1415555{:04}", i), 1);
+            log.record(&address, &random_key(), SystemTime::now())
+                .unwrap();
+        }
+
+        for tree_size in 1..=17 {
+            let root = log.root_hash(tree_size).unwrap();
+            for index in 0..tree_size {
+                let proof = log.inclusion_proof(index, tree_size).unwrap();
+                let leaf = log.entry(index).unwrap().hash();
+                proof.verify(&leaf, &root).unwrap();
+
+                // The same proof must not work for a neighbouring leaf.
+                let other = log.entry((index + 1) % 17).unwrap().hash();
+                assert!(proof.verify(&other, &root).is_err());
+            }
+        }
+        assert!(log.inclusion_proof(3, 3).is_err());
+        assert!(log.root_hash(18).is_err());
+    }
+
+    #[test]
+    fn test_consistency_proofs_all_sizes() {
+        let mut log = IdentityKeyLog::new();
+        for i in 0..17 {
+            let address = ProtocolAddress::new(format!("+1415555{:04}", i), 1);
+            log.record(&address, &random_key(), SystemTime::now())
+                .unwrap();
+        }
+
+        for new_size in 0..=17 {
+            let new_root = log.root_hash(new_size).unwrap();
+            for old_size in 0..=new_size {
+                let old_root = log.root_hash(old_size).unwrap();
+                let proof = log.consistency_proof(old_size, new_size).unwrap();
+                proof.verify(&old_root, &new_root).unwrap();
+
+                if old_size == 0 {
+                    continue;
+                }
+                // A different old tree, or any altered path element, must be rejected.
+                let mut other = log.clone();
+                other.entries[old_size as usize - 1].identity_key = random_key();
+                let other_root = other.root_hash(old_size).unwrap();
+                assert!(proof.verify(&other_root, &new_root).is_err());
+                for i in 0..proof.path.len() {
+                    let mut tampered = proof.clone();
+                    tampered.path[i][0] ^= 1;
+                    assert!(tampered.verify(&old_root, &new_root).is_err());
+                }
+                if old_size < new_size {
+                    let backwards = ConsistencyProof::new(new_size, old_size, proof.path.clone());
+                    assert!(backwards.verify(&new_root, &old_root).is_err());
+                }
+            }
+        }
+        assert!(log.consistency_proof(5, 4).is_err());
+        assert!(log.consistency_proof(3, 18).is_err());
+    }
+
+    #[test]
+    fn test_hash_chain() {
+        let alice = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob = ProtocolAddress::new("+14151111112".to_owned(), 1);
+        let (k1, k2) = (random_key(), random_key());
+
+        let mut log = IdentityKeyLog::new();
+        assert_eq!(log.record(&alice, &k1, SystemTime::now()).unwrap(), Some(0));
+        assert_eq!(log.record(&alice, &k1, SystemTime::now()).unwrap(), None);
+        assert_eq!(log.record(&bob, &k1, SystemTime::now()).unwrap(), Some(1));
+        assert_eq!(log.record(&alice, &k2, SystemTime::now()).unwrap(), Some(2));
+
+        assert_eq!(log.current_identity(&alice), Some(&k2));
+        let history: Vec<IdentityKey> =
+            log.history(&alice).iter().map(|e| e.identity_key).collect();
+        assert_eq!(history, vec![k1, k2]);
+
+        let restored = IdentityKeyLog::deserialize(&log.serialize().unwrap()).unwrap();
+        assert_eq!(restored.entries(), log.entries());
+
+        // Rewriting history breaks the chain.
+        let mut forged = log.clone();
+        forged.entries[1].identity_key = k2;
+        assert!(forged.verify_chain().is_err());
+        assert!(IdentityKeyLog::deserialize(&forged.serialize().unwrap()).is_err());
+    }
+}
@@ -9,39 +9,67 @@
 #![deny(unsafe_code)]
 
 mod address;
+mod attachment;
+mod backup;
 mod consts;
 mod crypto;
 mod curve;
+mod decrypt_delta;
+#[cfg(test)]
+mod dudect;
 mod error;
 mod fingerprint;
+mod franking;
 mod group_cipher;
 mod identity_key;
+mod identity_log;
 mod kdf;
 mod proto;
 mod protocol;
+mod qr;
 mod ratchet;
 mod sender_keys;
 mod session;
 mod session_cipher;
 mod state;
 mod storage;
+#[cfg(feature = "test-vectors")]
+mod test_vectors;
 mod utils;
 
 pub use {
     address::ProtocolAddress,
-    curve::{KeyPair, PrivateKey, PublicKey},
-    error::SignalProtocolError,
-    fingerprint::{DisplayableFingerprint, Fingerprint, ScannableFingerprint},
+    attachment::{
+        decrypt_attachment, encrypt_attachment, AttachmentDecryptor, AttachmentEncryptor,
+        AttachmentKey, AttachmentSecrets, DEFAULT_ATTACHMENT_CHUNK_SIZE,
+    },
+    backup::{export_backup, import_backup, BackupManifest, BackupParams, ProtocolBackup},
+    curve::{KeyPair, KeyType, PrivateKey, PublicKey},
+    decrypt_delta::{commit_decrypt_delta, DecryptDelta},
+    error::{ErrorCategory, SignalProtocolError, StoreError},
+    fingerprint::{
+        DisplayableFingerprint, Fingerprint, FingerprintComparison, GroupFingerprint,
+        ScannableFingerprint, ScannableGroupFingerprint, StableIdentifier,
+        DEFAULT_FINGERPRINT_ITERATIONS,
+    },
+    franking::{franking_tag, verify_franking_report, FrankingOpening},
     group_cipher::{
-        create_sender_key_distribution_message, group_decrypt, group_encrypt,
-        process_sender_key_distribution_message,
+        create_sender_key_distribution_message, group_decrypt, group_decrypt_batch,
+        group_decrypt_preview, group_encrypt, process_sender_key_distribution_message,
+    },
+    identity_key::{IdentityKey, IdentityKeyPair, IdentityKeySigner, SignerRng},
+    identity_log::{
+        ConsistencyProof, IdentityKeyLog, IdentityLogEntry, InclusionProof,
+        LocalTransparencyServer, LoggingIdentityKeyStore, SignedLogHead,
     },
-    identity_key::{IdentityKey, IdentityKeyPair},
     kdf::HKDF,
     protocol::{
-        CiphertextMessage, CiphertextMessageType, PreKeySignalMessage,
-        SenderKeyDistributionMessage, SenderKeyMessage, SignalMessage,
+        CiphertextMessage, CiphertextMessageType, DecryptionErrorMessage, MessageHeader,
+        PreKeySignalMessage, PreKeySignalMessageRef, SenderKeyDistributionMessage,
+        SenderKeyMessage, SenderKeyMessageRef, SignalMessage, SignalMessageHeader,
+        SignalMessageRef,
     },
+    qr::QrCode,
     ratchet::{
         are_we_alice, initialize_alice_session, initialize_bob_session,
         AliceSignalProtocolParameters, BobSignalProtocolParameters, ChainKey, MessageKeys, RootKey,
@@ -51,13 +79,18 @@ pub use {
     },
     session::*,
     session_cipher::{
-        message_decrypt, message_decrypt_prekey, message_decrypt_signal, message_encrypt,
-        remote_registration_id, session_version,
+        message_decrypt, message_decrypt_batch, message_decrypt_franked, message_decrypt_prekey,
+        message_decrypt_preview, message_decrypt_signal, message_encrypt, message_encrypt_franked,
+        message_encrypt_into, message_encrypt_to_slice, remote_registration_id, session_version,
     },
     state::{PreKeyBundle, PreKeyRecord, SessionRecord, SessionState, SignedPreKeyRecord},
     storage::{
-        Context, Direction, IdentityKeyStore, InMemIdentityKeyStore, InMemPreKeyStore,
-        InMemSenderKeyStore, InMemSessionStore, InMemSignalProtocolStore, InMemSignedPreKeyStore,
-        PreKeyStore, ProtocolStore, SenderKeyStore, SessionStore, SignedPreKeyStore,
+        Context, Direction, EncryptedStore, IdentityKeyStore, InMemIdentityKeyStore,
+        InMemPreKeyStore, InMemRecordStore, InMemSenderKeyStore, InMemSessionStore,
+        InMemSignalProtocolStore, InMemSignedPreKeyStore, PreKeyStore, ProtocolStore, RecordKind,
+        RecordStore, SenderKeyStore, SessionStore, SignedPreKeyStore, StorageKey,
     },
 };
+
+#[cfg(feature = "test-vectors")]
+pub use test_vectors::{record_session_transcript, replay_session_transcript, TranscriptParty};
@@ -11,7 +11,9 @@ use crate::{curve, proto};
 
 use std::convert::TryFrom;
 
+use bytes::BufMut;
 use hmac::{Hmac, Mac, NewMac};
+use prost::encoding::{self, WireType};
 use prost::Message;
 use rand::{CryptoRng, Rng};
 use sha2::Sha256;
@@ -19,11 +21,34 @@ use subtle::ConstantTimeEq;
 
 pub const CIPHERTEXT_MESSAGE_CURRENT_VERSION: u8 = 3;
 
+/// Check the ciphertext version in the low nibble of a message's first byte and return the
+/// message version from the high nibble.
+fn check_ciphertext_version(version_byte: u8) -> Result<u8> {
+    let message_version = version_byte >> 4;
+    let ciphertext_version = version_byte & 0x0F;
+    if ciphertext_version < CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+        return Err(SignalProtocolError::LegacyCiphertextVersion(
+            ciphertext_version,
+        ));
+    }
+    if ciphertext_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+        return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
+            ciphertext_version,
+        ));
+    }
+    Ok(message_version)
+}
+
+fn decode_franking_commitment(value: &[u8]) -> Result<[u8; 32]> {
+    <[u8; 32]>::try_from(value).map_err(|_| SignalProtocolError::InvalidProtobufEncoding)
+}
+
 pub enum CiphertextMessage {
     SignalMessage(SignalMessage),
     PreKeySignalMessage(PreKeySignalMessage),
     SenderKeyMessage(SenderKeyMessage),
     SenderKeyDistributionMessage(SenderKeyDistributionMessage),
+    DecryptionErrorMessage(DecryptionErrorMessage),
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -32,6 +57,7 @@ pub enum CiphertextMessageType {
     PreKey,
     SenderKey,
     SenderKeyDistribution,
+    DecryptionError,
 }
 
 impl CiphertextMessageType {
@@ -41,6 +67,7 @@ impl CiphertextMessageType {
             CiphertextMessageType::PreKey => 3,
             CiphertextMessageType::SenderKey => 4,
             CiphertextMessageType::SenderKeyDistribution => 5,
+            CiphertextMessageType::DecryptionError => 6,
         }
     }
 }
@@ -54,6 +81,7 @@ impl CiphertextMessage {
             CiphertextMessage::SenderKeyDistributionMessage(_) => {
                 CiphertextMessageType::SenderKeyDistribution
             }
+            CiphertextMessage::DecryptionErrorMessage(_) => CiphertextMessageType::DecryptionError,
         }
     }
 
@@ -63,6 +91,125 @@ impl CiphertextMessage {
             CiphertextMessage::PreKeySignalMessage(x) => x.serialized(),
             CiphertextMessage::SenderKeyMessage(x) => x.serialized(),
             CiphertextMessage::SenderKeyDistributionMessage(x) => x.serialized(),
+            CiphertextMessage::DecryptionErrorMessage(x) => x.serialized(),
+        }
+    }
+
+    /// Parse `bytes` as a message of type `message_type`, as given by the envelope it arrived in.
+    pub fn parse(bytes: &[u8], message_type: CiphertextMessageType) -> Result<Self> {
+        Ok(match message_type {
+            CiphertextMessageType::Whisper => {
+                CiphertextMessage::SignalMessage(SignalMessage::try_from(bytes)?)
+            }
+            CiphertextMessageType::PreKey => {
+                CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::try_from(bytes)?)
+            }
+            CiphertextMessageType::SenderKey => {
+                CiphertextMessage::SenderKeyMessage(SenderKeyMessage::try_from(bytes)?)
+            }
+            CiphertextMessageType::SenderKeyDistribution => {
+                CiphertextMessage::SenderKeyDistributionMessage(
+                    SenderKeyDistributionMessage::try_from(bytes)?,
+                )
+            }
+            CiphertextMessageType::DecryptionError => {
+                CiphertextMessage::DecryptionErrorMessage(DecryptionErrorMessage::try_from(bytes)?)
+            }
+        })
+    }
+
+    /// The unauthenticated metadata of the message, readable without any session state.
+    ///
+    /// Nothing here has been checked against a MAC or signature, so it must not be trusted
+    /// beyond routing and deduplication.
+    pub fn inspect(&self) -> MessageHeader {
+        match self {
+            CiphertextMessage::SignalMessage(x) => MessageHeader::Signal(x.header()),
+            CiphertextMessage::PreKeySignalMessage(x) => MessageHeader::PreKeySignal {
+                message_version: x.message_version,
+                registration_id: x.registration_id,
+                pre_key_id: x.pre_key_id,
+                signed_pre_key_id: x.signed_pre_key_id,
+                base_key: x.base_key,
+                identity_key: x.identity_key,
+                message: x.message.header(),
+            },
+            CiphertextMessage::SenderKeyMessage(x) => MessageHeader::SenderKey {
+                message_version: x.message_version,
+                key_id: x.key_id,
+                iteration: x.iteration,
+            },
+            CiphertextMessage::SenderKeyDistributionMessage(x) => {
+                MessageHeader::SenderKeyDistribution {
+                    message_version: x.message_version,
+                    id: x.id,
+                    iteration: x.iteration,
+                    signing_key: x.signing_key,
+                }
+            }
+            CiphertextMessage::DecryptionErrorMessage(x) => MessageHeader::DecryptionError {
+                message_version: x.message_version,
+                ratchet_key: x.ratchet_key,
+                timestamp: x.timestamp,
+                device_id: x.device_id,
+            },
+        }
+    }
+}
+
+/// The header of a [`SignalMessage`], also carried inside every [`PreKeySignalMessage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalMessageHeader {
+    pub message_version: u8,
+    pub sender_ratchet_key: curve::PublicKey,
+    pub counter: u32,
+    pub previous_counter: u32,
+    /// The sender's commitment to the plaintext, for servers that tag franked messages.
+    pub franking_commitment: Option<[u8; 32]>,
+}
+
+/// Metadata of a serialized message, as returned by [`CiphertextMessage::inspect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageHeader {
+    Signal(SignalMessageHeader),
+    PreKeySignal {
+        message_version: u8,
+        registration_id: u32,
+        pre_key_id: Option<u32>,
+        signed_pre_key_id: u32,
+        base_key: curve::PublicKey,
+        identity_key: IdentityKey,
+        message: SignalMessageHeader,
+    },
+    SenderKey {
+        message_version: u8,
+        key_id: u32,
+        iteration: u32,
+    },
+    SenderKeyDistribution {
+        message_version: u8,
+        id: u32,
+        iteration: u32,
+        signing_key: curve::PublicKey,
+    },
+    DecryptionError {
+        message_version: u8,
+        ratchet_key: Option<curve::PublicKey>,
+        timestamp: u64,
+        device_id: u32,
+    },
+}
+
+impl MessageHeader {
+    pub fn message_type(&self) -> CiphertextMessageType {
+        match self {
+            MessageHeader::Signal(_) => CiphertextMessageType::Whisper,
+            MessageHeader::PreKeySignal { .. } => CiphertextMessageType::PreKey,
+            MessageHeader::SenderKey { .. } => CiphertextMessageType::SenderKey,
+            MessageHeader::SenderKeyDistribution { .. } => {
+                CiphertextMessageType::SenderKeyDistribution
+            }
+            MessageHeader::DecryptionError { .. } => CiphertextMessageType::DecryptionError,
         }
     }
 }
@@ -72,9 +219,9 @@ pub struct SignalMessage {
     message_version: u8,
     sender_ratchet_key: curve::PublicKey,
     counter: u32,
-    #[allow(dead_code)]
     previous_counter: u32,
     ciphertext: Box<[u8]>,
+    franking_commitment: Option<[u8; 32]>,
     serialized: Box<[u8]>,
 }
 
@@ -91,31 +238,30 @@ impl SignalMessage {
         sender_identity_key: &IdentityKey,
         receiver_identity_key: &IdentityKey,
     ) -> Result<Self> {
-        let message = proto::wire::SignalMessage {
-            ratchet_key: Some(sender_ratchet_key.serialize().into_vec()),
-            counter: Some(counter),
-            previous_counter: Some(previous_counter),
-            ciphertext: Some(Vec::<u8>::from(&ciphertext[..])),
-        };
-        let mut serialized = vec![0u8; 1 + message.encoded_len() + Self::MAC_LENGTH];
-        serialized[0] = ((message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION;
-        message.encode(&mut &mut serialized[1..message.encoded_len() + 1])?;
-        let msg_len_for_mac = serialized.len() - Self::MAC_LENGTH;
-        let mac = Self::compute_mac(
-            sender_identity_key,
-            receiver_identity_key,
-            mac_key,
-            &serialized[..msg_len_for_mac],
-        )?;
-        serialized[msg_len_for_mac..].copy_from_slice(&mac);
-        let serialized = serialized.into_boxed_slice();
-        Ok(Self {
+        Self::from_parts(SignalMessageParts {
             message_version,
+            mac_key,
             sender_ratchet_key,
             counter,
             previous_counter,
-            ciphertext: ciphertext.into(),
-            serialized,
+            ciphertext,
+            franking_commitment: None,
+            sender_identity_key: *sender_identity_key,
+            receiver_identity_key: *receiver_identity_key,
+        })
+    }
+
+    pub(crate) fn from_parts(parts: SignalMessageParts) -> Result<Self> {
+        let mut serialized = Vec::with_capacity(parts.encoded_len());
+        parts.encode(&mut serialized)?;
+        Ok(Self {
+            message_version: parts.message_version,
+            sender_ratchet_key: parts.sender_ratchet_key,
+            counter: parts.counter,
+            previous_counter: parts.previous_counter,
+            ciphertext: parts.ciphertext.into(),
+            franking_commitment: parts.franking_commitment,
+            serialized: serialized.into_boxed_slice(),
         })
     }
 
@@ -134,6 +280,12 @@ impl SignalMessage {
         self.counter
     }
 
+    /// The length of the sender's previous sending chain.
+    #[inline]
+    pub fn previous_counter(&self) -> u32 {
+        self.previous_counter
+    }
+
     #[inline]
     pub fn serialized(&self) -> &[u8] {
         &*self.serialized
@@ -144,6 +296,23 @@ impl SignalMessage {
         &*self.ciphertext
     }
 
+    /// The sender's commitment to the plaintext, if the message was franked. It is covered by
+    /// the MAC, and checked against the plaintext when the message is decrypted.
+    #[inline]
+    pub fn franking_commitment(&self) -> Option<&[u8; 32]> {
+        self.franking_commitment.as_ref()
+    }
+
+    pub fn header(&self) -> SignalMessageHeader {
+        SignalMessageHeader {
+            message_version: self.message_version,
+            sender_ratchet_key: self.sender_ratchet_key,
+            counter: self.counter,
+            previous_counter: self.previous_counter,
+            franking_commitment: self.franking_commitment,
+        }
+    }
+
     pub fn verify_mac(
         &self,
         sender_identity_key: &IdentityKey,
@@ -166,6 +335,19 @@ impl SignalMessage {
         mac_key: &[u8],
         message: &[u8],
     ) -> Result<[u8; Self::MAC_LENGTH]> {
+        let mut mac = Self::new_mac(sender_identity_key, receiver_identity_key, mac_key)?;
+        mac.update(message);
+        let mut result = [0u8; Self::MAC_LENGTH];
+        result.copy_from_slice(&mac.finalize().into_bytes()[..Self::MAC_LENGTH]);
+        Ok(result)
+    }
+
+    /// An HMAC keyed with `mac_key` that has already absorbed both identity keys.
+    fn new_mac(
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        mac_key: &[u8],
+    ) -> Result<Hmac<Sha256>> {
         if mac_key.len() != 32 {
             return Err(SignalProtocolError::InvalidMacKeyLength(mac_key.len()));
         }
@@ -178,10 +360,7 @@ impl SignalMessage {
 
         mac.update(sender_identity_key.public_key().serialize().as_ref());
         mac.update(receiver_identity_key.public_key().serialize().as_ref());
-        mac.update(message);
-        let mut result = [0u8; Self::MAC_LENGTH];
-        result.copy_from_slice(&mac.finalize().into_bytes()[..Self::MAC_LENGTH]);
-        Ok(result)
+        Ok(mac)
     }
 }
 
@@ -198,18 +377,7 @@ impl TryFrom<&[u8]> for SignalMessage {
         if value.len() < SignalMessage::MAC_LENGTH + 1 {
             return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
         }
-        let message_version = value[0] >> 4;
-        let ciphertext_version = value[0] & 0x0F;
-        if ciphertext_version < CIPHERTEXT_MESSAGE_CURRENT_VERSION {
-            return Err(SignalProtocolError::LegacyCiphertextVersion(
-                ciphertext_version,
-            ));
-        }
-        if ciphertext_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
-            return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
-                ciphertext_version,
-            ));
-        }
+        let message_version = check_ciphertext_version(value[0])?;
 
         let proto_structure =
             proto::wire::SignalMessage::decode(&value[1..value.len() - SignalMessage::MAC_LENGTH])?;
@@ -226,6 +394,10 @@ impl TryFrom<&[u8]> for SignalMessage {
             .ciphertext
             .ok_or(SignalProtocolError::InvalidProtobufEncoding)?
             .into_boxed_slice();
+        let franking_commitment = proto_structure
+            .franking_commitment
+            .map(|v| decode_franking_commitment(&v))
+            .transpose()?;
 
         Ok(SignalMessage {
             message_version,
@@ -233,6 +405,7 @@ impl TryFrom<&[u8]> for SignalMessage {
             counter,
             previous_counter,
             ciphertext,
+            franking_commitment,
             serialized: Box::from(value),
         })
     }
@@ -338,18 +511,7 @@ impl TryFrom<&[u8]> for PreKeySignalMessage {
             return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
         }
 
-        let message_version = value[0] >> 4;
-        let ciphertext_version = value[0] & 0x0F;
-        if ciphertext_version < CIPHERTEXT_MESSAGE_CURRENT_VERSION {
-            return Err(SignalProtocolError::LegacyCiphertextVersion(
-                ciphertext_version,
-            ));
-        }
-        if ciphertext_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
-            return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
-                ciphertext_version,
-            ));
-        }
+        let message_version = check_ciphertext_version(value[0])?;
 
         let proto_structure = proto::wire::PreKeySignalMessage::decode(&value[1..])?;
         if proto_structure.signed_pre_key_id.is_none()
@@ -427,6 +589,24 @@ impl SenderKeyMessage {
         Ok(valid)
     }
 
+    /// Checks the signature of each message against the paired signing key, using
+    /// [`curve::PublicKey::verify_signatures_batch`].
+    pub fn verify_signatures_batch<R: CryptoRng + Rng>(
+        messages: &[(&SenderKeyMessage, &curve::PublicKey)],
+        csprng: &mut R,
+    ) -> Result<()> {
+        let entries: Vec<_> = messages
+            .iter()
+            .map(|(message, signature_key)| {
+                let (signed, signature) = message
+                    .serialized
+                    .split_at(message.serialized.len() - Self::SIGNATURE_LEN);
+                (*signature_key, signed, signature)
+            })
+            .collect();
+        curve::PublicKey::verify_signatures_batch(&entries, csprng)
+    }
+
     #[inline]
     pub fn message_version(&self) -> u8 {
         self.message_version
@@ -466,18 +646,7 @@ impl TryFrom<&[u8]> for SenderKeyMessage {
         if value.len() < 1 + Self::SIGNATURE_LEN {
             return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
         }
-        let message_version = value[0] >> 4;
-        let ciphertext_version = value[0] & 0x0F;
-        if ciphertext_version < CIPHERTEXT_MESSAGE_CURRENT_VERSION {
-            return Err(SignalProtocolError::LegacyCiphertextVersion(
-                ciphertext_version,
-            ));
-        }
-        if ciphertext_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
-            return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
-                ciphertext_version,
-            ));
-        }
+        let message_version = check_ciphertext_version(value[0])?;
         let proto_structure =
             proto::wire::SenderKeyMessage::decode(&value[1..value.len() - Self::SIGNATURE_LEN])?;
 
@@ -631,137 +800,849 @@ impl TryFrom<&[u8]> for SenderKeyDistributionMessage {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Sent back to the sender of a message that could not be decrypted, so that it can repair the
+/// session (for 1:1 messages) or redistribute its sender key (for group messages).
+#[derive(Debug, Clone)]
+pub struct DecryptionErrorMessage {
+    message_version: u8,
+    ratchet_key: Option<curve::PublicKey>,
+    timestamp: u64,
+    device_id: u32,
+    serialized: Box<[u8]>,
+}
 
-    use rand::rngs::OsRng;
-    use rand::{CryptoRng, Rng};
+impl DecryptionErrorMessage {
+    pub fn new(
+        ratchet_key: Option<curve::PublicKey>,
+        timestamp: u64,
+        device_id: u32,
+    ) -> Result<Self> {
+        let proto_message = proto::wire::DecryptionErrorMessage {
+            ratchet_key: ratchet_key.map(|k| k.serialize().into_vec()),
+            timestamp: Some(timestamp),
+            device_id: Some(device_id),
+        };
+        let message_version = CIPHERTEXT_MESSAGE_CURRENT_VERSION;
+        let mut serialized = vec![0u8; 1 + proto_message.encoded_len()];
+        serialized[0] = ((message_version & 0xF) << 4) | message_version;
+        proto_message.encode(&mut &mut serialized[1..])?;
 
-    fn create_signal_message<T>(csprng: &mut T) -> SignalMessage
-    where
-        T: Rng + CryptoRng,
-    {
-        let mut mac_key = [0u8; 32];
-        csprng.fill_bytes(&mut mac_key);
-        let mac_key = mac_key;
+        Ok(Self {
+            message_version,
+            ratchet_key,
+            timestamp,
+            device_id,
+            serialized: serialized.into_boxed_slice(),
+        })
+    }
 
-        let mut ciphertext = [0u8; 20];
-        csprng.fill_bytes(&mut ciphertext);
-        let ciphertext = ciphertext;
+    /// Build an error message for `original_bytes`, a message of type `original_type` that
+    /// failed to decrypt. `original_timestamp` and `original_sender_device_id` come from the
+    /// envelope the message was delivered in.
+    ///
+    /// Sender key messages carry no ratchet key, which tells their sender to redistribute its
+    /// sender key rather than to touch its session.
+    pub fn for_original(
+        original_bytes: &[u8],
+        original_type: CiphertextMessageType,
+        original_timestamp: u64,
+        original_sender_device_id: u32,
+    ) -> Result<Self> {
+        let ratchet_key = match original_type {
+            CiphertextMessageType::Whisper => {
+                Some(*SignalMessage::try_from(original_bytes)?.sender_ratchet_key())
+            }
+            CiphertextMessageType::PreKey => Some(
+                *PreKeySignalMessage::try_from(original_bytes)?
+                    .message()
+                    .sender_ratchet_key(),
+            ),
+            CiphertextMessageType::SenderKey => None,
+            _ => {
+                return Err(SignalProtocolError::InvalidArgument(format!(
+                    "cannot create a decryption error for a {:?} message",
+                    original_type
+                )))
+            }
+        };
+        Self::new(ratchet_key, original_timestamp, original_sender_device_id)
+    }
 
-        let sender_ratchet_key_pair = curve::KeyPair::generate(csprng);
-        let sender_identity_key_pair = curve::KeyPair::generate(csprng);
-        let receiver_identity_key_pair = curve::KeyPair::generate(csprng);
+    #[inline]
+    pub fn message_version(&self) -> u8 {
+        self.message_version
+    }
 
-        SignalMessage::new(
-            3,
-            &mac_key,
-            sender_ratchet_key_pair.public_key,
-            42,
-            41,
-            &ciphertext,
-            &sender_identity_key_pair.public_key.into(),
-            &receiver_identity_key_pair.public_key.into(),
-        )
-        .unwrap()
+    /// The ratchet key of the failed message, or `None` if it was a sender key message.
+    #[inline]
+    pub fn ratchet_key(&self) -> Option<&curve::PublicKey> {
+        self.ratchet_key.as_ref()
     }
 
-    fn assert_signal_message_equals(m1: &SignalMessage, m2: &SignalMessage) {
-        assert_eq!(m1.message_version, m2.message_version);
-        assert_eq!(m1.sender_ratchet_key, m2.sender_ratchet_key);
-        assert_eq!(m1.counter, m2.counter);
-        assert_eq!(m1.previous_counter, m2.previous_counter);
-        assert_eq!(m1.ciphertext, m2.ciphertext);
-        assert_eq!(m1.serialized, m2.serialized);
+    #[inline]
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
     }
 
-    #[test]
-    fn test_signal_message_serialize_deserialize() {
-        let mut csprng = OsRng;
-        let message = create_signal_message(&mut csprng);
-        let deser_message =
-            SignalMessage::try_from(message.as_ref()).expect("should deserialize without error");
-        assert_signal_message_equals(&message, &deser_message);
+    /// The device that sent the failed message.
+    #[inline]
+    pub fn device_id(&self) -> u32 {
+        self.device_id
     }
 
-    #[test]
-    fn test_pre_key_signal_message_serialize_deserialize() {
-        let mut csprng = OsRng;
-        let identity_key_pair = curve::KeyPair::generate(&mut csprng);
-        let base_key_pair = curve::KeyPair::generate(&mut csprng);
-        let message = create_signal_message(&mut csprng);
-        let pre_key_signal_message = PreKeySignalMessage::new(
-            3,
-            365,
-            None,
-            97,
-            base_key_pair.public_key,
-            identity_key_pair.public_key.into(),
-            message,
-        )
-        .unwrap();
-        let deser_pre_key_signal_message =
-            PreKeySignalMessage::try_from(pre_key_signal_message.as_ref())
-                .expect("should deserialize without error");
-        assert_eq!(
-            pre_key_signal_message.message_version,
-            deser_pre_key_signal_message.message_version
-        );
-        assert_eq!(
-            pre_key_signal_message.registration_id,
-            deser_pre_key_signal_message.registration_id
-        );
-        assert_eq!(
-            pre_key_signal_message.pre_key_id,
-            deser_pre_key_signal_message.pre_key_id
-        );
-        assert_eq!(
-            pre_key_signal_message.signed_pre_key_id,
-            deser_pre_key_signal_message.signed_pre_key_id
-        );
-        assert_eq!(
-            pre_key_signal_message.base_key,
-            deser_pre_key_signal_message.base_key
-        );
-        assert_eq!(
-            pre_key_signal_message.identity_key.public_key(),
-            deser_pre_key_signal_message.identity_key.public_key()
-        );
-        assert_signal_message_equals(
-            &pre_key_signal_message.message,
-            &deser_pre_key_signal_message.message,
-        );
-        assert_eq!(
-            pre_key_signal_message.serialized,
-            deser_pre_key_signal_message.serialized
-        );
+    #[inline]
+    pub fn serialized(&self) -> &[u8] {
+        &*self.serialized
     }
+}
 
-    #[test]
-    fn test_sender_key_message_serialize_deserialize() {
-        let mut csprng = OsRng;
-        let signature_key_pair = curve::KeyPair::generate(&mut csprng);
-        let sender_key_message = SenderKeyMessage::new(
-            42,
-            7,
-            &[1u8, 2, 3],
-            &mut csprng,
-            &signature_key_pair.private_key,
-        )
-        .unwrap();
-        let deser_sender_key_message = SenderKeyMessage::try_from(sender_key_message.as_ref())
-            .expect("should deserialize without error");
-        assert_eq!(
-            sender_key_message.message_version,
-            deser_sender_key_message.message_version
-        );
-        assert_eq!(sender_key_message.key_id, deser_sender_key_message.key_id);
-        assert_eq!(
-            sender_key_message.iteration,
-            deser_sender_key_message.iteration
-        );
-        assert_eq!(
+impl AsRef<[u8]> for DecryptionErrorMessage {
+    fn as_ref(&self) -> &[u8] {
+        &*self.serialized
+    }
+}
+
+impl TryFrom<&[u8]> for DecryptionErrorMessage {
+    type Error = SignalProtocolError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        if value.is_empty() {
+            return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
+        }
+
+        let message_version = value[0] >> 4;
+        if message_version < CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+            return Err(SignalProtocolError::LegacyCiphertextVersion(
+                message_version,
+            ));
+        }
+        if message_version > CIPHERTEXT_MESSAGE_CURRENT_VERSION {
+            return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
+                message_version,
+            ));
+        }
+
+        let proto_structure = proto::wire::DecryptionErrorMessage::decode(&value[1..])?;
+
+        let ratchet_key = proto_structure
+            .ratchet_key
+            .map(|k| curve::decode_point(&k))
+            .transpose()?;
+        let timestamp = proto_structure
+            .timestamp
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        let device_id = proto_structure
+            .device_id
+            .ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+
+        Ok(DecryptionErrorMessage {
+            message_version,
+            ratchet_key,
+            timestamp,
+            device_id,
+            serialized: Box::from(value),
+        })
+    }
+}
+
+/// Writes protobuf fields, handing every piece of output to `emit` so that callers can copy
+/// and authenticate a message in a single pass. Fields must be written in tag order to match
+/// prost's encoding.
+struct FieldWriter<F: FnMut(&[u8])> {
+    emit: F,
+}
+
+impl<F: FnMut(&[u8])> FieldWriter<F> {
+    fn varint(&mut self, value: u64) {
+        let mut buf = [0u8; 10];
+        let mut rest = &mut buf[..];
+        encoding::encode_varint(value, &mut rest);
+        let len = 10 - rest.len();
+        (self.emit)(&buf[..len]);
+    }
+
+    fn key(&mut self, tag: u32, wire_type: WireType) {
+        self.varint(u64::from(tag << 3 | wire_type as u32));
+    }
+
+    fn uint32(&mut self, tag: u32, value: u32) {
+        self.key(tag, WireType::Varint);
+        self.varint(u64::from(value));
+    }
+
+    fn bytes_header(&mut self, tag: u32, len: usize) {
+        self.key(tag, WireType::LengthDelimited);
+        self.varint(len as u64);
+    }
+
+    fn bytes(&mut self, tag: u32, value: &[u8]) {
+        self.bytes_header(tag, value.len());
+        (self.emit)(value);
+    }
+}
+
+fn uint32_field_len(tag: u32, value: u32) -> usize {
+    encoding::key_len(tag) + encoding::encoded_len_varint(u64::from(value))
+}
+
+fn bytes_field_len(tag: u32, len: usize) -> usize {
+    encoding::key_len(tag) + encoding::encoded_len_varint(len as u64) + len
+}
+
+/// The contents of a [`SignalMessage`] before serialization.
+pub(crate) struct SignalMessageParts<'a> {
+    pub(crate) message_version: u8,
+    pub(crate) mac_key: &'a [u8],
+    pub(crate) sender_ratchet_key: curve::PublicKey,
+    pub(crate) counter: u32,
+    pub(crate) previous_counter: u32,
+    pub(crate) ciphertext: &'a [u8],
+    pub(crate) franking_commitment: Option<[u8; 32]>,
+    pub(crate) sender_identity_key: IdentityKey,
+    pub(crate) receiver_identity_key: IdentityKey,
+}
+
+impl SignalMessageParts<'_> {
+    fn proto_len(&self) -> usize {
+        bytes_field_len(1, self.sender_ratchet_key.serialize().len())
+            + uint32_field_len(2, self.counter)
+            + uint32_field_len(3, self.previous_counter)
+            + bytes_field_len(4, self.ciphertext.len())
+            + self
+                .franking_commitment
+                .map_or(0, |commitment| bytes_field_len(5, commitment.len()))
+    }
+
+    pub(crate) fn encoded_len(&self) -> usize {
+        1 + self.proto_len() + SignalMessage::MAC_LENGTH
+    }
+
+    /// Write the serialized message to `buf`, which must have room for
+    /// [`encoded_len`](Self::encoded_len) bytes.
+    pub(crate) fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        let mut mac = SignalMessage::new_mac(
+            &self.sender_identity_key,
+            &self.receiver_identity_key,
+            self.mac_key,
+        )?;
+        let mut writer = FieldWriter {
+            emit: |bytes: &[u8]| {
+                mac.update(bytes);
+                buf.put_slice(bytes);
+            },
+        };
+        (writer.emit)(&[((self.message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION]);
+        writer.bytes(1, &self.sender_ratchet_key.serialize());
+        writer.uint32(2, self.counter);
+        writer.uint32(3, self.previous_counter);
+        writer.bytes(4, self.ciphertext);
+        if let Some(commitment) = &self.franking_commitment {
+            writer.bytes(5, commitment);
+        }
+
+        buf.put_slice(&mac.finalize().into_bytes()[..SignalMessage::MAC_LENGTH]);
+        Ok(())
+    }
+}
+
+/// The contents of a [`PreKeySignalMessage`] before serialization.
+pub(crate) struct PreKeySignalMessageParts<'a> {
+    pub(crate) message_version: u8,
+    pub(crate) registration_id: u32,
+    pub(crate) pre_key_id: Option<u32>,
+    pub(crate) signed_pre_key_id: u32,
+    pub(crate) base_key: curve::PublicKey,
+    pub(crate) identity_key: IdentityKey,
+    pub(crate) message: SignalMessageParts<'a>,
+}
+
+impl PreKeySignalMessageParts<'_> {
+    pub(crate) fn encoded_len(&self) -> usize {
+        1 + self.pre_key_id.map_or(0, |id| uint32_field_len(1, id))
+            + bytes_field_len(2, self.base_key.serialize().len())
+            + bytes_field_len(3, self.identity_key.serialize().len())
+            + bytes_field_len(4, self.message.encoded_len())
+            + uint32_field_len(5, self.registration_id)
+            + uint32_field_len(6, self.signed_pre_key_id)
+    }
+
+    pub(crate) fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        let mut writer = FieldWriter {
+            emit: |bytes: &[u8]| buf.put_slice(bytes),
+        };
+        (writer.emit)(&[((self.message_version & 0xF) << 4) | CIPHERTEXT_MESSAGE_CURRENT_VERSION]);
+        if let Some(pre_key_id) = self.pre_key_id {
+            writer.uint32(1, pre_key_id);
+        }
+        writer.bytes(2, &self.base_key.serialize());
+        writer.bytes(3, &self.identity_key.serialize());
+        writer.bytes_header(4, self.message.encoded_len());
+
+        self.message.encode(buf)?;
+
+        let mut writer = FieldWriter {
+            emit: |bytes: &[u8]| buf.put_slice(bytes),
+        };
+        writer.uint32(5, self.registration_id);
+        writer.uint32(6, self.signed_pre_key_id);
+        Ok(())
+    }
+}
+
+/// An outgoing 1:1 message that has been encrypted but not yet serialized.
+pub(crate) enum OutgoingMessage<'a> {
+    Signal(SignalMessageParts<'a>),
+    PreKey(PreKeySignalMessageParts<'a>),
+}
+
+impl OutgoingMessage<'_> {
+    pub(crate) fn message_type(&self) -> CiphertextMessageType {
+        match self {
+            OutgoingMessage::Signal(_) => CiphertextMessageType::Whisper,
+            OutgoingMessage::PreKey(_) => CiphertextMessageType::PreKey,
+        }
+    }
+
+    pub(crate) fn encoded_len(&self) -> usize {
+        match self {
+            OutgoingMessage::Signal(parts) => parts.encoded_len(),
+            OutgoingMessage::PreKey(parts) => parts.encoded_len(),
+        }
+    }
+
+    pub(crate) fn encode<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        match self {
+            OutgoingMessage::Signal(parts) => parts.encode(buf),
+            OutgoingMessage::PreKey(parts) => parts.encode(buf),
+        }
+    }
+
+    pub(crate) fn into_ciphertext_message(self) -> Result<CiphertextMessage> {
+        Ok(match self {
+            OutgoingMessage::Signal(parts) => {
+                CiphertextMessage::SignalMessage(SignalMessage::from_parts(parts)?)
+            }
+            OutgoingMessage::PreKey(parts) => {
+                CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::new(
+                    parts.message_version,
+                    parts.registration_id,
+                    parts.pre_key_id,
+                    parts.signed_pre_key_id,
+                    parts.base_key,
+                    parts.identity_key,
+                    SignalMessage::from_parts(parts.message)?,
+                )?)
+            }
+        })
+    }
+}
+
+enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Walk the fields of an encoded protobuf message without copying, skipping fixed-width fields.
+/// As with prost, the last occurrence of a field wins.
+fn for_each_field<'a>(
+    mut buf: &'a [u8],
+    mut f: impl FnMut(u32, FieldValue<'a>) -> Result<()>,
+) -> Result<()> {
+    while !buf.is_empty() {
+        let (tag, wire_type) = encoding::decode_key(&mut buf)?;
+        let value = match wire_type {
+            WireType::Varint => FieldValue::Varint(encoding::decode_varint(&mut buf)?),
+            WireType::LengthDelimited => {
+                let len = encoding::decode_varint(&mut buf)?;
+                if len > buf.len() as u64 {
+                    return Err(SignalProtocolError::InvalidProtobufEncoding);
+                }
+                let (value, rest) = buf.split_at(len as usize);
+                buf = rest;
+                FieldValue::Bytes(value)
+            }
+            WireType::SixtyFourBit | WireType::ThirtyTwoBit => {
+                let len = if wire_type == WireType::SixtyFourBit {
+                    8
+                } else {
+                    4
+                };
+                if len > buf.len() {
+                    return Err(SignalProtocolError::InvalidProtobufEncoding);
+                }
+                buf = &buf[len..];
+                continue;
+            }
+            WireType::StartGroup | WireType::EndGroup => {
+                return Err(SignalProtocolError::InvalidProtobufEncoding)
+            }
+        };
+        f(tag, value)?;
+    }
+    Ok(())
+}
+
+/// A borrowed view of a serialized [`SignalMessage`] that does not copy the ciphertext.
+#[derive(Debug, Clone, Copy)]
+pub struct SignalMessageRef<'a> {
+    message_version: u8,
+    sender_ratchet_key: curve::PublicKey,
+    counter: u32,
+    previous_counter: u32,
+    ciphertext: &'a [u8],
+    franking_commitment: Option<[u8; 32]>,
+    serialized: &'a [u8],
+}
+
+impl<'a> SignalMessageRef<'a> {
+    #[inline]
+    pub fn message_version(&self) -> u8 {
+        self.message_version
+    }
+
+    #[inline]
+    pub fn sender_ratchet_key(&self) -> &curve::PublicKey {
+        &self.sender_ratchet_key
+    }
+
+    #[inline]
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    #[inline]
+    pub fn previous_counter(&self) -> u32 {
+        self.previous_counter
+    }
+
+    #[inline]
+    pub fn serialized(&self) -> &'a [u8] {
+        self.serialized
+    }
+
+    #[inline]
+    pub fn body(&self) -> &'a [u8] {
+        self.ciphertext
+    }
+
+    #[inline]
+    pub fn franking_commitment(&self) -> Option<&[u8; 32]> {
+        self.franking_commitment.as_ref()
+    }
+
+    pub fn verify_mac(
+        &self,
+        sender_identity_key: &IdentityKey,
+        receiver_identity_key: &IdentityKey,
+        mac_key: &[u8],
+    ) -> Result<bool> {
+        let (message, their_mac) = self
+            .serialized
+            .split_at(self.serialized.len() - SignalMessage::MAC_LENGTH);
+        let our_mac = SignalMessage::compute_mac(
+            sender_identity_key,
+            receiver_identity_key,
+            mac_key,
+            message,
+        )?;
+        Ok(our_mac.ct_eq(their_mac).into())
+    }
+
+    pub fn into_owned(self) -> SignalMessage {
+        SignalMessage {
+            message_version: self.message_version,
+            sender_ratchet_key: self.sender_ratchet_key,
+            counter: self.counter,
+            previous_counter: self.previous_counter,
+            ciphertext: self.ciphertext.into(),
+            franking_commitment: self.franking_commitment,
+            serialized: self.serialized.into(),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for SignalMessageRef<'a> {
+    type Error = SignalProtocolError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self> {
+        if value.len() < SignalMessage::MAC_LENGTH + 1 {
+            return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
+        }
+        let message_version = check_ciphertext_version(value[0])?;
+
+        let mut ratchet_key = None;
+        let mut counter = None;
+        let mut previous_counter = None;
+        let mut ciphertext = None;
+        let mut franking_commitment = None;
+        for_each_field(
+            &value[1..value.len() - SignalMessage::MAC_LENGTH],
+            |tag, field| {
+                match (tag, field) {
+                    (1, FieldValue::Bytes(v)) => ratchet_key = Some(v),
+                    (2, FieldValue::Varint(v)) => counter = Some(v as u32),
+                    (3, FieldValue::Varint(v)) => previous_counter = Some(v as u32),
+                    (4, FieldValue::Bytes(v)) => ciphertext = Some(v),
+                    (5, FieldValue::Bytes(v)) => franking_commitment = Some(v),
+                    (1..=5, _) => return Err(SignalProtocolError::InvalidProtobufEncoding),
+                    _ => {}
+                }
+                Ok(())
+            },
+        )?;
+
+        let sender_ratchet_key = ratchet_key.ok_or(SignalProtocolError::InvalidProtobufEncoding)?;
+        Ok(SignalMessageRef {
+            message_version,
+            sender_ratchet_key: curve::decode_point(sender_ratchet_key)?,
+            counter: counter.ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            previous_counter: previous_counter.unwrap_or(0),
+            ciphertext: ciphertext.ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            franking_commitment: franking_commitment
+                .map(decode_franking_commitment)
+                .transpose()?,
+            serialized: value,
+        })
+    }
+}
+
+/// A borrowed view of a serialized [`PreKeySignalMessage`].
+#[derive(Debug, Clone, Copy)]
+pub struct PreKeySignalMessageRef<'a> {
+    message_version: u8,
+    registration_id: u32,
+    pre_key_id: Option<u32>,
+    signed_pre_key_id: u32,
+    base_key: curve::PublicKey,
+    identity_key: IdentityKey,
+    message: SignalMessageRef<'a>,
+    serialized: &'a [u8],
+}
+
+impl<'a> PreKeySignalMessageRef<'a> {
+    #[inline]
+    pub fn message_version(&self) -> u8 {
+        self.message_version
+    }
+
+    #[inline]
+    pub fn registration_id(&self) -> u32 {
+        self.registration_id
+    }
+
+    #[inline]
+    pub fn pre_key_id(&self) -> Option<u32> {
+        self.pre_key_id
+    }
+
+    #[inline]
+    pub fn signed_pre_key_id(&self) -> u32 {
+        self.signed_pre_key_id
+    }
+
+    #[inline]
+    pub fn base_key(&self) -> &curve::PublicKey {
+        &self.base_key
+    }
+
+    #[inline]
+    pub fn identity_key(&self) -> &IdentityKey {
+        &self.identity_key
+    }
+
+    #[inline]
+    pub fn message(&self) -> &SignalMessageRef<'a> {
+        &self.message
+    }
+
+    #[inline]
+    pub fn serialized(&self) -> &'a [u8] {
+        self.serialized
+    }
+
+    pub fn into_owned(self) -> PreKeySignalMessage {
+        PreKeySignalMessage {
+            message_version: self.message_version,
+            registration_id: self.registration_id,
+            pre_key_id: self.pre_key_id,
+            signed_pre_key_id: self.signed_pre_key_id,
+            base_key: self.base_key,
+            identity_key: self.identity_key,
+            message: self.message.into_owned(),
+            serialized: self.serialized.into(),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for PreKeySignalMessageRef<'a> {
+    type Error = SignalProtocolError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self> {
+        if value.is_empty() {
+            return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
+        }
+        let message_version = check_ciphertext_version(value[0])?;
+
+        let mut registration_id = None;
+        let mut pre_key_id = None;
+        let mut signed_pre_key_id = None;
+        let mut base_key = None;
+        let mut identity_key = None;
+        let mut message = None;
+        for_each_field(&value[1..], |tag, field| {
+            match (tag, field) {
+                (1, FieldValue::Varint(v)) => pre_key_id = Some(v as u32),
+                (2, FieldValue::Bytes(v)) => base_key = Some(v),
+                (3, FieldValue::Bytes(v)) => identity_key = Some(v),
+                (4, FieldValue::Bytes(v)) => message = Some(v),
+                (5, FieldValue::Varint(v)) => registration_id = Some(v as u32),
+                (6, FieldValue::Varint(v)) => signed_pre_key_id = Some(v as u32),
+                (1..=6, _) => return Err(SignalProtocolError::InvalidProtobufEncoding),
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        match (signed_pre_key_id, base_key, identity_key, message) {
+            (Some(signed_pre_key_id), Some(base_key), Some(identity_key), Some(message)) => {
+                Ok(PreKeySignalMessageRef {
+                    message_version,
+                    registration_id: registration_id.unwrap_or(0),
+                    pre_key_id,
+                    signed_pre_key_id,
+                    base_key: curve::decode_point(base_key)?,
+                    identity_key: IdentityKey::try_from(identity_key)?,
+                    message: SignalMessageRef::try_from(message)?,
+                    serialized: value,
+                })
+            }
+            _ => Err(SignalProtocolError::InvalidProtobufEncoding),
+        }
+    }
+}
+
+/// A borrowed view of a serialized [`SenderKeyMessage`] that does not copy the ciphertext.
+#[derive(Debug, Clone, Copy)]
+pub struct SenderKeyMessageRef<'a> {
+    message_version: u8,
+    key_id: u32,
+    iteration: u32,
+    ciphertext: &'a [u8],
+    serialized: &'a [u8],
+}
+
+impl<'a> SenderKeyMessageRef<'a> {
+    pub fn verify_signature(&self, signature_key: &curve::PublicKey) -> Result<bool> {
+        let (message, signature) = self
+            .serialized
+            .split_at(self.serialized.len() - SenderKeyMessage::SIGNATURE_LEN);
+        curve::verify_signature(signature_key, message, signature)
+    }
+
+    #[inline]
+    pub fn message_version(&self) -> u8 {
+        self.message_version
+    }
+
+    #[inline]
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    #[inline]
+    pub fn iteration(&self) -> u32 {
+        self.iteration
+    }
+
+    #[inline]
+    pub fn ciphertext(&self) -> &'a [u8] {
+        self.ciphertext
+    }
+
+    #[inline]
+    pub fn serialized(&self) -> &'a [u8] {
+        self.serialized
+    }
+
+    pub fn into_owned(self) -> SenderKeyMessage {
+        SenderKeyMessage {
+            message_version: self.message_version,
+            key_id: self.key_id,
+            iteration: self.iteration,
+            ciphertext: self.ciphertext.into(),
+            serialized: self.serialized.into(),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for SenderKeyMessageRef<'a> {
+    type Error = SignalProtocolError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self> {
+        if value.len() < 1 + SenderKeyMessage::SIGNATURE_LEN {
+            return Err(SignalProtocolError::CiphertextMessageTooShort(value.len()));
+        }
+        let message_version = check_ciphertext_version(value[0])?;
+
+        let mut key_id = None;
+        let mut iteration = None;
+        let mut ciphertext = None;
+        for_each_field(
+            &value[1..value.len() - SenderKeyMessage::SIGNATURE_LEN],
+            |tag, field| {
+                match (tag, field) {
+                    (1, FieldValue::Varint(v)) => key_id = Some(v as u32),
+                    (2, FieldValue::Varint(v)) => iteration = Some(v as u32),
+                    (3, FieldValue::Bytes(v)) => ciphertext = Some(v),
+                    (1..=3, _) => return Err(SignalProtocolError::InvalidProtobufEncoding),
+                    _ => {}
+                }
+                Ok(())
+            },
+        )?;
+
+        Ok(SenderKeyMessageRef {
+            message_version,
+            key_id: key_id.ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            iteration: iteration.ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            ciphertext: ciphertext.ok_or(SignalProtocolError::InvalidProtobufEncoding)?,
+            serialized: value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::OsRng;
+    use rand::{CryptoRng, Rng};
+
+    fn create_signal_message<T>(csprng: &mut T) -> SignalMessage
+    where
+        T: Rng + CryptoRng,
+    {
+        let mut mac_key = [0u8; 32];
+        csprng.fill_bytes(&mut mac_key);
+        let mac_key = mac_key;
+
+        let mut ciphertext = [0u8; 20];
+        csprng.fill_bytes(&mut ciphertext);
+        let ciphertext = ciphertext;
+
+        let sender_ratchet_key_pair = curve::KeyPair::generate(csprng);
+        let sender_identity_key_pair = curve::KeyPair::generate(csprng);
+        let receiver_identity_key_pair = curve::KeyPair::generate(csprng);
+
+        SignalMessage::new(
+            3,
+            &mac_key,
+            sender_ratchet_key_pair.public_key,
+            42,
+            41,
+            &ciphertext,
+            &sender_identity_key_pair.public_key.into(),
+            &receiver_identity_key_pair.public_key.into(),
+        )
+        .unwrap()
+    }
+
+    fn assert_signal_message_equals(m1: &SignalMessage, m2: &SignalMessage) {
+        assert_eq!(m1.message_version, m2.message_version);
+        assert_eq!(m1.sender_ratchet_key, m2.sender_ratchet_key);
+        assert_eq!(m1.counter, m2.counter);
+        assert_eq!(m1.previous_counter, m2.previous_counter);
+        assert_eq!(m1.ciphertext, m2.ciphertext);
+        assert_eq!(m1.franking_commitment, m2.franking_commitment);
+        assert_eq!(m1.serialized, m2.serialized);
+    }
+
+    #[test]
+    fn test_signal_message_serialize_deserialize() {
+        let mut csprng = OsRng;
+        let message = create_signal_message(&mut csprng);
+        let deser_message =
+            SignalMessage::try_from(message.as_ref()).expect("should deserialize without error");
+        assert_signal_message_equals(&message, &deser_message);
+    }
+
+    #[test]
+    fn test_pre_key_signal_message_serialize_deserialize() {
+        let mut csprng = OsRng;
+        let identity_key_pair = curve::KeyPair::generate(&mut csprng);
+        let base_key_pair = curve::KeyPair::generate(&mut csprng);
+        let message = create_signal_message(&mut csprng);
+        let pre_key_signal_message = PreKeySignalMessage::new(
+            3,
+            365,
+            None,
+            97,
+            base_key_pair.public_key,
+            identity_key_pair.public_key.into(),
+            message,
+        )
+        .unwrap();
+        let deser_pre_key_signal_message =
+            PreKeySignalMessage::try_from(pre_key_signal_message.as_ref())
+                .expect("should deserialize without error");
+        assert_eq!(
+            pre_key_signal_message.message_version,
+            deser_pre_key_signal_message.message_version
+        );
+        assert_eq!(
+            pre_key_signal_message.registration_id,
+            deser_pre_key_signal_message.registration_id
+        );
+        assert_eq!(
+            pre_key_signal_message.pre_key_id,
+            deser_pre_key_signal_message.pre_key_id
+        );
+        assert_eq!(
+            pre_key_signal_message.signed_pre_key_id,
+            deser_pre_key_signal_message.signed_pre_key_id
+        );
+        assert_eq!(
+            pre_key_signal_message.base_key,
+            deser_pre_key_signal_message.base_key
+        );
+        assert_eq!(
+            pre_key_signal_message.identity_key.public_key(),
+            deser_pre_key_signal_message.identity_key.public_key()
+        );
+        assert_signal_message_equals(
+            &pre_key_signal_message.message,
+            &deser_pre_key_signal_message.message,
+        );
+        assert_eq!(
+            pre_key_signal_message.serialized,
+            deser_pre_key_signal_message.serialized
+        );
+    }
+
+    #[test]
+    fn test_sender_key_message_serialize_deserialize() {
+        let mut csprng = OsRng;
+        let signature_key_pair = curve::KeyPair::generate(&mut csprng);
+        let sender_key_message = SenderKeyMessage::new(
+            42,
+            7,
+            &[1u8, 2, 3],
+            &mut csprng,
+            &signature_key_pair.private_key,
+        )
+        .unwrap();
+        let deser_sender_key_message = SenderKeyMessage::try_from(sender_key_message.as_ref())
+            .expect("should deserialize without error");
+        assert_eq!(
+            sender_key_message.message_version,
+            deser_sender_key_message.message_version
+        );
+        assert_eq!(sender_key_message.key_id, deser_sender_key_message.key_id);
+        assert_eq!(
+            sender_key_message.iteration,
+            deser_sender_key_message.iteration
+        );
+        assert_eq!(
             sender_key_message.ciphertext,
             deser_sender_key_message.ciphertext
         );
@@ -770,4 +1651,343 @@ mod tests {
             deser_sender_key_message.serialized
         );
     }
+
+    #[test]
+    fn test_decryption_error_message_serialize_deserialize() {
+        let mut csprng = OsRng;
+        let signal_message = create_signal_message(&mut csprng);
+        let error_message = DecryptionErrorMessage::for_original(
+            signal_message.as_ref(),
+            CiphertextMessageType::Whisper,
+            1_600_000_000_000,
+            3,
+        )
+        .unwrap();
+        assert_eq!(
+            error_message.ratchet_key(),
+            Some(signal_message.sender_ratchet_key())
+        );
+
+        let deser_error_message = DecryptionErrorMessage::try_from(error_message.as_ref())
+            .expect("should deserialize without error");
+        assert_eq!(error_message.ratchet_key, deser_error_message.ratchet_key);
+        assert_eq!(error_message.timestamp, deser_error_message.timestamp);
+        assert_eq!(error_message.device_id, deser_error_message.device_id);
+        assert_eq!(error_message.serialized, deser_error_message.serialized);
+
+        let signature_key_pair = curve::KeyPair::generate(&mut csprng);
+        let sender_key_message = SenderKeyMessage::new(
+            42,
+            7,
+            &[1u8, 2, 3],
+            &mut csprng,
+            &signature_key_pair.private_key,
+        )
+        .unwrap();
+        let error_message = DecryptionErrorMessage::for_original(
+            sender_key_message.as_ref(),
+            CiphertextMessageType::SenderKey,
+            1_600_000_000_000,
+            3,
+        )
+        .unwrap();
+        let deser_error_message = DecryptionErrorMessage::try_from(error_message.as_ref())
+            .expect("should deserialize without error");
+        assert!(deser_error_message.ratchet_key().is_none());
+    }
+
+    #[test]
+    fn test_signal_message_encoding_matches_prost() {
+        let mut csprng = OsRng;
+        let message = create_signal_message(&mut csprng);
+
+        let proto_message = proto::wire::SignalMessage {
+            ratchet_key: Some(message.sender_ratchet_key.serialize().into_vec()),
+            counter: Some(message.counter),
+            previous_counter: Some(message.previous_counter),
+            ciphertext: Some(message.ciphertext.to_vec()),
+            franking_commitment: None,
+        };
+        let mut expected = vec![message.serialized[0]];
+        proto_message.encode(&mut expected).unwrap();
+        expected.extend_from_slice(&message.serialized[expected.len()..]);
+        assert_eq!(&expected[..], message.serialized());
+    }
+
+    #[test]
+    fn test_signal_message_franking_commitment() {
+        let mut csprng = OsRng;
+        let sender_identity: IdentityKey = curve::KeyPair::generate(&mut csprng).public_key.into();
+        let receiver_identity: IdentityKey =
+            curve::KeyPair::generate(&mut csprng).public_key.into();
+        let commitment = [5u8; 32];
+        let message = SignalMessage::from_parts(SignalMessageParts {
+            message_version: 3,
+            mac_key: &[7u8; 32],
+            sender_ratchet_key: curve::KeyPair::generate(&mut csprng).public_key,
+            counter: 1,
+            previous_counter: 0,
+            ciphertext: &[9u8; 16],
+            franking_commitment: Some(commitment),
+            sender_identity_key: sender_identity,
+            receiver_identity_key: receiver_identity,
+        })
+        .unwrap();
+        assert_eq!(message.franking_commitment(), Some(&commitment));
+        assert_eq!(message.header().franking_commitment, Some(commitment));
+        assert!(message
+            .verify_mac(&sender_identity, &receiver_identity, &[7u8; 32])
+            .unwrap());
+
+        let proto_message = proto::wire::SignalMessage {
+            ratchet_key: Some(message.sender_ratchet_key.serialize().into_vec()),
+            counter: Some(1),
+            previous_counter: Some(0),
+            ciphertext: Some(vec![9u8; 16]),
+            franking_commitment: Some(commitment.to_vec()),
+        };
+        let mut expected = vec![message.serialized[0]];
+        proto_message.encode(&mut expected).unwrap();
+        assert_eq!(
+            &expected[..],
+            &message.serialized()[..message.serialized().len() - SignalMessage::MAC_LENGTH]
+        );
+
+        let parsed = SignalMessage::try_from(message.serialized()).unwrap();
+        assert_eq!(parsed.franking_commitment(), Some(&commitment));
+        let parsed_ref = SignalMessageRef::try_from(message.serialized()).unwrap();
+        assert_eq!(parsed_ref.franking_commitment(), Some(&commitment));
+        assert_signal_message_equals(&parsed_ref.into_owned(), &message);
+
+        // A commitment of the wrong length is rejected by both parsers.
+        let mut short = proto_message;
+        short.franking_commitment = Some(vec![5u8; 31]);
+        let mut serialized = vec![message.serialized[0]];
+        short.encode(&mut serialized).unwrap();
+        serialized.extend_from_slice(&[0u8; SignalMessage::MAC_LENGTH]);
+        assert!(SignalMessage::try_from(&serialized[..]).is_err());
+        assert!(SignalMessageRef::try_from(&serialized[..]).is_err());
+    }
+
+    #[test]
+    fn test_pre_key_signal_message_parts_encoding() {
+        let mut csprng = OsRng;
+        let mac_key = [7u8; 32];
+        let ciphertext = [9u8; 48];
+        let sender_identity: IdentityKey = curve::KeyPair::generate(&mut csprng).public_key.into();
+        let receiver_identity: IdentityKey =
+            curve::KeyPair::generate(&mut csprng).public_key.into();
+        let ratchet_key = curve::KeyPair::generate(&mut csprng).public_key;
+        let base_key = curve::KeyPair::generate(&mut csprng).public_key;
+
+        for &pre_key_id in &[None, Some(5), Some(u32::MAX)] {
+            for &franking_commitment in &[None, Some([3u8; 32])] {
+                let parts = PreKeySignalMessageParts {
+                    message_version: 3,
+                    registration_id: 12345,
+                    pre_key_id,
+                    signed_pre_key_id: 300,
+                    base_key,
+                    identity_key: sender_identity,
+                    message: SignalMessageParts {
+                        message_version: 3,
+                        mac_key: &mac_key,
+                        sender_ratchet_key: ratchet_key,
+                        counter: 200,
+                        previous_counter: 1 << 20,
+                        ciphertext: &ciphertext,
+                        franking_commitment,
+                        sender_identity_key: sender_identity,
+                        receiver_identity_key: receiver_identity,
+                    },
+                };
+                let mut encoded = vec![];
+                parts.encode(&mut encoded).unwrap();
+                assert_eq!(encoded.len(), parts.encoded_len());
+
+                let expected = OutgoingMessage::PreKey(parts)
+                    .into_ciphertext_message()
+                    .unwrap();
+                assert_eq!(&encoded[..], expected.serialize());
+            }
+        }
+    }
+
+    #[test]
+    fn test_message_refs_match_owned_parsing() {
+        let mut csprng = OsRng;
+        let identity_key_pair = curve::KeyPair::generate(&mut csprng);
+        let base_key_pair = curve::KeyPair::generate(&mut csprng);
+        let message = create_signal_message(&mut csprng);
+
+        let message_ref = SignalMessageRef::try_from(message.serialized()).unwrap();
+        assert_eq!(message_ref.counter(), 42);
+        assert_eq!(message_ref.previous_counter(), 41);
+        assert_eq!(message_ref.body(), message.body());
+        assert_signal_message_equals(&message_ref.into_owned(), &message);
+
+        let pre_key_signal_message = PreKeySignalMessage::new(
+            3,
+            365,
+            Some(23),
+            97,
+            base_key_pair.public_key,
+            identity_key_pair.public_key.into(),
+            message.clone(),
+        )
+        .unwrap();
+        let pre_key_ref =
+            PreKeySignalMessageRef::try_from(pre_key_signal_message.serialized()).unwrap();
+        assert_eq!(pre_key_ref.registration_id(), 365);
+        assert_eq!(pre_key_ref.pre_key_id(), Some(23));
+        assert_eq!(pre_key_ref.signed_pre_key_id(), 97);
+        assert_eq!(pre_key_ref.base_key(), &base_key_pair.public_key);
+        assert_eq!(pre_key_ref.message().serialized(), message.serialized());
+        let owned = pre_key_ref.into_owned();
+        assert_eq!(owned.serialized, pre_key_signal_message.serialized);
+        assert_signal_message_equals(&owned.message, &message);
+
+        let signature_key_pair = curve::KeyPair::generate(&mut csprng);
+        let sender_key_message = SenderKeyMessage::new(
+            42,
+            7,
+            &[1u8, 2, 3],
+            &mut csprng,
+            &signature_key_pair.private_key,
+        )
+        .unwrap();
+        let sender_key_ref =
+            SenderKeyMessageRef::try_from(sender_key_message.serialized()).unwrap();
+        assert_eq!(sender_key_ref.key_id(), 42);
+        assert_eq!(sender_key_ref.iteration(), 7);
+        assert_eq!(sender_key_ref.ciphertext(), &[1u8, 2, 3]);
+        assert!(sender_key_ref
+            .verify_signature(&signature_key_pair.public_key)
+            .unwrap());
+        assert_eq!(
+            sender_key_ref.into_owned().serialized,
+            sender_key_message.serialized
+        );
+
+        // Every truncation that the owned parsers reject, the borrowed ones reject too.
+        let serialized = pre_key_signal_message.serialized();
+        for len in 0..serialized.len() {
+            assert_eq!(
+                PreKeySignalMessage::try_from(&serialized[..len]).is_ok(),
+                PreKeySignalMessageRef::try_from(&serialized[..len]).is_ok()
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_and_inspect() {
+        let mut csprng = OsRng;
+        let identity_key_pair = curve::KeyPair::generate(&mut csprng);
+        let base_key_pair = curve::KeyPair::generate(&mut csprng);
+        let message = create_signal_message(&mut csprng);
+        let signal_header = SignalMessageHeader {
+            message_version: 3,
+            sender_ratchet_key: *message.sender_ratchet_key(),
+            counter: 42,
+            previous_counter: 41,
+            franking_commitment: None,
+        };
+
+        let parsed =
+            CiphertextMessage::parse(message.serialized(), CiphertextMessageType::Whisper).unwrap();
+        assert_eq!(parsed.inspect(), MessageHeader::Signal(signal_header));
+        assert_eq!(
+            parsed.inspect().message_type(),
+            CiphertextMessageType::Whisper
+        );
+
+        let pre_key_signal_message = PreKeySignalMessage::new(
+            3,
+            365,
+            Some(23),
+            97,
+            base_key_pair.public_key,
+            identity_key_pair.public_key.into(),
+            message.clone(),
+        )
+        .unwrap();
+        let parsed = CiphertextMessage::parse(
+            pre_key_signal_message.serialized(),
+            CiphertextMessageType::PreKey,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.inspect(),
+            MessageHeader::PreKeySignal {
+                message_version: 3,
+                registration_id: 365,
+                pre_key_id: Some(23),
+                signed_pre_key_id: 97,
+                base_key: base_key_pair.public_key,
+                identity_key: identity_key_pair.public_key.into(),
+                message: signal_header,
+            }
+        );
+
+        let signature_key_pair = curve::KeyPair::generate(&mut csprng);
+        let sender_key_message = SenderKeyMessage::new(
+            42,
+            7,
+            &[1u8, 2, 3],
+            &mut csprng,
+            &signature_key_pair.private_key,
+        )
+        .unwrap();
+        let parsed = CiphertextMessage::parse(
+            sender_key_message.serialized(),
+            CiphertextMessageType::SenderKey,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.inspect(),
+            MessageHeader::SenderKey {
+                message_version: 3,
+                key_id: 42,
+                iteration: 7,
+            }
+        );
+
+        let distribution_message =
+            SenderKeyDistributionMessage::new(42, 7, &[9u8; 32], signature_key_pair.public_key)
+                .unwrap();
+        let parsed = CiphertextMessage::parse(
+            distribution_message.serialized(),
+            CiphertextMessageType::SenderKeyDistribution,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.inspect(),
+            MessageHeader::SenderKeyDistribution {
+                message_version: 3,
+                id: 42,
+                iteration: 7,
+                signing_key: signature_key_pair.public_key,
+            }
+        );
+
+        let error_message = DecryptionErrorMessage::new(None, 1_600_000_000_000, 2).unwrap();
+        let parsed = CiphertextMessage::parse(
+            error_message.serialized(),
+            CiphertextMessageType::DecryptionError,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.inspect(),
+            MessageHeader::DecryptionError {
+                message_version: 3,
+                ratchet_key: None,
+                timestamp: 1_600_000_000_000,
+                device_id: 2,
+            }
+        );
+
+        assert!(
+            CiphertextMessage::parse(message.serialized(), CiphertextMessageType::PreKey).is_err()
+        );
+    }
 }
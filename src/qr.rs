@@ -0,0 +1,897 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+//! A minimal QR code encoder for fingerprint payloads.
+//!
+//! Only what scannable fingerprints need is supported: byte mode, error correction level M and
+//! versions 1 to 10 (up to 213 bytes). The construction follows ISO/IEC 18004, and masks are
+//! chosen the way qrcodegen chooses them, so the same payload gives the same matrix as that
+//! encoder. [`QrCode::payload`] reads an undamaged matrix back, which is enough to check a
+//! rendering but is not a scanner.
+
+use crate::error::{Result, SignalProtocolError};
+
+use std::fmt::Write;
+
+const MAX_VERSION: usize = 10;
+
+/// Error correction codewords per block at level M, indexed by version.
+const ECC_CODEWORDS_PER_BLOCK: [usize; MAX_VERSION + 1] =
+    [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26];
+
+/// Number of error correction blocks at level M, indexed by version.
+const NUM_ERROR_CORRECTION_BLOCKS: [usize; MAX_VERSION + 1] = [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5];
+
+/// The format information bits identifying level M.
+const ECC_LEVEL_M_FORMAT_BITS: u32 = 0;
+
+const QUIET_ZONE: usize = 4;
+
+/// A QR code symbol: a square matrix of dark and light modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrCode {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode `payload` in the smallest version that holds it.
+    pub fn new(payload: &[u8]) -> Result<Self> {
+        let version = (1..=MAX_VERSION)
+            .find(|&v| 4 + char_count_bits(v) + payload.len() * 8 <= num_data_codewords(v) * 8)
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidArgument(format!(
+                    "QR payload of {} bytes is too long",
+                    payload.len()
+                ))
+            })?;
+
+        let codewords = add_ecc_and_interleave(version, &encode_data(version, payload));
+
+        let mut matrix = Matrix::new(version);
+        matrix.draw_function_patterns();
+        matrix.draw_codewords(&codewords);
+
+        let mut best: Option<(u32, Vec<bool>)> = None;
+        for mask in 0..8 {
+            let mut candidate = matrix.clone();
+            candidate.apply_mask(mask);
+            candidate.draw_format_bits(mask);
+            let penalty = candidate.penalty_score();
+            let better = match &best {
+                Some((best_penalty, _)) => penalty < *best_penalty,
+                None => true,
+            };
+            if better {
+                best = Some((penalty, candidate.modules));
+            }
+        }
+
+        Ok(Self {
+            version,
+            size: matrix.size,
+            modules: best.expect("eight masks tried").1,
+        })
+    }
+
+    /// Wrap a scanned matrix, given row by row as `size * size` modules (`true` for dark).
+    pub fn from_modules(size: usize, modules: Vec<bool>) -> Result<Self> {
+        if !(21..=17 + 4 * MAX_VERSION).contains(&size) || (size - 17) % 4 != 0 {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "unsupported QR code size {}",
+                size
+            )));
+        }
+        if modules.len() != size * size {
+            return Err(SignalProtocolError::InvalidArgument(
+                "QR module count does not match size".to_string(),
+            ));
+        }
+        Ok(Self {
+            version: (size - 17) / 4,
+            size,
+            modules,
+        })
+    }
+
+    #[inline]
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// The width and height of the symbol in modules, not counting the quiet zone.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module in column `x` and row `y` is dark.
+    #[inline]
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /// Render the symbol, with its quiet zone, as an SVG document one unit per module.
+    pub fn to_svg(&self) -> String {
+        let dimension = self.size + 2 * QUIET_ZONE;
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.is_dark(x, y) {
+                    if !path.is_empty() {
+                        path.push(' ');
+                    }
+                    write!(path, "M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE)
+                        .expect("writing to a String");
+                }
+            }
+        }
+        format!(
+            concat!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" ",
+                "viewBox=\"0 0 {0} {0}\" shape-rendering=\"crispEdges\">",
+                "<rect width=\"100%\" height=\"100%\" fill=\"#FFFFFF\"/>",
+                "<path d=\"{1}\" fill=\"#000000\"/></svg>"
+            ),
+            dimension, path
+        )
+    }
+
+    /// Read the payload back out of the matrix.
+    ///
+    /// No error correction is attempted, so this only succeeds on an undamaged symbol.
+    pub fn payload(&self) -> Result<Vec<u8>> {
+        let invalid = || SignalProtocolError::InvalidArgument("invalid QR code".to_string());
+
+        let mut template = Matrix::new(self.version);
+        template.draw_function_patterns();
+
+        let format = (0..15).fold(0u32, |acc, i| {
+            let (x, y) = FORMAT_BIT_POSITIONS[i];
+            acc | (self.is_dark(x, y) as u32) << i
+        });
+        let mask = (0..8)
+            .find(|&mask| format_bits(mask) == format)
+            .ok_or_else(invalid)?;
+
+        let mut codewords = vec![0u8; num_raw_codewords(self.version)];
+        let num_bits = codewords.len() * 8;
+        for (i, (x, y)) in template
+            .codeword_positions()
+            .into_iter()
+            .take(num_bits)
+            .enumerate()
+        {
+            let dark = self.is_dark(x, y) ^ mask_bit(mask, x, y);
+            codewords[i >> 3] |= (dark as u8) << (7 - (i & 7));
+        }
+
+        let data = deinterleave_data(self.version, &codewords);
+        let mut reader = BitReader {
+            data: &data,
+            pos: 0,
+        };
+        if reader.read(4).ok_or_else(invalid)? != 0b0100 {
+            return Err(invalid());
+        }
+        let len = reader
+            .read(char_count_bits(self.version))
+            .ok_or_else(invalid)? as usize;
+        (0..len)
+            .map(|_| reader.read(8).map(|b| b as u8).ok_or_else(invalid))
+            .collect()
+    }
+}
+
+fn char_count_bits(version: usize) -> usize {
+    if version <= 9 {
+        8
+    } else {
+        16
+    }
+}
+
+/// The number of modules available for codewords and remainder bits.
+fn num_raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let num_align = version / 7 + 2;
+        result -= (25 * num_align - 10) * num_align - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+fn num_raw_codewords(version: usize) -> usize {
+    num_raw_data_modules(version) / 8
+}
+
+fn num_data_codewords(version: usize) -> usize {
+    num_raw_codewords(version)
+        - ECC_CODEWORDS_PER_BLOCK[version] * NUM_ERROR_CORRECTION_BLOCKS[version]
+}
+
+fn alignment_pattern_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return vec![];
+    }
+    let num_align = version / 7 + 2;
+    let step = (version * 8 + num_align * 3 + 5) / (num_align * 4 - 4) * 2;
+    let mut result: Vec<usize> = (0..num_align - 1)
+        .map(|i| version * 4 + 10 - i * step)
+        .collect();
+    result.push(6);
+    result.reverse();
+    result
+}
+
+fn encode_data(version: usize, payload: &[u8]) -> Vec<u8> {
+    let capacity = num_data_codewords(version) * 8;
+    let mut bits = BitWriter::default();
+    bits.write(0b0100, 4);
+    bits.write(payload.len() as u32, char_count_bits(version));
+    for &b in payload {
+        bits.write(u32::from(b), 8);
+    }
+    let terminator = std::cmp::min(4, capacity - bits.len);
+    bits.write(0, terminator);
+    let padding = (8 - bits.len % 8) % 8;
+    bits.write(0, padding);
+
+    let mut data = bits.bytes;
+    for &pad in [0xECu8, 0x11].iter().cycle() {
+        if data.len() * 8 >= capacity {
+            break;
+        }
+        data.push(pad);
+    }
+    data
+}
+
+/// Split the data codewords into blocks as (offset, length) pairs, short blocks first.
+fn data_blocks(version: usize) -> Vec<(usize, usize)> {
+    let num_blocks = NUM_ERROR_CORRECTION_BLOCKS[version];
+    let raw_codewords = num_raw_codewords(version);
+    let num_short_blocks = num_blocks - raw_codewords % num_blocks;
+    let short_data_len = raw_codewords / num_blocks - ECC_CODEWORDS_PER_BLOCK[version];
+
+    let mut offset = 0;
+    (0..num_blocks)
+        .map(|i| {
+            let len = short_data_len + (i >= num_short_blocks) as usize;
+            let block = (offset, len);
+            offset += len;
+            block
+        })
+        .collect()
+}
+
+fn add_ecc_and_interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let ecc_len = ECC_CODEWORDS_PER_BLOCK[version];
+    let divisor = reed_solomon_divisor(ecc_len);
+    let blocks = data_blocks(version);
+    let max_data_len = blocks.iter().map(|&(_, len)| len).max().unwrap_or(0);
+
+    let eccs: Vec<Vec<u8>> = blocks
+        .iter()
+        .map(|&(offset, len)| reed_solomon_remainder(&data[offset..offset + len], &divisor))
+        .collect();
+
+    let mut result = Vec::with_capacity(num_raw_codewords(version));
+    for i in 0..max_data_len {
+        for &(offset, len) in &blocks {
+            if i < len {
+                result.push(data[offset + i]);
+            }
+        }
+    }
+    for i in 0..ecc_len {
+        for ecc in &eccs {
+            result.push(ecc[i]);
+        }
+    }
+    result
+}
+
+fn deinterleave_data(version: usize, codewords: &[u8]) -> Vec<u8> {
+    let blocks = data_blocks(version);
+    let max_data_len = blocks.iter().map(|&(_, len)| len).max().unwrap_or(0);
+
+    let mut data = vec![0u8; num_data_codewords(version)];
+    let mut next = codewords.iter();
+    for i in 0..max_data_len {
+        for &(offset, len) in &blocks {
+            if i < len {
+                data[offset + i] = *next.next().expect("enough codewords");
+            }
+        }
+    }
+    data
+}
+
+fn gf256_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((u32::from(y) >> i) & 1) * u32::from(x);
+    }
+    z as u8
+}
+
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf256_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf256_multiply(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &b in data {
+        let factor = b ^ result.remove(0);
+        result.push(0);
+        for (r, &coef) in result.iter_mut().zip(divisor) {
+            *r ^= gf256_multiply(coef, factor);
+        }
+    }
+    result
+}
+
+fn format_bits(mask: u8) -> u32 {
+    let data = ECC_LEVEL_M_FORMAT_BITS << 3 | u32::from(mask);
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    (data << 10 | rem) ^ 0x5412
+}
+
+/// Where the first copy of each format bit lives, as (x, y).
+const FORMAT_BIT_POSITIONS: [(usize, usize); 15] = [
+    (8, 0),
+    (8, 1),
+    (8, 2),
+    (8, 3),
+    (8, 4),
+    (8, 5),
+    (8, 7),
+    (8, 8),
+    (7, 8),
+    (5, 8),
+    (4, 8),
+    (3, 8),
+    (2, 8),
+    (1, 8),
+    (0, 8),
+];
+
+fn mask_bit(mask: u8, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y) % 2 == 0,
+        1 => y % 2 == 0,
+        2 => x % 3 == 0,
+        3 => (x + y) % 3 == 0,
+        4 => (x / 3 + y / 2) % 2 == 0,
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3) % 2 == 0,
+        7 => ((x + y) % 2 + x * y % 3) % 2 == 0,
+        _ => unreachable!("mask is in 0..8"),
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len % 8 == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().expect("pushed above") |= bit << (7 - self.len % 8);
+            self.len += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: usize) -> Option<u32> {
+        if self.pos + count > self.data.len() * 8 {
+            return None;
+        }
+        let mut value = 0u32;
+        for _ in 0..count {
+            let bit = (self.data[self.pos >> 3] >> (7 - (self.pos & 7))) & 1;
+            value = value << 1 | u32::from(bit);
+            self.pos += 1;
+        }
+        Some(value)
+    }
+}
+
+#[derive(Clone)]
+struct Matrix {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+    is_function: Vec<bool>,
+}
+
+impl Matrix {
+    fn new(version: usize) -> Self {
+        let size = version * 4 + 17;
+        Self {
+            version,
+            size,
+            modules: vec![false; size * size],
+            is_function: vec![false; size * size],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.is_function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self) {
+        for i in 0..self.size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        let far = self.size - 4;
+        for &(x, y) in &[(3, 3), (far, 3), (3, far)] {
+            self.draw_finder_pattern(x, y);
+        }
+
+        let positions = alignment_pattern_positions(self.version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // The finder patterns already occupy three corners.
+                let corner =
+                    (i == 0 || i == last) && (j == 0 || j == last) && (i, j) != (last, last);
+                if !corner {
+                    self.draw_alignment_pattern(x, y);
+                }
+            }
+        }
+
+        // Reserve the format areas; the real bits are drawn once the mask is chosen.
+        self.draw_format_bits(0);
+        self.draw_version_bits();
+    }
+
+    fn draw_finder_pattern(&mut self, x: usize, y: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (xx, yy) = (x as i32 + dx, y as i32 + dy);
+                if 0 <= xx && xx < self.size as i32 && 0 <= yy && yy < self.size as i32 {
+                    let dist = std::cmp::max(dx.abs(), dy.abs());
+                    self.set_function(xx as usize, yy as usize, dist != 2 && dist != 4);
+                }
+            }
+        }
+    }
+
+    fn draw_alignment_pattern(&mut self, x: usize, y: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let dark = std::cmp::max(dx.abs(), dy.abs()) != 1;
+                self.set_function((x as i32 + dx) as usize, (y as i32 + dy) as usize, dark);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u8) {
+        let bits = format_bits(mask);
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+        for (i, &(x, y)) in FORMAT_BIT_POSITIONS.iter().enumerate() {
+            self.set_function(x, y, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(self.size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, self.size - 15 + i, bit(i));
+        }
+        self.set_function(8, self.size - 8, true);
+    }
+
+    fn draw_version_bits(&mut self) {
+        if self.version < 7 {
+            return;
+        }
+        let mut rem = self.version as u32;
+        for _ in 0..12 {
+            rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+        }
+        let bits = (self.version as u32) << 12 | rem;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let a = self.size - 11 + i % 3;
+            let b = i / 3;
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    /// The data module positions in placement order: two-column strips zigzagging up and down
+    /// from the right edge, skipping the vertical timing pattern and function modules.
+    fn codeword_positions(&self) -> Vec<(usize, usize)> {
+        let mut result = Vec::new();
+        let mut right = self.size as i32 - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vert in 0..self.size {
+                for j in 0..2 {
+                    let x = (right - j) as usize;
+                    let y = if upward { self.size - 1 - vert } else { vert };
+                    if !self.is_function[y * self.size + x] {
+                        result.push((x, y));
+                    }
+                }
+            }
+            right -= 2;
+        }
+        result
+    }
+
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        for (i, (x, y)) in self.codeword_positions().into_iter().enumerate() {
+            // Remainder bits past the last codeword stay light.
+            if i < codewords.len() * 8 {
+                self.modules[y * self.size + x] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 != 0;
+            }
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u8) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let i = y * self.size + x;
+                if !self.is_function[i] {
+                    self.modules[i] ^= mask_bit(mask, x, y);
+                }
+            }
+        }
+    }
+
+    /// The penalty rules of ISO/IEC 18004 section 7.8.3; lower is better.
+    fn penalty_score(&self) -> u32 {
+        let size = self.size;
+        let mut penalty = 0;
+
+        let lines = (0..size).flat_map(|i| {
+            vec![
+                (0..size).map(|j| self.get(j, i)).collect::<Vec<_>>(),
+                (0..size).map(|j| self.get(i, j)).collect::<Vec<_>>(),
+            ]
+        });
+        for line in lines {
+            let mut run = 1;
+            for j in 1..=size {
+                if j < size && line[j] == line[j - 1] {
+                    run += 1;
+                } else {
+                    if run >= 5 {
+                        penalty += 3 + (run - 5) as u32;
+                    }
+                    run = 1;
+                }
+            }
+            penalty += finder_like_patterns(&line) * 40;
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let c = self.get(x, y);
+                if c == self.get(x + 1, y) && c == self.get(x, y + 1) && c == self.get(x + 1, y + 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // 10 points for each full 5% step the dark share is away from 45%-55%.
+        let dark = self.modules.iter().filter(|&&m| m).count() as i64;
+        let total = (size * size) as i64;
+        let k = ((dark * 20 - total * 10).abs() + total - 1) / total - 1;
+        penalty += k as u32 * 10;
+
+        penalty
+    }
+}
+
+/// Count the finder-like patterns in a row or column: dark, light, dark, light and dark runs in
+/// the ratio 1:1:3:1:1, with light four units long on one side. The quiet zone counts as light,
+/// and a pattern with enough light on both sides counts twice.
+fn finder_like_patterns(line: &[bool]) -> u32 {
+    // Alternating run lengths, light first and last; the edges get the quiet zone's light.
+    let mut runs = vec![line.len()];
+    let mut color = false;
+    for &module in line {
+        if module != color {
+            runs.push(0);
+            color = module;
+        }
+        *runs.last_mut().expect("not empty") += 1;
+    }
+    if color {
+        runs.push(0);
+    }
+    *runs.last_mut().expect("not empty") += line.len();
+
+    runs.windows(7)
+        .step_by(2)
+        .map(|w| {
+            let n = w[1];
+            let core = w[2] == n && w[3] == 3 * n && w[4] == n && w[5] == n;
+            let before = core && w[0] >= 4 * n && w[6] >= n;
+            let after = core && w[6] >= 4 * n && w[0] >= n;
+            before as u32 + after as u32
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qr_round_trip() {
+        for &len in &[0usize, 1, 14, 15, 84, 85, 110, 180, 181, 213] {
+            let payload: Vec<u8> = (0..len).map(|i| (i * 37) as u8).collect();
+            let code = QrCode::new(&payload).unwrap();
+            assert_eq!(code.size(), code.version() * 4 + 17);
+            assert_eq!(code.payload().unwrap(), payload);
+
+            let scanned = QrCode::from_modules(code.size(), code.modules.clone()).unwrap();
+            assert_eq!(scanned.payload().unwrap(), payload);
+        }
+        assert_eq!(QrCode::new(&[0u8; 14]).unwrap().version(), 1);
+        assert_eq!(QrCode::new(&[0u8; 15]).unwrap().version(), 2);
+        assert_eq!(QrCode::new(&[0u8; 181]).unwrap().version(), 10);
+        assert!(QrCode::new(&[0u8; 214]).is_err());
+    }
+
+    #[test]
+    fn test_qr_structure() {
+        let code = QrCode::new(b"safety number").unwrap();
+        let size = code.size();
+
+        // Finder patterns in three corners, with their separators.
+        for &(x0, y0) in &[(0, 0), (size - 7, 0), (0, size - 7)] {
+            for i in 0..7 {
+                assert!(code.is_dark(x0 + i, y0));
+                assert!(code.is_dark(x0, y0 + i));
+                assert!(code.is_dark(x0 + 3, y0 + 3));
+            }
+        }
+        assert!(!code.is_dark(7, 7));
+        // Timing patterns and the dark module.
+        for i in 8..size - 8 {
+            assert_eq!(code.is_dark(i, 6), i % 2 == 0);
+            assert_eq!(code.is_dark(6, i), i % 2 == 0);
+        }
+        assert!(code.is_dark(8, size - 8));
+
+        let svg = code.to_svg();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(&format!("viewBox=\"0 0 {0} {0}\"", size + 8)));
+    }
+
+    fn assert_matrix(code: &QrCode, rows: &[&str]) {
+        assert_eq!(code.size(), rows.len());
+        for (y, row) in rows.iter().enumerate() {
+            let ours: String = (0..code.size())
+                .map(|x| if code.is_dark(x, y) { '#' } else { '.' })
+                .collect();
+            assert_eq!(&ours, row, "row {}", y);
+        }
+    }
+
+    #[test]
+    fn test_qr_matches_reference_encoder() {
+        // Full matrices from qrcodegen 1.8.0 (Project Nayuki's reference implementation), using
+        // QrCode::encode_segments_advanced with one byte segment, QrCodeEcc::Medium, versions 1
+        // to 40, automatic mask selection and no ECC boost.
+
+        // "Hello, world!": version 1, mask 2.
+        assert_matrix(
+            &QrCode::new(b"Hello, world!").unwrap(),
+            &[
+                "#######.....#.#######",
+                "#.....#..#.#..#.....#",
+                "#.###.#.#.###.#.###.#",
+                "#.###.#.#.....#.###.#",
+                "#.###.#.##..#.#.###.#",
+                "#.....#.####..#.....#",
+                "#######.#.#.#.#######",
+                "........#.#..........",
+                "#.#####..###..#####..",
+                "...##..##...##..###.#",
+                "...#..#.###.###..###.",
+                ".##..#.#..####.#.##..",
+                "##.####.#...#.##....#",
+                "........#....#####...",
+                "#######..##.####..##.",
+                "#.....#.#.#.##.#.###.",
+                "#.###.#.##.####.#..##",
+                "#.###.#.#.#....###...",
+                "#.###.#.#####.##..#..",
+                "#.....#...#.##..###..",
+                "#######.##.#..#.#..#.",
+            ],
+        );
+
+        // Alice's version 1 scannable fingerprint from the fingerprint tests: version 5, mask 6.
+        let payload = hex::decode(
+            "080112220a201e301a0353dce3dbe7684cb8336e85136cdc0ee96219494ada305d62a7bd61df1a220a20\
+             d62cbf73a11592015b6b9f1682ac306fea3aaf3885b84d12bca631e9d4fb3a4d",
+        )
+        .unwrap();
+        assert_matrix(
+            &QrCode::new(&payload).unwrap(),
+            &[
+                "#######.###..#..##.##.#.#.#...#######",
+                "#.....#.#...#.##.###.########.#.....#",
+                "#.###.#.##..#.######..#..#....#.###.#",
+                "#.###.#..#......###.##.##..##.#.###.#",
+                "#.###.#.##.##..#..##.#.####.#.#.###.#",
+                "#.....#..##...###...##.###....#.....#",
+                "#######.#.#.#.#.#.#.#.#.#.#.#.#######",
+                "..........#..#.#####.....####........",
+                "#..######...#..#......##...###..#.###",
+                "#.#.#....####.#.#.#..#.###.#...###.##",
+                "..#...####..#..##..###..#..#.#.#..###",
+                "...#.#.####.###.#..##...##..##..###.#",
+                ".#######..##.####.#....#..#####.#..##",
+                ".###.......###.#...#.......##.###..#.",
+                "##.##.#.####.####.##.####......#.#...",
+                ".#.###.##..###.##...##...#####..#.#.#",
+                ".######.##..###..##.#.#..##.##.####..",
+                "#......##.#.###.......####.##.##..###",
+                ".###.##..#.#..#.##.#..##.######.#..##",
+                "##.###.##.#..####..#.....##.##.##.#.#",
+                "##.#.#####..##..##..#####....##.##...",
+                "#.###...##.#.##.######.#.#...##.#.#..",
+                "...#..#.#.#.##.#....##.#.#..#....####",
+                "#..##..##.###..##.##.#..#.###..##.##.",
+                "##....######..##.#..##.##.#########.#",
+                "######..##..#.#...##.##..#.#.####...#",
+                "##.#..#..#.#.###..##.##.#...#.###.#.#",
+                "#....#..##.##...##.##...#..###..#.#..",
+                "#.###.#....#..##.#.#.##..#.######..#.",
+                "........####..####..#.####..#...#...#",
+                "#######.#.....#####.##.#.##.#.#.##...",
+                "#.....#.#####..#....##....###...#...#",
+                "#.###.#.##.#..#.#.#.#....#..######..#",
+                "#.###.#.####..#..#.###.####.##.####.#",
+                "#.###.#...#.##.......#...#..#.#.#...#",
+                "#.....#...#.####.#.#...#.#..#.#....##",
+                "#######.###.###..##.##.#####.#.##.#.#",
+            ],
+        );
+
+        // Alice's scannable fingerprint over ACIs, with identifier hashes: version 8, mask 4.
+        // Large enough to carry the version information blocks.
+        let payload = hex::decode(
+            "080212440a208a2c258a99951098bd3222aa59be88e53fad3f3712fbd9ed3aa9ba26092317791a207aac\
+             8e4de4935105a8d2db0cd2be11142718f0e100be1d32a1f5cde4953846bb1a440a200a6bb4b36fb0dc81\
+             a39f14f4acac47b1250386e1a5fccce93971d687a1b856b61a202543d8674b937bbbec6361c6d40093ca\
+             b846dd68eec7ffa62abda45da707c5d6",
+        )
+        .unwrap();
+        assert_matrix(
+            &QrCode::new(&payload).unwrap(),
+            &[
+                "#######.#.#...##...#..#####.#...###.#...#.#######",
+                "#.....#...#####.#.##.##..###......#..####.#.....#",
+                "#.###.#.....###.##...#....###..####.##.##.#.###.#",
+                "#.###.#.#.......#..#.###.###...###.##..#..#.###.#",
+                "#.###.#.###....###.##.#####.#.#.#..#.#....#.###.#",
+                "#.....#.#.....#.###.###...##.#..#.###.#...#.....#",
+                "#######.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#.#######",
+                "........#.##.#.##....##...#..#..#...##.#.........",
+                "#...#.###.##.#.....#..#########...#...##.#####..#",
+                ".#..##.#...#.#.#....##.##..#..###....#..###...#..",
+                "#..#.####.##....###..#.#.#.###....##..#####..#.#.",
+                "...#...##..#.####...###.##....#.###.######.##.###",
+                "#.##.###..#.####.#####....###.#..#..#..#..##..#..",
+                ".##.##.#..#####...#..##.....##.##..####.#.......#",
+                "####.###.##.#####.#.##.....###..##..##...#.##..#.",
+                "####.#...#######...##...##..#..##..##..##.#...##.",
+                "###...######.####.#.#...#..##.##..###....##.###..",
+                ".#.###.###.##....##...####.#.###.####....#.#...##",
+                "#...###.....##...#.###....#.#...###.#...######.##",
+                ".###.....#.#..#..##..######.#.#.####..####..###.#",
+                "###.###...##.##.#####.#..#.###.#..###.#...####...",
+                ".##.##..#.###..#..####.....#....#.#.##..##.#....#",
+                "....#####.#....#......#########..#..##..########.",
+                ".####...###...##.###.##...##.#.####...###...#.##.",
+                ".##.#.#.##.....#.##.###.#.#.#.#..#.#.####.#.###.#",
+                "##.##...###.#.##.#.##.#...#.#..##...#...#...#.###",
+                ".#.######.#####.#.###.#######.#.#.#.##.######..#.",
+                ".##..#.####....###.....##...#.#...#.##.##...##...",
+                "#.#..##.#...####..#.##..##....##.#...##...##.##..",
+                "#.#..#..#.#..##.##.##..#..####.#....#...###.#.#.#",
+                "#....##.....##..##.#..#####.#############.#....#.",
+                "#.#..#.#.####.#####..##.#.###.#.#.#...#.##.###.##",
+                "##....##.##.###...###..##..#########.#.##..#.###.",
+                "#...#........#..##..#.###.###..#.##..#......#...#",
+                "..#.####.....####..#.###.##.####.....###.#.###...",
+                ".##.##...#..##.#.#.#.#.##.#...#.##.###.#.##...#.#",
+                "##.##.###.#.#.####.#########.##...#...#.#####..##",
+                "#.####...#..#...#.#.#.....##..##.#..####..##..#..",
+                ".#...####..#.#..##..####..##..##..#.##.####..#.#.",
+                ".###...##.#.######..#.#..##...##..##....#..###.##",
+                "###...#.###.##.#..##..#####....##...##..#####.#..",
+                "........#.#..##.#######...##..#....#....#...##.#.",
+                "#######.#..#..#..#.#..#.#.#....#.#.###.##.#.###..",
+                "#.....#..####..##.#.###...#..######..#..#...##..#",
+                "#.###.#.#.###.#.#..##.#####..###.##..############",
+                "#.###.#..#...###..#..#.#.####.#.#.#.##.#.#.##.#.#",
+                "#.###.#.....###.###.####.#....#.####..#..#.....##",
+                "#.....#..#.#.#.##.#####....##...#..#..##.##....#.",
+                "#######.####...#.###...#..#..#.#.#######..#.#.###",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_reed_solomon() {
+        // Codewords followed by their remainder are divisible by the generator polynomial.
+        let divisor = reed_solomon_divisor(10);
+        let data = b"\x40\xd2\x75\x47\x76\x17\x32\x06\x27\x26\x96\xc6\xc6\x96\x70\xec";
+        let mut message = data.to_vec();
+        message.extend_from_slice(&reed_solomon_remainder(data, &divisor));
+        assert!(reed_solomon_remainder(&message, &divisor)
+            .iter()
+            .all(|&b| b == 0));
+
+        // Annex I of the standard: "01234567" at version 1-M.
+        assert_eq!(
+            reed_solomon_remainder(
+                &[
+                    0x10, 0x20, 0x0C, 0x56, 0x61, 0x80, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC,
+                    0x11, 0xEC, 0x11
+                ],
+                &divisor
+            ),
+            vec![0xA5, 0x24, 0xD4, 0xC1, 0xED, 0x36, 0xC7, 0x87, 0x2C, 0x55]
+        );
+    }
+}
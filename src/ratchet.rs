@@ -32,7 +32,8 @@ pub fn initialize_alice_session<R: Rng + CryptoRng>(
     parameters: &AliceSignalProtocolParameters,
     mut csprng: &mut R,
 ) -> Result<SessionState> {
-    let local_identity = parameters.our_identity_key_pair().identity_key();
+    let our_identity_key_signer = parameters.our_identity_key_signer();
+    let local_identity = our_identity_key_signer.get_identity_key()?;
 
     let sending_ratchet_key = curve::KeyPair::generate(&mut csprng);
 
@@ -42,10 +43,9 @@ pub fn initialize_alice_session<R: Rng + CryptoRng>(
 
     let our_base_private_key = parameters.our_base_key_pair().private_key;
 
-    secrets.extend_from_slice(&curve::calculate_agreement(
-        parameters.their_signed_pre_key(),
-        parameters.our_identity_key_pair().private_key(),
-    )?);
+    secrets.extend_from_slice(
+        &our_identity_key_signer.calculate_agreement(parameters.their_signed_pre_key())?,
+    );
 
     secrets.extend_from_slice(&curve::calculate_agreement(
         parameters.their_identity_key().public_key(),
@@ -85,6 +85,7 @@ pub fn initialize_alice_session<R: Rng + CryptoRng>(
         local_registration_id: 0,
         needs_refresh: false,
         alice_base_key: vec![],
+        archived_at: 0,
     };
 
     let mut session = SessionState::new(session);
@@ -96,7 +97,8 @@ pub fn initialize_alice_session<R: Rng + CryptoRng>(
 }
 
 pub fn initialize_bob_session(parameters: &BobSignalProtocolParameters) -> Result<SessionState> {
-    let local_identity = parameters.our_identity_key_pair().identity_key();
+    let our_identity_key_signer = parameters.our_identity_key_signer();
+    let local_identity = our_identity_key_signer.get_identity_key()?;
 
     let mut secrets = Vec::with_capacity(32 * 5);
 
@@ -107,10 +109,9 @@ pub fn initialize_bob_session(parameters: &BobSignalProtocolParameters) -> Resul
         &parameters.our_signed_pre_key_pair().private_key,
     )?);
 
-    secrets.extend_from_slice(&curve::calculate_agreement(
-        parameters.their_base_key(),
-        parameters.our_identity_key_pair().private_key(),
-    )?);
+    secrets.extend_from_slice(
+        &our_identity_key_signer.calculate_agreement(parameters.their_base_key())?,
+    );
 
     secrets.extend_from_slice(&curve::calculate_agreement(
         parameters.their_base_key(),
@@ -140,6 +141,7 @@ pub fn initialize_bob_session(parameters: &BobSignalProtocolParameters) -> Resul
         local_registration_id: 0,
         needs_refresh: false,
         alice_base_key: vec![],
+        archived_at: 0,
     };
 
     let mut session = SessionState::new(session);
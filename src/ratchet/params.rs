@@ -6,10 +6,10 @@
 //
 
 pub use super::super::curve::{KeyPair as CurveKeyPair, PublicKey as CurvePublicKey};
-pub use super::super::{IdentityKey, IdentityKeyPair};
+pub use super::super::{IdentityKey, IdentityKeySigner};
 
-pub struct AliceSignalProtocolParameters {
-    our_identity_key_pair: IdentityKeyPair,
+pub struct AliceSignalProtocolParameters<'a> {
+    our_identity_key_signer: &'a dyn IdentityKeySigner,
     our_base_key_pair: CurveKeyPair,
 
     their_identity_key: IdentityKey,
@@ -18,9 +18,9 @@ pub struct AliceSignalProtocolParameters {
     their_ratchet_key: CurvePublicKey,
 }
 
-impl AliceSignalProtocolParameters {
+impl<'a> AliceSignalProtocolParameters<'a> {
     pub fn new(
-        our_identity_key_pair: IdentityKeyPair,
+        our_identity_key_signer: &'a dyn IdentityKeySigner,
         our_base_key_pair: CurveKeyPair,
         their_identity_key: IdentityKey,
         their_signed_pre_key: CurvePublicKey,
@@ -28,7 +28,7 @@ impl AliceSignalProtocolParameters {
         their_ratchet_key: CurvePublicKey,
     ) -> Self {
         Self {
-            our_identity_key_pair,
+            our_identity_key_signer,
             our_base_key_pair,
             their_identity_key,
             their_signed_pre_key,
@@ -38,8 +38,8 @@ impl AliceSignalProtocolParameters {
     }
 
     #[inline]
-    pub fn our_identity_key_pair(&self) -> &IdentityKeyPair {
-        &self.our_identity_key_pair
+    pub fn our_identity_key_signer(&self) -> &'a dyn IdentityKeySigner {
+        self.our_identity_key_signer
     }
 
     #[inline]
@@ -68,8 +68,8 @@ impl AliceSignalProtocolParameters {
     }
 }
 
-pub struct BobSignalProtocolParameters {
-    our_identity_key_pair: IdentityKeyPair,
+pub struct BobSignalProtocolParameters<'a> {
+    our_identity_key_signer: &'a dyn IdentityKeySigner,
     our_signed_pre_key_pair: CurveKeyPair,
     our_one_time_pre_key_pair: Option<CurveKeyPair>,
     our_ratchet_key_pair: CurveKeyPair,
@@ -78,9 +78,9 @@ pub struct BobSignalProtocolParameters {
     their_base_key: CurvePublicKey,
 }
 
-impl BobSignalProtocolParameters {
+impl<'a> BobSignalProtocolParameters<'a> {
     pub fn new(
-        our_identity_key_pair: IdentityKeyPair,
+        our_identity_key_signer: &'a dyn IdentityKeySigner,
         our_signed_pre_key_pair: CurveKeyPair,
         our_one_time_pre_key_pair: Option<CurveKeyPair>,
         our_ratchet_key_pair: CurveKeyPair,
@@ -88,7 +88,7 @@ impl BobSignalProtocolParameters {
         their_base_key: CurvePublicKey,
     ) -> Self {
         Self {
-            our_identity_key_pair,
+            our_identity_key_signer,
             our_signed_pre_key_pair,
             our_one_time_pre_key_pair,
             our_ratchet_key_pair,
@@ -98,8 +98,8 @@ impl BobSignalProtocolParameters {
     }
 
     #[inline]
-    pub fn our_identity_key_pair(&self) -> &IdentityKeyPair {
-        &self.our_identity_key_pair
+    pub fn our_identity_key_signer(&self) -> &'a dyn IdentityKeySigner {
+        self.our_identity_key_signer
     }
 
     #[inline]
@@ -11,10 +11,12 @@ use crate::curve;
 use crate::error::{Result, SignalProtocolError};
 use crate::kdf::HKDF;
 use crate::proto::storage as storage_proto;
+use crate::utils::{self, Expiry};
 use crate::ProtocolAddress;
 
 use prost::Message;
 use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct SenderKeyName {
@@ -93,6 +95,7 @@ impl SenderMessageKey {
             storage_proto::sender_key_state_structure::SenderMessageKey {
                 iteration: self.iteration,
                 seed: self.seed.clone(),
+                created_at: 0,
             },
         )
     }
@@ -245,10 +248,15 @@ impl SenderKeyState {
         Ok(self.state.clone())
     }
 
-    pub fn add_sender_message_key(&mut self, sender_message_key: &SenderMessageKey) -> Result<()> {
-        self.state
-            .sender_message_keys
-            .push(sender_message_key.as_protobuf()?);
+    /// Store a skipped message key, stamped with `now` for expiry.
+    pub fn add_sender_message_key(
+        &mut self,
+        sender_message_key: &SenderMessageKey,
+        now: SystemTime,
+    ) -> Result<()> {
+        let mut key = sender_message_key.as_protobuf()?;
+        key.created_at = utils::unix_seconds(now);
+        self.state.sender_message_keys.push(key);
         while self.state.sender_message_keys.len() > consts::MAX_MESSAGE_KEYS {
             self.state.sender_message_keys.remove(0);
         }
@@ -271,6 +279,15 @@ impl SenderKeyState {
             Ok(None)
         }
     }
+
+    fn prune_expired_message_keys(&mut self, expiry: &Expiry) {
+        for key in &mut self.state.sender_message_keys {
+            expiry.stamp(&mut key.created_at);
+        }
+        self.state
+            .sender_message_keys
+            .retain(|key| !expiry.is_expired(key.created_at));
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -355,6 +372,17 @@ impl SenderKeyRecord {
         )
     }
 
+    /// Drop skipped message keys that are at least `max_age` old at `now`.
+    ///
+    /// Keys stored before timestamps were recorded are stamped with `now` and expire `max_age`
+    /// later. The record must be stored again afterwards.
+    pub fn prune_expired(&mut self, now: SystemTime, max_age: Duration) {
+        let expiry = Expiry::new(now, max_age);
+        for state in &mut self.states {
+            state.prune_expired_message_keys(&expiry);
+        }
+    }
+
     pub fn as_protobuf(&self) -> Result<storage_proto::SenderKeyRecordStructure> {
         let mut states = Vec::with_capacity(self.states.len());
         for state in &self.states {
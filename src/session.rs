@@ -12,12 +12,13 @@ use crate::{
 
 use crate::curve;
 use crate::error::Result;
-use crate::protocol::PreKeySignalMessage;
+use crate::protocol::{DecryptionErrorMessage, PreKeySignalMessage};
 use crate::ratchet;
 use crate::ratchet::{AliceSignalProtocolParameters, BobSignalProtocolParameters};
 use crate::state::{PreKeyBundle, PreKeyId};
 use crate::storage::Direction;
 use rand::{CryptoRng, Rng};
+use std::time::SystemTime;
 
 /*
 These functions are on SessionBuilder in Java
@@ -35,6 +36,34 @@ pub fn process_prekey(
     identity_store: &mut dyn IdentityKeyStore,
     pre_key_store: &mut dyn PreKeyStore,
     signed_prekey_store: &mut dyn SignedPreKeyStore,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<Option<PreKeyId>> {
+    let unsigned_pre_key_id = process_prekey_in_record(
+        message,
+        remote_address,
+        session_record,
+        identity_store,
+        pre_key_store,
+        signed_prekey_store,
+        now,
+        ctx,
+    )?;
+
+    identity_store.save_identity(&remote_address, message.identity_key(), ctx)?;
+
+    Ok(unsigned_pre_key_id)
+}
+
+/// Like [`process_prekey`], but only updates `session_record`; the sender's identity is not saved.
+pub(crate) fn process_prekey_in_record(
+    message: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_record: &mut SessionRecord,
+    identity_store: &dyn IdentityKeyStore,
+    pre_key_store: &dyn PreKeyStore,
+    signed_prekey_store: &dyn SignedPreKeyStore,
+    now: SystemTime,
     ctx: Context,
 ) -> Result<Option<PreKeyId>> {
     let their_identity_key = message.identity_key();
@@ -50,26 +79,24 @@ pub fn process_prekey(
         ));
     }
 
-    let unsigned_pre_key_id = process_prekey_v3(
+    process_prekey_v3(
         message,
         session_record,
         signed_prekey_store,
         pre_key_store,
         identity_store,
+        now,
         ctx,
-    )?;
-
-    identity_store.save_identity(&remote_address, their_identity_key, ctx)?;
-
-    Ok(unsigned_pre_key_id)
+    )
 }
 
 fn process_prekey_v3(
     message: &PreKeySignalMessage,
     session_record: &mut SessionRecord,
-    signed_prekey_store: &mut dyn SignedPreKeyStore,
-    pre_key_store: &mut dyn PreKeyStore,
-    identity_store: &mut dyn IdentityKeyStore,
+    signed_prekey_store: &dyn SignedPreKeyStore,
+    pre_key_store: &dyn PreKeyStore,
+    identity_store: &dyn IdentityKeyStore,
+    now: SystemTime,
     ctx: Context,
 ) -> Result<Option<PreKeyId>> {
     if session_record.has_session_state(
@@ -90,8 +117,10 @@ fn process_prekey_v3(
         None
     };
 
+    let our_identity_key_signer = identity_store.get_identity_key_signer(ctx)?;
+
     let parameters = BobSignalProtocolParameters::new(
-        identity_store.get_identity_key_pair(ctx)?,
+        &*our_identity_key_signer,
         our_signed_pre_key_pair, // signed pre key
         our_one_time_pre_key_pair,
         our_signed_pre_key_pair, // ratchet key
@@ -99,7 +128,7 @@ fn process_prekey_v3(
         *message.base_key(),
     );
 
-    session_record.archive_current_state()?;
+    session_record.archive_current_state(now)?;
 
     let mut new_session = ratchet::initialize_bob_session(&parameters)?;
 
@@ -107,7 +136,7 @@ fn process_prekey_v3(
     new_session.set_remote_registration_id(message.registration_id())?;
     new_session.set_alice_base_key(&message.base_key().serialize())?;
 
-    session_record.promote_state(new_session)?;
+    session_record.promote_state(new_session, now)?;
 
     Ok(message.pre_key_id())
 }
@@ -118,6 +147,7 @@ pub fn process_prekey_bundle<R: Rng + CryptoRng>(
     identity_store: &mut dyn IdentityKeyStore,
     bundle: &PreKeyBundle,
     mut csprng: &mut R,
+    now: SystemTime,
     ctx: Context,
 ) -> Result<()> {
     let their_identity_key = bundle.identity_key()?;
@@ -151,18 +181,20 @@ pub fn process_prekey_bundle<R: Rng + CryptoRng>(
     let their_one_time_prekey = bundle.pre_key_public()?;
     let their_one_time_prekey_id = bundle.pre_key_id()?;
 
-    let our_identity_key_pair = identity_store.get_identity_key_pair(ctx)?;
+    let mut session = {
+        let our_identity_key_signer = identity_store.get_identity_key_signer(ctx)?;
 
-    let parameters = AliceSignalProtocolParameters::new(
-        our_identity_key_pair,
-        our_base_key_pair,
-        *their_identity_key,
-        their_signed_prekey,
-        their_one_time_prekey,
-        their_signed_prekey,
-    );
+        let parameters = AliceSignalProtocolParameters::new(
+            &*our_identity_key_signer,
+            our_base_key_pair,
+            *their_identity_key,
+            their_signed_prekey,
+            their_one_time_prekey,
+            their_signed_prekey,
+        );
 
-    let mut session = ratchet::initialize_alice_session(&parameters, csprng)?;
+        ratchet::initialize_alice_session(&parameters, csprng)?
+    };
 
     session.set_unacknowledged_pre_key_message(
         their_one_time_prekey_id,
@@ -176,9 +208,59 @@ pub fn process_prekey_bundle<R: Rng + CryptoRng>(
 
     identity_store.save_identity(&remote_address, their_identity_key, ctx)?;
 
-    session_record.promote_state(session)?;
+    session_record.promote_state(session, now)?;
 
     session_store.store_session(&remote_address, &session_record, ctx)?;
 
     Ok(())
 }
+
+/// What [`process_decryption_error_message`] did, and what is left for the caller to do.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecryptionErrorAction {
+    /// The session the failed message was sent on was archived. The caller should fetch a new
+    /// pre key bundle for the remote address before resending.
+    SessionArchived,
+    /// The failed message was a sender key message. The caller should send its sender key
+    /// distribution message for the group to the remote address again.
+    ResendSenderKeyDistribution,
+    /// The error refers to another local device, or to a session that has already moved on.
+    NoAction,
+}
+
+/// Handle a [`DecryptionErrorMessage`] received from `remote_address` by the device
+/// `local_device_id`.
+///
+/// If the failed message was sent on our current session with `remote_address`, that session is
+/// archived so the next message starts a new one.
+pub fn process_decryption_error_message(
+    message: &DecryptionErrorMessage,
+    remote_address: &ProtocolAddress,
+    local_device_id: u32,
+    session_store: &mut dyn SessionStore,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<DecryptionErrorAction> {
+    if message.device_id() != local_device_id {
+        return Ok(DecryptionErrorAction::NoAction);
+    }
+
+    let ratchet_key = match message.ratchet_key() {
+        Some(ratchet_key) => ratchet_key,
+        None => return Ok(DecryptionErrorAction::ResendSenderKeyDistribution),
+    };
+
+    let mut session_record = match session_store.load_session(remote_address, ctx)? {
+        Some(session_record) => session_record,
+        None => return Ok(DecryptionErrorAction::NoAction),
+    };
+
+    match &session_record.current_session {
+        Some(session) if session.sender_ratchet_key()? == *ratchet_key => {}
+        _ => return Ok(DecryptionErrorAction::NoAction),
+    }
+
+    session_record.archive_current_state(now)?;
+    session_store.store_session(remote_address, &session_record, ctx)?;
+    Ok(DecryptionErrorAction::SessionArchived)
+}
@@ -6,20 +6,29 @@
 //
 
 use crate::{
-    Context, IdentityKeyStore, PreKeyStore, ProtocolAddress, SessionRecord, SessionState,
-    SessionStore, SignalProtocolError, SignedPreKeyStore,
+    Context, IdentityKey, IdentityKeyStore, PreKeyStore, ProtocolAddress, SessionRecord,
+    SessionState, SessionStore, SignalProtocolError, SignedPreKeyStore,
 };
 
 use crate::consts::MAX_FORWARD_JUMPS;
 use crate::crypto;
 use crate::curve;
-use crate::error::Result;
-use crate::protocol::{CiphertextMessage, PreKeySignalMessage, SignalMessage};
+use crate::decrypt_delta::DecryptDelta;
+use crate::error::{ErrorCategory, Result};
+use crate::franking::{self, FrankingOpening};
+use crate::protocol::{
+    CiphertextMessage, CiphertextMessageType, OutgoingMessage, PreKeySignalMessage,
+    PreKeySignalMessageParts, SignalMessage, SignalMessageParts,
+};
 use crate::ratchet::{ChainKey, MessageKeys};
 use crate::session;
+use crate::state::PreKeyId;
 use crate::storage::Direction;
 
+use bytes::BufMut;
 use rand::{CryptoRng, Rng};
+use std::collections::HashMap;
+use std::time::SystemTime;
 
 pub fn message_encrypt(
     ptext: &[u8],
@@ -28,9 +37,112 @@ pub fn message_encrypt(
     identity_store: &mut dyn IdentityKeyStore,
     ctx: Context,
 ) -> Result<CiphertextMessage> {
+    encrypt_with(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        false,
+        ctx,
+        |_| Ok(()),
+        |message| message.into_ciphertext_message(),
+    )
+}
+
+/// Encrypt like [`message_encrypt`], with a franking commitment to `ptext` in the message so
+/// that the recipient can report it to a server that tagged the message with
+/// [`franking_tag`](crate::franking_tag).
+pub fn message_encrypt_franked(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    ctx: Context,
+) -> Result<CiphertextMessage> {
+    encrypt_with(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        true,
+        ctx,
+        |_| Ok(()),
+        |message| message.into_ciphertext_message(),
+    )
+}
+
+/// Encrypt like [`message_encrypt`], but serialize the message straight into `out` instead of
+/// allocating a [`CiphertextMessage`].
+///
+/// If `out` cannot hold the whole message this fails with
+/// [`SignalProtocolError::OutputBufferTooSmall`] before the session is advanced, so the call can
+/// be retried with a larger buffer. Nothing is written to `out` unless the recipient's identity
+/// is trusted and the advanced session has been stored.
+pub fn message_encrypt_into<B: BufMut>(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    out: &mut B,
+    ctx: Context,
+) -> Result<CiphertextMessageType> {
+    let available = out.remaining_mut();
+    encrypt_with(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        false,
+        ctx,
+        |message| {
+            let required = message.encoded_len();
+            if available < required {
+                return Err(SignalProtocolError::OutputBufferTooSmall(required));
+            }
+            Ok(())
+        },
+        |message| {
+            message.encode(out)?;
+            Ok(message.message_type())
+        },
+    )
+}
+
+/// Encrypt into the start of `out`, returning the message type and the number of bytes written.
+pub fn message_encrypt_to_slice(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    out: &mut [u8],
+    ctx: Context,
+) -> Result<(CiphertextMessageType, usize)> {
+    let available = out.len();
+    let mut cursor = out;
+    let message_type = message_encrypt_into(
+        ptext,
+        remote_address,
+        session_store,
+        identity_store,
+        &mut cursor,
+        ctx,
+    )?;
+    Ok((message_type, available - cursor.len()))
+}
+
+fn encrypt_with<T>(
+    ptext: &[u8],
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    franking: bool,
+    ctx: Context,
+    check: impl FnOnce(&OutgoingMessage) -> Result<()>,
+    write: impl FnOnce(OutgoingMessage) -> Result<T>,
+) -> Result<T> {
     let mut session_record = session_store
         .load_session(&remote_address, ctx)?
-        .ok_or(SignalProtocolError::SessionNotFound)?;
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
     let session_state = session_record.session_state_mut()?;
 
     let chain_key = session_state.get_sender_chain_key()?;
@@ -47,46 +159,42 @@ pub fn message_encrypt(
         .ok_or(SignalProtocolError::InvalidSessionStructure)?;
 
     let ctext = crypto::aes_256_cbc_encrypt(ptext, message_keys.cipher_key(), message_keys.iv())?;
+    let franking_commitment = if franking {
+        let franking_key = franking::franking_key(message_keys.mac_key())?;
+        Some(franking::commit(&franking_key, ptext)?)
+    } else {
+        None
+    };
+
+    let message = SignalMessageParts {
+        message_version: session_version,
+        mac_key: message_keys.mac_key(),
+        sender_ratchet_key: sender_ephemeral,
+        counter: chain_key.index(),
+        previous_counter,
+        ciphertext: &ctext,
+        franking_commitment,
+        sender_identity_key: local_identity_key,
+        receiver_identity_key: their_identity_key,
+    };
 
     let message = if let Some(items) = session_state.unacknowledged_pre_key_message_items()? {
-        let local_registration_id = session_state.local_registration_id()?;
-
-        let message = SignalMessage::new(
-            session_version,
-            message_keys.mac_key(),
-            sender_ephemeral,
-            chain_key.index(),
-            previous_counter,
-            &ctext,
-            &local_identity_key,
-            &their_identity_key,
-        )?;
-
-        CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::new(
-            session_version,
-            local_registration_id,
-            items.pre_key_id()?,
-            items.signed_pre_key_id()?,
-            *items.base_key()?,
-            local_identity_key,
+        OutgoingMessage::PreKey(PreKeySignalMessageParts {
+            message_version: session_version,
+            registration_id: session_state.local_registration_id()?,
+            pre_key_id: items.pre_key_id()?,
+            signed_pre_key_id: items.signed_pre_key_id()?,
+            base_key: *items.base_key()?,
+            identity_key: local_identity_key,
             message,
-        )?)
+        })
     } else {
-        CiphertextMessage::SignalMessage(SignalMessage::new(
-            session_version,
-            message_keys.mac_key(),
-            sender_ephemeral,
-            chain_key.index(),
-            previous_counter,
-            &ctext,
-            &local_identity_key,
-            &their_identity_key,
-        )?)
+        OutgoingMessage::Signal(message)
     };
+    check(&message)?;
 
     session_state.set_sender_chain_key(&chain_key.next_chain_key()?)?;
 
-    // XXX why is this check after everything else?!!
     if !identity_store.is_trusted_identity(
         &remote_address,
         &their_identity_key,
@@ -102,9 +210,12 @@ pub fn message_encrypt(
     identity_store.save_identity(&remote_address, &their_identity_key, ctx)?;
 
     session_store.store_session(&remote_address, &session_record, ctx)?;
-    Ok(message)
+
+    // Only hand out the message once its key can no longer be reused.
+    write(message)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn message_decrypt<R: Rng + CryptoRng>(
     ciphertext: &CiphertextMessage,
     remote_address: &ProtocolAddress,
@@ -113,18 +224,49 @@ pub fn message_decrypt<R: Rng + CryptoRng>(
     pre_key_store: &mut dyn PreKeyStore,
     signed_pre_key_store: &mut dyn SignedPreKeyStore,
     csprng: &mut R,
+    now: SystemTime,
     ctx: Context,
 ) -> Result<Vec<u8>> {
+    let (ptext, _) = message_decrypt_franked(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        csprng,
+        now,
+        ctx,
+    )?;
+    Ok(ptext)
+}
+
+/// Decrypt like [`message_decrypt`], also returning the opening of the message's franking
+/// commitment if it was sent with [`message_encrypt_franked`]. Keep the opening with the
+/// plaintext to be able to report the message later.
+#[allow(clippy::too_many_arguments)]
+pub fn message_decrypt_franked<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<(Vec<u8>, Option<FrankingOpening>)> {
     match ciphertext {
-        CiphertextMessage::SignalMessage(m) => message_decrypt_signal(
+        CiphertextMessage::SignalMessage(m) => decrypt_signal(
             m,
             remote_address,
             session_store,
             identity_store,
             csprng,
+            now,
             ctx,
         ),
-        CiphertextMessage::PreKeySignalMessage(m) => message_decrypt_prekey(
+        CiphertextMessage::PreKeySignalMessage(m) => decrypt_prekey(
             m,
             remote_address,
             session_store,
@@ -132,6 +274,7 @@ pub fn message_decrypt<R: Rng + CryptoRng>(
             pre_key_store,
             signed_pre_key_store,
             csprng,
+            now,
             ctx,
         ),
         _ => Err(SignalProtocolError::InvalidArgument(
@@ -140,6 +283,7 @@ pub fn message_decrypt<R: Rng + CryptoRng>(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn message_decrypt_prekey<R: Rng + CryptoRng>(
     ciphertext: &PreKeySignalMessage,
     remote_address: &ProtocolAddress,
@@ -148,23 +292,58 @@ pub fn message_decrypt_prekey<R: Rng + CryptoRng>(
     pre_key_store: &mut dyn PreKeyStore,
     signed_pre_key_store: &mut dyn SignedPreKeyStore,
     csprng: &mut R,
+    now: SystemTime,
     ctx: Context,
 ) -> Result<Vec<u8>> {
+    let (ptext, _) = decrypt_prekey(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        csprng,
+        now,
+        ctx,
+    )?;
+    Ok(ptext)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn decrypt_prekey<R: Rng + CryptoRng>(
+    ciphertext: &PreKeySignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<(Vec<u8>, Option<FrankingOpening>)> {
     let mut session_record = session_store
         .load_session(&remote_address, ctx)?
         .unwrap_or_else(SessionRecord::new_fresh);
-
-    let pre_key_id = session::process_prekey(
+    let pre_key_id = session::process_prekey_in_record(
         ciphertext,
         &remote_address,
         &mut session_record,
         identity_store,
         pre_key_store,
         signed_pre_key_store,
+        now,
         ctx,
     )?;
+    identity_store.save_identity(remote_address, ciphertext.identity_key(), ctx)?;
 
-    let ptext = decrypt_message_with_record(&mut session_record, ciphertext.message(), csprng)?;
+    let decrypted = decrypt_message_with_record(
+        remote_address,
+        CiphertextMessageType::PreKey,
+        &mut session_record,
+        ciphertext.message(),
+        csprng,
+        now,
+    )?;
 
     session_store.store_session(&remote_address, &session_record, ctx)?;
 
@@ -172,7 +351,7 @@ pub fn message_decrypt_prekey<R: Rng + CryptoRng>(
         pre_key_store.remove_pre_key(pre_key_id, ctx)?;
     }
 
-    Ok(ptext)
+    Ok(decrypted)
 }
 
 pub fn message_decrypt_signal<R: Rng + CryptoRng>(
@@ -181,13 +360,42 @@ pub fn message_decrypt_signal<R: Rng + CryptoRng>(
     session_store: &mut dyn SessionStore,
     identity_store: &mut dyn IdentityKeyStore,
     csprng: &mut R,
+    now: SystemTime,
     ctx: Context,
 ) -> Result<Vec<u8>> {
+    let (ptext, _) = decrypt_signal(
+        ciphertext,
+        remote_address,
+        session_store,
+        identity_store,
+        csprng,
+        now,
+        ctx,
+    )?;
+    Ok(ptext)
+}
+
+fn decrypt_signal<R: Rng + CryptoRng>(
+    ciphertext: &SignalMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    csprng: &mut R,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<(Vec<u8>, Option<FrankingOpening>)> {
     let mut session_record = session_store
         .load_session(&remote_address, ctx)?
-        .ok_or(SignalProtocolError::SessionNotFound)?;
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
 
-    let ptext = decrypt_message_with_record(&mut session_record, ciphertext, csprng)?;
+    let decrypted = decrypt_message_with_record(
+        remote_address,
+        CiphertextMessageType::Whisper,
+        &mut session_record,
+        ciphertext,
+        csprng,
+        now,
+    )?;
 
     // Why are we performing this check after decryption instead of before?
     let their_identity_key = session_record
@@ -210,24 +418,272 @@ pub fn message_decrypt_signal<R: Rng + CryptoRng>(
 
     session_store.store_session(&remote_address, &session_record, ctx)?;
 
-    Ok(ptext)
+    Ok(decrypted)
+}
+
+/// Decrypt `ciphertext` without writing to any store.
+///
+/// Returns the plaintext together with the state changes a regular [`message_decrypt`] would
+/// have saved. Pass the delta to [`commit_decrypt_delta`](crate::commit_decrypt_delta) to apply
+/// them, or drop it to leave the session as it was.
+#[allow(clippy::too_many_arguments)]
+pub fn message_decrypt_preview<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    session_store: &dyn SessionStore,
+    identity_store: &dyn IdentityKeyStore,
+    pre_key_store: &dyn PreKeyStore,
+    signed_pre_key_store: &dyn SignedPreKeyStore,
+    csprng: &mut R,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<(Vec<u8>, DecryptDelta)> {
+    let base_record = session_store.load_session(remote_address, ctx)?;
+
+    let decrypted = decrypt_without_saving(
+        ciphertext,
+        remote_address,
+        base_record.as_ref(),
+        identity_store,
+        pre_key_store,
+        signed_pre_key_store,
+        csprng,
+        now,
+        ctx,
+    )?;
+
+    let delta = DecryptDelta::for_session(
+        base_record.as_ref(),
+        remote_address,
+        decrypted.record,
+        decrypted.identity_key,
+        decrypted.pre_key_id,
+    )?;
+    Ok((decrypted.ptext, delta))
+}
+
+/// Decrypt a queue of messages, loading and storing each session only once.
+///
+/// Messages are decrypted in order against a cached copy of their sender's session, so several
+/// messages from one sender behave as if passed to [`message_decrypt`] one by one. Each message
+/// gets its own result; a message that fails leaves the cached session as it was. A sender whose
+/// identity key changes within the batch gets [`SignalProtocolError::UntrustedIdentity`] for the
+/// later messages, since the new key cannot be checked against the store before the first one
+/// is saved. Likewise a one-time pre key can only set up one session per batch; a later message
+/// that uses it again gets [`SignalProtocolError::InvalidPreKeyId`], as it would once the key had
+/// been removed.
+///
+/// Nothing is written until every message has been tried. Then the senders' identities are
+/// saved, touched sessions are written back, and any one-time pre keys that were used are
+/// removed.
+///
+/// A store failure ([`ErrorCategory::Retryable`]) aborts the batch. During decryption nothing has
+/// been written yet, so every message can be decrypted again. The write-back is not atomic: if a
+/// write fails, the writes before it are kept, and messages whose sessions were already stored
+/// will not decrypt a second time.
+pub fn message_decrypt_batch<R: Rng + CryptoRng>(
+    messages: &[(ProtocolAddress, CiphertextMessage)],
+    session_store: &mut dyn SessionStore,
+    identity_store: &mut dyn IdentityKeyStore,
+    pre_key_store: &mut dyn PreKeyStore,
+    signed_pre_key_store: &mut dyn SignedPreKeyStore,
+    csprng: &mut R,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<Vec<Result<Vec<u8>>>> {
+    let mut records: HashMap<ProtocolAddress, (Option<SessionRecord>, bool)> = HashMap::new();
+    let mut load_order = Vec::new();
+    let mut identities: HashMap<ProtocolAddress, IdentityKey> = HashMap::new();
+    let mut used_pre_keys = Vec::new();
+    let mut results = Vec::with_capacity(messages.len());
+
+    for (remote_address, ciphertext) in messages {
+        if !records.contains_key(remote_address) {
+            let record = session_store.load_session(remote_address, ctx)?;
+            records.insert(remote_address.clone(), (record, false));
+            load_order.push(remote_address.clone());
+        }
+        let (cached, dirty) = records.get_mut(remote_address).expect("loaded above");
+
+        let decrypted = match decrypt_without_saving(
+            ciphertext,
+            remote_address,
+            cached.as_ref(),
+            identity_store,
+            pre_key_store,
+            signed_pre_key_store,
+            csprng,
+            now,
+            ctx,
+        ) {
+            Ok(decrypted) => decrypted,
+            Err(e) if e.category() == ErrorCategory::Retryable => return Err(e),
+            Err(e) => {
+                results.push(Err(e));
+                continue;
+            }
+        };
+
+        if let Some(pre_key_id) = decrypted.pre_key_id {
+            if used_pre_keys.contains(&pre_key_id) {
+                results.push(Err(SignalProtocolError::InvalidPreKeyId));
+                continue;
+            }
+        }
+        match identities.get(remote_address) {
+            Some(identity_key) if *identity_key != decrypted.identity_key => {
+                results.push(Err(SignalProtocolError::UntrustedIdentity(
+                    remote_address.clone(),
+                )));
+                continue;
+            }
+            Some(_) => {}
+            None => {
+                identities.insert(remote_address.clone(), decrypted.identity_key);
+            }
+        }
+        if let Some(pre_key_id) = decrypted.pre_key_id {
+            used_pre_keys.push(pre_key_id);
+        }
+        *cached = Some(decrypted.record);
+        *dirty = true;
+        results.push(Ok(decrypted.ptext));
+    }
+
+    for remote_address in &load_order {
+        if let Some(identity_key) = identities.get(remote_address) {
+            identity_store.save_identity(remote_address, identity_key, ctx)?;
+        }
+    }
+    for remote_address in &load_order {
+        if let (Some(record), true) = &records[remote_address] {
+            session_store.store_session(remote_address, record, ctx)?;
+        }
+    }
+    for pre_key_id in used_pre_keys {
+        pre_key_store.remove_pre_key(pre_key_id, ctx)?;
+    }
+
+    Ok(results)
+}
+
+struct DecryptedMessage {
+    ptext: Vec<u8>,
+    record: SessionRecord,
+    identity_key: IdentityKey,
+    pre_key_id: Option<PreKeyId>,
+}
+
+/// Decrypt `ciphertext` against a copy of `base_record`, checking but not saving the sender's
+/// identity.
+#[allow(clippy::too_many_arguments)]
+fn decrypt_without_saving<R: Rng + CryptoRng>(
+    ciphertext: &CiphertextMessage,
+    remote_address: &ProtocolAddress,
+    base_record: Option<&SessionRecord>,
+    identity_store: &dyn IdentityKeyStore,
+    pre_key_store: &dyn PreKeyStore,
+    signed_pre_key_store: &dyn SignedPreKeyStore,
+    csprng: &mut R,
+    now: SystemTime,
+    ctx: Context,
+) -> Result<DecryptedMessage> {
+    match ciphertext {
+        CiphertextMessage::SignalMessage(m) => {
+            let mut record = base_record
+                .cloned()
+                .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
+            let (ptext, _) = decrypt_message_with_record(
+                remote_address,
+                CiphertextMessageType::Whisper,
+                &mut record,
+                m,
+                csprng,
+                now,
+            )?;
+
+            let identity_key = record
+                .session_state()?
+                .remote_identity_key()?
+                .ok_or(SignalProtocolError::InvalidSessionStructure)?;
+            if !identity_store.is_trusted_identity(
+                remote_address,
+                &identity_key,
+                Direction::Receiving,
+                ctx,
+            )? {
+                return Err(SignalProtocolError::UntrustedIdentity(
+                    remote_address.clone(),
+                ));
+            }
+            Ok(DecryptedMessage {
+                ptext,
+                record,
+                identity_key,
+                pre_key_id: None,
+            })
+        }
+        CiphertextMessage::PreKeySignalMessage(m) => {
+            let mut record = base_record
+                .cloned()
+                .unwrap_or_else(SessionRecord::new_fresh);
+            let pre_key_id = session::process_prekey_in_record(
+                m,
+                remote_address,
+                &mut record,
+                identity_store,
+                pre_key_store,
+                signed_pre_key_store,
+                now,
+                ctx,
+            )?;
+            let (ptext, _) = decrypt_message_with_record(
+                remote_address,
+                CiphertextMessageType::PreKey,
+                &mut record,
+                m.message(),
+                csprng,
+                now,
+            )?;
+            Ok(DecryptedMessage {
+                ptext,
+                record,
+                identity_key: *m.identity_key(),
+                pre_key_id,
+            })
+        }
+        _ => Err(SignalProtocolError::InvalidArgument(
+            "SessionCipher::decrypt cannot decrypt this message type".to_owned(),
+        )),
+    }
 }
 
 fn decrypt_message_with_record<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    message_type: CiphertextMessageType,
     record: &mut SessionRecord,
     ciphertext: &SignalMessage,
     csprng: &mut R,
-) -> Result<Vec<u8>> {
+    now: SystemTime,
+) -> Result<(Vec<u8>, Option<FrankingOpening>)> {
     let mut current_state = record.session_state()?.clone();
 
-    let result = decrypt_message_with_state(&mut current_state, ciphertext, csprng);
+    let result = decrypt_message_with_state(
+        remote_address,
+        message_type,
+        &mut current_state,
+        ciphertext,
+        csprng,
+        now,
+    );
 
     match result {
-        Ok(ptext) => {
+        Ok(decrypted) => {
             record.set_session_state(current_state)?; // update the state
-            return Ok(ptext);
+            return Ok(decrypted);
         }
-        Err(SignalProtocolError::DuplicatedMessage(_, _)) => {
+        Err(SignalProtocolError::DuplicatedMessage { .. })
+        | Err(SignalProtocolError::InvalidFrankingCommitment) => {
             return result;
         }
         Err(_) => {}
@@ -238,35 +694,47 @@ fn decrypt_message_with_record<R: Rng + CryptoRng>(
     for (idx, previous) in record.previous_session_states()?.enumerate() {
         let mut updated = previous.clone();
 
-        let result = decrypt_message_with_state(&mut updated, ciphertext, csprng);
+        let result = decrypt_message_with_state(
+            remote_address,
+            message_type,
+            &mut updated,
+            ciphertext,
+            csprng,
+            now,
+        );
 
         match result {
-            Ok(ptext) => {
-                updated_session = Some((ptext, idx, updated));
+            Ok(decrypted) => {
+                updated_session = Some((decrypted, idx, updated));
                 break;
             }
-            Err(SignalProtocolError::DuplicatedMessage(_, _)) => {
+            Err(SignalProtocolError::DuplicatedMessage { .. })
+            | Err(SignalProtocolError::InvalidFrankingCommitment) => {
                 return result;
             }
             _ => {}
         }
     }
 
-    if let Some((ptext, idx, updated_session)) = updated_session {
-        record.promote_old_session(idx, updated_session)?;
-        Ok(ptext)
+    if let Some((decrypted, idx, updated_session)) = updated_session {
+        record.promote_old_session(idx, updated_session, now)?;
+        Ok(decrypted)
     } else {
-        Err(SignalProtocolError::InvalidMessage(
-            "decryption failed; no matching session state",
-        ))
+        Err(SignalProtocolError::NoMatchingSessionState {
+            sender: remote_address.clone(),
+            message_type,
+        })
     }
 }
 
 fn decrypt_message_with_state<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    message_type: CiphertextMessageType,
     state: &mut SessionState,
     ciphertext: &SignalMessage,
     csprng: &mut R,
-) -> Result<Vec<u8>> {
+    now: SystemTime,
+) -> Result<(Vec<u8>, Option<FrankingOpening>)> {
     if !state.has_sender_chain()? {
         return Err(SignalProtocolError::InvalidSessionStructure);
     }
@@ -280,8 +748,24 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
 
     let their_ephemeral = ciphertext.sender_ratchet_key();
     let counter = ciphertext.counter();
-    let chain_key = get_or_create_chain_key(state, their_ephemeral, csprng)?;
-    let message_keys = get_or_create_message_key(state, their_ephemeral, &chain_key, counter)?;
+    let chain_key = get_or_create_chain_key(
+        remote_address,
+        message_type,
+        state,
+        their_ephemeral,
+        ciphertext.previous_counter(),
+        csprng,
+        now,
+    )?;
+    let message_keys = get_or_create_message_key(
+        remote_address,
+        message_type,
+        state,
+        their_ephemeral,
+        &chain_key,
+        counter,
+        now,
+    )?;
 
     let their_identity_key = state
         .remote_identity_key()?
@@ -303,9 +787,21 @@ fn decrypt_message_with_state<R: Rng + CryptoRng>(
         message_keys.iv(),
     )?;
 
+    let franking_opening = match ciphertext.franking_commitment() {
+        Some(commitment) => {
+            let franking_key = franking::franking_key(message_keys.mac_key())?;
+            let opening = FrankingOpening::new(franking_key, *commitment);
+            if !opening.verify(&ptext)? {
+                return Err(SignalProtocolError::InvalidFrankingCommitment);
+            }
+            Some(opening)
+        }
+        None => None,
+    };
+
     state.clear_unacknowledged_pre_key_message()?;
 
-    Ok(ptext)
+    Ok((ptext, franking_opening))
 }
 
 pub fn remote_registration_id(
@@ -315,7 +811,7 @@ pub fn remote_registration_id(
 ) -> Result<u32> {
     let session_record = session_store
         .load_session(&remote_address, ctx)?
-        .ok_or(SignalProtocolError::SessionNotFound)?;
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
     session_record.session_state()?.remote_registration_id()
 }
 
@@ -326,19 +822,25 @@ pub fn session_version(
 ) -> Result<u32> {
     let session_record = session_store
         .load_session(&remote_address, ctx)?
-        .ok_or(SignalProtocolError::SessionNotFound)?;
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
     session_record.session_state()?.session_version()
 }
 
 fn get_or_create_chain_key<R: Rng + CryptoRng>(
+    remote_address: &ProtocolAddress,
+    message_type: CiphertextMessageType,
     state: &mut SessionState,
     their_ephemeral: &curve::PublicKey,
+    previous_counter: u32,
     csprng: &mut R,
+    now: SystemTime,
 ) -> Result<ChainKey> {
     if let Some(chain) = state.get_receiver_chain_key(their_ephemeral)? {
         return Ok(chain);
     }
 
+    skip_previous_chain_keys(remote_address, message_type, state, previous_counter, now)?;
+
     let root_key = state.root_key()?;
     let our_ephemeral = state.sender_ratchet_private_key()?;
     let receiver_chain = root_key.create_chain(their_ephemeral, &our_ephemeral)?;
@@ -362,18 +864,69 @@ fn get_or_create_chain_key<R: Rng + CryptoRng>(
     Ok(receiver_chain.1)
 }
 
+/// On a DH ratchet step, store the keys for the rest of the sender's previous chain, which
+/// ended at `previous_counter`, so that its messages still decrypt when delivered after the turn.
+///
+/// Senders write `max(index - 1, 0)`, so a counter of 0 on a chain we have received nothing from
+/// may mean the chain is empty. Nothing is stored then; if message 0 does exist, its key is still
+/// derived from the retained chain key when it arrives.
+fn skip_previous_chain_keys(
+    remote_address: &ProtocolAddress,
+    message_type: CiphertextMessageType,
+    state: &mut SessionState,
+    previous_counter: u32,
+    now: SystemTime,
+) -> Result<()> {
+    let (their_previous_ephemeral, mut chain_key) = match state.latest_receiver_chain_key()? {
+        None => return Ok(()),
+        Some(chain) => chain,
+    };
+
+    if chain_key.index() > previous_counter || (chain_key.index() == 0 && previous_counter == 0) {
+        return Ok(());
+    }
+
+    let jump = (previous_counter - chain_key.index()) as usize + 1;
+
+    if jump > MAX_FORWARD_JUMPS {
+        return Err(SignalProtocolError::MessageTooFarInFuture {
+            sender: remote_address.clone(),
+            message_type,
+            chain_index: chain_key.index(),
+            counter: previous_counter,
+        });
+    }
+
+    let mut skipped = Vec::with_capacity(jump);
+    while chain_key.index() <= previous_counter {
+        skipped.push(chain_key.message_keys()?);
+        chain_key = chain_key.next_chain_key()?;
+    }
+
+    state.add_message_keys(&their_previous_ephemeral, &skipped, now)?;
+    state.set_receiver_chain_key(&their_previous_ephemeral, &chain_key)
+}
+
 fn get_or_create_message_key(
+    remote_address: &ProtocolAddress,
+    message_type: CiphertextMessageType,
     state: &mut SessionState,
     their_ephemeral: &curve::PublicKey,
     chain_key: &ChainKey,
     counter: u32,
+    now: SystemTime,
 ) -> Result<MessageKeys> {
     let chain_index = chain_key.index();
 
     if chain_index > counter {
         return match state.get_message_keys(their_ephemeral, counter)? {
             Some(keys) => Ok(keys),
-            None => Err(SignalProtocolError::DuplicatedMessage(chain_index, counter)),
+            None => Err(SignalProtocolError::DuplicatedMessage {
+                sender: remote_address.clone(),
+                message_type,
+                chain_index,
+                counter,
+            }),
         };
     }
 
@@ -382,18 +935,24 @@ fn get_or_create_message_key(
     let jump = (counter - chain_index) as usize;
 
     if jump > MAX_FORWARD_JUMPS {
-        return Err(SignalProtocolError::InvalidMessage(
-            "message from too far into the future",
-        ));
+        return Err(SignalProtocolError::MessageTooFarInFuture {
+            sender: remote_address.clone(),
+            message_type,
+            chain_index,
+            counter,
+        });
     }
 
     let mut chain_key = chain_key.clone();
 
+    let mut skipped = Vec::with_capacity(jump);
     while chain_key.index() < counter {
-        let message_keys = chain_key.message_keys()?;
-        state.set_message_keys(their_ephemeral, &message_keys)?;
+        skipped.push(chain_key.message_keys()?);
         chain_key = chain_key.next_chain_key()?;
     }
+    if !skipped.is_empty() {
+        state.add_message_keys(their_ephemeral, &skipped, now)?;
+    }
 
     state.set_receiver_chain_key(their_ephemeral, &chain_key.next_chain_key()?)?;
     Ok(chain_key.message_keys()?)
@@ -11,6 +11,8 @@ use crate::IdentityKey;
 use crate::error::{Result, SignalProtocolError};
 use crate::state::{PreKeyId, SignedPreKeyId};
 
+use rand::{CryptoRng, Rng};
+
 #[derive(Debug, Clone)]
 pub struct PreKeyBundle {
     registration_id: u32,
@@ -81,4 +83,28 @@ impl PreKeyBundle {
     pub fn identity_key(&self) -> Result<&IdentityKey> {
         Ok(&self.identity_key)
     }
+
+    /// Checks each bundle's signed prekey signature against its identity key, as
+    /// `process_prekey_bundle` does, using [`curve::PublicKey::verify_signatures_batch`].
+    pub fn verify_signed_pre_key_signatures<R: CryptoRng + Rng>(
+        bundles: &[PreKeyBundle],
+        csprng: &mut R,
+    ) -> Result<()> {
+        let signed_pre_keys: Vec<Box<[u8]>> = bundles
+            .iter()
+            .map(|bundle| bundle.signed_pre_key_public.serialize())
+            .collect();
+        let entries: Vec<_> = bundles
+            .iter()
+            .zip(&signed_pre_keys)
+            .map(|(bundle, signed_pre_key)| {
+                (
+                    bundle.identity_key.public_key(),
+                    &signed_pre_key[..],
+                    &bundle.signed_pre_key_signature[..],
+                )
+            })
+            .collect();
+        curve::PublicKey::verify_signatures_batch(&entries, csprng)
+    }
 }
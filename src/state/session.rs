@@ -14,10 +14,12 @@ use crate::curve;
 use crate::kdf;
 use crate::proto::storage::session_structure;
 use crate::proto::storage::{RecordStructure, SessionStructure};
+use crate::utils::{self, Expiry};
 use prost::Message;
 
 use std::collections::VecDeque;
 use std::iter::FromIterator;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Clone)]
 pub struct UnacknowledgedPreKeyMessageItems {
@@ -142,6 +144,12 @@ impl SessionState {
         &self,
         sender: &curve::PublicKey,
     ) -> Result<Option<(session_structure::Chain, usize)>> {
+        Ok(self
+            .receiver_chain_index(sender)?
+            .map(|idx| (self.session.receiver_chains[idx].clone(), idx)))
+    }
+
+    fn receiver_chain_index(&self, sender: &curve::PublicKey) -> Result<Option<usize>> {
         let sender_bytes = sender.serialize();
 
         for (idx, chain) in self.session.receiver_chains.iter().enumerate() {
@@ -153,7 +161,7 @@ impl SessionState {
             let this_point = curve::decode_point(&chain.sender_ratchet_key)?.serialize();
 
             if this_point == sender_bytes {
-                return Ok(Some((chain.clone(), idx)));
+                return Ok(Some(idx));
             }
         }
 
@@ -176,6 +184,19 @@ impl SessionState {
         }
     }
 
+    /// The ratchet key and chain key of the most recently added receiver chain.
+    pub(crate) fn latest_receiver_chain_key(&self) -> Result<Option<(curve::PublicKey, ChainKey)>> {
+        let chain = match self.session.receiver_chains.last() {
+            None => return Ok(None),
+            Some(chain) => chain,
+        };
+        let sender = curve::decode_point(&chain.sender_ratchet_key)?;
+        match self.get_receiver_chain_key(&sender)? {
+            None => Ok(None),
+            Some(chain_key) => Ok(Some((sender, chain_key))),
+        }
+    }
+
     pub fn add_receiver_chain(
         &mut self,
         sender: &curve::PublicKey,
@@ -293,34 +314,43 @@ impl SessionState {
         Ok(None)
     }
 
+    /// Store a skipped message key for `sender`'s chain, stamped with `now` for expiry.
     pub fn set_message_keys(
         &mut self,
         sender: &curve::PublicKey,
         message_keys: &MessageKeys,
+        now: SystemTime,
     ) -> Result<()> {
-        let new_keys = session_structure::chain::MessageKey {
-            cipher_key: message_keys.cipher_key().to_vec(),
-            mac_key: message_keys.mac_key().to_vec(),
-            iv: message_keys.iv().to_vec(),
-            index: message_keys.counter(),
-        };
-
-        if let Some(chain_and_index) = self.get_receiver_chain(sender)? {
-            let mut updated_chain = chain_and_index.0;
-            updated_chain.message_keys.insert(0, new_keys);
+        self.add_message_keys(sender, std::slice::from_ref(message_keys), now)
+    }
 
-            if updated_chain.message_keys.len() > consts::MAX_MESSAGE_KEYS {
-                updated_chain.message_keys.pop();
-            }
+    /// Store skipped message keys for `sender`'s chain, given in the order they were derived.
+    pub(crate) fn add_message_keys(
+        &mut self,
+        sender: &curve::PublicKey,
+        message_keys: &[MessageKeys],
+        now: SystemTime,
+    ) -> Result<()> {
+        let idx = self.receiver_chain_index(sender)?.ok_or_else(|| {
+            SignalProtocolError::InvalidState("set_message_keys", "No receiver".to_string())
+        })?;
+        let created_at = utils::unix_seconds(now);
+        let new_keys = message_keys
+            .iter()
+            .rev()
+            .map(|keys| session_structure::chain::MessageKey {
+                cipher_key: keys.cipher_key().to_vec(),
+                mac_key: keys.mac_key().to_vec(),
+                iv: keys.iv().to_vec(),
+                index: keys.counter(),
+                created_at,
+            });
 
-            self.session.receiver_chains[chain_and_index.1] = updated_chain;
-            Ok(())
-        } else {
-            Err(SignalProtocolError::InvalidState(
-                "set_message_keys",
-                "No receiver".to_string(),
-            ))
-        }
+        // Newest first, keeping at most MAX_MESSAGE_KEYS.
+        let chain = &mut self.session.receiver_chains[idx];
+        chain.message_keys.splice(0..0, new_keys);
+        chain.message_keys.truncate(consts::MAX_MESSAGE_KEYS);
+        Ok(())
     }
 
     pub fn set_receiver_chain_key(
@@ -328,14 +358,12 @@ impl SessionState {
         sender: &curve::PublicKey,
         chain_key: &ChainKey,
     ) -> Result<()> {
-        if let Some(chain_and_index) = self.get_receiver_chain(sender)? {
-            let mut updated_chain = chain_and_index.0;
-            updated_chain.chain_key = Some(session_structure::chain::ChainKey {
-                index: chain_key.index(),
-                key: chain_key.key().to_vec(),
-            });
-
-            self.session.receiver_chains[chain_and_index.1] = updated_chain;
+        if let Some(idx) = self.receiver_chain_index(sender)? {
+            self.session.receiver_chains[idx].chain_key =
+                Some(session_structure::chain::ChainKey {
+                    index: chain_key.index(),
+                    key: chain_key.key().to_vec(),
+                });
             return Ok(());
         }
 
@@ -475,6 +503,18 @@ impl SessionState {
         Ok(self.session.local_registration_id)
     }
 
+    /// Drop skipped message keys older than the expiry's maximum age.
+    fn prune_expired_message_keys(&mut self, expiry: &Expiry) {
+        for chain in &mut self.session.receiver_chains {
+            for key in &mut chain.message_keys {
+                expiry.stamp(&mut key.created_at);
+            }
+            chain
+                .message_keys
+                .retain(|key| !expiry.is_expired(key.created_at));
+        }
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>> {
         let mut buf = vec![];
         self.session.encode(&mut buf)?;
@@ -590,27 +630,30 @@ impl SessionRecord {
         &mut self,
         old_session: usize,
         updated_session: SessionState,
+        now: SystemTime,
     ) -> Result<()> {
         self.previous_sessions.remove(old_session).ok_or_else(|| {
             SignalProtocolError::InvalidState("promote_old_session", "out of range".into())
         })?;
-        self.promote_state(updated_session)
+        self.promote_state(updated_session, now)
     }
 
     pub fn is_fresh(&self) -> Result<bool> {
         Ok(self.current_session.is_none() && self.previous_sessions.is_empty())
     }
 
-    pub fn promote_state(&mut self, new_state: SessionState) -> Result<()> {
-        self.archive_current_state()?;
+    pub fn promote_state(&mut self, new_state: SessionState, now: SystemTime) -> Result<()> {
+        self.archive_current_state(now)?;
         self.current_session = Some(new_state);
         Ok(())
     }
 
-    pub fn archive_current_state(&mut self) -> Result<()> {
-        if self.current_session.is_some() {
-            self.previous_sessions
-                .push_front(self.current_session.take().expect("Checked is_some"));
+    /// Move the current state to the front of the previous states, stamped with `now` as the
+    /// time it was archived.
+    pub fn archive_current_state(&mut self, now: SystemTime) -> Result<()> {
+        if let Some(mut state) = self.current_session.take() {
+            state.session.archived_at = utils::unix_seconds(now);
+            self.previous_sessions.push_front(state);
             if self.previous_sessions.len() > consts::ARCHIVED_STATES_MAX_LENGTH {
                 self.previous_sessions.pop_back();
             }
@@ -619,6 +662,27 @@ impl SessionRecord {
         Ok(())
     }
 
+    /// Drop skipped message keys and archived states that are at least `max_age` old at `now`.
+    ///
+    /// Keys and states stored before timestamps were recorded are stamped with `now` and
+    /// expire `max_age` later. The record must be stored again afterwards.
+    pub fn prune_expired(&mut self, now: SystemTime, max_age: Duration) {
+        let expiry = Expiry::new(now, max_age);
+        for state in &mut self.previous_sessions {
+            expiry.stamp(&mut state.session.archived_at);
+        }
+        self.previous_sessions
+            .retain(|state| !expiry.is_expired(state.session.archived_at));
+
+        for state in self
+            .current_session
+            .iter_mut()
+            .chain(self.previous_sessions.iter_mut())
+        {
+            state.prune_expired_message_keys(&expiry);
+        }
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>> {
         let mut buf = vec![];
 
@@ -8,7 +8,9 @@
 use crate::curve;
 use crate::error::Result;
 use crate::proto::storage::SignedPreKeyRecordStructure;
+use crate::IdentityKeySigner;
 use prost::Message;
+use rand::{CryptoRng, Rng};
 
 pub type SignedPreKeyId = u32;
 
@@ -33,6 +35,19 @@ impl SignedPreKeyRecord {
         }
     }
 
+    /// Generates a new signed prekey, signed by the identity key behind `identity_key_signer`.
+    pub fn generate<R: Rng + CryptoRng>(
+        id: SignedPreKeyId,
+        timestamp: u64,
+        identity_key_signer: &dyn IdentityKeySigner,
+        mut csprng: &mut R,
+    ) -> Result<Self> {
+        let key = curve::KeyPair::generate(&mut csprng);
+        let signature =
+            identity_key_signer.calculate_signature(&key.public_key.serialize(), csprng)?;
+        Ok(Self::new(id, timestamp, &key, &signature))
+    }
+
     pub fn deserialize(data: &[u8]) -> Result<Self> {
         Ok(Self {
             signed_pre_key: SignedPreKeyRecordStructure::decode(data)?,
@@ -5,16 +5,18 @@
 // SPDX-License-Identifier: GPL-3.0-only
 //
 
+mod encrypted;
 mod inmem;
 mod traits;
 
 pub use {
+    encrypted::{EncryptedStore, StorageKey},
     inmem::{
-        InMemIdentityKeyStore, InMemPreKeyStore, InMemSenderKeyStore, InMemSessionStore,
-        InMemSignalProtocolStore, InMemSignedPreKeyStore,
+        InMemIdentityKeyStore, InMemPreKeyStore, InMemRecordStore, InMemSenderKeyStore,
+        InMemSessionStore, InMemSignalProtocolStore, InMemSignedPreKeyStore,
     },
     traits::{
-        Context, Direction, IdentityKeyStore, PreKeyStore, ProtocolStore, SenderKeyStore,
-        SessionStore, SignedPreKeyStore,
+        Context, Direction, IdentityKeyStore, PreKeyStore, ProtocolStore, RecordKind, RecordStore,
+        SenderKeyStore, SessionStore, SignedPreKeyStore,
     },
 };
@@ -0,0 +1,477 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+use crate::crypto;
+use crate::error::{Result, SignalProtocolError};
+use crate::kdf::HKDF;
+use crate::state::{PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId, SignedPreKeyRecord};
+use crate::storage::traits::{self, RecordKind, RecordStore};
+use crate::storage::Context;
+use crate::{
+    IdentityKey, IdentityKeyPair, IdentityKeySigner, ProtocolAddress, SenderKeyName,
+    SenderKeyRecord,
+};
+
+use arrayref::array_ref;
+use rand::{CryptoRng, Rng};
+
+const SEALED_RECORD_VERSION: u8 = 1;
+const SEALED_RECORD_HEADER_LENGTH: usize = 1 + 4 + 16;
+
+/// A key used to seal records at rest, identified by a caller-chosen id.
+#[derive(Clone)]
+pub struct StorageKey {
+    id: u32,
+    cipher_key: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+impl StorageKey {
+    pub fn new(id: u32, key: &[u8]) -> Result<Self> {
+        if key.len() != 32 {
+            return Err(SignalProtocolError::InvalidArgument(format!(
+                "storage key must be 32 bytes, got {}",
+                key.len()
+            )));
+        }
+        let derived = HKDF::new(3)?.derive_secrets(key, b"SignalStorageEncryption", 64)?;
+        Ok(Self {
+            id,
+            cipher_key: *array_ref![derived, 0, 32],
+            mac_key: *array_ref![derived, 32, 32],
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Wraps a [`RecordStore`] so that every session, prekey, signed prekey and sender key record
+/// is sealed before it reaches the backing store.
+///
+/// Each record is encrypted with AES-256-CBC and authenticated with HMAC-SHA256 under the
+/// current [`StorageKey`]. The associated data binds the record to its kind and to its address
+/// or id, so a sealed record copied to another slot fails to open. Records sealed under a key
+/// that has been rotated out can still be read, and are re-sealed under the current key the
+/// next time they are written.
+///
+/// Identity keys are public and the local identity key pair is not sealed, so if the backing
+/// store is also an [`IdentityKeyStore`](traits::IdentityKeyStore) identity calls are passed
+/// through to it.
+///
+/// # Migrating an existing store
+///
+/// The backing store only holds sealed records; records in an existing [`SessionStore`](
+/// traits::SessionStore), [`PreKeyStore`](traits::PreKeyStore) and so on are not visible
+/// through an `EncryptedStore`, and sessions that are not copied over are lost. To adopt
+/// encryption on an existing device, load each session, prekey, signed prekey and sender key
+/// record from the old store and save it through the `EncryptedStore`, which seals it, before
+/// deleting the plaintext copy.
+pub struct EncryptedStore<S, R: Rng + CryptoRng> {
+    inner: S,
+    current_key: StorageKey,
+    previous_keys: Vec<StorageKey>,
+    csprng: R,
+}
+
+impl<S, R: Rng + CryptoRng> EncryptedStore<S, R> {
+    pub fn new(inner: S, key: StorageKey, csprng: R) -> Self {
+        Self {
+            inner,
+            current_key: key,
+            previous_keys: Vec::new(),
+            csprng,
+        }
+    }
+
+    /// Seal new records under `key`. The previous key is kept for opening existing records.
+    pub fn rotate_key(&mut self, key: StorageKey) {
+        let previous = std::mem::replace(&mut self.current_key, key);
+        self.previous_keys.retain(|k| k.id != previous.id);
+        self.previous_keys.push(previous);
+    }
+
+    /// Forget a previous key; records still sealed under it can no longer be opened.
+    pub fn retire_key(&mut self, key_id: u32) {
+        self.previous_keys.retain(|k| k.id != key_id);
+    }
+
+    pub fn current_key_id(&self) -> u32 {
+        self.current_key.id
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn key_for_id(&self, key_id: u32) -> Result<&StorageKey> {
+        if self.current_key.id == key_id {
+            return Ok(&self.current_key);
+        }
+        self.previous_keys
+            .iter()
+            .find(|k| k.id == key_id)
+            .ok_or(SignalProtocolError::UnknownStorageKey(key_id))
+    }
+
+    fn seal(&mut self, kind: RecordKind, id: &[u8], record: &[u8]) -> Result<Vec<u8>> {
+        let mut iv = [0u8; 16];
+        self.csprng.fill_bytes(&mut iv);
+
+        let mut sealed = Vec::with_capacity(SEALED_RECORD_HEADER_LENGTH + record.len() + 48);
+        sealed.push(SEALED_RECORD_VERSION);
+        sealed.extend_from_slice(&self.current_key.id.to_be_bytes());
+        sealed.extend_from_slice(&iv);
+
+        let ad = associated_data(&sealed[..5], kind, id);
+        sealed.extend_from_slice(&crypto::aes256_cbc_hmacsha256_seal(
+            record,
+            &self.current_key.cipher_key,
+            &self.current_key.mac_key,
+            &iv,
+            &ad,
+        )?);
+        Ok(sealed)
+    }
+
+    fn open(&self, kind: RecordKind, id: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < SEALED_RECORD_HEADER_LENGTH {
+            return Err(SignalProtocolError::InvalidCiphertext);
+        }
+        if sealed[0] != SEALED_RECORD_VERSION {
+            return Err(SignalProtocolError::UnrecognizedCiphertextVersion(
+                sealed[0],
+            ));
+        }
+        let key = self.key_for_id(u32::from_be_bytes(*array_ref![sealed, 1, 4]))?;
+        let ad = associated_data(&sealed[..5], kind, id);
+        crypto::aes256_cbc_hmacsha256_open(
+            &sealed[SEALED_RECORD_HEADER_LENGTH..],
+            &key.cipher_key,
+            &key.mac_key,
+            &sealed[5..SEALED_RECORD_HEADER_LENGTH],
+            &ad,
+        )
+    }
+}
+
+impl<S: RecordStore, R: Rng + CryptoRng> EncryptedStore<S, R> {
+    fn load(&self, kind: RecordKind, id: &[u8], ctx: Context) -> Result<Option<Vec<u8>>> {
+        match self.inner.load_record(kind, id, ctx)? {
+            None => Ok(None),
+            Some(sealed) => Ok(Some(self.open(kind, id, &sealed)?)),
+        }
+    }
+
+    fn store(&mut self, kind: RecordKind, id: &[u8], record: &[u8], ctx: Context) -> Result<()> {
+        let sealed = self.seal(kind, id, record)?;
+        self.inner.store_record(kind, id, &sealed, ctx)
+    }
+}
+
+fn associated_data(header: &[u8], kind: RecordKind, id: &[u8]) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(header.len() + 1 + id.len());
+    ad.extend_from_slice(header);
+    ad.push(kind.encoding());
+    ad.extend_from_slice(id);
+    ad
+}
+
+fn address_record_id(address: &ProtocolAddress) -> Vec<u8> {
+    let mut id = Vec::with_capacity(4 + address.name().len() + 4);
+    id.extend_from_slice(&(address.name().len() as u32).to_be_bytes());
+    id.extend_from_slice(address.name().as_bytes());
+    id.extend_from_slice(&address.device_id().to_be_bytes());
+    id
+}
+
+fn sender_key_record_id(sender_key_name: &SenderKeyName) -> Result<Vec<u8>> {
+    let group_id = sender_key_name.group_id()?;
+    let mut id = Vec::new();
+    id.extend_from_slice(&(group_id.len() as u32).to_be_bytes());
+    id.extend_from_slice(group_id.as_bytes());
+    id.extend_from_slice(&address_record_id(&sender_key_name.sender()?));
+    Ok(id)
+}
+
+impl<S: traits::IdentityKeyStore, R: Rng + CryptoRng> traits::IdentityKeyStore
+    for EncryptedStore<S, R>
+{
+    fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair> {
+        self.inner.get_identity_key_pair(ctx)
+    }
+
+    fn get_identity_key_signer(&self, ctx: Context) -> Result<Box<dyn IdentityKeySigner + '_>> {
+        self.inner.get_identity_key_signer(ctx)
+    }
+
+    fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
+        self.inner.get_local_registration_id(ctx)
+    }
+
+    fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.inner.save_identity(address, identity, ctx)
+    }
+
+    fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: traits::Direction,
+        ctx: Context,
+    ) -> Result<bool> {
+        self.inner
+            .is_trusted_identity(address, identity, direction, ctx)
+    }
+
+    fn get_identity(&self, address: &ProtocolAddress, ctx: Context) -> Result<Option<IdentityKey>> {
+        self.inner.get_identity(address, ctx)
+    }
+}
+
+impl<S: RecordStore, R: Rng + CryptoRng> traits::PreKeyStore for EncryptedStore<S, R> {
+    fn get_pre_key(&self, id: PreKeyId, ctx: Context) -> Result<PreKeyRecord> {
+        match self.load(RecordKind::PreKey, &id.to_be_bytes(), ctx)? {
+            Some(record) => PreKeyRecord::deserialize(&record),
+            None => Err(SignalProtocolError::InvalidPreKeyId),
+        }
+    }
+
+    fn save_pre_key(&mut self, id: PreKeyId, record: &PreKeyRecord, ctx: Context) -> Result<()> {
+        self.store(
+            RecordKind::PreKey,
+            &id.to_be_bytes(),
+            &record.serialize()?,
+            ctx,
+        )
+    }
+
+    fn remove_pre_key(&mut self, id: PreKeyId, ctx: Context) -> Result<()> {
+        self.inner
+            .remove_record(RecordKind::PreKey, &id.to_be_bytes(), ctx)
+    }
+}
+
+impl<S: RecordStore, R: Rng + CryptoRng> traits::SignedPreKeyStore for EncryptedStore<S, R> {
+    fn get_signed_pre_key(&self, id: SignedPreKeyId, ctx: Context) -> Result<SignedPreKeyRecord> {
+        match self.load(RecordKind::SignedPreKey, &id.to_be_bytes(), ctx)? {
+            Some(record) => SignedPreKeyRecord::deserialize(&record),
+            None => Err(SignalProtocolError::InvalidSignedPreKeyId),
+        }
+    }
+
+    fn save_signed_pre_key(
+        &mut self,
+        id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.store(
+            RecordKind::SignedPreKey,
+            &id.to_be_bytes(),
+            &record.serialize()?,
+            ctx,
+        )
+    }
+}
+
+impl<S: RecordStore, R: Rng + CryptoRng> traits::SessionStore for EncryptedStore<S, R> {
+    fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<SessionRecord>> {
+        match self.load(RecordKind::Session, &address_record_id(address), ctx)? {
+            Some(record) => Ok(Some(SessionRecord::deserialize(&record)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.store(
+            RecordKind::Session,
+            &address_record_id(address),
+            &record.serialize()?,
+            ctx,
+        )
+    }
+}
+
+impl<S: RecordStore, R: Rng + CryptoRng> traits::SenderKeyStore for EncryptedStore<S, R> {
+    fn store_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        record: &SenderKeyRecord,
+        ctx: Context,
+    ) -> Result<()> {
+        self.store(
+            RecordKind::SenderKey,
+            &sender_key_record_id(sender_key_name)?,
+            &record.serialize()?,
+            ctx,
+        )
+    }
+
+    fn load_sender_key(
+        &mut self,
+        sender_key_name: &SenderKeyName,
+        ctx: Context,
+    ) -> Result<Option<SenderKeyRecord>> {
+        match self.load(
+            RecordKind::SenderKey,
+            &sender_key_record_id(sender_key_name)?,
+            ctx,
+        )? {
+            Some(record) => Ok(Some(SenderKeyRecord::deserialize(&record)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<S: RecordStore + traits::IdentityKeyStore, R: Rng + CryptoRng> traits::ProtocolStore
+    for EncryptedStore<S, R>
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{InMemPreKeyStore, InMemRecordStore, InMemSessionStore};
+    use crate::{KeyPair, PreKeyStore, SessionStore};
+
+    use rand::rngs::OsRng;
+
+    fn test_key() -> StorageKey {
+        StorageKey::new(1, &[0x42u8; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_records_are_sealed() -> Result<()> {
+        let mut store = EncryptedStore::new(InMemRecordStore::new(), test_key(), OsRng);
+        let pre_key = PreKeyRecord::new(7, &KeyPair::generate(&mut OsRng));
+        store.save_pre_key(7, &pre_key, None)?;
+
+        let sealed = store
+            .inner()
+            .load_record(RecordKind::PreKey, &7u32.to_be_bytes(), None)?
+            .unwrap();
+        let private_key = pre_key.private_key()?.serialize();
+        assert!(!sealed.windows(32).any(|w| w == &private_key[..]));
+
+        let loaded = store.get_pre_key(7, None)?;
+        assert_eq!(loaded.serialize()?, pre_key.serialize()?);
+
+        // A record written to the backing store directly is refused rather than trusted.
+        store.inner.store_record(
+            RecordKind::PreKey,
+            &8u32.to_be_bytes(),
+            &pre_key.serialize()?,
+            None,
+        )?;
+        assert!(store.get_pre_key(8, None).is_err());
+
+        store.remove_pre_key(7, None)?;
+        assert_eq!(
+            store.get_pre_key(7, None).unwrap_err(),
+            SignalProtocolError::InvalidPreKeyId
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_records_are_bound_to_their_address() -> Result<()> {
+        let mut store = EncryptedStore::new(InMemRecordStore::new(), test_key(), OsRng);
+        let alice = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let mallory = ProtocolAddress::new("+14151111111".to_owned(), 2);
+
+        store.store_session(&alice, &SessionRecord::new_fresh(), None)?;
+
+        let sealed = store
+            .inner()
+            .load_record(RecordKind::Session, &address_record_id(&alice), None)?
+            .unwrap();
+        store.inner.store_record(
+            RecordKind::Session,
+            &address_record_id(&mallory),
+            &sealed,
+            None,
+        )?;
+
+        assert!(store.load_session(&alice, None)?.is_some());
+        assert_eq!(
+            store.load_session(&mallory, None).unwrap_err(),
+            SignalProtocolError::InvalidCiphertext
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_rotation() -> Result<()> {
+        let mut store = EncryptedStore::new(InMemRecordStore::new(), test_key(), OsRng);
+        let alice = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let bob = ProtocolAddress::new("+14151111112".to_owned(), 1);
+        store.store_session(&alice, &SessionRecord::new_fresh(), None)?;
+        store.store_session(&bob, &SessionRecord::new_fresh(), None)?;
+
+        store.rotate_key(StorageKey::new(2, &[0x17u8; 32])?);
+        assert_eq!(store.current_key_id(), 2);
+
+        // Still readable under the previous key, and re-sealed under the new one when written.
+        let record = store.load_session(&alice, None)?.unwrap();
+        store.store_session(&alice, &record, None)?;
+        store.retire_key(1);
+
+        assert!(store.load_session(&alice, None)?.is_some());
+        assert_eq!(
+            store.load_session(&bob, None).unwrap_err(),
+            SignalProtocolError::UnknownStorageKey(1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_plaintext_store() -> Result<()> {
+        let alice = ProtocolAddress::new("+14151111111".to_owned(), 1);
+        let mut sessions = InMemSessionStore::new();
+        let mut pre_keys = InMemPreKeyStore::new();
+        sessions.store_session(&alice, &SessionRecord::new_fresh(), None)?;
+        let pre_key = PreKeyRecord::new(7, &KeyPair::generate(&mut OsRng));
+        pre_keys.save_pre_key(7, &pre_key, None)?;
+
+        let mut store = EncryptedStore::new(InMemRecordStore::new(), test_key(), OsRng);
+        assert!(store.load_session(&alice, None)?.is_none());
+
+        let record = sessions.load_session(&alice, None)?.unwrap();
+        store.store_session(&alice, &record, None)?;
+        store.save_pre_key(7, &pre_keys.get_pre_key(7, None)?, None)?;
+
+        assert_eq!(
+            store.load_session(&alice, None)?.unwrap().serialize()?,
+            record.serialize()?
+        );
+        assert_eq!(
+            store.get_pre_key(7, None)?.serialize()?,
+            pre_key.serialize()?
+        );
+        Ok(())
+    }
+}
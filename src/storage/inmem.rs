@@ -9,7 +9,10 @@ use crate::error::{Result, SignalProtocolError};
 use crate::state::{PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId, SignedPreKeyRecord};
 use crate::storage::traits;
 use crate::storage::Context;
-use crate::{IdentityKey, IdentityKeyPair, ProtocolAddress, SenderKeyName, SenderKeyRecord};
+use crate::{
+    IdentityKey, IdentityKeyPair, IdentityKeySigner, ProtocolAddress, SenderKeyName,
+    SenderKeyRecord,
+};
 
 use std::collections::HashMap;
 
@@ -275,6 +278,10 @@ impl traits::IdentityKeyStore for InMemSignalProtocolStore {
         self.identity_store.get_identity_key_pair(ctx)
     }
 
+    fn get_identity_key_signer(&self, ctx: Context) -> Result<Box<dyn IdentityKeySigner + '_>> {
+        self.identity_store.get_identity_key_signer(ctx)
+    }
+
     fn get_local_registration_id(&self, ctx: Context) -> Result<u32> {
         self.identity_store.get_local_registration_id(ctx)
     }
@@ -374,3 +381,49 @@ impl traits::SenderKeyStore for InMemSignalProtocolStore {
 }
 
 impl traits::ProtocolStore for InMemSignalProtocolStore {}
+
+#[derive(Clone)]
+pub struct InMemRecordStore {
+    records: HashMap<(traits::RecordKind, Vec<u8>), Vec<u8>>,
+}
+
+impl InMemRecordStore {
+    pub fn new() -> Self {
+        Self {
+            records: HashMap::new(),
+        }
+    }
+}
+
+impl Default for InMemRecordStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl traits::RecordStore for InMemRecordStore {
+    fn load_record(
+        &self,
+        kind: traits::RecordKind,
+        id: &[u8],
+        _ctx: Context,
+    ) -> Result<Option<Vec<u8>>> {
+        Ok(self.records.get(&(kind, id.to_vec())).cloned())
+    }
+
+    fn store_record(
+        &mut self,
+        kind: traits::RecordKind,
+        id: &[u8],
+        record: &[u8],
+        _ctx: Context,
+    ) -> Result<()> {
+        self.records.insert((kind, id.to_vec()), record.to_vec());
+        Ok(())
+    }
+
+    fn remove_record(&mut self, kind: traits::RecordKind, id: &[u8], _ctx: Context) -> Result<()> {
+        self.records.remove(&(kind, id.to_vec()));
+        Ok(())
+    }
+}
@@ -7,7 +7,10 @@
 
 use crate::error::Result;
 use crate::state::{PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId, SignedPreKeyRecord};
-use crate::{IdentityKey, IdentityKeyPair, ProtocolAddress, SenderKeyName, SenderKeyRecord};
+use crate::{
+    IdentityKey, IdentityKeyPair, IdentityKeySigner, ProtocolAddress, SenderKeyName,
+    SenderKeyRecord,
+};
 
 pub type Context = Option<*mut std::ffi::c_void>;
 
@@ -18,8 +21,17 @@ pub enum Direction {
 }
 
 pub trait IdentityKeyStore {
+    /// The local identity key pair, including the private key. A store whose private key cannot
+    /// be exported should override [`get_identity_key_signer`](Self::get_identity_key_signer) and
+    /// may return an error here; only backups need the raw key.
     fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair>;
 
+    /// A signer for the local identity key, used for session setup. By default this is the key
+    /// pair from [`get_identity_key_pair`](Self::get_identity_key_pair).
+    fn get_identity_key_signer(&self, ctx: Context) -> Result<Box<dyn IdentityKeySigner + '_>> {
+        Ok(Box::new(self.get_identity_key_pair(ctx)?))
+    }
+
     fn get_local_registration_id(&self, ctx: Context) -> Result<u32>;
 
     fn save_identity(
@@ -99,3 +111,42 @@ pub trait SenderKeyStore {
 }
 
 pub trait ProtocolStore: SessionStore + PreKeyStore + SignedPreKeyStore + IdentityKeyStore {}
+
+/// The kind of protocol record held by a [`RecordStore`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum RecordKind {
+    Session,
+    PreKey,
+    SignedPreKey,
+    SenderKey,
+}
+
+impl RecordKind {
+    pub fn encoding(&self) -> u8 {
+        match self {
+            RecordKind::Session => 1,
+            RecordKind::PreKey => 2,
+            RecordKind::SignedPreKey => 3,
+            RecordKind::SenderKey => 4,
+        }
+    }
+}
+
+/// Storage of opaque serialized records, keyed by kind and an encoded record id.
+///
+/// This is the backing store for [`EncryptedStore`](crate::EncryptedStore), which only ever
+/// hands it sealed bytes. Sealed records are kept apart from the protocol record types, so no
+/// code reading a [`SessionStore`] or [`PreKeyStore`] can mistake one for an empty record.
+pub trait RecordStore {
+    fn load_record(&self, kind: RecordKind, id: &[u8], ctx: Context) -> Result<Option<Vec<u8>>>;
+
+    fn store_record(
+        &mut self,
+        kind: RecordKind,
+        id: &[u8],
+        record: &[u8],
+        ctx: Context,
+    ) -> Result<()>;
+
+    fn remove_record(&mut self, kind: RecordKind, id: &[u8], ctx: Context) -> Result<()>;
+}
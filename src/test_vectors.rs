@@ -0,0 +1,437 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+//! Recorded Alice/Bob transcripts for cross-implementation testing.
+//!
+//! A transcript uses the fixture format described in `tests/data/interop/README.md`: a list of
+//! encrypt and decrypt cases, each carrying the acting party's keys, its session record before
+//! the operation, the message, and the state the operation must leave behind. Replaying needs
+//! only those recorded values, so any implementation can check a transcript whatever RNG it
+//! uses.
+//!
+//! When recording, every key and signature is drawn from a [`StdRng`] seeded with the
+//! transcript's seed. The seed makes recording repeatable with this crate's dependencies, but
+//! `StdRng` is not stable across `rand` releases, so replay never relies on it.
+
+use crate::error::{Result, SignalProtocolError};
+use crate::{
+    message_decrypt, message_encrypt, process_prekey_bundle, CiphertextMessage,
+    CiphertextMessageType, IdentityKey, IdentityKeyPair, IdentityKeyStore,
+    InMemSignalProtocolStore, KeyPair, PreKeyBundle, PreKeyRecord, PreKeyStore, PrivateKey,
+    ProtocolAddress, PublicKey, SessionRecord, SessionStore, SignedPreKeyRecord, SignedPreKeyStore,
+};
+
+use rand::rngs::{OsRng, StdRng};
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::time::SystemTime;
+
+const TRANSCRIPT_FORMAT_VERSION: u64 = 1;
+
+/// Which side of a transcript sends a message.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TranscriptParty {
+    Alice,
+    Bob,
+}
+
+impl TranscriptParty {
+    fn name(self) -> &'static str {
+        match self {
+            TranscriptParty::Alice => "alice",
+            TranscriptParty::Bob => "bob",
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn malformed(what: &str) -> SignalProtocolError {
+    SignalProtocolError::InvalidArgument(format!("malformed transcript: {}", what))
+}
+
+fn hex_field(value: &Value, field: &str) -> Result<Vec<u8>> {
+    value[field]
+        .as_str()
+        .and_then(from_hex)
+        .ok_or_else(|| malformed(field))
+}
+
+fn u32_field(value: &Value, field: &str) -> Result<u32> {
+    value[field]
+        .as_u64()
+        .filter(|&v| v <= u64::from(u32::MAX))
+        .map(|v| v as u32)
+        .ok_or_else(|| malformed(field))
+}
+
+fn sender_ratchet_key(message: &CiphertextMessage) -> Result<PublicKey> {
+    match message {
+        CiphertextMessage::SignalMessage(m) => Ok(*m.sender_ratchet_key()),
+        CiphertextMessage::PreKeySignalMessage(m) => Ok(*m.message().sender_ratchet_key()),
+        _ => Err(malformed("message_type")),
+    }
+}
+
+/// The parts of the session state that an operation determines, given the recorded inputs.
+///
+/// After encrypting that is the root key and sending chain. After decrypting, the receiver may
+/// have generated a fresh ratchet key, so only the receiving chain for the sender is compared.
+fn state_summary(
+    store: &InMemSignalProtocolStore,
+    remote_address: &ProtocolAddress,
+    received_from: Option<&PublicKey>,
+) -> Result<Value> {
+    let record = store
+        .load_session(remote_address, None)?
+        .ok_or_else(|| SignalProtocolError::SessionNotFound(remote_address.clone()))?;
+    let state = record.session_state()?;
+    Ok(match received_from {
+        None => {
+            let chain_key = state.get_sender_chain_key()?;
+            json!({
+                "session_version": state.session_version()?,
+                "root_key": to_hex(state.root_key()?.key()),
+                "sender_chain_key": to_hex(chain_key.key()),
+                "sender_chain_index": chain_key.index(),
+            })
+        }
+        Some(sender) => {
+            let chain_key = state.get_receiver_chain_key(sender)?.ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "state_summary",
+                    "no receiver chain for the sender".to_string(),
+                )
+            })?;
+            json!({
+                "session_version": state.session_version()?,
+                "receiver_chain_key": to_hex(chain_key.key()),
+                "receiver_chain_index": chain_key.index(),
+            })
+        }
+    })
+}
+
+/// The fields of a case describing the acting party before the operation.
+fn case_base(
+    store: &InMemSignalProtocolStore,
+    remote_address: &ProtocolAddress,
+    name: &str,
+    kind: &str,
+) -> Result<Value> {
+    let identity = store.get_identity_key_pair(None)?;
+    Ok(json!({
+        "name": name,
+        "kind": kind,
+        "local_identity": {
+            "public": to_hex(&identity.identity_key().serialize()),
+            "private": to_hex(&identity.private_key().serialize()),
+        },
+        "local_registration_id": store.get_local_registration_id(None)?,
+        "remote_address": {
+            "name": remote_address.name(),
+            "device_id": remote_address.device_id(),
+        },
+        "session_record": match store.load_session(remote_address, None)? {
+            Some(record) => Value::from(to_hex(&record.serialize()?)),
+            None => Value::Null,
+        },
+    }))
+}
+
+fn key_pair_value(id: u32, key_pair: &KeyPair) -> Value {
+    json!({
+        "id": id,
+        "public": to_hex(&key_pair.public_key.serialize()),
+        "private": to_hex(&key_pair.private_key.serialize()),
+    })
+}
+
+fn record_transcript(seed: [u8; 32], exchanges: &[(TranscriptParty, &[u8])]) -> Result<Value> {
+    let mut rng = StdRng::from_seed(seed);
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let alice_identity = IdentityKeyPair::generate(&mut rng);
+    let bob_identity = IdentityKeyPair::generate(&mut rng);
+    let alice_registration_id = rng.gen::<u32>() & 0x3fff;
+    let bob_registration_id = rng.gen::<u32>() & 0x3fff;
+    let mut alice_store = InMemSignalProtocolStore::new(alice_identity, alice_registration_id)?;
+    let mut bob_store = InMemSignalProtocolStore::new(bob_identity, bob_registration_id)?;
+
+    let pre_key_id = rng.gen::<u32>() & 0xffffff;
+    let pre_key = KeyPair::generate(&mut rng);
+    let signed_pre_key_id = rng.gen::<u32>() & 0xffffff;
+    let signed_pre_key = KeyPair::generate(&mut rng);
+    let signed_pre_key_signature = bob_identity
+        .private_key()
+        .calculate_signature(&signed_pre_key.public_key.serialize(), &mut rng)?;
+
+    bob_store.save_pre_key(pre_key_id, &PreKeyRecord::new(pre_key_id, &pre_key), None)?;
+    bob_store.save_signed_pre_key(
+        signed_pre_key_id,
+        &SignedPreKeyRecord::new(
+            signed_pre_key_id,
+            0,
+            &signed_pre_key,
+            &signed_pre_key_signature,
+        ),
+        None,
+    )?;
+
+    let bundle = PreKeyBundle::new(
+        bob_registration_id,
+        bob_address.device_id(),
+        Some(pre_key_id),
+        Some(pre_key.public_key),
+        signed_pre_key_id,
+        signed_pre_key.public_key,
+        signed_pre_key_signature.to_vec(),
+        *bob_identity.identity_key(),
+    )?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bundle,
+        &mut rng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let mut cases = Vec::with_capacity(2 * exchanges.len());
+    for (i, (sender, plaintext)) in exchanges.iter().enumerate() {
+        let (from, from_address, to, to_address) = match sender {
+            TranscriptParty::Alice => (
+                &mut alice_store,
+                &alice_address,
+                &mut bob_store,
+                &bob_address,
+            ),
+            TranscriptParty::Bob => (
+                &mut bob_store,
+                &bob_address,
+                &mut alice_store,
+                &alice_address,
+            ),
+        };
+
+        let name = format!("message {} from {}", i, sender.name());
+        let mut encrypt_case = case_base(from, to_address, &name, "encrypt")?;
+        let mut decrypt_case = case_base(to, from_address, &name, "decrypt")?;
+
+        let ciphertext = message_encrypt(
+            plaintext,
+            to_address,
+            &mut from.session_store,
+            &mut from.identity_store,
+            None,
+        )?;
+        let decrypted = message_decrypt(
+            &ciphertext,
+            from_address,
+            &mut to.session_store,
+            &mut to.identity_store,
+            &mut to.pre_key_store,
+            &mut to.signed_pre_key_store,
+            &mut rng,
+            SystemTime::now(),
+            None,
+        )?;
+        if decrypted != *plaintext {
+            return Err(SignalProtocolError::InternalError(
+                "transcript message did not round trip",
+            ));
+        }
+
+        for case in [&mut encrypt_case, &mut decrypt_case].iter_mut() {
+            case["message_type"] = json!(ciphertext.message_type().encoding());
+            case["ciphertext"] = json!(to_hex(ciphertext.serialize()));
+            case["plaintext"] = json!(to_hex(plaintext));
+        }
+        encrypt_case["expected_state"] = state_summary(from, to_address, None)?;
+        decrypt_case["expected_state"] =
+            state_summary(to, from_address, Some(&sender_ratchet_key(&ciphertext)?))?;
+        if decrypt_case["session_record"].is_null() {
+            decrypt_case["pre_keys"] = json!([key_pair_value(pre_key_id, &pre_key)]);
+            let mut signed = key_pair_value(signed_pre_key_id, &signed_pre_key);
+            signed["signature"] = json!(to_hex(&signed_pre_key_signature));
+            signed["timestamp"] = json!(0);
+            decrypt_case["signed_pre_keys"] = json!([signed]);
+        }
+
+        cases.push(encrypt_case);
+        cases.push(decrypt_case);
+    }
+
+    Ok(json!({
+        "format_version": TRANSCRIPT_FORMAT_VERSION,
+        "producer": format!("libsignal-protocol-rust {}", env!("CARGO_PKG_VERSION")),
+        "seed": to_hex(&seed),
+        "cases": cases,
+    }))
+}
+
+/// Run a full session between Alice and Bob, with all randomness drawn from `seed`, and return
+/// the transcript as pretty-printed JSON.
+///
+/// Alice starts the session from Bob's pre-key bundle; each exchange is then encrypted by its
+/// sender and decrypted by the other party, giving one encrypt and one decrypt case.
+pub fn record_session_transcript(
+    seed: [u8; 32],
+    exchanges: &[(TranscriptParty, &[u8])],
+) -> Result<String> {
+    let transcript = record_transcript(seed, exchanges)?;
+    serde_json::to_string_pretty(&transcript)
+        .map_err(|_| SignalProtocolError::InternalError("failed to encode transcript"))
+}
+
+fn store_for_case(
+    case: &Value,
+    remote_address: &ProtocolAddress,
+) -> Result<InMemSignalProtocolStore> {
+    let identity = &case["local_identity"];
+    let identity = IdentityKeyPair::new(
+        IdentityKey::decode(&hex_field(identity, "public")?)?,
+        PrivateKey::deserialize(&hex_field(identity, "private")?)?,
+    );
+    let mut store =
+        InMemSignalProtocolStore::new(identity, u32_field(case, "local_registration_id")?)?;
+
+    if !case["remote_identity"].is_null() {
+        let remote_identity = IdentityKey::decode(&hex_field(case, "remote_identity")?)?;
+        store.save_identity(remote_address, &remote_identity, None)?;
+    }
+    if !case["session_record"].is_null() {
+        let record = SessionRecord::deserialize(&hex_field(case, "session_record")?)?;
+        store.store_session(remote_address, &record, None)?;
+    }
+    for pre_key in case["pre_keys"].as_array().into_iter().flatten() {
+        let id = u32_field(pre_key, "id")?;
+        let key_pair = KeyPair::from_public_and_private(
+            &hex_field(pre_key, "public")?,
+            &hex_field(pre_key, "private")?,
+        )?;
+        store.save_pre_key(id, &PreKeyRecord::new(id, &key_pair), None)?;
+    }
+    for signed_pre_key in case["signed_pre_keys"].as_array().into_iter().flatten() {
+        let id = u32_field(signed_pre_key, "id")?;
+        let key_pair = KeyPair::from_public_and_private(
+            &hex_field(signed_pre_key, "public")?,
+            &hex_field(signed_pre_key, "private")?,
+        )?;
+        let record = SignedPreKeyRecord::new(
+            id,
+            signed_pre_key["timestamp"].as_u64().unwrap_or(0),
+            &key_pair,
+            &hex_field(signed_pre_key, "signature")?,
+        );
+        store.save_signed_pre_key(id, &record, None)?;
+    }
+    Ok(store)
+}
+
+fn replay_case(index: usize, case: &Value) -> Result<()> {
+    let differs = |what: &str| {
+        SignalProtocolError::InvalidState(
+            "replay_session_transcript",
+            format!("case {} ({}): {} differs", index, case["name"], what),
+        )
+    };
+
+    let remote = &case["remote_address"];
+    let remote_address = ProtocolAddress::new(
+        remote["name"]
+            .as_str()
+            .ok_or_else(|| malformed("remote_address"))?
+            .to_owned(),
+        u32_field(remote, "device_id")?,
+    );
+    let mut store = store_for_case(case, &remote_address)?;
+    let message_type = match u32_field(case, "message_type")? {
+        2 => CiphertextMessageType::Whisper,
+        3 => CiphertextMessageType::PreKey,
+        _ => return Err(malformed("message_type")),
+    };
+    let ciphertext = hex_field(case, "ciphertext")?;
+    let plaintext = hex_field(case, "plaintext")?;
+
+    let received_from = match case["kind"].as_str() {
+        Some("decrypt") => {
+            let message = CiphertextMessage::parse(&ciphertext, message_type)?;
+            // Any fresh ratchet key the receiver generates is outside the recorded state.
+            let decrypted = message_decrypt(
+                &message,
+                &remote_address,
+                &mut store.session_store,
+                &mut store.identity_store,
+                &mut store.pre_key_store,
+                &mut store.signed_pre_key_store,
+                &mut OsRng,
+                SystemTime::now(),
+                None,
+            )?;
+            if decrypted != plaintext {
+                return Err(differs("plaintext"));
+            }
+            Some(sender_ratchet_key(&message)?)
+        }
+        Some("encrypt") => {
+            let message = message_encrypt(
+                &plaintext,
+                &remote_address,
+                &mut store.session_store,
+                &mut store.identity_store,
+                None,
+            )?;
+            if message.message_type() != message_type || message.serialize() != &ciphertext[..] {
+                return Err(differs("ciphertext"));
+            }
+            None
+        }
+        _ => return Err(malformed("kind")),
+    };
+
+    if state_summary(&store, &remote_address, received_from.as_ref())? != case["expected_state"] {
+        return Err(differs("session state"));
+    }
+    Ok(())
+}
+
+/// Replay every case of a transcript from its recorded keys and session records, checking that
+/// each operation produces the recorded plaintext or ciphertext and session state.
+///
+/// Accepts transcripts from [`record_session_transcript`] and interop fixtures written by other
+/// implementations alike; the seed, if present, is not used.
+pub fn replay_session_transcript(transcript: &str) -> Result<()> {
+    let transcript: Value = serde_json::from_str(transcript).map_err(|_| malformed("not JSON"))?;
+    if transcript["format_version"] != json!(TRANSCRIPT_FORMAT_VERSION) {
+        return Err(malformed("unsupported format version"));
+    }
+
+    let cases = transcript["cases"]
+        .as_array()
+        .filter(|cases| !cases.is_empty())
+        .ok_or_else(|| malformed("cases"))?;
+    for (i, case) in cases.iter().enumerate() {
+        replay_case(i, case)?;
+    }
+    Ok(())
+}
@@ -6,6 +6,7 @@
 //
 
 use std::cmp::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 fn expand_top_bit(a: u8) -> u8 {
     //if (a >> 7) == 1 { 0xFF } else { 0 }
@@ -83,6 +84,73 @@ pub(crate) fn constant_time_cmp(x: &[u8], y: &[u8]) -> Ordering {
     }
 }
 
+/*
+* Returns the length of `data` without its PKCS#7 padding, or None if the
+* padding is invalid. The last `block_size` bytes are always all examined,
+* so the time taken reveals whether the padding was valid but not its
+* length or where a bad padding byte was.
+ */
+pub(crate) fn constant_time_pkcs7_unpadded_len(data: &[u8], block_size: usize) -> Option<usize> {
+    debug_assert!(block_size > 0 && block_size < 256);
+    if data.len() < block_size {
+        return None;
+    }
+
+    let last_block = &data[data.len() - block_size..];
+    let pad = last_block[block_size - 1];
+
+    // pad must be in 1..=block_size
+    let mut valid = !ct_is_zero(pad) & !ct_is_lt(block_size as u8, pad);
+
+    for i in 0..block_size {
+        let b = last_block[block_size - 1 - i];
+        let in_padding = ct_is_lt(i as u8, pad);
+        valid &= !in_padding | ct_is_eq(b, pad);
+    }
+
+    if valid == 0xFF {
+        Some(data.len() - pad as usize)
+    } else {
+        None
+    }
+}
+
+/// Seconds since the Unix epoch, as stored in record timestamps.
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Tracks expiry of record entries stamped with [`unix_seconds`]. Entries with no timestamp,
+/// written before timestamps were recorded, are stamped with the current time instead of being
+/// expired at once.
+pub(crate) struct Expiry {
+    now: u64,
+    max_age: u64,
+}
+
+impl Expiry {
+    pub(crate) fn new(now: SystemTime, max_age: Duration) -> Self {
+        Self {
+            now: unix_seconds(now),
+            max_age: max_age.as_secs(),
+        }
+    }
+
+    /// Give an entry that has no timestamp the current time.
+    pub(crate) fn stamp(&self, timestamp: &mut u64) {
+        if *timestamp == 0 {
+            *timestamp = self.now;
+        }
+    }
+
+    /// Whether an entry stamped with `timestamp` should be dropped.
+    pub(crate) fn is_expired(&self, timestamp: u64) -> bool {
+        self.now.saturating_sub(timestamp) >= self.max_age
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -110,6 +178,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_constant_time_pkcs7_unpadded_len() {
+        for pad in 1..=16u8 {
+            let mut data = vec![0x42; 32];
+            for b in &mut data[32 - pad as usize..] {
+                *b = pad;
+            }
+            assert_eq!(
+                constant_time_pkcs7_unpadded_len(&data, 16),
+                Some(32 - pad as usize)
+            );
+
+            // A wrong byte anywhere in the padding invalidates it.
+            for i in 32 - pad as usize..31 {
+                let mut bad = data.clone();
+                bad[i] ^= 1;
+                assert_eq!(constant_time_pkcs7_unpadded_len(&bad, 16), None);
+            }
+        }
+
+        for &pad in &[0u8, 17, 0xFF] {
+            let mut data = vec![pad; 32];
+            data[0] = 0;
+            assert_eq!(constant_time_pkcs7_unpadded_len(&data, 16), None);
+        }
+        assert_eq!(constant_time_pkcs7_unpadded_len(&[1; 15], 16), None);
+        assert_eq!(constant_time_pkcs7_unpadded_len(&[], 16), None);
+    }
+
     #[test]
     fn test_ct_is_zero() {
         assert_eq!(ct_is_zero(0), 0xFF);
@@ -0,0 +1,206 @@
+/*
+ * Copyright (C) 2020 Signal Messenger, LLC.
+ * All rights reserved.
+ *
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+import org.whispersystems.libsignal.IdentityKeyPair;
+import org.whispersystems.libsignal.SessionBuilder;
+import org.whispersystems.libsignal.SessionCipher;
+import org.whispersystems.libsignal.SignalProtocolAddress;
+import org.whispersystems.libsignal.ecc.Curve;
+import org.whispersystems.libsignal.ecc.ECKeyPair;
+import org.whispersystems.libsignal.ecc.ECPublicKey;
+import org.whispersystems.libsignal.protocol.CiphertextMessage;
+import org.whispersystems.libsignal.protocol.PreKeySignalMessage;
+import org.whispersystems.libsignal.protocol.SignalMessage;
+import org.whispersystems.libsignal.ratchet.ChainKey;
+import org.whispersystems.libsignal.state.PreKeyBundle;
+import org.whispersystems.libsignal.state.PreKeyRecord;
+import org.whispersystems.libsignal.state.SessionRecord;
+import org.whispersystems.libsignal.state.SessionState;
+import org.whispersystems.libsignal.state.SignedPreKeyRecord;
+import org.whispersystems.libsignal.state.impl.InMemorySignalProtocolStore;
+import org.whispersystems.libsignal.util.KeyHelper;
+
+import java.io.FileOutputStream;
+import java.nio.charset.StandardCharsets;
+import java.util.ArrayList;
+import java.util.Arrays;
+import java.util.List;
+
+/**
+ * Writes an interop transcript in the format described in tests/data/interop/README.md of
+ * libsignal-protocol-rust.
+ *
+ * Build against libsignal-protocol-java and run with the output path and the producer string
+ * as arguments, e.g. "java-2.8.1.json" and "libsignal-protocol-java 2.8.1". The exchange is the
+ * same as the one in rust.json: two pre-key messages from Alice, then replies in turn.
+ */
+public class GenerateTranscript {
+
+  private static final int PRE_KEY_ID        = 31337;
+  private static final int SIGNED_PRE_KEY_ID = 22;
+
+  private static final SignalProtocolAddress ALICE = new SignalProtocolAddress("+14151111111", 1);
+  private static final SignalProtocolAddress BOB   = new SignalProtocolAddress("+14151111112", 1);
+
+  private final InMemorySignalProtocolStore aliceStore;
+  private final InMemorySignalProtocolStore bobStore;
+  private final ECKeyPair                   preKey;
+  private final ECKeyPair                   signedPreKey;
+  private final byte[]                      signedPreKeySignature;
+  private final List<String>                cases = new ArrayList<>();
+
+  private GenerateTranscript() throws Exception {
+    aliceStore = new InMemorySignalProtocolStore(KeyHelper.generateIdentityKeyPair(),
+                                                 KeyHelper.generateRegistrationId(false));
+    bobStore   = new InMemorySignalProtocolStore(KeyHelper.generateIdentityKeyPair(),
+                                                 KeyHelper.generateRegistrationId(false));
+
+    preKey                = Curve.generateKeyPair();
+    signedPreKey          = Curve.generateKeyPair();
+    signedPreKeySignature = Curve.calculateSignature(bobStore.getIdentityKeyPair().getPrivateKey(),
+                                                     signedPreKey.getPublicKey().serialize());
+
+    bobStore.storePreKey(PRE_KEY_ID, new PreKeyRecord(PRE_KEY_ID, preKey));
+    bobStore.storeSignedPreKey(SIGNED_PRE_KEY_ID,
+                               new SignedPreKeyRecord(SIGNED_PRE_KEY_ID, 0, signedPreKey,
+                                                      signedPreKeySignature));
+
+    PreKeyBundle bundle = new PreKeyBundle(bobStore.getLocalRegistrationId(), BOB.getDeviceId(),
+                                           PRE_KEY_ID, preKey.getPublicKey(),
+                                           SIGNED_PRE_KEY_ID, signedPreKey.getPublicKey(),
+                                           signedPreKeySignature,
+                                           bobStore.getIdentityKeyPair().getPublicKey());
+    new SessionBuilder(aliceStore, BOB).process(bundle);
+  }
+
+  private void send(boolean fromAlice, String name, String plaintext) throws Exception {
+    InMemorySignalProtocolStore from        = fromAlice ? aliceStore : bobStore;
+    InMemorySignalProtocolStore to          = fromAlice ? bobStore : aliceStore;
+    SignalProtocolAddress       fromAddress = fromAlice ? ALICE : BOB;
+    SignalProtocolAddress       toAddress   = fromAlice ? BOB : ALICE;
+    byte[]                      message     = plaintext.getBytes(StandardCharsets.UTF_8);
+
+    List<String> encryptCase = caseBase(from, toAddress, name, "encrypt");
+    List<String> decryptCase = caseBase(to, fromAddress, name, "decrypt");
+
+    CiphertextMessage ciphertext = new SessionCipher(from, toAddress).encrypt(message);
+
+    byte[]      decrypted;
+    ECPublicKey senderRatchetKey;
+    if (ciphertext.getType() == CiphertextMessage.PREKEY_TYPE) {
+      PreKeySignalMessage preKeyMessage = new PreKeySignalMessage(ciphertext.serialize());
+      senderRatchetKey = preKeyMessage.getWhisperMessage().getSenderRatchetKey();
+      decrypted        = new SessionCipher(to, fromAddress).decrypt(preKeyMessage);
+    } else {
+      SignalMessage signalMessage = new SignalMessage(ciphertext.serialize());
+      senderRatchetKey = signalMessage.getSenderRatchetKey();
+      decrypted        = new SessionCipher(to, fromAddress).decrypt(signalMessage);
+    }
+    if (!Arrays.equals(decrypted, message)) {
+      throw new AssertionError("message did not round trip");
+    }
+
+    for (List<String> c : Arrays.asList(encryptCase, decryptCase)) {
+      c.add(field("message_type", Integer.toString(ciphertext.getType())));
+      c.add(field("ciphertext", string(hex(ciphertext.serialize()))));
+      c.add(field("plaintext", string(hex(message))));
+    }
+
+    SessionState sender = from.loadSession(toAddress).getSessionState();
+    encryptCase.add(field("expected_state", object(
+        field("session_version", Integer.toString(sender.getSessionVersion())),
+        field("root_key", string(hex(sender.getRootKey().getKeyBytes()))),
+        field("sender_chain_key", string(hex(sender.getSenderChainKey().getKey()))),
+        field("sender_chain_index", Integer.toString(sender.getSenderChainKey().getIndex())))));
+
+    SessionState receiver      = to.loadSession(fromAddress).getSessionState();
+    ChainKey     receiverChain = receiver.getReceiverChainKey(senderRatchetKey);
+    decryptCase.add(field("expected_state", object(
+        field("session_version", Integer.toString(receiver.getSessionVersion())),
+        field("receiver_chain_key", string(hex(receiverChain.getKey()))),
+        field("receiver_chain_index", Integer.toString(receiverChain.getIndex())))));
+
+    cases.add(object(encryptCase.toArray(new String[0])));
+    cases.add(object(decryptCase.toArray(new String[0])));
+  }
+
+  private List<String> caseBase(InMemorySignalProtocolStore store, SignalProtocolAddress remote,
+                                String name, String kind)
+  {
+    IdentityKeyPair identity = store.getIdentityKeyPair();
+    List<String>    fields   = new ArrayList<>();
+
+    fields.add(field("name", string(name)));
+    fields.add(field("kind", string(kind)));
+    fields.add(field("local_identity", object(
+        field("public", string(hex(identity.getPublicKey().serialize()))),
+        field("private", string(hex(identity.getPrivateKey().serialize()))))));
+    fields.add(field("local_registration_id", Integer.toString(store.getLocalRegistrationId())));
+    fields.add(field("remote_address", object(
+        field("name", string(remote.getName())),
+        field("device_id", Integer.toString(remote.getDeviceId())))));
+
+    if (store.containsSession(remote)) {
+      SessionRecord record = store.loadSession(remote);
+      fields.add(field("session_record", string(hex(record.serialize()))));
+    } else {
+      fields.add(field("session_record", "null"));
+      fields.add(field("pre_keys", "[" + object(
+          field("id", Integer.toString(PRE_KEY_ID)),
+          field("public", string(hex(preKey.getPublicKey().serialize()))),
+          field("private", string(hex(preKey.getPrivateKey().serialize())))) + "]"));
+      fields.add(field("signed_pre_keys", "[" + object(
+          field("id", Integer.toString(SIGNED_PRE_KEY_ID)),
+          field("public", string(hex(signedPreKey.getPublicKey().serialize()))),
+          field("private", string(hex(signedPreKey.getPrivateKey().serialize()))),
+          field("signature", string(hex(signedPreKeySignature))),
+          field("timestamp", "0")) + "]"));
+    }
+    return fields;
+  }
+
+  private static String hex(byte[] bytes) {
+    StringBuilder builder = new StringBuilder();
+    for (byte b : bytes) builder.append(String.format("%02x", b & 0xff));
+    return builder.toString();
+  }
+
+  // Names, hex strings and labels never need escaping.
+  private static String string(String value) {
+    return "\"" + value + "\"";
+  }
+
+  private static String field(String name, String value) {
+    return string(name) + ": " + value;
+  }
+
+  private static String object(String... fields) {
+    return "{" + String.join(", ", fields) + "}";
+  }
+
+  public static void main(String[] args) throws Exception {
+    if (args.length != 2) {
+      System.err.println("usage: GenerateTranscript <output.json> <producer>");
+      System.exit(1);
+    }
+
+    GenerateTranscript transcript = new GenerateTranscript();
+    transcript.send(true, "first pre-key message", "hi bob");
+    transcript.send(true, "second pre-key message", "still there?");
+    transcript.send(false, "first reply", "hello alice");
+    transcript.send(true, "after ratchet", "ratcheted");
+    transcript.send(false, "second ratchet", "and again");
+
+    String json = object(field("format_version", "1"),
+                         field("producer", string(args[1])),
+                         field("cases", "[\n" + String.join(",\n", transcript.cases) + "\n]"));
+
+    try (FileOutputStream out = new FileOutputStream(args[0])) {
+      out.write((json + "\n").getBytes(StandardCharsets.UTF_8));
+    }
+  }
+}
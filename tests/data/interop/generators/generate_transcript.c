@@ -0,0 +1,312 @@
+/*
+ * Copyright (C) 2020 Signal Messenger, LLC.
+ * All rights reserved.
+ *
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+/*
+ * Writes an interop transcript in the format described in tests/data/interop/README.md of
+ * libsignal-protocol-rust.
+ *
+ * Build inside libsignal-protocol-c's tests directory, linked with test_common.c for its
+ * OpenSSL crypto provider and in-memory stores, and run with the output path and the producer
+ * string as arguments, e.g. "c-2.3.3.json" "libsignal-protocol-c 2.3.3". The exchange is the
+ * same as the one in rust.json: two pre-key messages from Alice, then replies in turn.
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "signal_protocol.h"
+#include "curve.h"
+#include "key_helper.h"
+#include "protocol.h"
+#include "ratchet.h"
+#include "session_builder.h"
+#include "session_cipher.h"
+#include "session_pre_key.h"
+#include "session_record.h"
+#include "session_state.h"
+#include "test_common.h"
+
+#define PRE_KEY_ID 31337
+#define SIGNED_PRE_KEY_ID 22
+
+#define CHECK(expr) do { \
+        if((expr) < 0) { \
+            fprintf(stderr, "%s:%d: %s failed\n", __FILE__, __LINE__, #expr); \
+            exit(1); \
+        } \
+    } while(0)
+
+static signal_context *global_context;
+static ec_key_pair *pre_key;
+static ec_key_pair *signed_pre_key;
+static signal_buffer *signed_pre_key_signature;
+
+static signal_protocol_address alice_address = { "+14151111111", 12, 1 };
+static signal_protocol_address bob_address = { "+14151111112", 12, 1 };
+
+static FILE *out;
+static int first_case = 1;
+
+static void write_hex(const uint8_t *data, size_t len)
+{
+    size_t i;
+    fputc('"', out);
+    for(i = 0; i < len; i++) {
+        fprintf(out, "%02x", data[i]);
+    }
+    fputc('"', out);
+}
+
+static void write_buffer(signal_buffer *buffer)
+{
+    write_hex(signal_buffer_data(buffer), signal_buffer_len(buffer));
+}
+
+static void write_public_key(ec_public_key *key)
+{
+    signal_buffer *buffer = 0;
+    CHECK(ec_public_key_serialize(&buffer, key));
+    write_buffer(buffer);
+    signal_buffer_free(buffer);
+}
+
+static void write_private_key(ec_private_key *key)
+{
+    signal_buffer *buffer = 0;
+    CHECK(ec_private_key_serialize(&buffer, key));
+    write_buffer(buffer);
+    signal_buffer_free(buffer);
+}
+
+/* Writes the fields describing the acting party before the operation, leaving the object open. */
+static void write_case_base(signal_protocol_store_context *store,
+        signal_protocol_address *remote, const char *name, const char *kind)
+{
+    ratchet_identity_key_pair *identity = 0;
+    uint32_t registration_id = 0;
+
+    CHECK(signal_protocol_identity_get_key_pair(store, &identity));
+    CHECK(signal_protocol_identity_get_local_registration_id(store, &registration_id));
+
+    fprintf(out, "%s{\"name\": \"%s\", \"kind\": \"%s\", ", first_case ? "" : ",\n", name, kind);
+    first_case = 0;
+
+    fprintf(out, "\"local_identity\": {\"public\": ");
+    write_public_key(ratchet_identity_key_pair_get_public(identity));
+    fprintf(out, ", \"private\": ");
+    write_private_key(ratchet_identity_key_pair_get_private(identity));
+    fprintf(out, "}, \"local_registration_id\": %u, ", registration_id);
+    fprintf(out, "\"remote_address\": {\"name\": \"%s\", \"device_id\": %d}, ",
+            remote->name, remote->device_id);
+
+    if(signal_protocol_session_contains_session(store, remote) == 1) {
+        session_record *record = 0;
+        signal_buffer *buffer = 0;
+        CHECK(signal_protocol_session_load_session(store, &record, remote));
+        CHECK(session_record_serialize(&buffer, record));
+        fprintf(out, "\"session_record\": ");
+        write_buffer(buffer);
+        signal_buffer_free(buffer);
+        SIGNAL_UNREF(record);
+    }
+    else {
+        fprintf(out, "\"session_record\": null, \"pre_keys\": [{\"id\": %d, \"public\": ", PRE_KEY_ID);
+        write_public_key(ec_key_pair_get_public(pre_key));
+        fprintf(out, ", \"private\": ");
+        write_private_key(ec_key_pair_get_private(pre_key));
+        fprintf(out, "}], \"signed_pre_keys\": [{\"id\": %d, \"public\": ", SIGNED_PRE_KEY_ID);
+        write_public_key(ec_key_pair_get_public(signed_pre_key));
+        fprintf(out, ", \"private\": ");
+        write_private_key(ec_key_pair_get_private(signed_pre_key));
+        fprintf(out, ", \"signature\": ");
+        write_buffer(signed_pre_key_signature);
+        fprintf(out, ", \"timestamp\": 0}]");
+    }
+
+    SIGNAL_UNREF(identity);
+}
+
+static void write_message(int type, signal_buffer *ciphertext, const char *plaintext)
+{
+    fprintf(out, ", \"message_type\": %d, \"ciphertext\": ", type);
+    write_buffer(ciphertext);
+    fprintf(out, ", \"plaintext\": ");
+    write_hex((const uint8_t *)plaintext, strlen(plaintext));
+}
+
+static session_state *current_state(signal_protocol_store_context *store,
+        signal_protocol_address *remote, session_record **record)
+{
+    CHECK(signal_protocol_session_load_session(store, record, remote));
+    return session_record_get_state(*record);
+}
+
+static void exchange(signal_protocol_store_context *from, signal_protocol_address *from_address,
+        signal_protocol_store_context *to, signal_protocol_address *to_address,
+        const char *name, const char *plaintext)
+{
+    session_cipher *encrypting = 0;
+    session_cipher *decrypting = 0;
+    ciphertext_message *message = 0;
+    signal_buffer *serialized;
+    signal_buffer *decrypted = 0;
+    signal_message *signal_msg = 0;
+    pre_key_signal_message *pre_key_message = 0;
+    ec_public_key *sender_ratchet_key;
+    session_record *record = 0;
+    session_state *state;
+    ratchet_chain_key *chain_key;
+    signal_buffer *buffer = 0;
+    int type;
+
+    /* Both cases need the state before the operation, so the encrypt case is written first and
+     * the decrypt case's session record is taken before decrypting. */
+    CHECK(session_cipher_create(&encrypting, from, to_address, global_context));
+    CHECK(session_cipher_create(&decrypting, to, from_address, global_context));
+
+    write_case_base(from, to_address, name, "encrypt");
+    CHECK(session_cipher_encrypt(encrypting, (const uint8_t *)plaintext, strlen(plaintext), &message));
+    type = ciphertext_message_get_type(message);
+    serialized = ciphertext_message_get_serialized(message);
+    write_message(type, serialized, plaintext);
+
+    state = current_state(from, to_address, &record);
+    CHECK(ratchet_root_key_get_key(session_state_get_root_key(state), &buffer));
+    fprintf(out, ", \"expected_state\": {\"session_version\": %u, \"root_key\": ",
+            session_state_get_session_version(state));
+    write_buffer(buffer);
+    signal_buffer_free(buffer);
+    buffer = 0;
+    chain_key = session_state_get_sender_chain_key(state);
+    CHECK(ratchet_chain_key_get_key(chain_key, &buffer));
+    fprintf(out, ", \"sender_chain_key\": ");
+    write_buffer(buffer);
+    signal_buffer_free(buffer);
+    buffer = 0;
+    fprintf(out, ", \"sender_chain_index\": %u}}", ratchet_chain_key_get_index(chain_key));
+    SIGNAL_UNREF(record);
+
+    write_case_base(to, from_address, name, "decrypt");
+    write_message(type, serialized, plaintext);
+    if(type == CIPHERTEXT_PREKEY_TYPE) {
+        CHECK(pre_key_signal_message_deserialize(&pre_key_message,
+                signal_buffer_data(serialized), signal_buffer_len(serialized), global_context));
+        sender_ratchet_key = signal_message_get_sender_ratchet_key(
+                pre_key_signal_message_get_signal_message(pre_key_message));
+        CHECK(session_cipher_decrypt_pre_key_signal_message(decrypting, pre_key_message, 0, &decrypted));
+    }
+    else {
+        CHECK(signal_message_deserialize(&signal_msg,
+                signal_buffer_data(serialized), signal_buffer_len(serialized), global_context));
+        sender_ratchet_key = signal_message_get_sender_ratchet_key(signal_msg);
+        CHECK(session_cipher_decrypt_signal_message(decrypting, signal_msg, 0, &decrypted));
+    }
+    if(signal_buffer_len(decrypted) != strlen(plaintext) ||
+            memcmp(signal_buffer_data(decrypted), plaintext, strlen(plaintext)) != 0) {
+        fprintf(stderr, "%s: message did not round trip\n", name);
+        exit(1);
+    }
+
+    state = current_state(to, from_address, &record);
+    chain_key = session_state_get_receiver_chain_key(state, sender_ratchet_key);
+    if(!chain_key) {
+        fprintf(stderr, "%s: no receiver chain for the sender\n", name);
+        exit(1);
+    }
+    CHECK(ratchet_chain_key_get_key(chain_key, &buffer));
+    fprintf(out, ", \"expected_state\": {\"session_version\": %u, \"receiver_chain_key\": ",
+            session_state_get_session_version(state));
+    write_buffer(buffer);
+    signal_buffer_free(buffer);
+    fprintf(out, ", \"receiver_chain_index\": %u}}", ratchet_chain_key_get_index(chain_key));
+    SIGNAL_UNREF(record);
+
+    signal_buffer_free(decrypted);
+    SIGNAL_UNREF(pre_key_message);
+    SIGNAL_UNREF(signal_msg);
+    SIGNAL_UNREF(message);
+    session_cipher_free(encrypting);
+    session_cipher_free(decrypting);
+}
+
+int main(int argc, char **argv)
+{
+    signal_protocol_store_context *alice_store = 0;
+    signal_protocol_store_context *bob_store = 0;
+    ratchet_identity_key_pair *bob_identity = 0;
+    session_pre_key *pre_key_record = 0;
+    session_signed_pre_key *signed_pre_key_record = 0;
+    session_pre_key_bundle *bundle = 0;
+    session_builder *builder = 0;
+    signal_buffer *signed_public = 0;
+    uint32_t bob_registration_id = 0;
+
+    if(argc != 3) {
+        fprintf(stderr, "usage: %s <output.json> <producer>\n", argv[0]);
+        return 1;
+    }
+
+    CHECK(signal_context_create(&global_context, 0));
+    setup_test_crypto_provider(global_context);
+    setup_test_store_context(&alice_store, global_context);
+    setup_test_store_context(&bob_store, global_context);
+
+    CHECK(curve_generate_key_pair(global_context, &pre_key));
+    CHECK(curve_generate_key_pair(global_context, &signed_pre_key));
+    CHECK(signal_protocol_identity_get_key_pair(bob_store, &bob_identity));
+    CHECK(signal_protocol_identity_get_local_registration_id(bob_store, &bob_registration_id));
+    CHECK(ec_public_key_serialize(&signed_public, ec_key_pair_get_public(signed_pre_key)));
+    CHECK(curve_calculate_signature(global_context, &signed_pre_key_signature,
+            ratchet_identity_key_pair_get_private(bob_identity),
+            signal_buffer_data(signed_public), signal_buffer_len(signed_public)));
+
+    CHECK(session_pre_key_create(&pre_key_record, PRE_KEY_ID, pre_key));
+    CHECK(signal_protocol_pre_key_store_key(bob_store, pre_key_record));
+    CHECK(session_signed_pre_key_create(&signed_pre_key_record, SIGNED_PRE_KEY_ID, 0,
+            signed_pre_key, signal_buffer_data(signed_pre_key_signature),
+            signal_buffer_len(signed_pre_key_signature)));
+    CHECK(signal_protocol_signed_pre_key_store_key(bob_store, signed_pre_key_record));
+
+    CHECK(session_pre_key_bundle_create(&bundle, bob_registration_id, bob_address.device_id,
+            PRE_KEY_ID, ec_key_pair_get_public(pre_key),
+            SIGNED_PRE_KEY_ID, ec_key_pair_get_public(signed_pre_key),
+            signal_buffer_data(signed_pre_key_signature), signal_buffer_len(signed_pre_key_signature),
+            ratchet_identity_key_pair_get_public(bob_identity)));
+    CHECK(session_builder_create(&builder, alice_store, &bob_address, global_context));
+    CHECK(session_builder_process_pre_key_bundle(builder, bundle));
+
+    out = fopen(argv[1], "w");
+    if(!out) {
+        perror(argv[1]);
+        return 1;
+    }
+    fprintf(out, "{\"format_version\": 1, \"producer\": \"%s\", \"cases\": [\n", argv[2]);
+
+    exchange(alice_store, &alice_address, bob_store, &bob_address, "first pre-key message", "hi bob");
+    exchange(alice_store, &alice_address, bob_store, &bob_address, "second pre-key message", "still there?");
+    exchange(bob_store, &bob_address, alice_store, &alice_address, "first reply", "hello alice");
+    exchange(alice_store, &alice_address, bob_store, &bob_address, "after ratchet", "ratcheted");
+    exchange(bob_store, &bob_address, alice_store, &alice_address, "second ratchet", "and again");
+
+    fprintf(out, "\n]}\n");
+    fclose(out);
+
+    session_builder_free(builder);
+    SIGNAL_UNREF(bundle);
+    SIGNAL_UNREF(signed_pre_key_record);
+    SIGNAL_UNREF(pre_key_record);
+    SIGNAL_UNREF(bob_identity);
+    signal_buffer_free(signed_public);
+    signal_buffer_free(signed_pre_key_signature);
+    SIGNAL_UNREF(signed_pre_key);
+    SIGNAL_UNREF(pre_key);
+    signal_protocol_store_context_destroy(alice_store);
+    signal_protocol_store_context_destroy(bob_store);
+    signal_context_destroy(global_context);
+    return 0;
+}
@@ -0,0 +1,142 @@
+#
+# Copyright 2020 Signal Messenger, LLC.
+# SPDX-License-Identifier: GPL-3.0-only
+#
+
+"""Second VXEdDSA signer used to cross-check the vectors in
+src/curve/curve25519.rs (test_vrf_signature).
+
+This follows the XEdDSA and VXEdDSA specification directly, using Python
+integers and hashlib only; it shares no code with the Rust implementation.
+
+    python3 vxeddsa.py <private key hex> <message hex> <Z hex>
+
+prints the 96-byte signature and the 32-byte VRF output, both hex encoded.
+The test vectors use Z = 00 01 02 .. 3f.
+
+It was written alongside the Rust code, so agreement only rules out bugs
+that are not shared by both. The vectors have not yet been compared against
+libsignal-protocol-c's VXEdDSA implementation (vxeddsa_fast_test in
+curve25519/ed25519/tests/internal_fast_tests.c).
+"""
+
+import hashlib, sys
+
+p = 2**255 - 19
+q = 2**252 + 27742317777372353535851937790883648493
+d = (-121665 * pow(121666, p - 2, p)) % p
+MA = 486662
+I = pow(2, (p - 1) // 4, p)
+
+
+def inv(x):
+    return pow(x, p - 2, p)
+
+
+def sqrt(a):
+    a %= p
+    x = pow(a, (p + 3) // 8, p)
+    if (x * x - a) % p != 0:
+        x = x * I % p
+    if (x * x - a) % p != 0:
+        return None
+    return x
+
+
+def is_square(a):
+    a %= p
+    return a == 0 or pow(a, (p - 1) // 2, p) == 1
+
+
+def recover_x(y, s):
+    x2 = (y * y - 1) * inv(d * y * y + 1) % p
+    x = sqrt(x2)
+    if x is None:
+        return None
+    if x == 0 and s == 1:
+        return None
+    if x & 1 != s:
+        x = p - x
+    return x
+
+
+def add(P, Q):
+    x1, y1 = P
+    x2, y2 = Q
+    t = d * x1 * x2 * y1 * y2 % p
+    x3 = (x1 * y2 + x2 * y1) * inv(1 + t) % p
+    y3 = (y1 * y2 + x1 * x2) * inv(1 - t) % p
+    return (x3, y3)
+
+
+def mul(k, P):
+    R = (0, 1)
+    while k:
+        if k & 1:
+            R = add(R, P)
+        P = add(P, P)
+        k >>= 1
+    return R
+
+
+def neg(P):
+    return ((-P[0]) % p, P[1])
+
+
+def enc(P):
+    x, y = P
+    return (y | ((x & 1) << 255)).to_bytes(32, "little")
+
+
+By = 4 * inv(5) % p
+B = (recover_x(By, 0), By)
+
+
+def hash_i(i, data):
+    prefix = bytes([0xFF - i]) + b"\xff" * 31
+    return hashlib.sha512(prefix + data).digest()
+
+
+def elligator2(r):
+    u1 = (-MA) * inv(1 + 2 * r * r) % p
+    w1 = u1 * (u1 * u1 + MA * u1 + 1) % p
+    if is_square(w1):
+        return u1
+    return (-MA - u1) % p
+
+
+def hash_to_point(X):
+    h = hash_i(2, X)
+    hv = int.from_bytes(h[:32], "little")
+    r = hv & (2**255 - 1)
+    s = hv >> 255
+    u = elligator2(r % p)
+    y = (u - 1) * inv(u + 1) % p
+    P = (recover_x(y, s), y)
+    return mul(8, P)
+
+
+def vxeddsa_sign(k_bytes, M, Z):
+    k = int.from_bytes(k_bytes, "little")
+    E = mul(k, B)
+    A = (E[0] if E[0] & 1 == 0 else p - E[0], E[1])
+    a = k % q if E[0] & 1 == 0 else (-k) % q
+    Ab = enc(A)
+    Bv = hash_to_point(Ab + M)
+    V = mul(a, Bv)
+    r = int.from_bytes(hash_i(3, a.to_bytes(32, "little") + enc(V) + Z), "little") % q
+    R = mul(r, B)
+    Rv = mul(r, Bv)
+    h = int.from_bytes(hash_i(4, Ab + enc(V) + enc(R) + enc(Rv) + M), "little") % q
+    s = (r + h * a) % q
+    v = hash_i(5, enc(mul(8, V)))[:32]
+    return enc(V) + h.to_bytes(32, "little") + s.to_bytes(32, "little"), v
+
+
+if __name__ == "__main__":
+    k = bytes.fromhex(sys.argv[1])
+    M = bytes.fromhex(sys.argv[2])
+    Z = bytes.fromhex(sys.argv[3])
+    sig, v = vxeddsa_sign(k, M, Z)
+    print(sig.hex())
+    print(v.hex())
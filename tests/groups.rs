@@ -12,6 +12,7 @@ use rand::rngs::OsRng;
 use rand::seq::SliceRandom;
 use rand::Rng;
 use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
 use support::test_in_memory_protocol_store;
 
 #[test]
@@ -120,7 +121,13 @@ fn group_no_recv_session() -> Result<(), SignalProtocolError> {
         None,
     )?;
 
-    let bob_plaintext = group_decrypt(&alice_ciphertext, &mut bob_store, &group_sender, None);
+    let bob_plaintext = group_decrypt(
+        &alice_ciphertext,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    );
 
     assert!(bob_plaintext.is_err());
 
@@ -159,10 +166,224 @@ fn group_basic_encrypt_decrypt() -> Result<(), SignalProtocolError> {
         None,
     )?;
 
-    let bob_plaintext = group_decrypt(&alice_ciphertext, &mut bob_store, &group_sender, None)?;
+    let bob_plaintext = group_decrypt(
+        &alice_ciphertext,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    )?;
+
+    assert_eq!(String::from_utf8(bob_plaintext).unwrap(), "space camp?");
+
+    Ok(())
+}
+
+#[test]
+fn group_decrypt_preview_and_commit() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1);
+    let group_sender =
+        SenderKeyName::new("summer camp planning committee".to_owned(), sender_address)?;
+
+    let mut alice_store = test_in_memory_protocol_store();
+    let mut bob_store = test_in_memory_protocol_store();
+
+    let sent_distribution_message =
+        create_sender_key_distribution_message(&group_sender, &mut alice_store, &mut csprng, None)?;
+    process_sender_key_distribution_message(
+        &group_sender,
+        &SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())?,
+        &mut bob_store,
+        None,
+    )?;
+
+    let alice_ciphertext = group_encrypt(
+        &mut alice_store,
+        &group_sender,
+        "space camp?".as_bytes(),
+        &mut csprng,
+        None,
+    )?;
 
+    let (bob_plaintext, delta) = group_decrypt_preview(
+        &alice_ciphertext,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    )?;
     assert_eq!(String::from_utf8(bob_plaintext).unwrap(), "space camp?");
 
+    // Nothing was saved, so the message can be previewed again.
+    let (_, second_delta) = group_decrypt_preview(
+        &alice_ciphertext,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let delta = DecryptDelta::deserialize(&delta.serialize()?)?;
+    commit_decrypt_delta(
+        &delta,
+        &mut bob_store.session_store,
+        &mut bob_store.identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.sender_key_store,
+        None,
+    )?;
+
+    assert!(matches!(
+        group_decrypt(
+            &alice_ciphertext,
+            &mut bob_store,
+            &group_sender,
+            SystemTime::now(),
+            None
+        ),
+        Err(SignalProtocolError::DuplicatedMessage { .. })
+    ));
+    assert_eq!(
+        commit_decrypt_delta(
+            &second_delta,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.sender_key_store,
+            None,
+        ),
+        Err(SignalProtocolError::StaleDecryptDelta)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn group_decrypt_batch_round_trips() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let mut alice_store = test_in_memory_protocol_store();
+    let mut bob_store = test_in_memory_protocol_store();
+
+    let mut queue = Vec::new();
+    for group in &["summer camp", "winter camp"] {
+        let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1);
+        let group_sender = SenderKeyName::new(group.to_string(), sender_address)?;
+
+        let sent_distribution_message = create_sender_key_distribution_message(
+            &group_sender,
+            &mut alice_store,
+            &mut csprng,
+            None,
+        )?;
+        process_sender_key_distribution_message(
+            &group_sender,
+            &SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())?,
+            &mut bob_store,
+            None,
+        )?;
+
+        for i in 0..3 {
+            let ciphertext = group_encrypt(
+                &mut alice_store,
+                &group_sender,
+                format!("{} {}", group, i).as_bytes(),
+                &mut csprng,
+                None,
+            )?;
+            queue.push((group_sender.clone(), ciphertext));
+        }
+    }
+    queue.swap(1, 4);
+    queue.push(queue[0].clone());
+    let unknown_group = SenderKeyName::new(
+        "no such camp".to_owned(),
+        ProtocolAddress::new("+14159999111".to_owned(), 1),
+    )?;
+    queue.push((unknown_group, queue[0].1.clone()));
+
+    let results = group_decrypt_batch(&queue, &mut bob_store, SystemTime::now(), None)?;
+    let plaintexts: Vec<_> = results[..6]
+        .iter()
+        .map(|r| String::from_utf8(r.clone().expect("decrypts")).unwrap())
+        .collect();
+    assert_eq!(
+        plaintexts,
+        vec![
+            "summer camp 0",
+            "winter camp 1",
+            "summer camp 2",
+            "winter camp 0",
+            "summer camp 1",
+            "winter camp 2"
+        ]
+    );
+    assert!(matches!(
+        results[6],
+        Err(SignalProtocolError::DuplicatedMessage { .. })
+    ));
+    assert_eq!(results[7], Err(SignalProtocolError::InvalidSenderKeyId));
+
+    // The updated records were written back.
+    assert!(matches!(
+        group_decrypt(
+            &queue[1].1,
+            &mut bob_store,
+            &queue[1].0,
+            SystemTime::now(),
+            None
+        ),
+        Err(SignalProtocolError::DuplicatedMessage { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn group_verify_signatures_batch() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let mut messages = Vec::new();
+    let mut signing_keys = Vec::new();
+    for (i, sender) in ["+14159999111", "+14159999222"].iter().enumerate() {
+        let sender_address = ProtocolAddress::new(sender.to_string(), 1);
+        let group_sender =
+            SenderKeyName::new("summer camp planning committee".to_owned(), sender_address)?;
+        let mut store = test_in_memory_protocol_store();
+        let distribution_message =
+            create_sender_key_distribution_message(&group_sender, &mut store, &mut csprng, None)?;
+        signing_keys.push(*distribution_message.signing_key()?);
+
+        for j in 0..20 {
+            let ciphertext = group_encrypt(
+                &mut store,
+                &group_sender,
+                format!("message {} from sender {}", j, i).as_bytes(),
+                &mut csprng,
+                None,
+            )?;
+            messages.push((SenderKeyMessage::try_from(&ciphertext[..])?, i));
+        }
+    }
+
+    let mut entries: Vec<_> = messages
+        .iter()
+        .map(|(message, sender)| (message, &signing_keys[*sender]))
+        .collect();
+    SenderKeyMessage::verify_signatures_batch(&entries, &mut csprng)?;
+
+    // Attribute two of the first sender's messages to the second sender.
+    entries[3].1 = &signing_keys[1];
+    entries[17].1 = &signing_keys[1];
+    match SenderKeyMessage::verify_signatures_batch(&entries, &mut csprng) {
+        Err(SignalProtocolError::BatchSignatureValidationFailed(failed)) => {
+            assert_eq!(failed, vec![3, 17])
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
     Ok(())
 }
 
@@ -203,7 +424,13 @@ fn group_large_messages() -> Result<(), SignalProtocolError> {
         None,
     )?;
 
-    let bob_plaintext = group_decrypt(&alice_ciphertext, &mut bob_store, &group_sender, None)?;
+    let bob_plaintext = group_decrypt(
+        &alice_ciphertext,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    )?;
 
     assert_eq!(bob_plaintext, large_message);
 
@@ -256,18 +483,47 @@ fn group_basic_ratchet() -> Result<(), SignalProtocolError> {
         None,
     )?;
 
-    let bob_plaintext1 = group_decrypt(&alice_ciphertext1, &mut bob_store, &group_sender, None)?;
+    let bob_plaintext1 = group_decrypt(
+        &alice_ciphertext1,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    )?;
     assert_eq!(String::from_utf8(bob_plaintext1).unwrap(), "swim camp");
 
     assert_eq!(
-        group_decrypt(&alice_ciphertext1, &mut bob_store, &group_sender, None),
-        Err(SignalProtocolError::DuplicatedMessage(1, 0))
+        group_decrypt(
+            &alice_ciphertext1,
+            &mut bob_store,
+            &group_sender,
+            SystemTime::now(),
+            None
+        ),
+        Err(SignalProtocolError::DuplicatedMessage {
+            sender: group_sender.sender()?,
+            message_type: CiphertextMessageType::SenderKey,
+            chain_index: 1,
+            counter: 0,
+        })
     );
 
-    let bob_plaintext3 = group_decrypt(&alice_ciphertext3, &mut bob_store, &group_sender, None)?;
+    let bob_plaintext3 = group_decrypt(
+        &alice_ciphertext3,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    )?;
     assert_eq!(String::from_utf8(bob_plaintext3).unwrap(), "ninja camp");
 
-    let bob_plaintext2 = group_decrypt(&alice_ciphertext2, &mut bob_store, &group_sender, None)?;
+    let bob_plaintext2 = group_decrypt(
+        &alice_ciphertext2,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    )?;
     assert_eq!(String::from_utf8(bob_plaintext2).unwrap(), "robot camp");
 
     Ok(())
@@ -316,7 +572,13 @@ fn group_late_join() -> Result<(), SignalProtocolError> {
         None,
     )?;
 
-    let bob_plaintext = group_decrypt(&alice_ciphertext, &mut bob_store, &group_sender, None)?;
+    let bob_plaintext = group_decrypt(
+        &alice_ciphertext,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    )?;
     assert_eq!(String::from_utf8(bob_plaintext).unwrap(), "welcome bob");
 
     Ok(())
@@ -367,6 +629,7 @@ fn group_out_of_order() -> Result<(), SignalProtocolError> {
             &ciphertext,
             &mut bob_store,
             &group_sender,
+            SystemTime::now(),
             None,
         )?);
     }
@@ -425,7 +688,14 @@ fn group_too_far_in_the_future() -> Result<(), SignalProtocolError> {
         None,
     )?;
 
-    assert!(group_decrypt(&alice_ciphertext, &mut bob_store, &group_sender, None).is_err());
+    assert!(group_decrypt(
+        &alice_ciphertext,
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None
+    )
+    .is_err());
 
     Ok(())
 }
@@ -471,6 +741,7 @@ fn group_message_key_limit() -> Result<(), SignalProtocolError> {
             &ciphertexts[1000],
             &mut bob_store,
             &group_sender,
+            SystemTime::now(),
             None,
         )?)
         .unwrap(),
@@ -481,12 +752,95 @@ fn group_message_key_limit() -> Result<(), SignalProtocolError> {
             &ciphertexts[ciphertexts.len() - 1],
             &mut bob_store,
             &group_sender,
+            SystemTime::now(),
             None,
         )?)
         .unwrap(),
         "too many messages"
     );
-    assert!(group_decrypt(&ciphertexts[0], &mut bob_store, &group_sender, None).is_err());
+    assert!(group_decrypt(
+        &ciphertexts[0],
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None
+    )
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn group_prune_expired_message_keys() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let sender_address = ProtocolAddress::new("+14159999111".to_owned(), 1);
+    let group_sender = SenderKeyName::new("summer camp".to_owned(), sender_address)?;
+
+    let mut alice_store = test_in_memory_protocol_store();
+    let mut bob_store = test_in_memory_protocol_store();
+
+    let sent_distribution_message =
+        create_sender_key_distribution_message(&group_sender, &mut alice_store, &mut csprng, None)?;
+    process_sender_key_distribution_message(
+        &group_sender,
+        &SenderKeyDistributionMessage::try_from(sent_distribution_message.serialized())?,
+        &mut bob_store,
+        None,
+    )?;
+
+    let mut ciphertexts = Vec::new();
+    for i in 0..3 {
+        ciphertexts.push(group_encrypt(
+            &mut alice_store,
+            &group_sender,
+            format!("message {}", i).as_bytes(),
+            &mut csprng,
+            None,
+        )?);
+    }
+    group_decrypt(
+        &ciphertexts[2],
+        &mut bob_store,
+        &group_sender,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let now = SystemTime::now();
+    let max_age = Duration::from_secs(60 * 60);
+
+    let mut record = bob_store
+        .load_sender_key(&group_sender, None)?
+        .expect("record");
+    record.prune_expired(now, max_age);
+    bob_store.store_sender_key(&group_sender, &record, None)?;
+    assert_eq!(
+        group_decrypt(
+            &ciphertexts[0],
+            &mut bob_store,
+            &group_sender,
+            SystemTime::now(),
+            None
+        )?,
+        b"message 0"
+    );
+
+    let mut record = bob_store
+        .load_sender_key(&group_sender, None)?
+        .expect("record");
+    record.prune_expired(now + 2 * max_age, max_age);
+    bob_store.store_sender_key(&group_sender, &record, None)?;
+    assert!(matches!(
+        group_decrypt(
+            &ciphertexts[1],
+            &mut bob_store,
+            &group_sender,
+            SystemTime::now(),
+            None
+        ),
+        Err(SignalProtocolError::DuplicatedMessage { .. })
+    ));
 
     Ok(())
 }
@@ -0,0 +1,66 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+//! Data-driven interoperability tests.
+//!
+//! Every `*.json` file in `tests/data/interop` is a transcript produced by some implementation
+//! of the protocol; see the README there for the format. Each one is replayed case by case from
+//! its recorded keys and session records with [`replay_session_transcript`].
+//!
+//! Only `rust.json`, recorded by this crate, is checked in so far; until a Java or C transcript
+//! joins it this is a regression test rather than an interoperability test.
+
+#![cfg(feature = "test-vectors")]
+
+use libsignal_protocol_rust::*;
+use serde_json::Value;
+use std::path::Path;
+
+fn fixture_dir() -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/interop")
+}
+
+#[test]
+fn interop_fixtures() {
+    let mut paths: Vec<_> = std::fs::read_dir(fixture_dir())
+        .expect("fixture directory")
+        .map(|entry| entry.expect("directory entry").path())
+        .filter(|path| path.extension() == Some("json".as_ref()))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no interop fixtures found");
+
+    for path in paths {
+        let transcript = std::fs::read_to_string(&path).expect("readable fixture");
+        let producer = serde_json::from_str::<Value>(&transcript)
+            .map(|fixture| fixture["producer"].clone())
+            .unwrap_or(Value::Null);
+        replay_session_transcript(&transcript)
+            .unwrap_or_else(|e| panic!("{} ({}): {}", path.display(), producer, e));
+    }
+}
+
+/// Write `rust.json` from a fresh transcript. Run with
+/// `cargo test --features test-vectors --test interop -- --ignored` after a change that is meant
+/// to alter the wire format or state transitions, and review the diff.
+#[test]
+#[ignore]
+fn regenerate_rust_fixture() -> Result<(), SignalProtocolError> {
+    let transcript = record_session_transcript(
+        [0x41; 32],
+        &[
+            (TranscriptParty::Alice, b"hi bob"),
+            (TranscriptParty::Alice, b"still there?"),
+            (TranscriptParty::Bob, b"hello alice"),
+            (TranscriptParty::Alice, b"ratcheted"),
+            (TranscriptParty::Bob, b"and again"),
+        ],
+    )?;
+    std::fs::write(fixture_dir().join("rust.json"), transcript + "\n")
+        .expect("writable fixture directory");
+    Ok(())
+}
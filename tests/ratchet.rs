@@ -53,7 +53,7 @@ fn test_ratcheting_session_as_bob() -> Result<(), SignalProtocolError> {
     let alice_identity_public = IdentityKey::decode(&alice_identity_public)?;
 
     let bob_parameters = BobSignalProtocolParameters::new(
-        bob_identity_key_pair,
+        &bob_identity_key_pair,
         bob_signed_prekey_pair,
         None, // one time pre key pair
         bob_ephemeral_pair,
@@ -122,7 +122,7 @@ fn test_ratcheting_session_as_alice() -> Result<(), SignalProtocolError> {
     let alice_base_key = KeyPair::from_public_and_private(&alice_base_public, &alice_base_private)?;
 
     let alice_parameters = AliceSignalProtocolParameters::new(
-        alice_identity_key_pair,
+        &alice_identity_key_pair,
         alice_base_key,
         bob_identity_public,
         bob_signed_prekey_public,
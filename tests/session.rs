@@ -9,7 +9,9 @@ mod support;
 
 use libsignal_protocol_rust::*;
 use rand::rngs::OsRng;
+use std::cell::Cell;
 use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
 use support::*;
 
 #[test]
@@ -51,6 +53,7 @@ fn test_basic_prekey_v3() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -175,6 +178,7 @@ fn test_basic_prekey_v3() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -215,6 +219,7 @@ fn test_basic_prekey_v3() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )
     .is_err());
@@ -265,6 +270,7 @@ fn test_bad_signed_pre_key_signature() -> Result<(), SignalProtocolError> {
             &mut alice_store.identity_store,
             &bob_pre_key_bundle,
             &mut csprng,
+            SystemTime::now(),
             None,
         )
         .is_err());
@@ -289,6 +295,7 @@ fn test_bad_signed_pre_key_signature() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -335,6 +342,7 @@ fn repeat_bundle_message_v3() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -445,6 +453,7 @@ fn bad_message_bundle() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -547,6 +556,7 @@ fn optional_one_time_prekey() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -590,6 +600,372 @@ fn optional_one_time_prekey() -> Result<(), SignalProtocolError> {
     Ok(())
 }
 
+#[test]
+fn ed25519_identity_keys() -> Result<(), SignalProtocolError> {
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut csprng = OsRng;
+
+    // Both Ed25519, then each side talking to a Djb identity.
+    for &(alice_ed25519, bob_ed25519) in &[(true, true), (true, false), (false, true)] {
+        let identity = |ed25519: bool, csprng: &mut OsRng| {
+            if ed25519 {
+                IdentityKeyPair::generate_ed25519(csprng)
+            } else {
+                IdentityKeyPair::generate(csprng)
+            }
+        };
+        let mut alice_store =
+            InMemSignalProtocolStore::new(identity(alice_ed25519, &mut csprng), 5)?;
+        let mut bob_store = InMemSignalProtocolStore::new(identity(bob_ed25519, &mut csprng), 5)?;
+
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            SystemTime::now(),
+            None,
+        )?;
+
+        let original_message = "L'homme est condamné à être libre";
+        let outgoing_message = encrypt(&mut alice_store, &bob_address, original_message)?;
+        assert_eq!(
+            outgoing_message.message_type(),
+            CiphertextMessageType::PreKey
+        );
+        let incoming_message = CiphertextMessage::PreKeySignalMessage(
+            PreKeySignalMessage::try_from(outgoing_message.serialize())?,
+        );
+        let ptext = decrypt(&mut bob_store, &alice_address, &incoming_message)?;
+        assert_eq!(String::from_utf8(ptext).unwrap(), original_message);
+
+        let bob_identity = bob_store
+            .get_identity(&alice_address, None)?
+            .expect("alice's identity saved");
+        assert_eq!(
+            bob_identity.public_key().key_type(),
+            if alice_ed25519 {
+                KeyType::Ed25519
+            } else {
+                KeyType::Djb
+            }
+        );
+
+        let bob_message = encrypt(&mut bob_store, &alice_address, "reply")?;
+        assert_eq!(bob_message.message_type(), CiphertextMessageType::Whisper);
+        let ptext = decrypt(&mut alice_store, &bob_address, &bob_message)?;
+        assert_eq!(String::from_utf8(ptext).unwrap(), "reply");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn verify_signed_pre_key_signatures_batch() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let mut bundles = Vec::new();
+    for i in 0..16 {
+        let identity = if i % 2 == 0 {
+            IdentityKeyPair::generate(&mut csprng)
+        } else {
+            IdentityKeyPair::generate_ed25519(&mut csprng)
+        };
+        let mut store = InMemSignalProtocolStore::new(identity, 5)?;
+        bundles.push(create_pre_key_bundle(&mut store, &mut csprng)?);
+    }
+    PreKeyBundle::verify_signed_pre_key_signatures(&bundles, &mut csprng)?;
+
+    // A signed prekey that was signed by a different identity.
+    let impostor = &bundles[5];
+    bundles[9] = PreKeyBundle::new(
+        impostor.registration_id()?,
+        impostor.device_id()?,
+        impostor.pre_key_id()?,
+        impostor.pre_key_public()?,
+        impostor.signed_pre_key_id()?,
+        impostor.signed_pre_key_public()?,
+        impostor.signed_pre_key_signature()?.to_vec(),
+        *bundles[9].identity_key()?,
+    )?;
+    match PreKeyBundle::verify_signed_pre_key_signatures(&bundles, &mut csprng) {
+        Err(SignalProtocolError::BatchSignatureValidationFailed(failed)) => {
+            assert_eq!(failed, vec![9])
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn external_identity_key_signer() -> Result<(), SignalProtocolError> {
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut csprng = OsRng;
+
+    let alice_signer =
+        ExternalIdentityKeySigner::spawn(IdentityKeyPair::generate(&mut csprng), OsRng);
+    let bob_signer =
+        ExternalIdentityKeySigner::spawn(IdentityKeyPair::generate(&mut csprng), OsRng);
+    let bob_identity = bob_signer.get_identity_key()?;
+
+    let mut alice_identity_store = ExternalIdentityKeyStore::new(alice_signer.clone(), 5);
+    let mut alice_session_store = InMemSessionStore::new();
+    let mut alice_pre_key_store = InMemPreKeyStore::new();
+    let mut alice_signed_pre_key_store = InMemSignedPreKeyStore::new();
+
+    let mut bob_identity_store = ExternalIdentityKeyStore::new(bob_signer.clone(), 6);
+    let mut bob_session_store = InMemSessionStore::new();
+    let mut bob_pre_key_store = InMemPreKeyStore::new();
+    let mut bob_signed_pre_key_store = InMemSignedPreKeyStore::new();
+
+    assert!(alice_identity_store.get_identity_key_pair(None).is_err());
+
+    let pre_key_pair = KeyPair::generate(&mut csprng);
+    let signed_pre_key = SignedPreKeyRecord::generate(2, 42, &bob_signer, &mut csprng)?;
+    assert_eq!(bob_signer.request_count(), 1);
+    bob_pre_key_store.save_pre_key(1, &PreKeyRecord::new(1, &pre_key_pair), None)?;
+    bob_signed_pre_key_store.save_signed_pre_key(2, &signed_pre_key, None)?;
+
+    let bob_pre_key_bundle = PreKeyBundle::new(
+        6,
+        1,
+        Some(1),
+        Some(pre_key_pair.public_key),
+        2,
+        signed_pre_key.public_key()?,
+        signed_pre_key.signature()?,
+        bob_identity,
+    )?;
+
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_session_store,
+        &mut alice_identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    assert_eq!(alice_signer.request_count(), 1);
+
+    let original_message = "Hell is other people";
+    let outgoing_message = message_encrypt(
+        original_message.as_bytes(),
+        &bob_address,
+        &mut alice_session_store,
+        &mut alice_identity_store,
+        None,
+    )?;
+    assert_eq!(
+        outgoing_message.message_type(),
+        CiphertextMessageType::PreKey
+    );
+
+    let ptext = message_decrypt(
+        &outgoing_message,
+        &alice_address,
+        &mut bob_session_store,
+        &mut bob_identity_store,
+        &mut bob_pre_key_store,
+        &mut bob_signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    assert_eq!(String::from_utf8(ptext).unwrap(), original_message);
+    assert_eq!(bob_signer.request_count(), 2);
+
+    let bob_message = message_encrypt(
+        b"reply",
+        &alice_address,
+        &mut bob_session_store,
+        &mut bob_identity_store,
+        None,
+    )?;
+    let ptext = message_decrypt(
+        &bob_message,
+        &bob_address,
+        &mut alice_session_store,
+        &mut alice_identity_store,
+        &mut alice_pre_key_store,
+        &mut alice_signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    assert_eq!(ptext, b"reply");
+
+    // Only session setup needs the identity private key.
+    assert_eq!(alice_signer.request_count(), 1);
+    assert_eq!(bob_signer.request_count(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn franked_messages_can_be_reported() -> Result<(), SignalProtocolError> {
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut csprng = OsRng;
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    // The server only sees the commitment, and tags it with who sent the message to whom.
+    let server_key = [0x42u8; 32];
+    let relay = |message: &CiphertextMessage, sender, recipient, timestamp| {
+        let commitment = match message.inspect() {
+            MessageHeader::Signal(header) => header.franking_commitment,
+            MessageHeader::PreKeySignal { message, .. } => message.franking_commitment,
+            _ => None,
+        }
+        .expect("franked");
+        franking_tag(&server_key, &commitment, sender, recipient, timestamp)
+    };
+
+    let original_message = "No one can make you feel inferior without your consent";
+    let outgoing_message = message_encrypt_franked(
+        original_message.as_bytes(),
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        None,
+    )?;
+    assert_eq!(
+        outgoing_message.message_type(),
+        CiphertextMessageType::PreKey
+    );
+    let tag = relay(&outgoing_message, &alice_address, &bob_address, 1000)?;
+
+    let (ptext, opening) = message_decrypt_franked(
+        &outgoing_message,
+        &alice_address,
+        &mut bob_store.session_store,
+        &mut bob_store.identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    assert_eq!(String::from_utf8(ptext.clone()).unwrap(), original_message);
+    let opening = opening.expect("franked");
+
+    assert!(verify_franking_report(
+        &server_key,
+        &tag,
+        &alice_address,
+        &bob_address,
+        1000,
+        &ptext,
+        &opening,
+    )?);
+    assert!(!verify_franking_report(
+        &server_key,
+        &tag,
+        &alice_address,
+        &bob_address,
+        1000,
+        b"something alice never said",
+        &opening,
+    )?);
+    assert!(!verify_franking_report(
+        &server_key,
+        &tag,
+        &bob_address,
+        &alice_address,
+        1000,
+        &ptext,
+        &opening,
+    )?);
+
+    // Franked Whisper messages decrypt with plain message_decrypt too.
+    let bob_message = message_encrypt_franked(
+        b"reply",
+        &alice_address,
+        &mut bob_store.session_store,
+        &mut bob_store.identity_store,
+        None,
+    )?;
+    assert_eq!(bob_message.message_type(), CiphertextMessageType::Whisper);
+    let tag = relay(&bob_message, &bob_address, &alice_address, 1001)?;
+    let (ptext, opening) = message_decrypt_franked(
+        &bob_message,
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &mut alice_store.pre_key_store,
+        &mut alice_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    assert!(verify_franking_report(
+        &server_key,
+        &tag,
+        &bob_address,
+        &alice_address,
+        1001,
+        &ptext,
+        &opening.expect("franked"),
+    )?);
+
+    let bob_message = message_encrypt_franked(
+        b"again",
+        &alice_address,
+        &mut bob_store.session_store,
+        &mut bob_store.identity_store,
+        None,
+    )?;
+    assert_eq!(
+        decrypt(&mut alice_store, &bob_address, &bob_message)?,
+        b"again"
+    );
+
+    // Unfranked messages carry no commitment and have no opening.
+    let alice_message = encrypt(&mut alice_store, &bob_address, "unfranked")?;
+    assert!(matches!(
+        alice_message.inspect(),
+        MessageHeader::Signal(SignalMessageHeader {
+            franking_commitment: None,
+            ..
+        })
+    ));
+    let (_, opening) = message_decrypt_franked(
+        &alice_message,
+        &alice_address,
+        &mut bob_store.session_store,
+        &mut bob_store.identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    assert_eq!(opening, None);
+
+    Ok(())
+}
+
 #[test]
 fn basic_session_v3() -> Result<(), SignalProtocolError> {
     let (alice_session, bob_session) = initialize_sessions_v3()?;
@@ -600,49 +976,1317 @@ fn basic_session_v3() -> Result<(), SignalProtocolError> {
 }
 
 #[test]
-fn message_key_limits() -> Result<(), SignalProtocolError> {
-    let (alice_session, bob_session) = initialize_sessions_v3()?;
-    let alice_session_record = SessionRecord::new(alice_session);
-    let bob_session_record = SessionRecord::new(bob_session);
+fn message_key_limits() -> Result<(), SignalProtocolError> {
+    let (alice_session, bob_session) = initialize_sessions_v3()?;
+    let alice_session_record = SessionRecord::new(alice_session);
+    let bob_session_record = SessionRecord::new(bob_session);
+
+    let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    alice_store.store_session(&bob_address, &alice_session_record, None)?;
+    bob_store.store_session(&alice_address, &bob_session_record, None)?;
+
+    const MAX_MESSAGE_KEYS: usize = 2000; // same value as in library
+    const TOO_MANY_MESSAGES: usize = MAX_MESSAGE_KEYS + 300;
+
+    let mut inflight = Vec::with_capacity(TOO_MANY_MESSAGES);
+
+    for i in 0..TOO_MANY_MESSAGES {
+        inflight.push(encrypt(
+            &mut alice_store,
+            &bob_address,
+            &format!("It's over {}", i),
+        )?);
+    }
+
+    assert_eq!(
+        String::from_utf8(decrypt(&mut bob_store, &alice_address, &inflight[1000])?).unwrap(),
+        "It's over 1000"
+    );
+    assert_eq!(
+        String::from_utf8(decrypt(
+            &mut bob_store,
+            &alice_address,
+            &inflight[TOO_MANY_MESSAGES - 1]
+        )?)
+        .unwrap(),
+        format!("It's over {}", TOO_MANY_MESSAGES - 1)
+    );
+
+    let err = decrypt(&mut bob_store, &alice_address, &inflight[5]).unwrap_err();
+    assert_eq!(
+        err,
+        SignalProtocolError::DuplicatedMessage {
+            sender: alice_address,
+            message_type: CiphertextMessageType::Whisper,
+            chain_index: 2300,
+            counter: 5,
+        }
+    );
+    assert_eq!(err.category(), ErrorCategory::Fatal);
+    Ok(())
+}
+
+#[test]
+fn encrypted_store_session() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+
+    let storage_key = StorageKey::new(1, &[0xABu8; 32])?;
+    let mut bob_session_store = EncryptedStore::new(InMemRecordStore::new(), storage_key, OsRng);
+
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let original_message = "L'enfer, c'est les autres";
+    let outgoing_message = encrypt(&mut alice_store, &bob_address, original_message)?;
+
+    let ptext = message_decrypt(
+        &outgoing_message,
+        &alice_address,
+        &mut bob_session_store,
+        &mut bob_store.identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    assert_eq!(String::from_utf8(ptext).unwrap(), original_message);
+
+    assert!(bob_session_store
+        .load_session(&alice_address, None)?
+        .is_some());
+    // The backing store only ever sees the sealed record.
+    let sealed = bob_session_store
+        .inner()
+        .load_record(
+            RecordKind::Session,
+            b"\x00\x00\x00\x0c+14151111111\x00\x00\x00\x01",
+            None,
+        )?
+        .expect("sealed session");
+    assert!(SessionRecord::deserialize(&sealed).is_err());
+
+    let bobs_response = "Who watches the watchers?";
+    let bob_outgoing = message_encrypt(
+        bobs_response.as_bytes(),
+        &alice_address,
+        &mut bob_session_store,
+        &mut bob_store.identity_store,
+        None,
+    )?;
+    assert_eq!(bob_outgoing.message_type(), CiphertextMessageType::Whisper);
+    assert_eq!(
+        String::from_utf8(decrypt(&mut alice_store, &bob_address, &bob_outgoing)?).unwrap(),
+        bobs_response
+    );
+
+    Ok(())
+}
+
+#[test]
+fn backup_and_restore_session() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let outgoing_message = encrypt(&mut alice_store, &bob_address, "before backup")?;
+    assert_eq!(
+        String::from_utf8(decrypt(&mut bob_store, &alice_address, &outgoing_message)?).unwrap(),
+        "before backup"
+    );
+
+    let manifest = BackupManifest {
+        addresses: vec![alice_address.clone()],
+        signed_pre_key_ids: vec![bob_pre_key_bundle.signed_pre_key_id()?],
+        ..Default::default()
+    };
+    let params = BackupParams::new(10, 8, 1)?;
+    let backup = export_backup(
+        &mut bob_store,
+        &manifest,
+        "hunter2",
+        &params,
+        &mut csprng,
+        None,
+    )?;
+
+    let restored = ProtocolBackup::decrypt(&backup, "hunter2")?;
+    let mut bob_store =
+        InMemSignalProtocolStore::new(*restored.identity_key_pair(), restored.registration_id())?;
+    restored.restore_into(&mut bob_store, None)?;
+
+    assert!(bob_store
+        .get_signed_pre_key(bob_pre_key_bundle.signed_pre_key_id()?, None)
+        .is_ok());
+
+    let bob_outgoing = encrypt(&mut bob_store, &alice_address, "after restore")?;
+    assert_eq!(bob_outgoing.message_type(), CiphertextMessageType::Whisper);
+    assert_eq!(
+        String::from_utf8(decrypt(&mut alice_store, &bob_address, &bob_outgoing)?).unwrap(),
+        "after restore"
+    );
+
+    let alice_outgoing = encrypt(&mut alice_store, &bob_address, "still talking")?;
+    assert_eq!(
+        String::from_utf8(decrypt(&mut bob_store, &alice_address, &alice_outgoing)?).unwrap(),
+        "still talking"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn decryption_error_heals_session() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let outgoing_message = encrypt(&mut alice_store, &bob_address, "hello")?;
+    decrypt(&mut bob_store, &alice_address, &outgoing_message)?;
+    let bob_outgoing = encrypt(&mut bob_store, &alice_address, "hi")?;
+    decrypt(&mut alice_store, &bob_address, &bob_outgoing)?;
+
+    // Bob loses his session state.
+    bob_store.session_store = InMemSessionStore::new();
+
+    let outgoing_message = encrypt(&mut alice_store, &bob_address, "are you there?")?;
+    assert_eq!(
+        outgoing_message.message_type(),
+        CiphertextMessageType::Whisper
+    );
+    assert!(decrypt(&mut bob_store, &alice_address, &outgoing_message).is_err());
+
+    let error_message = DecryptionErrorMessage::for_original(
+        outgoing_message.serialize(),
+        outgoing_message.message_type(),
+        1_600_000_000_000,
+        alice_address.device_id(),
+    )?;
+    let error_message = DecryptionErrorMessage::try_from(error_message.serialized())?;
+
+    assert_eq!(
+        process_decryption_error_message(
+            &error_message,
+            &bob_address,
+            2,
+            &mut alice_store.session_store,
+            SystemTime::now(),
+            None
+        )?,
+        DecryptionErrorAction::NoAction
+    );
+    assert_eq!(
+        process_decryption_error_message(
+            &error_message,
+            &bob_address,
+            alice_address.device_id(),
+            &mut alice_store.session_store,
+            SystemTime::now(),
+            None
+        )?,
+        DecryptionErrorAction::SessionArchived
+    );
+    let alice_record = alice_store
+        .load_session(&bob_address, None)?
+        .expect("session record kept");
+    assert!(alice_record.current_session.is_none());
+    assert_eq!(alice_record.previous_sessions.len(), 1);
+
+    // A repeated error for the archived session is ignored.
+    assert_eq!(
+        process_decryption_error_message(
+            &error_message,
+            &bob_address,
+            alice_address.device_id(),
+            &mut alice_store.session_store,
+            SystemTime::now(),
+            None
+        )?,
+        DecryptionErrorAction::NoAction
+    );
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    let outgoing_message = encrypt(&mut alice_store, &bob_address, "are you there?")?;
+    assert_eq!(
+        outgoing_message.message_type(),
+        CiphertextMessageType::PreKey
+    );
+    assert_eq!(
+        String::from_utf8(decrypt(&mut bob_store, &alice_address, &outgoing_message)?).unwrap(),
+        "are you there?"
+    );
+
+    let group_error_message = DecryptionErrorMessage::new(None, 1_600_000_000_000, 1)?;
+    assert_eq!(
+        process_decryption_error_message(
+            &group_error_message,
+            &bob_address,
+            alice_address.device_id(),
+            &mut alice_store.session_store,
+            SystemTime::now(),
+            None
+        )?,
+        DecryptionErrorAction::ResendSenderKeyDistribution
+    );
+
+    Ok(())
+}
+
+#[test]
+fn message_decrypt_preview_and_commit() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let outgoing_message = encrypt(&mut alice_store, &bob_address, "preview me")?;
+    assert_eq!(
+        outgoing_message.message_type(),
+        CiphertextMessageType::PreKey
+    );
+
+    let (ptext, delta) = message_decrypt_preview(
+        &outgoing_message,
+        &alice_address,
+        &bob_store.session_store,
+        &bob_store.identity_store,
+        &bob_store.pre_key_store,
+        &bob_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    assert_eq!(String::from_utf8(ptext).unwrap(), "preview me");
+
+    // The preview left every store untouched.
+    assert!(bob_store.load_session(&alice_address, None)?.is_none());
+    assert!(bob_store.get_identity(&alice_address, None)?.is_none());
+    assert!(bob_store
+        .get_pre_key(bob_pre_key_bundle.pre_key_id()?.unwrap(), None)
+        .is_ok());
+
+    let delta = DecryptDelta::deserialize(&delta.serialize()?)?;
+    commit_decrypt_delta(
+        &delta,
+        &mut bob_store.session_store,
+        &mut bob_store.identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.sender_key_store,
+        None,
+    )?;
+
+    assert!(bob_store.load_session(&alice_address, None)?.is_some());
+    assert!(bob_store.get_identity(&alice_address, None)?.is_some());
+    assert!(bob_store
+        .get_pre_key(bob_pre_key_bundle.pre_key_id()?.unwrap(), None)
+        .is_err());
+
+    let bob_outgoing = encrypt(&mut bob_store, &alice_address, "committed")?;
+    assert_eq!(
+        String::from_utf8(decrypt(&mut alice_store, &bob_address, &bob_outgoing)?).unwrap(),
+        "committed"
+    );
+
+    // A preview taken before another message was decrypted can no longer be committed.
+    let first = encrypt(&mut alice_store, &bob_address, "first")?;
+    let second = encrypt(&mut alice_store, &bob_address, "second")?;
+    let (_, stale_delta) = message_decrypt_preview(
+        &second,
+        &alice_address,
+        &bob_store.session_store,
+        &bob_store.identity_store,
+        &bob_store.pre_key_store,
+        &bob_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+    decrypt(&mut bob_store, &alice_address, &first)?;
+    assert_eq!(
+        commit_decrypt_delta(
+            &stale_delta,
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.sender_key_store,
+            None,
+        ),
+        Err(SignalProtocolError::StaleDecryptDelta)
+    );
+    assert_eq!(
+        String::from_utf8(decrypt(&mut bob_store, &alice_address, &second)?).unwrap(),
+        "second"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn commit_decrypt_delta_rejects_reused_one_time_prekey() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let carol_address = ProtocolAddress::new("+14151111113".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut carol_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    // Both senders set up a session from the same bundle and one-time prekey.
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    for store in [&mut alice_store, &mut carol_store].iter_mut() {
+        process_prekey_bundle(
+            &bob_address,
+            &mut store.session_store,
+            &mut store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            SystemTime::now(),
+            None,
+        )?;
+    }
+    let from_alice = encrypt(&mut alice_store, &bob_address, "from alice")?;
+    let from_carol = encrypt(&mut carol_store, &bob_address, "from carol")?;
+
+    let mut deltas = vec![];
+    for (message, sender) in &[(&from_alice, &alice_address), (&from_carol, &carol_address)] {
+        let (_, delta) = message_decrypt_preview(
+            message,
+            sender,
+            &bob_store.session_store,
+            &bob_store.identity_store,
+            &bob_store.pre_key_store,
+            &bob_store.signed_pre_key_store,
+            &mut csprng,
+            SystemTime::now(),
+            None,
+        )?;
+        deltas.push(delta);
+    }
+
+    commit_decrypt_delta(
+        &deltas[0],
+        &mut bob_store.session_store,
+        &mut bob_store.identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.sender_key_store,
+        None,
+    )?;
+    assert_eq!(
+        commit_decrypt_delta(
+            &deltas[1],
+            &mut bob_store.session_store,
+            &mut bob_store.identity_store,
+            &mut bob_store.pre_key_store,
+            &mut bob_store.sender_key_store,
+            None,
+        ),
+        Err(SignalProtocolError::InvalidPreKeyId)
+    );
+    assert!(bob_store.load_session(&alice_address, None)?.is_some());
+    assert!(bob_store.load_session(&carol_address, None)?.is_none());
+    assert!(bob_store.get_identity(&carol_address, None)?.is_none());
+
+    Ok(())
+}
+
+struct CountingSessionStore {
+    store: InMemSessionStore,
+    loads: Cell<usize>,
+    stores: usize,
+}
+
+impl SessionStore for CountingSessionStore {
+    fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+        self.loads.set(self.loads.get() + 1);
+        self.store.load_session(address, ctx)
+    }
+
+    fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        self.stores += 1;
+        self.store.store_session(address, record, ctx)
+    }
+}
+
+#[test]
+fn message_decrypt_batch_round_trips() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+    let carol_address = ProtocolAddress::new("+14151111113".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+    let mut carol_store = support::test_in_memory_protocol_store();
+
+    for sender_store in [&mut alice_store, &mut carol_store] {
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut sender_store.session_store,
+            &mut sender_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            SystemTime::now(),
+            None,
+        )?;
+    }
+
+    let a1 = encrypt(&mut alice_store, &bob_address, "a1")?;
+    let a2 = encrypt(&mut alice_store, &bob_address, "a2")?;
+    let a3 = encrypt(&mut alice_store, &bob_address, "a3")?;
+    let c1 = encrypt(&mut carol_store, &bob_address, "c1")?;
+    let c2 = encrypt(&mut carol_store, &bob_address, "c2")?;
+
+    let duplicate =
+        CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::try_from(a1.serialize())?);
+    let queue = vec![
+        (alice_address.clone(), a1),
+        (carol_address.clone(), c1),
+        (alice_address.clone(), a3),
+        (alice_address.clone(), a2),
+        (carol_address.clone(), c2),
+        (alice_address.clone(), duplicate),
+    ];
+
+    let mut bob_session_store = CountingSessionStore {
+        store: InMemSessionStore::new(),
+        loads: Cell::new(0),
+        stores: 0,
+    };
+    let results = message_decrypt_batch(
+        &queue,
+        &mut bob_session_store,
+        &mut bob_store.identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let plaintexts: Vec<_> = results[..5]
+        .iter()
+        .map(|r| String::from_utf8(r.clone().expect("decrypts")).unwrap())
+        .collect();
+    assert_eq!(plaintexts, vec!["a1", "c1", "a3", "a2", "c2"]);
+    assert!(matches!(
+        results[5],
+        Err(SignalProtocolError::DuplicatedMessage { .. })
+    ));
+
+    assert_eq!(bob_session_store.loads.get(), 2);
+    assert_eq!(bob_session_store.stores, 2);
+
+    let bob_outgoing = message_encrypt(
+        b"got them",
+        &alice_address,
+        &mut bob_session_store,
+        &mut bob_store.identity_store,
+        None,
+    )?;
+    assert_eq!(bob_outgoing.message_type(), CiphertextMessageType::Whisper);
+    assert_eq!(
+        String::from_utf8(decrypt(&mut alice_store, &bob_address, &bob_outgoing)?).unwrap(),
+        "got them"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn message_decrypt_batch_rejects_reused_one_time_prekey() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+    let carol_address = ProtocolAddress::new("+14151111113".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+    let mut carol_store = support::test_in_memory_protocol_store();
+
+    // Both senders set up a session from the same bundle and one-time prekey.
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    for sender_store in [&mut alice_store, &mut carol_store] {
+        process_prekey_bundle(
+            &bob_address,
+            &mut sender_store.session_store,
+            &mut sender_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            SystemTime::now(),
+            None,
+        )?;
+    }
+
+    let a1 = encrypt(&mut alice_store, &bob_address, "a1")?;
+    let a2 = encrypt(&mut alice_store, &bob_address, "a2")?;
+    let c1 = encrypt(&mut carol_store, &bob_address, "c1")?;
+    let queue = vec![
+        (alice_address.clone(), a1),
+        (carol_address.clone(), c1),
+        (alice_address.clone(), a2),
+    ];
+
+    let results = message_decrypt_batch(
+        &queue,
+        &mut bob_store.session_store,
+        &mut bob_store.identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    // Alice's second message uses the session her first one set up; Carol's needs the prekey
+    // again, as message_decrypt one by one would.
+    assert_eq!(results[0], Ok(b"a1".to_vec()));
+    assert_eq!(results[1], Err(SignalProtocolError::InvalidPreKeyId));
+    assert_eq!(results[2], Ok(b"a2".to_vec()));
+
+    assert!(bob_store.load_session(&alice_address, None)?.is_some());
+    assert!(bob_store.load_session(&carol_address, None)?.is_none());
+    assert!(bob_store.get_identity(&carol_address, None)?.is_none());
+    assert!(bob_store
+        .get_pre_key(bob_pre_key_bundle.pre_key_id()?.unwrap(), None)
+        .is_err());
+
+    Ok(())
+}
+
+struct UnreachableIdentityStore(InMemIdentityKeyStore);
+
+impl IdentityKeyStore for UnreachableIdentityStore {
+    fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair, SignalProtocolError> {
+        self.0.get_identity_key_pair(ctx)
+    }
+
+    fn get_local_registration_id(&self, ctx: Context) -> Result<u32, SignalProtocolError> {
+        self.0.get_local_registration_id(ctx)
+    }
+
+    fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        self.0.save_identity(address, identity, ctx)
+    }
+
+    fn is_trusted_identity(
+        &self,
+        _address: &ProtocolAddress,
+        _identity: &IdentityKey,
+        _direction: Direction,
+        _ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        Err(SignalProtocolError::store_failure(
+            "is_trusted_identity",
+            "database unreachable",
+        ))
+    }
+
+    fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        self.0.get_identity(address, ctx)
+    }
+}
+
+struct FailingSessionStore {
+    store: InMemSessionStore,
+    fail_for: ProtocolAddress,
+}
+
+impl SessionStore for FailingSessionStore {
+    fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        ctx: Context,
+    ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+        self.store.load_session(address, ctx)
+    }
+
+    fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        if *address == self.fail_for {
+            return Err(SignalProtocolError::store_failure(
+                "store_session",
+                "disk full",
+            ));
+        }
+        self.store.store_session(address, record, ctx)
+    }
+}
+
+#[test]
+fn message_decrypt_batch_store_failures() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+    let carol_address = ProtocolAddress::new("+14151111113".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+    let mut carol_store = support::test_in_memory_protocol_store();
+
+    let mut pre_key_ids = Vec::new();
+    for sender_store in [&mut alice_store, &mut carol_store] {
+        let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+        pre_key_ids.push(bob_pre_key_bundle.pre_key_id()?.expect("one-time pre key"));
+        process_prekey_bundle(
+            &bob_address,
+            &mut sender_store.session_store,
+            &mut sender_store.identity_store,
+            &bob_pre_key_bundle,
+            &mut csprng,
+            SystemTime::now(),
+            None,
+        )?;
+    }
+
+    let queue = vec![
+        (
+            alice_address.clone(),
+            encrypt(&mut alice_store, &bob_address, "a1")?,
+        ),
+        (
+            carol_address.clone(),
+            encrypt(&mut carol_store, &bob_address, "c1")?,
+        ),
+    ];
+
+    // A failing trust check aborts the batch before anything is written.
+    let mut bob_identity_store = UnreachableIdentityStore(bob_store.identity_store);
+    let err = message_decrypt_batch(
+        &queue,
+        &mut bob_store.session_store,
+        &mut bob_identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        SignalProtocolError::StoreFailure("is_trusted_identity", _)
+    ));
+    assert_eq!(err.category(), ErrorCategory::Retryable);
+    let mut bob_identity_store = bob_identity_store.0;
+    for address in &[&alice_address, &carol_address] {
+        assert!(bob_store
+            .session_store
+            .load_session(address, None)?
+            .is_none());
+        assert!(bob_identity_store.get_identity(address, None)?.is_none());
+    }
+    for pre_key_id in &pre_key_ids {
+        assert!(bob_store
+            .pre_key_store
+            .get_pre_key(*pre_key_id, None)
+            .is_ok());
+    }
+
+    // A failing write stops the write-back; writes made before it are kept.
+    let mut bob_session_store = FailingSessionStore {
+        store: InMemSessionStore::new(),
+        fail_for: carol_address.clone(),
+    };
+    let err = message_decrypt_batch(
+        &queue,
+        &mut bob_session_store,
+        &mut bob_identity_store,
+        &mut bob_store.pre_key_store,
+        &mut bob_store.signed_pre_key_store,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        SignalProtocolError::StoreFailure("store_session", _)
+    ));
+    assert!(bob_session_store
+        .load_session(&alice_address, None)?
+        .is_some());
+    assert!(bob_session_store
+        .load_session(&carol_address, None)?
+        .is_none());
+    for pre_key_id in &pre_key_ids {
+        assert!(bob_store
+            .pre_key_store
+            .get_pre_key(*pre_key_id, None)
+            .is_ok());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn attachment_secrets_sent_in_session() -> Result<(), SignalProtocolError> {
+    use rand::RngCore;
+
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let mut attachment = vec![0u8; 2 * DEFAULT_ATTACHMENT_CHUNK_SIZE + 1000];
+    csprng.fill_bytes(&mut attachment);
+
+    let mut uploaded = Vec::new();
+    let secrets =
+        encrypt_attachment(&mut &attachment[..], &mut uploaded, &mut csprng).expect("encrypts");
+
+    let pointer = message_encrypt(
+        &secrets.serialize(),
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        None,
+    )?;
+    let received = decrypt(&mut bob_store, &alice_address, &pointer)?;
+    let received = AttachmentSecrets::deserialize(&received)?;
+
+    let mut downloaded = Vec::new();
+    let length = decrypt_attachment(&uploaded[..], &mut downloaded, &received).expect("decrypts");
+    assert_eq!(length, received.plaintext_length());
+    assert_eq!(downloaded, attachment);
+
+    Ok(())
+}
+
+#[test]
+fn message_encrypt_into_caller_buffers() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let mut small = [0u8; 16];
+    let required = match message_encrypt_to_slice(
+        b"hello bob",
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &mut small,
+        None,
+    ) {
+        Err(SignalProtocolError::OutputBufferTooSmall(required)) => required,
+        r => panic!("unexpected result {:?}", r),
+    };
+
+    let mut buf = vec![0u8; required + 100];
+    let (message_type, len) = message_encrypt_to_slice(
+        b"hello bob",
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &mut buf,
+        None,
+    )?;
+    assert_eq!(message_type, CiphertextMessageType::PreKey);
+    assert_eq!(len, required);
+
+    // The failed attempt did not consume a message key.
+    let incoming = PreKeySignalMessageRef::try_from(&buf[..len])?;
+    assert_eq!(incoming.message().counter(), 0);
+
+    let incoming = CiphertextMessage::PreKeySignalMessage(incoming.into_owned());
+    assert_eq!(
+        String::from_utf8(decrypt(&mut bob_store, &alice_address, &incoming)?).unwrap(),
+        "hello bob"
+    );
+
+    let mut out = bytes::BytesMut::with_capacity(256);
+    let message_type = message_encrypt_into(
+        b"hello alice",
+        &alice_address,
+        &mut bob_store.session_store,
+        &mut bob_store.identity_store,
+        &mut out,
+        None,
+    )?;
+    assert_eq!(message_type, CiphertextMessageType::Whisper);
+
+    let reply = SignalMessageRef::try_from(&out[..])?;
+    assert_eq!(reply.counter(), 0);
+    let reply = CiphertextMessage::SignalMessage(SignalMessage::try_from(&out[..])?);
+    assert_eq!(
+        String::from_utf8(decrypt(&mut alice_store, &bob_address, &reply)?).unwrap(),
+        "hello alice"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn message_encrypt_into_untrusted_identity_leaves_buffer_untouched(
+) -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
 
-    let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1);
-    let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
 
     let mut alice_store = support::test_in_memory_protocol_store();
     let mut bob_store = support::test_in_memory_protocol_store();
 
-    alice_store.store_session(&bob_address, &alice_session_record, None)?;
-    bob_store.store_session(&alice_address, &bob_session_record, None)?;
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
 
-    const MAX_MESSAGE_KEYS: usize = 2000; // same value as in library
-    const TOO_MANY_MESSAGES: usize = MAX_MESSAGE_KEYS + 300;
+    let bob_identity = *bob_store.get_identity_key_pair(None)?.identity_key();
+    let impostor = IdentityKeyPair::generate(&mut csprng);
+    alice_store.save_identity(&bob_address, impostor.identity_key(), None)?;
 
-    let mut inflight = Vec::with_capacity(TOO_MANY_MESSAGES);
+    let mut buf = [0u8; 512];
+    assert_eq!(
+        message_encrypt_to_slice(
+            b"hello bob",
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &mut buf,
+            None,
+        )
+        .unwrap_err(),
+        SignalProtocolError::UntrustedIdentity(bob_address.clone())
+    );
+    assert!(buf.iter().all(|b| *b == 0));
 
-    for i in 0..TOO_MANY_MESSAGES {
-        inflight.push(encrypt(
-            &mut alice_store,
+    let mut out = bytes::BytesMut::with_capacity(512);
+    assert_eq!(
+        message_encrypt_into(
+            b"hello bob",
             &bob_address,
-            &format!("It's over {}", i),
-        )?);
+            &mut alice_store.session_store,
+            &mut alice_store.identity_store,
+            &mut out,
+            None,
+        )
+        .unwrap_err(),
+        SignalProtocolError::UntrustedIdentity(bob_address.clone())
+    );
+    assert!(out.is_empty());
+
+    // Once the identity is trusted again, the unused message key is the one used.
+    alice_store.save_identity(&bob_address, &bob_identity, None)?;
+    let (_, len) = message_encrypt_to_slice(
+        b"hello bob",
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &mut buf,
+        None,
+    )?;
+    let incoming = PreKeySignalMessageRef::try_from(&buf[..len])?;
+    assert_eq!(incoming.message().counter(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn late_messages_across_ratchet_turns() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let first = encrypt(&mut alice_store, &bob_address, "a0")?;
+    decrypt(&mut bob_store, &alice_address, &first)?;
+    let alice_first_ratchet_key = match first.inspect() {
+        MessageHeader::PreKeySignal { message, .. } => message.sender_ratchet_key,
+        header => panic!("unexpected header {:?}", header),
+    };
+
+    let mut held = Vec::new();
+    for i in 1..5 {
+        held.push((
+            i,
+            encrypt(&mut alice_store, &bob_address, &format!("a{}", i))?,
+        ));
     }
 
+    let reply = encrypt(&mut bob_store, &alice_address, "b0")?;
+    decrypt(&mut alice_store, &bob_address, &reply)?;
+
+    let turn = encrypt(&mut alice_store, &bob_address, "a5")?;
+    match turn.inspect() {
+        MessageHeader::Signal(header) => assert_eq!(header.previous_counter, 4),
+        header => panic!("unexpected header {:?}", header),
+    }
     assert_eq!(
-        String::from_utf8(decrypt(&mut bob_store, &alice_address, &inflight[1000])?).unwrap(),
-        "It's over 1000"
+        String::from_utf8(decrypt(&mut bob_store, &alice_address, &turn)?).unwrap(),
+        "a5"
+    );
+
+    // The turn stored the keys for the rest of Alice's first chain.
+    let bob_record = bob_store
+        .session_store
+        .load_session(&alice_address, None)?
+        .expect("session");
+    let old_chain_key = bob_record
+        .session_state()?
+        .get_receiver_chain_key(&alice_first_ratchet_key)?
+        .expect("old chain");
+    assert_eq!(old_chain_key.index(), 5);
+
+    // Another full turn before the late messages arrive.
+    let reply = encrypt(&mut bob_store, &alice_address, "b1")?;
+    decrypt(&mut alice_store, &bob_address, &reply)?;
+    let turn = encrypt(&mut alice_store, &bob_address, "a6")?;
+    decrypt(&mut bob_store, &alice_address, &turn)?;
+
+    for &i in &[3, 1, 4, 2] {
+        let (_, message) = &held[i - 1];
+        assert_eq!(
+            String::from_utf8(decrypt(&mut bob_store, &alice_address, message)?).unwrap(),
+            format!("a{}", i)
+        );
+    }
+
+    assert!(matches!(
+        decrypt(&mut bob_store, &alice_address, &held[1].1),
+        Err(SignalProtocolError::DuplicatedMessage { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn empty_previous_chain_stores_no_message_keys() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let first = encrypt(&mut alice_store, &bob_address, "a0")?;
+    decrypt(&mut bob_store, &alice_address, &first)?;
+
+    // Bob never sent on the chain of his signed pre key, so his reply says previous counter 0
+    // for an empty chain.
+    let reply = encrypt(&mut bob_store, &alice_address, "b0")?;
+    decrypt(&mut alice_store, &bob_address, &reply)?;
+
+    let mut state = alice_store
+        .load_session(&bob_address, None)?
+        .expect("session")
+        .session_state()?
+        .clone();
+    let bob_initial_ratchet_key = bob_pre_key_bundle.signed_pre_key_public()?;
+    assert!(state.has_receiver_chain(&bob_initial_ratchet_key)?);
+    assert!(state
+        .get_message_keys(&bob_initial_ratchet_key, 0)?
+        .is_none());
+
+    Ok(())
+}
+
+#[test]
+fn previous_chain_longer_than_max_forward_jumps() -> Result<(), SignalProtocolError> {
+    const MAX_FORWARD_JUMPS: u32 = 2000; // same value as in library
+
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let first = encrypt(&mut alice_store, &bob_address, "a0")?;
+    decrypt(&mut bob_store, &alice_address, &first)?;
+
+    // Never delivered; Bob's receiver chain for them stays at index 1.
+    for _ in 0..MAX_FORWARD_JUMPS + 1 {
+        encrypt(&mut alice_store, &bob_address, "lost")?;
+    }
+
+    let reply = encrypt(&mut bob_store, &alice_address, "b0")?;
+    decrypt(&mut alice_store, &bob_address, &reply)?;
+
+    let turn = encrypt(&mut alice_store, &bob_address, "a")?;
+    let err = decrypt(&mut bob_store, &alice_address, &turn).unwrap_err();
+    assert_eq!(
+        err,
+        SignalProtocolError::NoMatchingSessionState {
+            sender: alice_address.clone(),
+            message_type: CiphertextMessageType::Whisper,
+        }
+    );
+    assert_eq!(err.category(), ErrorCategory::Security);
+
+    Ok(())
+}
+
+#[test]
+fn replayed_prekey_message_reports_its_type() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let first = encrypt(&mut alice_store, &bob_address, "a0")?;
+    assert_eq!(first.message_type(), CiphertextMessageType::PreKey);
+    decrypt(&mut bob_store, &alice_address, &first)?;
+
+    assert_eq!(
+        decrypt(&mut bob_store, &alice_address, &first).unwrap_err(),
+        SignalProtocolError::DuplicatedMessage {
+            sender: alice_address,
+            message_type: CiphertextMessageType::PreKey,
+            chain_index: 1,
+            counter: 0,
+        }
     );
+
+    Ok(())
+}
+
+#[test]
+fn prune_expired_message_keys_and_archived_states() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let alice_address = ProtocolAddress::new("+14151111111".to_owned(), 1);
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let mut bob_store = support::test_in_memory_protocol_store();
+
+    let bob_pre_key_bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+    process_prekey_bundle(
+        &bob_address,
+        &mut alice_store.session_store,
+        &mut alice_store.identity_store,
+        &bob_pre_key_bundle,
+        &mut csprng,
+        SystemTime::now(),
+        None,
+    )?;
+
+    let mut messages = Vec::new();
+    for i in 0..4 {
+        messages.push(encrypt(&mut alice_store, &bob_address, &format!("a{}", i))?);
+    }
+    decrypt(&mut bob_store, &alice_address, &messages[3])?;
+
+    let now = SystemTime::now();
+    let max_age = Duration::from_secs(60 * 60);
+
+    let mut record = bob_store
+        .load_session(&alice_address, None)?
+        .expect("session");
+    record.prune_expired(now, max_age);
+    bob_store.store_session(&alice_address, &record, None)?;
     assert_eq!(
-        String::from_utf8(decrypt(
-            &mut bob_store,
-            &alice_address,
-            &inflight[TOO_MANY_MESSAGES - 1]
-        )?)
-        .unwrap(),
-        format!("It's over {}", TOO_MANY_MESSAGES - 1)
+        String::from_utf8(decrypt(&mut bob_store, &alice_address, &messages[1])?).unwrap(),
+        "a1"
     );
 
-    let err = decrypt(&mut bob_store, &alice_address, &inflight[5]).unwrap_err();
-    assert_eq!(err, SignalProtocolError::DuplicatedMessage(2300, 5));
+    let mut record = bob_store
+        .load_session(&alice_address, None)?
+        .expect("session");
+    record.prune_expired(now + 2 * max_age, max_age);
+    bob_store.store_session(&alice_address, &record, None)?;
+    assert!(matches!(
+        decrypt(&mut bob_store, &alice_address, &messages[2]),
+        Err(SignalProtocolError::DuplicatedMessage { .. })
+    ));
+
+    let mut record = bob_store
+        .load_session(&alice_address, None)?
+        .expect("session");
+    record.archive_current_state(now)?;
+    record.prune_expired(now, max_age);
+    assert_eq!(record.previous_session_states()?.count(), 1);
+    record.prune_expired(now + 2 * max_age, max_age);
+    assert!(record.is_fresh()?);
+
     Ok(())
 }
 
@@ -841,6 +2485,7 @@ fn basic_simultaneous_initiate() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -850,6 +2495,7 @@ fn basic_simultaneous_initiate() -> Result<(), SignalProtocolError> {
         &mut bob_store.identity_store,
         &alice_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -973,6 +2619,7 @@ fn simultaneous_initiate_with_lossage() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -982,6 +2629,7 @@ fn simultaneous_initiate_with_lossage() -> Result<(), SignalProtocolError> {
         &mut bob_store.identity_store,
         &alice_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -1090,6 +2738,7 @@ fn simultaneous_initiate_lost_message() -> Result<(), SignalProtocolError> {
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -1099,6 +2748,7 @@ fn simultaneous_initiate_lost_message() -> Result<(), SignalProtocolError> {
         &mut bob_store.identity_store,
         &alice_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
 
@@ -1213,6 +2863,7 @@ fn simultaneous_initiate_repeated_messages() -> Result<(), SignalProtocolError>
             &mut alice_store.identity_store,
             &bob_pre_key_bundle,
             &mut csprng,
+            SystemTime::now(),
             None,
         )?;
 
@@ -1222,6 +2873,7 @@ fn simultaneous_initiate_repeated_messages() -> Result<(), SignalProtocolError>
             &mut bob_store.identity_store,
             &alice_pre_key_bundle,
             &mut csprng,
+            SystemTime::now(),
             None,
         )?;
 
@@ -1394,6 +3046,7 @@ fn simultaneous_initiate_lost_message_repeated_messages() -> Result<(), SignalPr
         &mut alice_store.identity_store,
         &bob_pre_key_bundle,
         &mut csprng,
+        SystemTime::now(),
         None,
     )?;
     let lost_message_for_bob = encrypt(&mut alice_store, &bob_address, "it was so long ago")?;
@@ -1408,6 +3061,7 @@ fn simultaneous_initiate_lost_message_repeated_messages() -> Result<(), SignalPr
             &mut alice_store.identity_store,
             &bob_pre_key_bundle,
             &mut csprng,
+            SystemTime::now(),
             None,
         )?;
 
@@ -1417,6 +3071,7 @@ fn simultaneous_initiate_lost_message_repeated_messages() -> Result<(), SignalPr
             &mut bob_store.identity_store,
             &alice_pre_key_bundle,
             &mut csprng,
+            SystemTime::now(),
             None,
         )?;
 
@@ -1603,3 +3258,102 @@ fn simultaneous_initiate_lost_message_repeated_messages() -> Result<(), SignalPr
 
     Ok(())
 }
+
+#[test]
+fn identity_key_changes_are_logged_and_provable() -> Result<(), SignalProtocolError> {
+    let mut csprng = OsRng;
+
+    let bob_address = ProtocolAddress::new("+14151111112".to_owned(), 1);
+    let mut alice_store = support::test_in_memory_protocol_store();
+    let observed_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let mut alice_identities = LoggingIdentityKeyStore::with_clock(
+        alice_store.identity_store.clone(),
+        IdentityKeyLog::new(),
+        Box::new(move || observed_at),
+    );
+
+    let mut server = LocalTransparencyServer::new(KeyPair::generate(&mut csprng));
+
+    let mut bob_keys = vec![];
+    let mut heads = vec![];
+    for i in 0..2 {
+        let mut bob_store = support::test_in_memory_protocol_store();
+        let bob_identity = *bob_store.get_identity_key_pair(None)?.identity_key();
+        bob_keys.push(bob_identity);
+        server.publish(&bob_address, &bob_identity, SystemTime::now())?;
+        // The server also logs keys that Alice never sees.
+        let carol_address = ProtocolAddress::new(format!("+1415333333{}", i), 1);
+        let carol_identity = *IdentityKeyPair::generate(&mut csprng).identity_key();
+        server.publish(&carol_address, &carol_identity, SystemTime::now())?;
+        heads.push(server.signed_head(SystemTime::now(), &mut csprng)?);
+
+        // A changed key is only accepted once the user has approved it.
+        if alice_identities.get_identity(&bob_address, None)?.is_some() {
+            alice_identities.save_identity(&bob_address, &bob_identity, None)?;
+        }
+        let bundle = create_pre_key_bundle(&mut bob_store, &mut csprng)?;
+        process_prekey_bundle(
+            &bob_address,
+            &mut alice_store.session_store,
+            &mut alice_identities,
+            &bundle,
+            &mut csprng,
+            SystemTime::now(),
+            None,
+        )?;
+    }
+
+    let log = alice_identities.log();
+    let seen: Vec<IdentityKey> = log
+        .history(&bob_address)
+        .iter()
+        .map(|e| *e.identity_key())
+        .collect();
+    assert_eq!(seen, bob_keys);
+    assert!(log
+        .history(&bob_address)
+        .iter()
+        .all(|e| e.observed_at() == 1_600_000_000));
+    log.verify_chain()?;
+
+    // The later head extends the earlier one, and not the other way round.
+    let (old_head, head) = (&heads[0], &heads[1]);
+    let proof = server.consistency_proof(old_head.tree_size(), head.tree_size())?;
+    head.verify_consistency(old_head, &proof, server.public_key())?;
+    assert!(old_head
+        .verify_consistency(head, &proof, server.public_key())
+        .is_err());
+
+    // Everything Alice observed for Bob is included in the server's log.
+    let bob_entries: Vec<(IdentityLogEntry, InclusionProof)> = server
+        .log()
+        .history(&bob_address)
+        .into_iter()
+        .map(|entry| {
+            let proof = server.inclusion_proof(entry.index(), head.tree_size())?;
+            Ok((entry.clone(), proof))
+        })
+        .collect::<Result<_, SignalProtocolError>>()?;
+    head.verify_observations(log, &bob_entries, server.public_key())?;
+
+    // A server that hides one of the keys Alice saw is caught.
+    assert!(head
+        .verify_observations(log, &bob_entries[..1], server.public_key())
+        .is_err());
+    // So is an entry that is not in the signed tree.
+    let entry = &bob_entries[1].0;
+    let wrong_proof = server.inclusion_proof(entry.index() - 1, head.tree_size())?;
+    assert!(head
+        .verify_observations(
+            log,
+            &[bob_entries[0].clone(), (entry.clone(), wrong_proof)],
+            server.public_key()
+        )
+        .is_err());
+
+    // A head signed by someone else is rejected.
+    let impostor = LocalTransparencyServer::new(KeyPair::generate(&mut csprng));
+    assert!(head.verify(impostor.public_key()).is_err());
+
+    Ok(())
+}
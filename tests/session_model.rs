@@ -0,0 +1,522 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+//! Randomized state machine tests for sessions between two or more parties.
+//!
+//! Each case sets up sessions between every pair of parties (sometimes with both sides initiating
+//! at once) and runs an arbitrary sequence of sends, deliveries, drops, duplications and fresh
+//! initiations. A reference model tracks, for every party and peer, which session states exist,
+//! which is current, whether an initiator's pre-key message has been acknowledged, and which
+//! receiving chains are held. Every operation is checked against what the model predicts.
+
+mod support;
+
+use libsignal_protocol_rust::*;
+use proptest::prelude::*;
+use rand::rngs::OsRng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::SystemTime;
+use support::*;
+
+// Mirrors the limits in src/consts.rs.
+const MAX_RECEIVER_CHAINS: usize = 5;
+const ARCHIVED_STATES_MAX_LENGTH: usize = 40;
+
+// Keeps the number of sessions per record well below ARCHIVED_STATES_MAX_LENGTH, so the model
+// need not track archive eviction.
+const MAX_INITIATIONS: usize = 8;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Send {
+        from: u8,
+        to: u8,
+    },
+    /// Deliver the `pick`th message in flight, so messages can arrive in any order.
+    Deliver {
+        from: u8,
+        to: u8,
+        pick: u8,
+    },
+    Drop {
+        from: u8,
+        to: u8,
+        pick: u8,
+    },
+    /// Put a copy of any message sent so far back in flight.
+    Duplicate {
+        from: u8,
+        to: u8,
+        pick: u8,
+    },
+    /// Start a new session from a fresh pre-key bundle.
+    Initiate {
+        from: u8,
+        to: u8,
+    },
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let pair = (any::<u8>(), any::<u8>());
+    prop_oneof![
+        4 => pair.prop_map(|(from, to)| Op::Send { from, to }),
+        6 => (pair, any::<u8>()).prop_map(|((from, to), pick)| Op::Deliver { from, to, pick }),
+        1 => (pair, any::<u8>()).prop_map(|((from, to), pick)| Op::Drop { from, to, pick }),
+        1 => (pair, any::<u8>()).prop_map(|((from, to), pick)| Op::Duplicate { from, to, pick }),
+        1 => pair.prop_map(|(from, to)| Op::Initiate { from, to }),
+    ]
+}
+
+/// How a pair of parties first set up their session.
+#[derive(Debug, Clone, Copy)]
+enum Setup {
+    LowerInitiates,
+    HigherInitiates,
+    Simultaneous,
+}
+
+fn setup() -> impl Strategy<Value = Setup> {
+    prop_oneof![
+        Just(Setup::LowerInitiates),
+        Just(Setup::HigherInitiates),
+        Just(Setup::Simultaneous),
+    ]
+}
+
+#[derive(Clone)]
+struct Message {
+    id: usize,
+    base_key: Vec<u8>,
+    ratchet_key: Vec<u8>,
+    message_type: CiphertextMessageType,
+    bytes: Vec<u8>,
+}
+
+/// One session state as one party sees it.
+#[derive(Default)]
+struct SessionModel {
+    initiator: bool,
+    acknowledged: bool,
+    /// Ratchet keys of the receiving chains held, oldest first.
+    chains: VecDeque<Vec<u8>>,
+    /// Every ratchet key a receiving chain has ever been held for.
+    seen: HashSet<Vec<u8>>,
+}
+
+impl SessionModel {
+    fn add_chain(&mut self, ratchet_key: &[u8]) {
+        if self.chains.iter().any(|k| k == ratchet_key) {
+            return;
+        }
+        self.chains.push_back(ratchet_key.to_vec());
+        self.seen.insert(ratchet_key.to_vec());
+        if self.chains.len() > MAX_RECEIVER_CHAINS {
+            self.chains.pop_front();
+        }
+    }
+
+    fn has_chain(&self, ratchet_key: &[u8]) -> bool {
+        self.chains.iter().any(|k| k == ratchet_key)
+    }
+
+    fn can_decrypt(&self, ratchet_key: &[u8]) -> bool {
+        // A chain is created on the first message from a new ratchet key, but once dropped it
+        // can never be recreated.
+        self.has_chain(ratchet_key) || !self.seen.contains(ratchet_key)
+    }
+}
+
+/// Everything one party knows about one peer.
+#[derive(Default)]
+struct PeerModel {
+    sessions: HashMap<Vec<u8>, SessionModel>,
+    current: Option<Vec<u8>>,
+    initiations: usize,
+}
+
+#[derive(Default)]
+struct Channel {
+    in_flight: Vec<Message>,
+    sent: Vec<Message>,
+}
+
+struct World {
+    addresses: Vec<ProtocolAddress>,
+    stores: Vec<InMemSignalProtocolStore>,
+    /// Keyed by (party, peer).
+    model: HashMap<(usize, usize), PeerModel>,
+    /// Keyed by (sender, recipient).
+    channels: HashMap<(usize, usize), Channel>,
+    decrypted: HashSet<usize>,
+    next_id: usize,
+}
+
+fn plaintext(id: usize) -> Vec<u8> {
+    format!("message {}", id).into_bytes()
+}
+
+impl World {
+    fn new(parties: usize) -> Self {
+        let mut world = Self {
+            addresses: (0..parties)
+                .map(|i| ProtocolAddress::new(format!("+1415000000{}", i), 1))
+                .collect(),
+            stores: (0..parties)
+                .map(|_| test_in_memory_protocol_store())
+                .collect(),
+            model: HashMap::new(),
+            channels: HashMap::new(),
+            decrypted: HashSet::new(),
+            next_id: 0,
+        };
+        for a in 0..parties {
+            for b in 0..parties {
+                if a != b {
+                    world.model.insert((a, b), PeerModel::default());
+                    world.channels.insert((a, b), Channel::default());
+                }
+            }
+        }
+        world
+    }
+
+    fn pair(&self, from: u8, to: u8) -> (usize, usize) {
+        let n = self.stores.len();
+        let from = from as usize % n;
+        let to = (from + 1 + to as usize % (n - 1)) % n;
+        (from, to)
+    }
+
+    fn load_session(&self, party: usize, peer: usize) -> Option<SessionRecord> {
+        self.stores[party]
+            .load_session(&self.addresses[peer], None)
+            .expect("load session")
+    }
+
+    fn initiate(&mut self, from: usize, to: usize) -> Result<(), SignalProtocolError> {
+        if self.model[&(from, to)].initiations >= MAX_INITIATIONS {
+            return Ok(());
+        }
+
+        let bundle = create_pre_key_bundle(&mut self.stores[to], &mut OsRng)?;
+        let store = &mut self.stores[from];
+        process_prekey_bundle(
+            &self.addresses[to],
+            &mut store.session_store,
+            &mut store.identity_store,
+            &bundle,
+            &mut OsRng,
+            SystemTime::now(),
+            None,
+        )?;
+
+        let record = self.load_session(from, to).expect("session after bundle");
+        let base_key = record.session_state()?.alice_base_key()?.to_vec();
+
+        let peer = self.model.get_mut(&(from, to)).expect("peer model");
+        let mut session = SessionModel {
+            initiator: true,
+            ..SessionModel::default()
+        };
+        // The initiator can already receive on the responder's signed pre-key.
+        session.add_chain(&bundle.signed_pre_key_public()?.serialize());
+        assert!(peer.sessions.insert(base_key.clone(), session).is_none());
+        peer.current = Some(base_key);
+        peer.initiations += 1;
+        Ok(())
+    }
+
+    fn send(&mut self, from: usize, to: usize) -> Result<(), SignalProtocolError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let store = &mut self.stores[from];
+        let result = message_encrypt(
+            &plaintext(id),
+            &self.addresses[to],
+            &mut store.session_store,
+            &mut store.identity_store,
+            None,
+        );
+
+        let peer = &self.model[&(from, to)];
+        let base_key = match &peer.current {
+            None => {
+                assert!(matches!(
+                    result,
+                    Err(SignalProtocolError::SessionNotFound(_))
+                ));
+                return Ok(());
+            }
+            Some(base_key) => base_key.clone(),
+        };
+        let session = &peer.sessions[&base_key];
+        let expected_type = if session.initiator && !session.acknowledged {
+            CiphertextMessageType::PreKey
+        } else {
+            CiphertextMessageType::Whisper
+        };
+
+        let ciphertext = result?;
+        assert_eq!(ciphertext.message_type(), expected_type);
+
+        let record = self.load_session(from, to).expect("session after encrypt");
+        let state = record.session_state()?;
+        assert_eq!(state.alice_base_key()?, &base_key[..]);
+
+        let message = Message {
+            id,
+            base_key,
+            ratchet_key: state.sender_ratchet_key()?.serialize().to_vec(),
+            message_type: ciphertext.message_type(),
+            bytes: ciphertext.serialize().to_vec(),
+        };
+        let channel = self.channels.get_mut(&(from, to)).expect("channel");
+        channel.in_flight.push(message.clone());
+        channel.sent.push(message);
+        Ok(())
+    }
+
+    fn deliver(&mut self, from: usize, to: usize, pick: u8) -> Result<(), SignalProtocolError> {
+        let channel = self.channels.get_mut(&(from, to)).expect("channel");
+        if channel.in_flight.is_empty() {
+            return Ok(());
+        }
+        let message = channel
+            .in_flight
+            .remove(pick as usize % channel.in_flight.len());
+
+        let ciphertext = CiphertextMessage::parse(&message.bytes, message.message_type)?;
+        let store = &mut self.stores[to];
+        let result = message_decrypt(
+            &ciphertext,
+            &self.addresses[from],
+            &mut store.session_store,
+            &mut store.identity_store,
+            &mut store.pre_key_store,
+            &mut store.signed_pre_key_store,
+            &mut OsRng,
+            SystemTime::now(),
+            None,
+        );
+
+        let peer = self.model.get_mut(&(to, from)).expect("peer model");
+        let session = peer.sessions.get(&message.base_key);
+
+        if self.decrypted.contains(&message.id) {
+            match session {
+                Some(session) if session.has_chain(&message.ratchet_key) => assert!(
+                    matches!(result, Err(SignalProtocolError::DuplicatedMessage { .. })),
+                    "message {} decrypted twice",
+                    message.id
+                ),
+                _ => assert!(result.is_err(), "message {} decrypted twice", message.id),
+            }
+            return Ok(());
+        }
+
+        let expect_success = match session {
+            Some(session) => session.can_decrypt(&message.ratchet_key),
+            None => {
+                // Only a pre-key message can introduce a session to its recipient.
+                assert_eq!(message.message_type, CiphertextMessageType::PreKey);
+                true
+            }
+        };
+        if !expect_success {
+            assert!(
+                result.is_err(),
+                "message {} decrypted after its chain was dropped",
+                message.id
+            );
+            return Ok(());
+        }
+
+        assert_eq!(result?, plaintext(message.id));
+        self.decrypted.insert(message.id);
+
+        let session = peer.sessions.entry(message.base_key.clone()).or_default();
+        session.add_chain(&message.ratchet_key);
+        session.acknowledged = true;
+        peer.current = Some(message.base_key);
+        Ok(())
+    }
+
+    fn drop_message(&mut self, from: usize, to: usize, pick: u8) {
+        let channel = self.channels.get_mut(&(from, to)).expect("channel");
+        if !channel.in_flight.is_empty() {
+            channel
+                .in_flight
+                .remove(pick as usize % channel.in_flight.len());
+        }
+    }
+
+    fn duplicate(&mut self, from: usize, to: usize, pick: u8) {
+        let channel = self.channels.get_mut(&(from, to)).expect("channel");
+        if !channel.sent.is_empty() {
+            let message = channel.sent[pick as usize % channel.sent.len()].clone();
+            channel.in_flight.push(message);
+        }
+    }
+
+    /// Check that every session record holds exactly the states and receiving chains the model
+    /// expects, and so stays within the size limits.
+    fn check_records(&self) -> Result<(), SignalProtocolError> {
+        for ((party, peer), model) in &self.model {
+            let record = match self.load_session(*party, *peer) {
+                None => {
+                    assert!(model.sessions.is_empty());
+                    continue;
+                }
+                Some(record) => record,
+            };
+
+            assert!(record.previous_sessions.len() <= ARCHIVED_STATES_MAX_LENGTH);
+            assert_eq!(
+                record.session_state()?.alice_base_key()?,
+                &model.current.as_ref().expect("current session")[..]
+            );
+
+            let states: Vec<&SessionState> = std::iter::once(record.session_state()?)
+                .chain(record.previous_session_states()?)
+                .collect();
+            assert_eq!(states.len(), model.sessions.len());
+
+            for state in states {
+                let session = &model.sessions[state.alice_base_key()?];
+                assert!(session.chains.len() <= MAX_RECEIVER_CHAINS);
+                for ratchet_key in &session.seen {
+                    assert_eq!(
+                        state.has_receiver_chain(&PublicKey::deserialize(ratchet_key)?)?,
+                        session.has_chain(ratchet_key)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, op: &Op) -> Result<(), SignalProtocolError> {
+        match *op {
+            Op::Send { from, to } => {
+                let (from, to) = self.pair(from, to);
+                self.send(from, to)
+            }
+            Op::Deliver { from, to, pick } => {
+                let (from, to) = self.pair(from, to);
+                self.deliver(from, to, pick)
+            }
+            Op::Drop { from, to, pick } => {
+                let (from, to) = self.pair(from, to);
+                self.drop_message(from, to, pick);
+                Ok(())
+            }
+            Op::Duplicate { from, to, pick } => {
+                let (from, to) = self.pair(from, to);
+                self.duplicate(from, to, pick);
+                Ok(())
+            }
+            Op::Initiate { from, to } => {
+                let (from, to) = self.pair(from, to);
+                self.initiate(from, to)
+            }
+        }
+    }
+
+    /// Deliver everything still in flight, in order.
+    fn drain(&mut self) -> Result<(), SignalProtocolError> {
+        let mut channels: Vec<(usize, usize)> = self.channels.keys().copied().collect();
+        channels.sort();
+        for (from, to) in channels {
+            while !self.channels[&(from, to)].in_flight.is_empty() {
+                self.deliver(from, to, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn run(parties: usize, setups: &[Setup], ops: &[Op]) -> Result<World, SignalProtocolError> {
+    let mut world = World::new(parties);
+
+    let mut setups = setups.iter().cycle();
+    for lower in 0..parties {
+        for higher in lower + 1..parties {
+            match setups.next().expect("cycled") {
+                Setup::LowerInitiates => world.initiate(lower, higher)?,
+                Setup::HigherInitiates => world.initiate(higher, lower)?,
+                Setup::Simultaneous => {
+                    world.initiate(lower, higher)?;
+                    world.initiate(higher, lower)?;
+                }
+            }
+        }
+    }
+    world.check_records()?;
+
+    for op in ops {
+        world.apply(op)?;
+        world.check_records()?;
+    }
+
+    world.drain()?;
+    world.check_records()?;
+    Ok(world)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(48))]
+
+    #[test]
+    fn sessions_match_reference_model(
+        parties in 2usize..=3,
+        setups in prop::collection::vec(setup(), 3),
+        ops in prop::collection::vec(op(), 1..200),
+    ) {
+        run(parties, &setups, &ops).expect("protocol operation failed");
+    }
+}
+
+#[test]
+fn dropped_receiver_chain_is_not_recreated() -> Result<(), SignalProtocolError> {
+    // Ping-pong until the receiver has turned its ratchet more often than it keeps chains for,
+    // then deliver the first reply late.
+    let mut ops = vec![
+        Op::Send { from: 0, to: 0 },
+        Op::Deliver {
+            from: 0,
+            to: 0,
+            pick: 0,
+        },
+        Op::Send { from: 1, to: 0 },
+        Op::Send { from: 1, to: 0 },
+    ];
+    for _ in 0..MAX_RECEIVER_CHAINS + 1 {
+        ops.push(Op::Deliver {
+            from: 1,
+            to: 0,
+            pick: 1,
+        });
+        ops.push(Op::Send { from: 0, to: 0 });
+        ops.push(Op::Deliver {
+            from: 0,
+            to: 0,
+            pick: 0,
+        });
+        ops.push(Op::Send { from: 1, to: 0 });
+    }
+    ops.push(Op::Deliver {
+        from: 1,
+        to: 0,
+        pick: 0,
+    });
+
+    let world = run(2, &[Setup::LowerInitiates], &ops)?;
+    assert!(world.decrypted.contains(&2));
+    assert!(!world.decrypted.contains(&1));
+    Ok(())
+}
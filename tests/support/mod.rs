@@ -7,6 +7,12 @@
 
 use libsignal_protocol_rust::*;
 use rand::{rngs::OsRng, CryptoRng, Rng};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::SystemTime;
 
 pub fn test_in_memory_protocol_store() -> InMemSignalProtocolStore {
     let mut csprng = OsRng;
@@ -46,6 +52,7 @@ pub fn decrypt(
         &mut store.pre_key_store,
         &mut store.signed_pre_key_store,
         &mut csprng,
+        SystemTime::now(),
         None,
     )
 }
@@ -56,17 +63,21 @@ pub fn create_pre_key_bundle<R: Rng + CryptoRng>(
     mut csprng: &mut R,
 ) -> Result<PreKeyBundle, SignalProtocolError> {
     let pre_key_pair = KeyPair::generate(&mut csprng);
-    let signed_pre_key_pair = KeyPair::generate(&mut csprng);
-
-    let signed_pre_key_public = signed_pre_key_pair.public_key.serialize();
-    let signed_pre_key_signature = store
-        .get_identity_key_pair(None)?
-        .private_key()
-        .calculate_signature(&signed_pre_key_public, &mut csprng)?;
 
     let device_id: u32 = csprng.gen();
     let pre_key_id: u32 = csprng.gen();
     let signed_pre_key_id: u32 = csprng.gen();
+    let timestamp = csprng.gen();
+
+    let identity_key_signer = store.get_identity_key_signer(None)?;
+    let signed_pre_key = SignedPreKeyRecord::generate(
+        signed_pre_key_id,
+        timestamp,
+        &*identity_key_signer,
+        &mut csprng,
+    )?;
+    let identity_key = identity_key_signer.get_identity_key()?;
+    drop(identity_key_signer);
 
     let pre_key_bundle = PreKeyBundle::new(
         store.get_local_registration_id(None)?,
@@ -74,9 +85,9 @@ pub fn create_pre_key_bundle<R: Rng + CryptoRng>(
         Some(pre_key_id),
         Some(pre_key_pair.public_key),
         signed_pre_key_id,
-        signed_pre_key_pair.public_key,
-        signed_pre_key_signature.to_vec(),
-        *store.get_identity_key_pair(None)?.identity_key(),
+        signed_pre_key.public_key()?,
+        signed_pre_key.signature()?,
+        identity_key,
     )?;
 
     store.save_pre_key(
@@ -85,18 +96,7 @@ pub fn create_pre_key_bundle<R: Rng + CryptoRng>(
         None,
     )?;
 
-    let timestamp = csprng.gen();
-
-    store.save_signed_pre_key(
-        signed_pre_key_id,
-        &SignedPreKeyRecord::new(
-            signed_pre_key_id,
-            timestamp,
-            &signed_pre_key_pair,
-            &signed_pre_key_signature,
-        ),
-        None,
-    )?;
+    store.save_signed_pre_key(signed_pre_key_id, &signed_pre_key, None)?;
 
     Ok(pre_key_bundle)
 }
@@ -113,7 +113,7 @@ pub fn initialize_sessions_v3() -> Result<(SessionState, SessionState), SignalPr
     let bob_ephemeral_key = bob_base_key;
 
     let alice_params = AliceSignalProtocolParameters::new(
-        alice_identity,
+        &alice_identity,
         alice_base_key,
         *bob_identity.identity_key(),
         bob_base_key.public_key,
@@ -124,7 +124,7 @@ pub fn initialize_sessions_v3() -> Result<(SessionState, SessionState), SignalPr
     let alice_session = initialize_alice_session(&alice_params, &mut csprng)?;
 
     let bob_params = BobSignalProtocolParameters::new(
-        bob_identity,
+        &bob_identity,
         bob_base_key,
         None,
         bob_ephemeral_key,
@@ -136,3 +136,172 @@ pub fn initialize_sessions_v3() -> Result<(SessionState, SessionState), SignalPr
 
     Ok((alice_session, bob_session))
 }
+
+const EXTERNAL_SIGNER_SIGN: u8 = 0;
+const EXTERNAL_SIGNER_AGREE: u8 = 1;
+
+type ExternalSignerRequest = (Vec<u8>, mpsc::Sender<Option<Vec<u8>>>);
+
+/// An [`IdentityKeySigner`] standing in for a key held by another process. The key pair lives on
+/// its own thread, which only sees byte-encoded requests and answers with the signature or shared
+/// secret, so nothing else can read the private key.
+#[derive(Clone)]
+#[allow(dead_code)]
+pub struct ExternalIdentityKeySigner {
+    identity_key: IdentityKey,
+    requests: mpsc::Sender<ExternalSignerRequest>,
+    request_count: Rc<Cell<usize>>,
+}
+
+#[allow(dead_code)]
+impl ExternalIdentityKeySigner {
+    /// Moves `identity_key_pair` to a new thread, which signs with randomness from `csprng`.
+    pub fn spawn<R: CryptoRng + Rng + Send + 'static>(
+        identity_key_pair: IdentityKeyPair,
+        mut csprng: R,
+    ) -> Self {
+        let identity_key = *identity_key_pair.identity_key();
+        let (requests, receiver) = mpsc::channel::<ExternalSignerRequest>();
+
+        thread::spawn(move || {
+            let private_key = identity_key_pair.private_key();
+            for (request, reply) in receiver {
+                let result = match request.split_first() {
+                    Some((&EXTERNAL_SIGNER_SIGN, message)) => {
+                        private_key.calculate_signature(message, &mut csprng)
+                    }
+                    Some((&EXTERNAL_SIGNER_AGREE, their_key)) => PublicKey::deserialize(their_key)
+                        .and_then(|their_key| private_key.calculate_agreement(&their_key)),
+                    _ => Err(SignalProtocolError::InvalidArgument(
+                        "unknown signer request".to_owned(),
+                    )),
+                };
+                let _ = reply.send(result.ok().map(Vec::from));
+            }
+        });
+
+        Self {
+            identity_key,
+            requests,
+            request_count: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// The number of signatures and agreements requested through this signer and its clones.
+    pub fn request_count(&self) -> usize {
+        self.request_count.get()
+    }
+
+    fn request(&self, op: u8, payload: &[u8]) -> Result<Box<[u8]>, SignalProtocolError> {
+        self.request_count.set(self.request_count.get() + 1);
+
+        let mut request = vec![op];
+        request.extend_from_slice(payload);
+        let (reply, response) = mpsc::channel();
+        let disconnected =
+            || SignalProtocolError::InvalidState("external signer", "disconnected".to_owned());
+        self.requests
+            .send((request, reply))
+            .map_err(|_| disconnected())?;
+        match response.recv().map_err(|_| disconnected())? {
+            Some(result) => Ok(result.into_boxed_slice()),
+            None => Err(SignalProtocolError::InvalidState(
+                "external signer",
+                "request failed".to_owned(),
+            )),
+        }
+    }
+}
+
+impl IdentityKeySigner for ExternalIdentityKeySigner {
+    fn get_identity_key(&self) -> Result<IdentityKey, SignalProtocolError> {
+        Ok(self.identity_key)
+    }
+
+    fn calculate_signature(
+        &self,
+        message: &[u8],
+        _csprng: &mut dyn SignerRng,
+    ) -> Result<Box<[u8]>, SignalProtocolError> {
+        self.request(EXTERNAL_SIGNER_SIGN, message)
+    }
+
+    fn calculate_agreement(&self, their_key: &PublicKey) -> Result<Box<[u8]>, SignalProtocolError> {
+        self.request(EXTERNAL_SIGNER_AGREE, &their_key.serialize())
+    }
+}
+
+/// An identity store whose private key is only reachable through an
+/// [`ExternalIdentityKeySigner`]. Remote identities are trusted on first use, as in
+/// [`InMemIdentityKeyStore`].
+#[allow(dead_code)]
+pub struct ExternalIdentityKeyStore {
+    signer: ExternalIdentityKeySigner,
+    id: u32,
+    known_keys: HashMap<ProtocolAddress, IdentityKey>,
+}
+
+#[allow(dead_code)]
+impl ExternalIdentityKeyStore {
+    pub fn new(signer: ExternalIdentityKeySigner, id: u32) -> Self {
+        Self {
+            signer,
+            id,
+            known_keys: HashMap::new(),
+        }
+    }
+}
+
+impl IdentityKeyStore for ExternalIdentityKeyStore {
+    fn get_identity_key_pair(&self, _ctx: Context) -> Result<IdentityKeyPair, SignalProtocolError> {
+        Err(SignalProtocolError::InvalidState(
+            "get_identity_key_pair",
+            "identity private key is held externally".to_owned(),
+        ))
+    }
+
+    fn get_identity_key_signer(
+        &self,
+        _ctx: Context,
+    ) -> Result<Box<dyn IdentityKeySigner + '_>, SignalProtocolError> {
+        Ok(Box::new(self.signer.clone()))
+    }
+
+    fn get_local_registration_id(&self, _ctx: Context) -> Result<u32, SignalProtocolError> {
+        Ok(self.id)
+    }
+
+    fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        // True only if a different key was replaced.
+        match self.known_keys.insert(address.clone(), *identity) {
+            Some(previous) => Ok(previous != *identity),
+            None => Ok(false),
+        }
+    }
+
+    fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _direction: Direction,
+        _ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        match self.known_keys.get(address) {
+            None => Ok(true), // first use
+            Some(k) => Ok(k == identity),
+        }
+    }
+
+    fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        Ok(self.known_keys.get(address).copied())
+    }
+}
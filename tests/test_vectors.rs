@@ -0,0 +1,73 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: GPL-3.0-only
+//
+
+#![cfg(feature = "test-vectors")]
+
+use libsignal_protocol_rust::*;
+
+fn exchanges() -> Vec<(TranscriptParty, &'static [u8])> {
+    vec![
+        (TranscriptParty::Alice, b"hi bob"),
+        (TranscriptParty::Alice, b"are you there?"),
+        (TranscriptParty::Bob, b"hello alice"),
+        (TranscriptParty::Alice, b"this one ratchets"),
+        (TranscriptParty::Bob, b"and back again"),
+    ]
+}
+
+#[test]
+fn transcripts_are_reproducible() -> Result<(), SignalProtocolError> {
+    let transcript = record_session_transcript([7u8; 32], &exchanges())?;
+    assert_eq!(
+        transcript,
+        record_session_transcript([7u8; 32], &exchanges())?
+    );
+    assert_ne!(
+        transcript,
+        record_session_transcript([8u8; 32], &exchanges())?
+    );
+
+    // Each message gives an encrypt and a decrypt case.
+    assert_eq!(transcript.matches("\"message_type\": 3").count(), 4);
+    assert_eq!(transcript.matches("\"message_type\": 2").count(), 6);
+
+    replay_session_transcript(&transcript)?;
+    Ok(())
+}
+
+#[test]
+fn replay_detects_altered_transcripts() -> Result<(), SignalProtocolError> {
+    let transcript = record_session_transcript([7u8; 32], &exchanges())?;
+
+    let flip_ciphertext = |at: usize| {
+        let at = at + "\"ciphertext\": \"".len();
+        let flipped = if &transcript[at..=at] == "0" {
+            "1"
+        } else {
+            "0"
+        };
+        let mut altered = transcript.clone();
+        altered.replace_range(at..=at, flipped);
+        altered
+    };
+
+    // The first case encrypts, so it no longer reproduces the recorded ciphertext.
+    let first = transcript.find("\"ciphertext\": \"").unwrap();
+    assert!(matches!(
+        replay_session_transcript(&flip_ciphertext(first)),
+        Err(SignalProtocolError::InvalidState(
+            "replay_session_transcript",
+            _
+        ))
+    ));
+    // The last case decrypts, so the altered message is rejected.
+    let last = transcript.rfind("\"ciphertext\": \"").unwrap();
+    assert!(replay_session_transcript(&flip_ciphertext(last)).is_err());
+
+    assert!(replay_session_transcript("{}").is_err());
+    Ok(())
+}